@@ -1,32 +1,46 @@
-use std::rc::Rc;
+use std::{rc::Rc, time::Instant};
 
 use glam::{EulerRot, Quat, Vec3};
 use winit::{
     application::ApplicationHandler,
-    event::WindowEvent,
+    event::{ElementState, WindowEvent},
     event_loop::{ActiveEventLoop, EventLoop},
+    keyboard::{KeyCode, PhysicalKey},
     window::WindowId,
 };
 
+use super::free_fly::FreeFly;
 use crate::{
     core::{Camera, ParticleInitData, ParticlePingPongBuffer},
-    systems::{RenderSystem, SimulationConfig, SimulationSystem},
+    systems::{
+        ColorMode, Colormap, DensityAlphaRange, Follow, Msaa, RenderMode, RenderSystem,
+        SimulationConfig, SimulationSystem, SpriteTexture,
+    },
     utils::VulkanoBackend,
 };
 
+/// Key pausing and resuming the simulation
+const PAUSE_KEY: KeyCode = KeyCode::Space;
+/// Key advancing a paused simulation by one step
+const SINGLE_STEP_KEY: KeyCode = KeyCode::Period;
+
 pub struct App {
     vulkano_backend: Rc<VulkanoBackend>,
     render_system: RenderSystem,
     simulation_system: SimulationSystem,
     camera: Camera,
     particles: ParticlePingPongBuffer,
+    free_fly: FreeFly,
+    last_frame: Option<Instant>,
 }
 
 impl App {
     pub fn new(event_loop: &EventLoop<()>) -> Self {
         let vulkano_backend = VulkanoBackend::new(event_loop);
-        let render_system = RenderSystem::new();
-        let simulation_system = SimulationSystem::new(SimulationConfig::default());
+        let simulation_config = SimulationConfig::default();
+        let mut render_system = RenderSystem::new(RenderMode::Points);
+        render_system.set_grid_size(simulation_config.grid_size);
+        let simulation_system = SimulationSystem::new(simulation_config);
 
         let camera = Camera::new(
             Vec3::new(0., 3., 3.),
@@ -43,9 +57,102 @@ impl App {
             simulation_system,
             camera,
             particles,
+            free_fly: FreeFly::default(),
+            last_frame: None,
+        }
+    }
+
+    /// Pause, resume or single-step the simulation on a key press
+    fn handle_simulation_key(&mut self, key: KeyCode, state: ElementState) {
+        if state != ElementState::Pressed {
+            return;
+        }
+        match key {
+            PAUSE_KEY => {
+                let paused = self.simulation_system.paused();
+                self.simulation_system.set_paused(!paused);
+            }
+            SINGLE_STEP_KEY => self.simulation_system.step_once(),
+            _ => {}
         }
     }
 
+    /// Free-fly camera speed in world units per second
+    pub fn set_camera_speed(&mut self, speed: f32) {
+        self.free_fly.set_speed(speed);
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_system.render_mode()
+    }
+
+    /// Draw particles as points, impostors or sphere meshes
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_system.set_render_mode(render_mode);
+    }
+
+    /// Scale particle alpha by density over `range`; `None` draws opaque
+    pub fn set_density_alpha_range(&mut self, range: Option<DensityAlphaRange>) {
+        self.render_system.set_density_alpha_range(range);
+    }
+
+    /// Shade a smoothed water surface instead of the individual particles
+    pub fn set_surface_rendering(&mut self, surface_rendering: bool) {
+        self.render_system.set_surface_rendering(surface_rendering);
+    }
+
+    /// Keep point sprites between `min` and `max` pixels after distance scaling
+    pub fn set_point_size_range(&mut self, min: f32, max: f32) {
+        self.render_system.set_point_size_range(min, max);
+    }
+
+    /// World-space radius particles are drawn with
+    pub fn set_particle_radius(&mut self, particle_radius: f32) {
+        self.render_system.set_particle_radius(particle_radius);
+    }
+
+    /// Color particles flatly or by speed
+    pub fn set_color_mode(&mut self, color_mode: ColorMode) {
+        self.render_system.set_color_mode(color_mode);
+    }
+
+    /// Gradient speeds are mapped through in [`ColorMode::Velocity`]
+    pub fn set_colormap(&mut self, colormap: Colormap) {
+        self.render_system.set_colormap(colormap);
+    }
+
+    /// Multiply point sprites with tightly packed RGBA8 sRGB pixels, row by row
+    pub fn set_sprite_texture(&mut self, extent: [u32; 2], pixels: &[u8]) {
+        let sprite_texture = SpriteTexture::from_rgba8(
+            self.vulkano_backend.device(),
+            self.vulkano_backend.memory_allocator(),
+            self.vulkano_backend.as_ref(),
+            extent,
+            pixels,
+        );
+        self.render_system.set_sprite_texture(sprite_texture);
+    }
+
+    /// Multisample the particle render, lowered to what the device supports
+    pub fn set_msaa(&mut self, msaa: Msaa) {
+        self.render_system.set_msaa(msaa);
+    }
+
+    /// Choose what the camera tracks
+    pub fn set_follow_target(&mut self, follow_target: Follow) {
+        self.render_system.set_follow_target(follow_target);
+    }
+
+    /// Time constant in seconds of the camera follow smoothing, zero to follow rigidly
+    pub fn set_follow_damping(&mut self, damping: f32) {
+        self.render_system.set_follow_damping(damping);
+    }
+
+    /// Toggle the wireframe overlay of grid cells occupied by particles
+    pub fn set_show_grid(&mut self, show_grid: bool) {
+        self.render_system.set_show_grid(show_grid);
+    }
+
     pub fn init(&mut self, event_loop: &ActiveEventLoop) {
         self.simulation_system.init(&self.vulkano_backend);
         self.render_system.init(event_loop, &self.vulkano_backend);
@@ -53,15 +160,18 @@ impl App {
             &[
                 ParticleInitData {
                     position: Vec3::new(0.5, 0.0, 0.5),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.5, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(-0.5, 0., -0.5),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
             ],
             self.vulkano_backend.memory_allocator(),
@@ -90,14 +200,35 @@ impl ApplicationHandler for App {
             WindowEvent::Resized(_) => {
                 self.render_system.request_recreate_swapchain();
             }
+            WindowEvent::KeyboardInput { event, .. } => {
+                if let PhysicalKey::Code(key) = event.physical_key {
+                    if !event.repeat && !self.free_fly.handle_key(key, event.state) {
+                        self.handle_simulation_key(key, event.state);
+                    }
+                }
+            }
             WindowEvent::RedrawRequested => {
                 self.particles.swap(self.vulkano_backend.as_ref());
                 self.update();
 
+                let now = Instant::now();
+                let dt = self
+                    .last_frame
+                    .map_or(0.0, |last| now.duration_since(last).as_secs_f32());
+                self.last_frame = Some(now);
+                self.free_fly.update(&mut self.camera, dt);
+
                 self.simulation_system.update(
                     self.vulkano_backend.descriptor_set_allocator(),
                     self.particles.dst(),
                 );
+                if self.render_system.follow_target() == Follow::CenterOfMass {
+                    let center_of_mass = self.simulation_system.center_of_mass(
+                        self.vulkano_backend.descriptor_set_allocator(),
+                        self.particles.dst(),
+                    );
+                    self.render_system.follow(&mut self.camera, center_of_mass);
+                }
                 self.render_system
                     .render(&self.camera, self.particles.src());
             }
@@ -0,0 +1,95 @@
+use std::time::Instant;
+
+use glam::Vec3;
+
+use crate::{
+    core::{scenes, Aabb, ParticleInitData},
+    systems::{HeadlessSimulation, SimulationConfig, SimulationStepTiming},
+};
+
+/// Frames between printed timing reports
+const REPORT_INTERVAL: u64 = 100;
+
+/// Physics-only loop on the headless backend, for profiling where no window can be opened
+pub struct BenchmarkApp {
+    simulation: HeadlessSimulation,
+    frame: u64,
+}
+
+impl Default for BenchmarkApp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BenchmarkApp {
+    /// A block of water dropped into the default simulation bounds
+    pub fn new() -> Self {
+        let particles_init_data = scenes::fill_predicate(
+            Aabb::new(Vec3::new(-0.5, -0.5, -0.5), Vec3::new(0.5, 0.5, 0.5)),
+            0.05,
+            |_| true,
+        );
+        Self::with_scene(SimulationConfig::default(), &particles_init_data)
+    }
+
+    pub fn with_scene(config: SimulationConfig, particles_init_data: &[ParticleInitData]) -> Self {
+        Self {
+            simulation: HeadlessSimulation::new(config, particles_init_data),
+            frame: 0,
+        }
+    }
+
+    /// Advance one fixed step of `max_time_step`, printing a report every `REPORT_INTERVAL` frames
+    pub fn step(&mut self) -> SimulationStepTiming {
+        let dt = self.simulation.config().max_time_step;
+        let timing = self.simulation.step_with_timing(dt);
+        self.frame += 1;
+        if self.frame.is_multiple_of(REPORT_INTERVAL) {
+            println!("Frame {}", self.frame);
+            timing.print_detailed(self.simulation.particles().count());
+        }
+        timing
+    }
+
+    pub fn run(&mut self, frames: u64) {
+        let start = Instant::now();
+        for _ in 0..frames {
+            self.step();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        println!(
+            "{} frames of {} particles in {:.2}s ({:.1} steps/s)",
+            frames,
+            self.simulation.particles().count(),
+            elapsed,
+            frames as f64 / elapsed
+        );
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headless_app_steps_without_a_window() {
+        let particles_init_data =
+            scenes::fill_predicate(Aabb::new(Vec3::ZERO, Vec3::splat(0.2)), 0.05, |_| true);
+        let mut app = BenchmarkApp::with_scene(SimulationConfig::default(), &particles_init_data);
+
+        for _ in 0..3 {
+            let timing = app.step();
+            assert!(timing.total_time >= timing.gravity_time);
+        }
+
+        assert_eq!(app.frame(), 3);
+        let positions = app.simulation.positions();
+        assert_eq!(positions.len(), particles_init_data.len());
+        assert!(positions.iter().all(|p| p.is_finite()));
+    }
+}
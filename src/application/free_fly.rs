@@ -0,0 +1,132 @@
+use glam::Vec3;
+use winit::{event::ElementState, keyboard::KeyCode};
+
+use crate::core::Camera;
+
+/// Key switching free-fly movement on and off
+pub(crate) const FREE_FLY_TOGGLE_KEY: KeyCode = KeyCode::KeyF;
+
+/// Camera-local direction of each movement key; the camera looks down -Z
+const MOVEMENT_KEYS: [(KeyCode, Vec3); 6] = [
+    (KeyCode::KeyW, Vec3::NEG_Z),
+    (KeyCode::KeyS, Vec3::Z),
+    (KeyCode::KeyA, Vec3::NEG_X),
+    (KeyCode::KeyD, Vec3::X),
+    (KeyCode::KeyQ, Vec3::NEG_Y),
+    (KeyCode::KeyE, Vec3::Y),
+];
+
+/// Free-fly camera movement along the camera's own axes on WASD/QE
+///
+/// Held keys are tracked from key events and applied once per frame, scaled by the frame
+/// time so the speed does not depend on the frame rate. Diagonals are normalized, so
+/// holding two keys is no faster than one.
+#[derive(Clone, Copy, Debug)]
+pub struct FreeFly {
+    enabled: bool,
+    /// World units per second
+    speed: f32,
+    /// Bit `i` is set while the key `MOVEMENT_KEYS[i]` is held
+    held: u8,
+}
+
+impl Default for FreeFly {
+    fn default() -> Self {
+        Self::new(2.0)
+    }
+}
+
+impl FreeFly {
+    pub fn new(speed: f32) -> Self {
+        Self {
+            enabled: false,
+            speed: speed.max(0.0),
+            held: 0,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    #[allow(unused)]
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.max(0.0);
+    }
+
+    /// Track a key event, returning whether free-fly handled the key
+    pub fn handle_key(&mut self, key: KeyCode, state: ElementState) -> bool {
+        if key == FREE_FLY_TOGGLE_KEY {
+            if state == ElementState::Pressed {
+                self.enabled = !self.enabled;
+            }
+            return true;
+        }
+
+        let Some(index) = MOVEMENT_KEYS.iter().position(|&(code, _)| code == key) else {
+            return false;
+        };
+        match state {
+            ElementState::Pressed => self.held |= 1 << index,
+            ElementState::Released => self.held &= !(1 << index),
+        }
+        true
+    }
+
+    /// Move `camera` by the held keys over `dt` seconds, doing nothing while disabled
+    pub fn update(&self, camera: &mut Camera, dt: f32) {
+        if !self.enabled {
+            return;
+        }
+
+        let direction = MOVEMENT_KEYS
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| self.held & (1 << index) != 0)
+            .map(|(_, &(_, direction))| direction)
+            .sum::<Vec3>()
+            .normalize_or_zero();
+        camera.translate_local(direction * self.speed * dt.max(0.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Quat;
+
+    #[test]
+    fn test_forward_moves_along_view_direction() {
+        let mut camera = Camera::new(Vec3::ZERO, Quat::IDENTITY, 60.0, 0.1, 100.0);
+        camera.look_at(Vec3::new(0.0, 3.0, 3.0), Vec3::ZERO, Vec3::Y);
+        let start = camera.position();
+        let view_direction = (Vec3::ZERO - start).normalize();
+
+        let mut free_fly = FreeFly::new(4.0);
+        free_fly.handle_key(KeyCode::KeyW, ElementState::Pressed);
+        // Held keys do nothing until free-fly is toggled on
+        free_fly.update(&mut camera, 0.5);
+        assert_eq!(camera.position(), start);
+
+        assert!(free_fly.handle_key(FREE_FLY_TOGGLE_KEY, ElementState::Pressed));
+        free_fly.update(&mut camera, 0.5);
+        let moved = camera.position() - start;
+        assert!(
+            moved.abs_diff_eq(view_direction * 2.0, 1e-5),
+            "{} vs {}",
+            moved,
+            view_direction * 2.0
+        );
+
+        // Releasing the key stops the camera
+        free_fly.handle_key(KeyCode::KeyW, ElementState::Released);
+        let stopped = camera.position();
+        free_fly.update(&mut camera, 0.5);
+        assert_eq!(camera.position(), stopped);
+    }
+}
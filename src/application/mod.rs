@@ -1,3 +1,6 @@
 mod app;
+mod benchmark;
+mod free_fly;
 
-pub(crate) use app::App;
+pub use app::App;
+pub use benchmark::BenchmarkApp;
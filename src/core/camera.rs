@@ -1,4 +1,6 @@
-use glam::{Mat4, Quat, Vec3};
+use glam::{Mat3, Mat4, Quat, Vec3};
+
+use super::geometry::Aabb;
 
 pub struct Camera {
     position: Vec3,
@@ -19,6 +21,56 @@ impl Camera {
         }
     }
 
+    /// Move to `eye` and turn to face `target`, keeping `up` above the view
+    ///
+    /// Falls back to another up axis when `up` is parallel to the view direction.
+    #[allow(unused)]
+    pub fn look_at(&mut self, eye: Vec3, target: Vec3, up: Vec3) {
+        let forward = (target - eye).normalize_or(-Vec3::Z);
+        let right = forward
+            .cross(up)
+            .try_normalize()
+            .unwrap_or_else(|| forward.any_orthonormal_vector());
+        let camera_up = right.cross(forward);
+
+        self.position = eye;
+        // Local +X is right, +Y is up and the camera looks down -Z
+        self.rotation = Quat::from_mat3(&Mat3::from_cols(right, camera_up, -forward));
+    }
+
+    /// Back away from the center of `aabb` along the current view direction until the
+    /// whole box fits in view at `aspect_ratio`
+    #[allow(unused)]
+    pub fn frame_aabb(&mut self, aabb: Aabb, aspect_ratio: f32) {
+        let center = (aabb.min() + aabb.max()) * 0.5;
+        let radius = (aabb.max() - aabb.min()).length() * 0.5;
+
+        // The bounding sphere must fit the narrower of the two fields of view
+        let half_fov_y = self.fov.to_radians() * 0.5;
+        let half_fov_x = (half_fov_y.tan() * aspect_ratio).atan();
+        let distance = radius / half_fov_y.min(half_fov_x).sin();
+
+        let forward = self.rotation * -Vec3::Z;
+        let up = self.rotation * Vec3::Y;
+        self.look_at(center - forward * distance, center, up);
+    }
+
+    #[allow(unused)]
+    pub fn position(&self) -> Vec3 {
+        self.position
+    }
+
+    /// Move without turning
+    pub fn translate(&mut self, offset: Vec3) {
+        self.position += offset;
+    }
+
+    /// Move without turning by `offset` in camera space, where +X is right, +Y is up and
+    /// the camera looks down -Z
+    pub fn translate_local(&mut self, offset: Vec3) {
+        self.position += self.rotation * offset;
+    }
+
     pub fn view_matrix(&self) -> Mat4 {
         let dir = self.rotation * -Vec3::Z;
         let up = self.rotation * -Vec3::Y;
@@ -30,3 +82,57 @@ impl Camera {
         Mat4::perspective_rh(fov_radians, aspect_ratio, self.near_plane, self.far_plane)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        Camera::new(Vec3::ZERO, Quat::IDENTITY, 60.0, 0.1, 100.0)
+    }
+
+    #[test]
+    fn test_look_at_points_forward_axis_from_eye_to_target() {
+        let mut camera = camera();
+        let (eye, target) = (Vec3::new(0.0, 3.0, 3.0), Vec3::new(0.5, 0.0, -0.5));
+        camera.look_at(eye, target, Vec3::Y);
+
+        // In view space the camera looks down -Z, so the target lies on that axis
+        let view = camera.view_matrix();
+        let target_view = view.transform_point3(target);
+        assert!(target_view.truncate().length() < 1e-5, "{:?}", target_view);
+        assert!((target_view.z + eye.distance(target)).abs() < 1e-4);
+        assert!(view.transform_point3(eye).length() < 1e-5);
+
+        // Looking straight down still yields a valid orientation
+        camera.look_at(Vec3::Y, Vec3::ZERO, Vec3::Y);
+        assert!(camera.view_matrix().is_finite());
+    }
+
+    #[test]
+    fn test_frame_aabb_keeps_every_corner_in_view() {
+        let aabb = Aabb::new(Vec3::new(-2.0, -1.0, -2.0), Vec3::new(2.0, 3.0, 2.0));
+        for aspect_ratio in [0.5, 16.0 / 9.0] {
+            let mut camera = camera();
+            camera.look_at(Vec3::new(0.0, 3.0, 3.0), Vec3::ZERO, Vec3::Y);
+            camera.frame_aabb(aabb, aspect_ratio);
+
+            let clip = camera.projection_matrix(aspect_ratio) * camera.view_matrix();
+            for i in 0..8 {
+                let corner = Vec3::select(
+                    glam::BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0),
+                    aabb.max(),
+                    aabb.min(),
+                );
+                let ndc = clip.project_point3(corner);
+                assert!(
+                    ndc.x.abs() <= 1.0 && ndc.y.abs() <= 1.0,
+                    "aspect {} corner {:?} at {:?}",
+                    aspect_ratio,
+                    corner,
+                    ndc
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+
+use glam::{IVec3, Vec3};
+
+use super::{geometry::Aabb, particle::ParticleInitData};
+
+/// Longest frame interval counted toward spawning, so a stall does not release a burst
+const DEFAULT_MAX_FRAME_TIME: f32 = 0.25;
+
+/// Region new particles are spawned over, relative to the emitter position
+#[derive(Clone, Copy, Debug)]
+pub enum EmissionShape {
+    Point,
+    /// Horizontal disk in the XZ plane, centered on the emitter
+    Disk {
+        radius: f32,
+    },
+    /// Segment between two offsets from the emitter
+    Line {
+        a: Vec3,
+        b: Vec3,
+    },
+    /// Box given as offsets from the emitter
+    Box {
+        aabb: Aabb,
+    },
+    /// Ball centered on the emitter
+    Sphere {
+        radius: f32,
+    },
+    /// Solid cone with its apex on the emitter, opening along `axis` by `half_angle`
+    /// radians up to `height`
+    Cone {
+        axis: Vec3,
+        half_angle: f32,
+        height: f32,
+    },
+}
+
+impl EmissionShape {
+    /// Map a sample in the unit cube onto the shape, uniformly over its area or volume
+    pub fn sample(&self, u: Vec3) -> Vec3 {
+        match *self {
+            EmissionShape::Point => Vec3::ZERO,
+            EmissionShape::Disk { radius } => {
+                let r = radius * u.x.sqrt();
+                let theta = std::f32::consts::TAU * u.y;
+                Vec3::new(r * theta.cos(), 0.0, r * theta.sin())
+            }
+            EmissionShape::Line { a, b } => a.lerp(b, u.x),
+            EmissionShape::Box { aabb } => aabb.min() + (aabb.max() - aabb.min()) * u,
+            EmissionShape::Sphere { radius } => {
+                let r = radius * u.x.cbrt();
+                let cos_theta = 1.0 - 2.0 * u.y;
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = std::f32::consts::TAU * u.z;
+                r * Vec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin())
+            }
+            EmissionShape::Cone {
+                axis,
+                half_angle,
+                height,
+            } => {
+                // Cross sections grow with the square of the distance from the apex
+                let axis = axis.normalize_or(Vec3::Y);
+                let (tangent, bitangent) = axis.any_orthonormal_pair();
+                let h = height * u.x.cbrt();
+                let r = h * half_angle.tan() * u.y.sqrt();
+                let phi = std::f32::consts::TAU * u.z;
+                axis * h + r * (tangent * phi.cos() + bitangent * phi.sin())
+            }
+        }
+    }
+}
+
+/// Spawns particles at a fixed rate over an [`EmissionShape`]
+///
+/// The rate is per second of frame time, so the spawn count does not depend on the frame
+/// rate. Frame time is capped separately from the physics step clamp, whose lower bound
+/// would otherwise overcount at high frame rates.
+pub struct Emitter {
+    position: Vec3,
+    shape: EmissionShape,
+    velocity: Vec3,
+    particles_per_second: f32,
+    /// Each velocity component is offset by up to this much in either direction
+    velocity_jitter: f32,
+    /// Seconds of counted frame time the emitter spawns for, forever when `None`
+    lifetime: Option<f32>,
+    /// Frame time counted so far
+    elapsed: f32,
+    max_frame_time: f32,
+    /// Fractional particles carried over to the next frame
+    pending: f32,
+    /// Spawn positions closer than this to another particle are skipped
+    min_spawn_distance: Option<f32>,
+    rng_state: u32,
+}
+
+impl Emitter {
+    pub fn new(
+        position: Vec3,
+        shape: EmissionShape,
+        velocity: Vec3,
+        particles_per_second: f32,
+    ) -> Self {
+        Self {
+            position,
+            shape,
+            velocity,
+            particles_per_second: particles_per_second.max(0.0),
+            velocity_jitter: 0.0,
+            lifetime: None,
+            elapsed: 0.0,
+            max_frame_time: DEFAULT_MAX_FRAME_TIME,
+            pending: 0.0,
+            min_spawn_distance: None,
+            rng_state: 0x9E37_79B9,
+        }
+    }
+
+    /// Skip spawn positions within `distance` of existing or newly spawned particles
+    pub fn with_min_spawn_distance(mut self, distance: f32) -> Self {
+        self.min_spawn_distance = Some(distance);
+        self
+    }
+
+    /// Randomize each spawned velocity by up to `jitter` per component
+    pub fn with_velocity_jitter(mut self, jitter: f32) -> Self {
+        self.velocity_jitter = jitter.abs();
+        self
+    }
+
+    /// Stop spawning after `lifetime` seconds of counted frame time
+    pub fn with_lifetime(mut self, lifetime: f32) -> Self {
+        self.lifetime = Some(lifetime.max(0.0));
+        self
+    }
+
+    /// Whether the lifetime has run out, so the emitter will not spawn again
+    pub fn is_finished(&self) -> bool {
+        self.lifetime
+            .is_some_and(|lifetime| self.elapsed >= lifetime)
+    }
+
+    /// Cap on the frame interval counted per [`Self::emit`] call, in seconds
+    pub fn with_max_frame_time(mut self, max_frame_time: f32) -> Self {
+        self.max_frame_time = max_frame_time.max(0.0);
+        self
+    }
+
+    pub fn shape(&self) -> EmissionShape {
+        self.shape
+    }
+
+    pub fn set_shape(&mut self, shape: EmissionShape) {
+        self.shape = shape;
+    }
+
+    /// Particles to spawn for a frame lasting `frame_time` seconds
+    pub fn emit(&mut self, frame_time: f32) -> Vec<ParticleInitData> {
+        let mut frame_time = frame_time.clamp(0.0, self.max_frame_time);
+        if let Some(lifetime) = self.lifetime {
+            frame_time = frame_time.min((lifetime - self.elapsed).max(0.0));
+        }
+        self.elapsed += frame_time;
+        self.pending += self.particles_per_second * frame_time;
+        let count = self.pending.floor();
+        self.pending -= count;
+
+        (0..count as u32)
+            .map(|_| {
+                let u = Vec3::new(self.next_unit(), self.next_unit(), self.next_unit());
+                let position = self.position + self.shape.sample(u);
+                let mut velocity = self.velocity;
+                if self.velocity_jitter > 0.0 {
+                    let v = Vec3::new(self.next_unit(), self.next_unit(), self.next_unit());
+                    velocity += (2.0 * v - 1.0) * self.velocity_jitter;
+                }
+                ParticleInitData {
+                    position,
+                    velocity,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    /// Particles to spawn this frame, leaving out those that would overlap a particle
+    ///
+    /// Without a minimum spawn distance this is [`Self::emit`]. Otherwise skipped particles
+    /// are not made up for, so an emitter inside a filled region spawns nothing.
+    pub fn emit_avoiding(
+        &mut self,
+        frame_time: f32,
+        existing_positions: &[Vec3],
+    ) -> Vec<ParticleInitData> {
+        let Some(min_spawn_distance) = self.min_spawn_distance else {
+            return self.emit(frame_time);
+        };
+
+        let mut grid = SpawnGrid::new(min_spawn_distance);
+        for &position in existing_positions {
+            grid.insert(position);
+        }
+        let mut emitted = self.emit(frame_time);
+        emitted.retain(|particle| {
+            if grid.query_sphere(particle.position, min_spawn_distance) {
+                return false;
+            }
+            grid.insert(particle.position);
+            true
+        });
+        emitted
+    }
+
+    /// Xorshift sample in [0, 1)
+    fn next_unit(&mut self) -> f32 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state = x;
+        (x >> 8) as f32 / (1u32 << 24) as f32
+    }
+}
+
+/// Uniform hash grid of points, with cells as wide as the query radius
+struct SpawnGrid {
+    cell_size: f32,
+    cells: HashMap<IVec3, Vec<Vec3>>,
+}
+
+impl SpawnGrid {
+    fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size: cell_size.max(f32::EPSILON),
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, position: Vec3) -> IVec3 {
+        (position / self.cell_size).floor().as_ivec3()
+    }
+
+    fn insert(&mut self, position: Vec3) {
+        self.cells
+            .entry(self.cell(position))
+            .or_default()
+            .push(position);
+    }
+
+    /// Whether any point lies closer than `radius` to `center`; `radius` must not exceed the cell size
+    fn query_sphere(&self, center: Vec3, radius: f32) -> bool {
+        let cell = self.cell(center);
+        (-1..=1)
+            .flat_map(|z| (-1..=1).flat_map(move |y| (-1..=1).map(move |x| IVec3::new(x, y, z))))
+            .filter_map(|offset| self.cells.get(&(cell + offset)))
+            .flatten()
+            .any(|point| point.distance_squared(center) < radius * radius)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::UVec3;
+
+    const ORIGIN: Vec3 = Vec3::new(0.5, 1.0, -0.5);
+
+    fn emit_many(shape: EmissionShape) -> Vec<ParticleInitData> {
+        // 64 particles per quarter second frame
+        let mut emitter = Emitter::new(ORIGIN, shape, Vec3::new(0.0, -1.0, 0.0), 256.0);
+        (0..8).flat_map(|_| emitter.emit(0.25)).collect()
+    }
+
+    #[test]
+    fn test_point_emission_stays_at_emitter() {
+        let emitted = emit_many(EmissionShape::Point);
+        assert_eq!(emitted.len(), 512);
+        for particle in emitted {
+            assert_eq!(particle.position, ORIGIN);
+            assert_eq!(particle.velocity, Vec3::new(0.0, -1.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn test_disk_emission_lies_within_radius() {
+        let radius = 0.3;
+        for particle in emit_many(EmissionShape::Disk { radius }) {
+            let offset = particle.position - ORIGIN;
+            assert!(offset.y.abs() < 1e-6);
+            assert!(offset.length() <= radius + 1e-5, "{:?}", offset);
+        }
+    }
+
+    #[test]
+    fn test_line_emission_lies_on_segment() {
+        let (a, b) = (Vec3::new(-0.2, 0.0, 0.0), Vec3::new(0.2, 0.1, 0.3));
+        for particle in emit_many(EmissionShape::Line { a, b }) {
+            let offset = particle.position - ORIGIN;
+            let t = (offset - a).dot(b - a) / (b - a).length_squared();
+            assert!((0.0..=1.0).contains(&t));
+            assert!(offset.distance(a.lerp(b, t)) < 1e-5, "{:?}", offset);
+        }
+    }
+
+    #[test]
+    fn test_box_emission_lies_within_aabb() {
+        let aabb = Aabb::new(Vec3::new(-0.1, 0.0, -0.2), Vec3::new(0.1, 0.3, 0.2));
+        let world_aabb = Aabb::new(ORIGIN + aabb.min(), ORIGIN + aabb.max());
+        for particle in emit_many(EmissionShape::Box { aabb }) {
+            assert!(
+                world_aabb.contains(particle.position),
+                "{:?}",
+                particle.position
+            );
+        }
+    }
+
+    #[test]
+    fn test_sphere_emission_lies_within_radius() {
+        let radius = 0.25;
+        for particle in emit_many(EmissionShape::Sphere { radius }) {
+            let offset = particle.position - ORIGIN;
+            assert!(offset.length() <= radius + 1e-5, "{:?}", offset);
+        }
+    }
+
+    #[test]
+    fn test_cone_emission_lies_within_cone() {
+        let (axis, half_angle, height) = (Vec3::new(1.0, 1.0, 0.0), 0.3f32, 0.5);
+        let emitted = emit_many(EmissionShape::Cone {
+            axis,
+            half_angle,
+            height,
+        });
+        let axis = axis.normalize();
+        for particle in &emitted {
+            let offset = particle.position - ORIGIN;
+            let along = offset.dot(axis);
+            assert!((0.0..=height + 1e-5).contains(&along), "{:?}", offset);
+            let across = (offset - axis * along).length();
+            assert!(across <= along * half_angle.tan() + 1e-5, "{:?}", offset);
+        }
+
+        // Most of the volume is toward the base
+        let near_base = emitted
+            .iter()
+            .filter(|p| (p.position - ORIGIN).dot(axis) > height * 0.5)
+            .count();
+        assert!(near_base > emitted.len() * 3 / 4, "{}", near_base);
+    }
+
+    #[test]
+    fn test_velocity_jitter_stays_within_bounds() {
+        let velocity = Vec3::new(0.0, -1.0, 0.0);
+        let mut emitter =
+            Emitter::new(ORIGIN, EmissionShape::Point, velocity, 256.0).with_velocity_jitter(0.1);
+        let emitted = emitter.emit(0.25);
+        assert!(emitted
+            .iter()
+            .all(|p| (p.velocity - velocity).abs().max_element() <= 0.1));
+        assert!(emitted.iter().any(|p| p.velocity != velocity));
+    }
+
+    #[test]
+    fn test_lifetime_stops_emission() {
+        let mut emitter =
+            Emitter::new(ORIGIN, EmissionShape::Point, Vec3::ZERO, 100.0).with_lifetime(0.5);
+        let count = (0..4).map(|_| emitter.emit(0.25).len()).sum::<usize>();
+        assert_eq!(count, 50);
+        assert!(emitter.is_finished());
+        assert!(emitter.emit(0.25).is_empty());
+    }
+
+    #[test]
+    fn test_spawn_skips_positions_near_existing_particles() {
+        let min_spawn_distance = 0.05;
+        // A dense block over the left half of the emitter box
+        let existing = ParticleInitData::block(UVec3::splat(10), 0.02, Vec3::new(-0.2, 0.0, -0.1))
+            .iter()
+            .map(|particle| particle.position)
+            .collect::<Vec<_>>();
+        let aabb = Aabb::new(Vec3::new(-0.2, 0.0, -0.1), Vec3::new(0.2, 0.2, 0.1));
+        let mut emitter = Emitter::new(Vec3::ZERO, EmissionShape::Box { aabb }, Vec3::ZERO, 1024.0)
+            .with_min_spawn_distance(min_spawn_distance);
+
+        for _ in 0..4 {
+            let emitted = emitter.emit_avoiding(0.25, &existing);
+            assert!(!emitted.is_empty());
+            assert!(emitted.len() < 256);
+            for (i, particle) in emitted.iter().enumerate() {
+                // Neither on the block nor on a particle spawned in the same frame
+                for position in existing
+                    .iter()
+                    .chain(emitted[i + 1..].iter().map(|p| &p.position))
+                {
+                    assert!(
+                        particle.position.distance(*position) >= min_spawn_distance,
+                        "{:?} spawned on {:?}",
+                        particle.position,
+                        position
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_spawn_count_over_sim_time_is_independent_of_frame_rate() {
+        let particles_per_second = 300.0;
+        let duration = 4.0;
+        let spawned_over = |frame_times: &mut dyn Iterator<Item = f32>| {
+            let mut emitter = Emitter::new(
+                ORIGIN,
+                EmissionShape::Point,
+                Vec3::ZERO,
+                particles_per_second,
+            );
+            let (mut time, mut count) = (0.0f64, 0);
+            for frame_time in frame_times {
+                if time >= duration {
+                    break;
+                }
+                let frame_time = frame_time.min((duration - time) as f32);
+                count += emitter.emit(frame_time).len();
+                time += frame_time as f64;
+            }
+            count
+        };
+
+        let expected = (particles_per_second as f64 * duration) as usize;
+        for fps in [24.0, 60.0, 144.0, 500.0] {
+            let count = spawned_over(&mut std::iter::repeat(1.0 / fps));
+            assert!(count.abs_diff(expected) <= 1, "{} fps: {}", fps, count);
+        }
+
+        // Irregular intervals between 2 ms and 50 ms
+        let mut jitter = Emitter::new(Vec3::ZERO, EmissionShape::Point, Vec3::ZERO, 0.0);
+        let count = spawned_over(&mut std::iter::from_fn(|| {
+            Some(0.002 + 0.048 * jitter.next_unit())
+        }));
+        assert!(count.abs_diff(expected) <= 1, "jittered: {}", count);
+
+        // A stall only counts up to the frame time cap
+        let mut emitter =
+            Emitter::new(ORIGIN, EmissionShape::Point, Vec3::ZERO, 100.0).with_max_frame_time(0.1);
+        assert_eq!(emitter.emit(5.0).len(), 10);
+    }
+}
@@ -0,0 +1,100 @@
+use glam::Vec3;
+
+/// Distance below which the field stops growing, so particles at the center are not flung
+const DEFAULT_MIN_DISTANCE: f32 = 0.05;
+
+/// Radial force field pulling particles toward or pushing them away from a point
+///
+/// Adds `strength * (center - position) / distance²` to the velocity per second, so the
+/// pull falls off as `1 / distance`. Distances below `min_distance` count as
+/// `min_distance`. Positive strengths attract, negative ones repel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ForceField {
+    center: Vec3,
+    strength: f32,
+    min_distance: f32,
+}
+
+impl ForceField {
+    /// Gravity well at `center`
+    pub fn attractor(center: Vec3, strength: f32) -> Self {
+        Self {
+            center,
+            strength: strength.abs(),
+            min_distance: DEFAULT_MIN_DISTANCE,
+        }
+    }
+
+    /// Explosion-like push away from `center`
+    pub fn repeller(center: Vec3, strength: f32) -> Self {
+        Self {
+            strength: -strength.abs(),
+            ..Self::attractor(center, strength)
+        }
+    }
+
+    pub fn with_min_distance(mut self, min_distance: f32) -> Self {
+        self.min_distance = min_distance.max(0.0);
+        self
+    }
+
+    pub fn center(&self) -> Vec3 {
+        self.center
+    }
+
+    /// Move the field, e.g. to drag a gravity well around
+    pub fn set_center(&mut self, center: Vec3) {
+        self.center = center;
+    }
+
+    /// Signed strength, negative for a repeller
+    pub fn strength(&self) -> f32 {
+        self.strength
+    }
+
+    pub fn min_distance(&self) -> f32 {
+        self.min_distance
+    }
+
+    /// Acceleration at `position`, same as the force field shader
+    pub fn acceleration(&self, position: Vec3) -> Vec3 {
+        let offset = self.center - position;
+        let distance_sq = offset
+            .length_squared()
+            .max(self.min_distance * self.min_distance);
+        if distance_sq == 0.0 {
+            return Vec3::ZERO;
+        }
+        self.strength * offset / distance_sq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attractor_pulls_and_repeller_pushes() {
+        let center = Vec3::new(1.0, 0.0, 0.0);
+        let position = Vec3::new(1.0, 2.0, 0.0);
+
+        let pull = ForceField::attractor(center, 4.0).acceleration(position);
+        assert!(
+            pull.abs_diff_eq(Vec3::new(0.0, -2.0, 0.0), 1e-6),
+            "{}",
+            pull
+        );
+        let push = ForceField::repeller(center, 4.0).acceleration(position);
+        assert_eq!(push, -pull);
+
+        // Inside the minimum distance the field keeps its strength at that distance
+        let clamped = ForceField::attractor(center, 4.0).with_min_distance(0.5);
+        let near = clamped.acceleration(center + Vec3::new(0.0, 0.1, 0.0));
+        assert!(
+            near.abs_diff_eq(Vec3::new(0.0, -1.6, 0.0), 1e-5),
+            "{}",
+            near
+        );
+        assert_eq!(clamped.acceleration(center), Vec3::ZERO);
+    }
+}
@@ -1,6 +1,7 @@
 use glam::Vec3;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Aabb {
     min: Vec3,
     max: Vec3,
@@ -11,7 +12,6 @@ impl Aabb {
         Self { min, max }
     }
 
-    #[allow(dead_code)]
     pub fn contains(&self, point: Vec3) -> bool {
         point.x >= self.min.x
             && point.x <= self.max.x
@@ -21,7 +21,6 @@ impl Aabb {
             && point.z <= self.max.z
     }
 
-    #[allow(dead_code)]
     pub fn intersects(&self, other: &Self) -> bool {
         self.min.x <= other.max.x
             && self.max.x >= other.min.x
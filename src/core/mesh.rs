@@ -0,0 +1,215 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use glam::Vec3;
+
+use super::geometry::Aabb;
+
+/// Ray direction for inside tests, skewed off the axes so it rarely grazes an edge or vertex
+const INSIDE_RAY: Vec3 = Vec3::new(0.912_870_9, 0.365_148_4, 0.182_574_2);
+
+/// Triangle soup read from a Wavefront OBJ file
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TriangleMesh {
+    positions: Vec<Vec3>,
+    triangles: Vec<[u32; 3]>,
+}
+
+#[allow(unused)]
+impl TriangleMesh {
+    pub fn load_obj(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse_obj(&fs::read_to_string(path)?)
+    }
+
+    /// Read `v` positions and `f` faces, ignoring every other statement
+    ///
+    /// Faces may use the `v/vt/vn` forms and negative indices; polygons are split into a fan.
+    pub fn parse_obj(source: &str) -> io::Result<Self> {
+        let invalid = |line: usize, message: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("OBJ line {}: {}", line + 1, message),
+            )
+        };
+
+        let mut mesh = Self::default();
+        for (line_index, line) in source.lines().enumerate() {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => {
+                    let coordinates = tokens
+                        .take(3)
+                        .map(str::parse::<f32>)
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| invalid(line_index, "invalid vertex coordinate"))?;
+                    if coordinates.len() != 3 {
+                        return Err(invalid(line_index, "vertex needs 3 coordinates"));
+                    }
+                    mesh.positions.push(Vec3::from_slice(&coordinates));
+                }
+                Some("f") => {
+                    let vertex_count = mesh.positions.len() as i64;
+                    let indices = tokens
+                        .map(|token| {
+                            let index = token
+                                .split('/')
+                                .next()
+                                .and_then(|index| index.parse::<i64>().ok())
+                                .ok_or_else(|| invalid(line_index, "invalid face index"))?;
+                            // 1-based, or relative to the end when negative
+                            let index = if index < 0 {
+                                vertex_count + index
+                            } else {
+                                index - 1
+                            };
+                            if !(0..vertex_count).contains(&index) {
+                                return Err(invalid(line_index, "face index out of range"));
+                            }
+                            Ok(index as u32)
+                        })
+                        .collect::<io::Result<Vec<_>>>()?;
+                    if indices.len() < 3 {
+                        return Err(invalid(line_index, "face needs at least 3 vertices"));
+                    }
+                    for k in 1..indices.len() - 1 {
+                        mesh.triangles
+                            .push([indices[0], indices[k], indices[k + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(mesh)
+    }
+
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    pub fn triangles(&self) -> &[[u32; 3]] {
+        &self.triangles
+    }
+
+    /// Bounds of the vertices, or an empty box at the origin without any
+    pub fn bounds(&self) -> Aabb {
+        let Some(&first) = self.positions.first() else {
+            return Aabb::default();
+        };
+        let (min, max) = self
+            .positions
+            .iter()
+            .fold((first, first), |(min, max), &p| (min.min(p), max.max(p)));
+        Aabb::new(min, max)
+    }
+
+    /// Distance to the closest triangle, negative inside the mesh
+    ///
+    /// Checks every triangle. The sign comes from the parity of ray crossings, so the mesh
+    /// should be closed.
+    pub fn signed_distance(&self, point: Vec3) -> f32 {
+        let mut distance_sq = f32::MAX;
+        let mut crossings = 0;
+        for triangle in &self.triangles {
+            let [a, b, c] = triangle.map(|index| self.positions[index as usize]);
+            distance_sq = distance_sq.min(point.distance_squared(closest_point(point, a, b, c)));
+            if ray_hits(point, INSIDE_RAY, a, b, c) {
+                crossings += 1;
+            }
+        }
+        let distance = distance_sq.sqrt();
+        if crossings % 2 == 1 {
+            -distance
+        } else {
+            distance
+        }
+    }
+}
+
+/// Closest point to `p` on triangle `abc`, by the Voronoi region it falls in
+fn closest_point(p: Vec3, a: Vec3, b: Vec3, c: Vec3) -> Vec3 {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denominator = 1.0 / (va + vb + vc);
+    a + ab * (vb * denominator) + ac * (vc * denominator)
+}
+
+/// Whether the ray from `origin` along `direction` crosses triangle `abc` (Möller–Trumbore)
+fn ray_hits(origin: Vec3, direction: Vec3, a: Vec3, b: Vec3, c: Vec3) -> bool {
+    let ab = b - a;
+    let ac = c - a;
+    let p = direction.cross(ac);
+    let determinant = ab.dot(p);
+    if determinant.abs() < 1e-12 {
+        return false;
+    }
+
+    let inverse = 1.0 / determinant;
+    let t_vec = origin - a;
+    let u = t_vec.dot(p) * inverse;
+    if !(0.0..=1.0).contains(&u) {
+        return false;
+    }
+    let q = t_vec.cross(ab);
+    let v = direction.dot(q) * inverse;
+    if v < 0.0 || u + v > 1.0 {
+        return false;
+    }
+    ac.dot(q) * inverse > 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faces_are_fan_triangulated_with_relative_indices() {
+        let mesh = TriangleMesh::parse_obj(
+            "# quad\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1 4//1\nf -4 -2 -1\n",
+        )
+        .unwrap();
+        assert_eq!(mesh.positions().len(), 4);
+        assert_eq!(mesh.triangles(), &[[0, 1, 2], [0, 2, 3], [0, 2, 3]]);
+
+        let error = TriangleMesh::parse_obj("v 0 0 0\nf 1 2 3\n").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,10 +1,24 @@
 mod camera;
+mod emitter;
+mod force_field;
 mod geometry;
+mod mesh;
 mod particle;
+pub(crate) mod scenes;
+mod sdf;
 
 pub(crate) use camera::Camera;
-pub(crate) use geometry::Aabb;
+pub use emitter::{EmissionShape, Emitter};
+pub use force_field::ForceField;
+pub use geometry::Aabb;
+#[allow(unused_imports)]
+pub(crate) use mesh::TriangleMesh;
 #[allow(unused_imports)]
 pub(crate) use particle::{
-    ParticleInitData, ParticlePingPongBuffer, ParticlePosition, ParticleVelocity, Particles, TaskId,
+    BoundaryParticles, ParticlePingPongBuffer, TaskId, CONTACTS_PER_PARTICLE,
+};
+pub use particle::{
+    NeighborStats, ParticleDensity, ParticleInitData, ParticlePosition, ParticleSnapshot,
+    ParticleVelocity, Particles,
 };
+pub use sdf::Sdf;
@@ -0,0 +1,70 @@
+use glam::Vec3;
+
+use crate::core::Aabb;
+
+/// Fixed particles lining the six faces of an [`Aabb`]
+///
+/// They fill in the neighborhoods that the walls cut off, so fluid next to a wall is not
+/// short of density and does not get pulled onto it.
+pub(crate) struct BoundaryParticles {
+    positions: Vec<Vec3>,
+}
+
+impl BoundaryParticles {
+    /// Lattice points on the faces of `aabb`, no further apart than `spacing` along each axis
+    ///
+    /// Every axis is split into whole intervals so the edges and corners are covered exactly.
+    pub fn new(aabb: Aabb, spacing: f32) -> Self {
+        let extent = aabb.max() - aabb.min();
+        let divisions = (extent / spacing.max(f32::EPSILON))
+            .ceil()
+            .max(Vec3::ONE)
+            .as_uvec3();
+
+        let mut positions = Vec::new();
+        for i in 0..=divisions.x {
+            for j in 0..=divisions.y {
+                for k in 0..=divisions.z {
+                    let on_face = i == 0
+                        || i == divisions.x
+                        || j == 0
+                        || j == divisions.y
+                        || k == 0
+                        || k == divisions.z;
+                    if on_face {
+                        let t = Vec3::new(i as f32, j as f32, k as f32) / divisions.as_vec3();
+                        positions.push(aabb.min() + extent * t);
+                    }
+                }
+            }
+        }
+        Self { positions }
+    }
+
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_covers_faces_without_interior_points() {
+        let aabb = Aabb::new(Vec3::ZERO, Vec3::new(0.4, 0.2, 0.2));
+        let boundary = BoundaryParticles::new(aabb, 0.1);
+
+        // A 5x3x3 lattice minus its single interior row of 3
+        assert_eq!(boundary.len(), 5 * 3 * 3 - 3);
+        for &p in boundary.positions() {
+            let distance_to_face = (p - aabb.min()).min(aabb.max() - p).min_element();
+            assert!(distance_to_face.abs() < 1e-6, "{:?} is off the faces", p);
+        }
+        assert!(boundary.positions().contains(&aabb.max()));
+    }
+}
@@ -1,7 +1,10 @@
+mod boundary_particles;
 mod particle_data;
 mod particles;
 mod ping_pong_buffer;
 
-pub(crate) use particle_data::{ParticlePosition, ParticleVelocity};
-pub(crate) use particles::{ParticleInitData, Particles, TaskId};
+pub(crate) use boundary_particles::BoundaryParticles;
+pub use particle_data::{ParticleDensity, ParticlePosition, ParticleVelocity};
+pub use particles::{NeighborStats, ParticleInitData, ParticleSnapshot, Particles};
+pub(crate) use particles::{TaskId, CONTACTS_PER_PARTICLE};
 pub(crate) use ping_pong_buffer::ParticlePingPongBuffer;
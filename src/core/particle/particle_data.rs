@@ -2,18 +2,25 @@ use vulkano::{buffer::BufferContents, pipeline::graphics::vertex_input::Vertex};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, BufferContents, Vertex)]
-pub(crate) struct ParticlePosition {
+pub struct ParticlePosition {
     #[format(R32G32B32A32_SFLOAT)]
     pub position: [f32; 4],
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, BufferContents, Vertex)]
-pub(crate) struct ParticleVelocity {
+pub struct ParticleVelocity {
     #[format(R32G32B32A32_SFLOAT)]
     pub velocity: [f32; 4],
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, BufferContents, Vertex)]
+pub struct ParticleDensity {
+    #[format(R32_SFLOAT)]
+    pub density: f32,
+}
+
 #[cfg(test)]
 mod tests {
     use glam::Vec3;
@@ -1,8 +1,8 @@
 use std::{any::TypeId, collections::HashMap, sync::Arc};
 
-use glam::Vec3;
+use glam::{UVec3, Vec3, Vec4};
 use vulkano::{
-    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    buffer::{Buffer, BufferContents, BufferCreateInfo, BufferUsage, Subbuffer},
     command_buffer::{
         AutoCommandBufferBuilder, BufferCopy, CopyBufferInfoTyped, PrimaryAutoCommandBuffer,
     },
@@ -12,22 +12,144 @@ use vulkano::{
     sync::{self, GpuFuture},
 };
 
-use crate::utils::{GpuTask, GpuTaskExecutor};
+use crate::{
+    core::{Aabb, Sdf},
+    utils::{GpuTask, GpuTaskExecutor},
+};
 
-use super::particle_data::{ParticlePosition, ParticleVelocity};
+use super::{
+    boundary_particles::BoundaryParticles,
+    particle_data::{ParticlePosition, ParticleVelocity},
+};
 
 pub(crate) type TaskId = TypeId;
 
+/// Capacity of [`Particles::new`]
 const PARTICLE_MAX_COUNT: u32 = 0x100000; // 1 million particles
 
+/// Bins per radix sort pass, each work group of 256 particles writes one histogram of them
+const RADIX_SORT_BINS: u32 = 256;
+
+/// Stride of the per-particle neighbor list in `contacts`
+pub(crate) const CONTACTS_PER_PARTICLE: u32 = 64;
+
+/// Starting state of a spawned particle
+///
+/// ```
+/// use aqua_gpu::ParticleInitData;
+/// use glam::Vec3;
+///
+/// let particle = ParticleInitData {
+///     position: Vec3::new(0.0, 1.0, 0.0),
+///     velocity: Vec3::new(0.5, 0.0, 0.0),
+///     ..Default::default()
+/// };
+/// assert_eq!(particle.velocity.x, 0.5);
+/// assert_eq!(particle.mass, None);
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
 pub struct ParticleInitData {
     pub position: Vec3,
-    pub velocitie: Vec3,
+    pub velocity: Vec3,
+    /// Mass relative to `particle_mass`, 1 when unset
+    pub mass: Option<f32>,
+    /// Rest density relative to `rest_density`, 1 when unset
+    pub rest_density: Option<f32>,
+}
+
+impl ParticleInitData {
+    /// `count` resting particles filling a cube layer by layer
+    ///
+    /// The cube side is the smallest that holds `count` particles. Particle `i` of side `n`
+    /// sits at `origin + (i % n, i / n % n, i / n²) * spacing`, so the last layer may be
+    /// partly filled.
+    pub fn grid(count: u32, spacing: f32, origin: Vec3) -> Vec<Self> {
+        let mut side = (count as f64).cbrt().round() as u32;
+        while side.pow(3) < count {
+            side += 1;
+        }
+        Self::block(UVec3::splat(side), spacing, origin)
+            .into_iter()
+            .take(count as usize)
+            .collect()
+    }
+
+    /// Resting particles filling a `dims.x` by `dims.y` by `dims.z` box, x varying fastest
+    /// and z slowest
+    pub fn block(dims: UVec3, spacing: f32, origin: Vec3) -> Vec<Self> {
+        (0..dims.element_product())
+            .map(|i| {
+                let cell = UVec3::new(i % dims.x, i / dims.x % dims.y, i / (dims.x * dims.y));
+                Self {
+                    position: origin + cell.as_vec3() * spacing,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Host-readable copy of the live particle state, taken by [`Particles::snapshot`]
+pub struct ParticleSnapshot {
+    count: u32,
+    cursor: u32,
+    position: Subbuffer<[ParticlePosition]>,
+    velocity: Subbuffer<[ParticleVelocity]>,
+    predicted_position: Subbuffer<[ParticlePosition]>,
+    density: Subbuffer<[f32]>,
+    pinned: Subbuffer<[u32]>,
+    /// Only taken while particles have their own masses
+    mass: Option<Subbuffer<[f32]>>,
+    /// Only taken while particles have their own rest densities
+    rest_density: Option<Subbuffer<[f32]>>,
+    /// Only taken while boundary velocities keep an up to date position history
+    previous_position: Option<Subbuffer<[ParticlePosition]>>,
+}
+
+impl ParticleSnapshot {
+    /// Live particle count at the time of the snapshot
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn positions(&self) -> Vec<Vec3> {
+        self.position.read().unwrap()[..self.count as usize]
+            .iter()
+            .map(|p| Vec3::from_slice(&p.position[..3]))
+            .collect()
+    }
+}
+
+/// Summary of the per-particle neighbor counts, taken by [`Particles::neighbor_stats`]
+///
+/// A low `min` or many particles without neighbors points at under-resolved regions; a
+/// `max` at the neighbor cap means lists are being cut short.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NeighborStats {
+    pub min: u32,
+    pub max: u32,
+    pub mean: f32,
+    /// Number of particles with at least one neighbor
+    pub particles_with_neighbors: u32,
+}
+
+impl NeighborStats {
+    /// Reduce host-side neighbor counts, all zero when there are none
+    pub fn from_counts(counts: &[u32]) -> Self {
+        Self {
+            min: counts.iter().copied().min().unwrap_or(0),
+            max: counts.iter().copied().max().unwrap_or(0),
+            mean: counts.iter().sum::<u32>() as f32 / counts.len().max(1) as f32,
+            particles_with_neighbors: counts.iter().filter(|&&count| count > 0).count() as u32,
+        }
+    }
 }
 
-pub(crate) struct Particles {
+pub struct Particles {
     count: u32,
     cursor: u32,
+    /// Length of every per-particle buffer, the most particles that can be live at once
+    capacity: u32,
     position: Subbuffer<[ParticlePosition]>,
     velocity: Subbuffer<[ParticleVelocity]>,
     hash: Subbuffer<[u32]>,
@@ -38,11 +160,94 @@ pub(crate) struct Particles {
     prefix_sums: Subbuffer<[u32]>,
     density: Subbuffer<[f32]>,
     predicted_position: Subbuffer<[ParticlePosition]>,
+    /// Nonzero for particles held in place; they still contribute to density
+    pinned: Subbuffer<[u32]>,
+    /// Per-particle CSPM matrix as three padded columns, read by the PBD pass. Holds a single
+    /// placeholder entry until enabled, so the PBD pass can always bind it.
+    gradient_correction: Subbuffer<[[[f32; 4]; 3]]>,
+    /// Per-particle mass relative to `particle_mass`, set by phases and summed by merging
+    /// duplicates. A single-element placeholder until [`Particles::enable_masses`].
+    mass: Subbuffer<[f32]>,
+    /// Per-particle rest density relative to `rest_density`, a single-element placeholder
+    /// until [`Particles::enable_phases`]
+    rest_density: Subbuffer<[f32]>,
+    /// Fixed shell read by the density and PBD passes as extra neighbors, apart from the
+    /// particle buffers. A single-element placeholder unless built by
+    /// [`Particles::with_boundary`].
+    boundary: Subbuffer<[ParticlePosition]>,
+    /// Positions as of the last boundary velocity pass, for velocities of animated boundaries
+    previous_position: Option<Subbuffer<[ParticlePosition]>>,
+    /// Set when `previous_position` does not hold the current particle set
+    previous_position_stale: bool,
+    /// Only allocated once a feature that needs it is enabled
+    predicted_velocity: Option<Subbuffer<[ParticleVelocity]>>,
+    /// Export-only neighbor-averaged velocity
+    smoothed_velocity: Option<Subbuffer<[ParticleVelocity]>>,
+    /// Outward surface normal from the density gradient, for shading
+    normal: Option<Subbuffer<[[f32; 4]]>>,
+    /// Curl of the velocity field, between the two vorticity confinement dispatches
+    vorticity: Option<Subbuffer<[[f32; 4]]>>,
+    /// Export-only uniform grid of splatted values, sized to the last requested resolution
+    field_grid: Option<Subbuffer<[f32]>>,
+    /// Signed distance samples of the static obstacle, as uploaded by [`Particles::set_sdf`]
+    sdf: Option<Subbuffer<[f32]>>,
+    /// Two-entry result of the mass moment reduction
+    moments: Option<Subbuffer<[[f32; 4]]>>,
+    /// Minimum then maximum position, the result of the bounds reduction
+    bounds: Option<Subbuffer<[[f32; 4]]>>,
+    /// Per-particle density summed over the frames of a time average
+    density_sum: Option<Subbuffer<[f32]>>,
+    /// Single-entry result of the isolated particle count
+    isolated_count: Option<Subbuffer<[u32]>>,
+    /// Single-entry result of the maximum speed reduction
+    max_speed: Option<Subbuffer<[f32]>>,
+    /// Neighbor lists, `CONTACTS_PER_PARTICLE` entries per particle
+    contacts: Option<Subbuffer<[u32]>>,
+    contact_counts: Option<Subbuffer<[u32]>>,
+    /// Single-entry count of particles whose neighbors did not all fit in their list
+    neighbor_overflow: Option<Subbuffer<[u32]>>,
+    /// Set when the particle set changes, until the neighbor lists are rebuilt or cleared
+    contacts_stale: bool,
+    /// Positions as of the last neighbor search, for deciding when `contacts` may be reused
+    search_position: Option<Subbuffer<[ParticlePosition]>>,
+    /// Single-entry result of the mean displacement reduction
+    mean_displacement: Option<Subbuffer<[f32]>>,
+    /// Positions as of the last radix sort, for deciding when the sort may be skipped
+    sort_position: Option<Subbuffer<[ParticlePosition]>>,
+    /// Single-entry result of the max displacement reduction
+    max_displacement: Option<Subbuffer<[f32]>>,
+    /// Merged position and mass, velocity and pinned flag, then rest density, three entries
+    /// per particle
+    merge_state: Option<Subbuffer<[[f32; 4]]>>,
+    /// Nonzero for sorted slots whose particle survives the merge
+    merge_keep: Option<Subbuffer<[u32]>>,
+    /// Compacted indices of the surviving particles, in sorted order
+    merge_survivors: Option<Subbuffer<[u32]>>,
+    /// Single-entry count of `merge_survivors`
+    merge_count: Option<Subbuffer<[u32]>>,
+    /// Sorted, distinct indices queued by [`Particles::remove`] until the next compaction
+    pending_removals: Vec<u32>,
+    /// GPU copy of `pending_removals`, searched by the removal pass
+    free_list: Option<Subbuffer<[u32]>>,
     descriptor_sets: HashMap<TaskId, Arc<DescriptorSet>>,
+    /// Dispatch size the cached descriptor sets were built for
+    descriptor_sets_work_groups: u32,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    allocation_create_info: AllocationCreateInfo,
 }
 
 impl Particles {
     pub fn new(memory_allocator: &Arc<StandardMemoryAllocator>) -> Self {
+        Self::with_capacity(memory_allocator, PARTICLE_MAX_COUNT)
+    }
+
+    /// Particles whose buffers hold at most `max_count` particles
+    ///
+    /// Every per-particle buffer, including those allocated later by the `enable_*`
+    /// methods, has `max_count` entries, so small scenes need not pay for the million
+    /// particles of [`Particles::new`].
+    pub fn with_capacity(memory_allocator: &Arc<StandardMemoryAllocator>, max_count: u32) -> Self {
+        assert!(max_count > 0, "particle capacity must be positive");
         let allocation_create_info = {
             let memory_type_filter = {
                 #[cfg(test)]
@@ -71,7 +276,7 @@ impl Particles {
                 ..Default::default()
             },
             allocation_create_info.clone(),
-            PARTICLE_MAX_COUNT as u64,
+            max_count as u64,
         )
         .unwrap();
 
@@ -85,7 +290,7 @@ impl Particles {
                 ..Default::default()
             },
             allocation_create_info.clone(),
-            PARTICLE_MAX_COUNT as u64,
+            max_count as u64,
         )
         .unwrap();
 
@@ -96,18 +301,18 @@ impl Particles {
                 ..Default::default()
             },
             allocation_create_info.clone(),
-            PARTICLE_MAX_COUNT as u64,
+            max_count as u64,
         )
         .unwrap();
 
         let index = Buffer::new_slice(
             memory_allocator.clone(),
             BufferCreateInfo {
-                usage: BufferUsage::STORAGE_BUFFER,
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
                 ..Default::default()
             },
             allocation_create_info.clone(),
-            PARTICLE_MAX_COUNT as u64,
+            max_count as u64,
         )
         .unwrap();
 
@@ -118,21 +323,22 @@ impl Particles {
                 ..Default::default()
             },
             allocation_create_info.clone(),
-            PARTICLE_MAX_COUNT as u64,
+            max_count as u64,
         )
         .unwrap();
 
         let index_temp = Buffer::new_slice(
             memory_allocator.clone(),
             BufferCreateInfo {
-                usage: BufferUsage::STORAGE_BUFFER,
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
                 ..Default::default()
             },
             allocation_create_info.clone(),
-            PARTICLE_MAX_COUNT as u64,
+            max_count as u64,
         )
         .unwrap();
 
+        // One histogram per work group, so the length rounds up to whole work groups
         let histograms = Buffer::new_slice(
             memory_allocator.clone(),
             BufferCreateInfo {
@@ -140,10 +346,11 @@ impl Particles {
                 ..Default::default()
             },
             allocation_create_info.clone(),
-            PARTICLE_MAX_COUNT as u64,
+            max_count.next_multiple_of(RADIX_SORT_BINS) as u64,
         )
         .unwrap();
 
+        // The global offset of every bin in every work group, laid out like the histograms
         let prefix_sums = Buffer::new_slice(
             memory_allocator.clone(),
             BufferCreateInfo {
@@ -151,19 +358,22 @@ impl Particles {
                 ..Default::default()
             },
             allocation_create_info.clone(),
-            PARTICLE_MAX_COUNT as u64,
+            max_count.next_multiple_of(RADIX_SORT_BINS) as u64,
         )
         .unwrap();
 
-        // SPH related buffers
+        // SPH related buffers, also read by the renderer for density-based alpha
         let density = Buffer::new_slice(
             memory_allocator.clone(),
             BufferCreateInfo {
-                usage: BufferUsage::STORAGE_BUFFER,
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::VERTEX_BUFFER
+                    | BufferUsage::TRANSFER_SRC
+                    | BufferUsage::TRANSFER_DST,
                 ..Default::default()
             },
             allocation_create_info.clone(),
-            PARTICLE_MAX_COUNT as u64,
+            max_count as u64,
         )
         .unwrap();
 
@@ -178,10 +388,39 @@ impl Particles {
                 ..Default::default()
             },
             allocation_create_info.clone(),
-            PARTICLE_MAX_COUNT as u64,
+            max_count as u64,
+        )
+        .unwrap();
+
+        // New particles start unpinned; add_particles zeroes their slots
+        let pinned = Buffer::new_slice(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::TRANSFER_SRC
+                    | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            allocation_create_info.clone(),
+            max_count as u64,
+        )
+        .unwrap();
+
+        let gradient_correction = Buffer::new_slice(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            allocation_create_info.clone(),
+            1,
         )
         .unwrap();
 
+        let [mass, rest_density] =
+            [(); 2].map(|_| Self::new_scalar_buffer(memory_allocator, &allocation_create_info, 1));
+        let boundary = Self::new_boundary_buffer(memory_allocator, &allocation_create_info, 1);
+
         Self {
             position,
             velocity,
@@ -193,174 +432,1778 @@ impl Particles {
             prefix_sums,
             density,
             predicted_position, // 新增
+            pinned,
+            gradient_correction,
+            mass,
+            rest_density,
+            boundary,
+            predicted_velocity: None,
+            smoothed_velocity: None,
+            normal: None,
+            vorticity: None,
+            field_grid: None,
+            sdf: None,
+            moments: None,
+            bounds: None,
+            density_sum: None,
+            isolated_count: None,
+            max_speed: None,
+            previous_position: None,
+            previous_position_stale: false,
+            contacts: None,
+            contact_counts: None,
+            neighbor_overflow: None,
+            contacts_stale: false,
+            search_position: None,
+            mean_displacement: None,
+            sort_position: None,
+            max_displacement: None,
+            merge_state: None,
+            merge_keep: None,
+            merge_survivors: None,
+            merge_count: None,
+            pending_removals: Vec::new(),
+            free_list: None,
             count: 0,
             cursor: 0,
+            capacity: max_count,
             descriptor_sets: HashMap::new(),
+            descriptor_sets_work_groups: 0,
+            memory_allocator: memory_allocator.clone(),
+            allocation_create_info,
         }
     }
 
-    #[allow(unused)]
-    pub fn position(&self) -> &Subbuffer<[ParticlePosition]> {
-        &self.position
-    }
+    /// [`Particles::with_capacity`] with a fixed shell of [`BoundaryParticles`] on the faces
+    /// of `aabb`
+    ///
+    /// The shell adds density to fluid next to the walls and pushes it off them in the PBD
+    /// solve, but never moves. It lives in its own buffer rather than among the particles, so
+    /// it does not count towards `count` or `max_count` and is never exported, drawn, merged
+    /// or removed. Every fluid particle visits every shell particle, so `spacing` should be
+    /// no finer than needed; about half the smoothing radius leaves no gaps in the
+    /// neighborhoods.
+    pub fn with_boundary(
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        max_count: u32,
+        aabb: Aabb,
+        spacing: f32,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Self {
+        let mut particles = Self::with_capacity(memory_allocator, max_count);
+        let shell = BoundaryParticles::new(aabb, spacing);
+        particles.boundary = Self::new_boundary_buffer(
+            memory_allocator,
+            &particles.allocation_create_info,
+            shell.len() as u64,
+        );
 
-    #[allow(unused)]
-    pub fn velocity(&self) -> &Subbuffer<[ParticleVelocity]> {
-        &self.velocity
+        let stage_buffer = Buffer::from_iter(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            shell.positions().iter().map(|p| ParticlePosition {
+                position: p.extend(0.0).to_array(),
+            }),
+        )
+        .unwrap();
+        let regions = vec![BufferCopy {
+            size: shell.len() as u64,
+            ..Default::default()
+        }];
+        let mut copy_task = BufferCopyTask::new(stage_buffer, particles.boundary.clone(), regions);
+        task_executor.execute(&mut copy_task);
+        particles
     }
 
-    #[allow(unused)]
-    pub fn hash(&self) -> &Subbuffer<[u32]> {
-        &self.hash
+    fn new_boundary_buffer(
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        allocation_create_info: &AllocationCreateInfo,
+        len: u64,
+    ) -> Subbuffer<[ParticlePosition]> {
+        Buffer::new_slice(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            allocation_create_info.clone(),
+            len,
+        )
+        .unwrap()
     }
 
-    pub fn index(&self) -> &Subbuffer<[u32]> {
-        &self.index
-    }
+    /// Allocate the predicted_velocity buffer used by the viscosity passes, if not already present
+    pub fn enable_predicted_velocity(&mut self) {
+        if self.predicted_velocity.is_some() {
+            return;
+        }
 
-    pub fn count(&self) -> u32 {
-        self.count
+        // Implicit viscosity solves into this buffer before it replaces velocity
+        let predicted_velocity = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::TRANSFER_SRC
+                    | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            self.capacity as u64,
+        )
+        .unwrap();
+        self.predicted_velocity = Some(predicted_velocity);
     }
 
-    pub fn histograms(&self) -> &Subbuffer<[u32]> {
-        &self.histograms
-    }
+    /// Grow the gradient_correction buffer to full size, if not already done
+    pub fn enable_gradient_correction(&mut self) {
+        if self.gradient_correction.len() > 1 {
+            return;
+        }
 
-    pub fn hash_temp(&self) -> &Subbuffer<[u32]> {
-        &self.hash_temp
+        self.gradient_correction = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            self.capacity as u64,
+        )
+        .unwrap();
+        // Cached descriptor sets still bind the placeholder
+        self.descriptor_sets.clear();
     }
 
-    pub fn index_temp(&self) -> &Subbuffer<[u32]> {
-        &self.index_temp
+    fn new_scalar_buffer(
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        allocation_create_info: &AllocationCreateInfo,
+        len: u64,
+    ) -> Subbuffer<[f32]> {
+        Buffer::new_slice(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::TRANSFER_SRC
+                    | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            allocation_create_info.clone(),
+            len,
+        )
+        .unwrap()
     }
 
-    pub fn prefix_sums(&self) -> &Subbuffer<[u32]> {
-        &self.prefix_sums
+    /// Single-element scalar buffer standing in for masses or rest densities while disabled
+    fn new_placeholder_buffer(&self) -> Subbuffer<[f32]> {
+        Self::new_scalar_buffer(&self.memory_allocator, &self.allocation_create_info, 1)
     }
 
-    // SPH related buffer accessors
-    pub fn density(&self) -> &Subbuffer<[f32]> {
-        &self.density
+    /// Full-size scalar buffer with every entry at 1, the scalar mass or rest density
+    fn new_unit_buffer(&self, task_executor: &dyn GpuTaskExecutor) -> Subbuffer<[f32]> {
+        let buffer = Self::new_scalar_buffer(
+            &self.memory_allocator,
+            &self.allocation_create_info,
+            self.capacity as u64,
+        );
+        let mut fill_task = BufferFillTask::new(buffer.clone().reinterpret(), 1.0f32.to_bits());
+        task_executor.execute(&mut fill_task);
+        buffer
     }
 
-    // 新增: predicted_position访问器
-    #[allow(unused)]
-    pub fn predicted_position(&self) -> &Subbuffer<[ParticlePosition]> {
-        &self.predicted_position
-    }
+    /// Grow the mass buffer to full size, giving every particle the scalar mass, if not
+    /// already done
+    pub fn enable_masses(&mut self, task_executor: &dyn GpuTaskExecutor) {
+        if self.masses_enabled() {
+            return;
+        }
 
-    pub fn descriptor_sets(&mut self) -> &mut HashMap<TaskId, Arc<DescriptorSet>> {
-        &mut self.descriptor_sets
+        self.mass = self.new_unit_buffer(task_executor);
+        // Cached descriptor sets still bind the placeholder
+        self.descriptor_sets.clear();
     }
 
-    /// Swap main hash buffer and temporary hash buffer
-    #[allow(unused)]
-    pub fn swap_hash_buffers(&mut self) {
-        std::mem::swap(&mut self.hash, &mut self.hash_temp);
+    /// Grow the mass and rest density buffers to full size, giving every particle the
+    /// scalar mass and rest density, if not already done
+    pub fn enable_phases(&mut self, task_executor: &dyn GpuTaskExecutor) {
+        self.enable_masses(task_executor);
+        if self.phases_enabled() {
+            return;
+        }
+
+        self.rest_density = self.new_unit_buffer(task_executor);
+        self.descriptor_sets.clear();
     }
 
-    /// Swap main index buffer and temporary index buffer
-    #[allow(unused)]
-    pub fn swap_index_buffers(&mut self) {
-        std::mem::swap(&mut self.index, &mut self.index_temp);
+    /// Whether particles may differ in mass
+    pub fn masses_enabled(&self) -> bool {
+        self.mass.len() > 1
     }
 
-    pub fn add_particles(
-        &mut self,
-        particles_init_data: &[ParticleInitData],
-        memory_allocator: &Arc<StandardMemoryAllocator>,
-        task_executor: &dyn GpuTaskExecutor,
-    ) {
-        let regions =
-            if (self.cursor + particles_init_data.len() as u32) < self.position.len() as u32 {
-                vec![BufferCopy {
-                    src_offset: 0,
-                    dst_offset: self.cursor as u64,
-                    size: particles_init_data.len() as u64,
-                    ..Default::default()
-                }]
-            } else {
-                let head_size = self.position.len() as u32 - self.cursor;
-                let tail_size = particles_init_data.len() as u32 - head_size;
-                vec![
-                    BufferCopy {
-                        src_offset: 0,
-                        dst_offset: self.cursor as u64,
-                        size: head_size as u64,
-                        ..Default::default()
-                    },
-                    BufferCopy {
-                        src_offset: head_size as u64,
-                        dst_offset: 0,
-                        size: tail_size as u64,
-                        ..Default::default()
-                    },
-                ]
-            };
-        self.replace_particles_from_init_data(
-            particles_init_data,
-            &regions,
-            memory_allocator,
-            task_executor,
-        );
-        self.count = (self.count + particles_init_data.len() as u32).min(PARTICLE_MAX_COUNT);
+    /// Whether particles may differ in rest density
+    pub fn phases_enabled(&self) -> bool {
+        self.rest_density.len() > 1
     }
 
-    pub fn replace_particles_from_init_data(
-        &mut self,
-        particles_init_data: &[ParticleInitData],
-        regions: &[BufferCopy],
-        memory_allocator: &Arc<StandardMemoryAllocator>,
-        task_executor: &dyn GpuTaskExecutor,
-    ) {
-        let positions = particles_init_data
-            .iter()
-            .map(|p| ParticlePosition {
-                position: p.position.extend(0.0).to_array(),
-            })
-            .collect::<Vec<_>>();
-        let velocities = particles_init_data
-            .iter()
-            .map(|p| ParticleVelocity {
-                velocity: p.velocitie.extend(0.0).to_array(),
-            })
-            .collect::<Vec<_>>();
+    /// Allocate the previous_position buffer, if not already present
+    ///
+    /// It holds no history until [`Particles::reset_previous_position`] runs.
+    pub fn enable_previous_position(&mut self) {
+        if self.previous_position.is_some() {
+            return;
+        }
 
-        let stage_position_buffer = Buffer::from_iter(
-            memory_allocator.clone(),
+        let previous_position = Buffer::new_slice(
+            self.memory_allocator.clone(),
             BufferCreateInfo {
-                usage: BufferUsage::TRANSFER_SRC,
-                ..Default::default()
-            },
-            AllocationCreateInfo {
-                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::TRANSFER_SRC
+                    | BufferUsage::TRANSFER_DST,
                 ..Default::default()
             },
-            positions.iter().cloned(),
+            self.allocation_create_info.clone(),
+            self.capacity as u64,
         )
         .unwrap();
-        let stage_velocity_buffer = Buffer::from_iter(
-            memory_allocator.clone(),
+        self.previous_position = Some(previous_position);
+        self.previous_position_stale = true;
+    }
+
+    /// Allocate the smoothed_velocity buffer, if not already present
+    pub fn enable_smoothed_velocity(&mut self) {
+        if self.smoothed_velocity.is_some() {
+            return;
+        }
+
+        let smoothed_velocity = Buffer::new_slice(
+            self.memory_allocator.clone(),
             BufferCreateInfo {
-                usage: BufferUsage::TRANSFER_SRC,
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
                 ..Default::default()
             },
-            AllocationCreateInfo {
-                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+            self.allocation_create_info.clone(),
+            self.capacity as u64,
+        )
+        .unwrap();
+        self.smoothed_velocity = Some(smoothed_velocity);
+    }
+
+    /// Allocate the normal buffer, if not already present
+    pub fn enable_normal(&mut self) {
+        if self.normal.is_some() {
+            return;
+        }
+
+        let normal = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::VERTEX_BUFFER
+                    | BufferUsage::TRANSFER_SRC,
                 ..Default::default()
             },
-            velocities.iter().cloned(),
+            self.allocation_create_info.clone(),
+            self.capacity as u64,
         )
         .unwrap();
+        self.normal = Some(normal);
+    }
 
-        let mut stage_task = ParticleStageTask::new(
-            stage_position_buffer,
-            stage_velocity_buffer,
-            self.position.clone(),
+    /// Allocate the vorticity buffer, if not already present
+    pub fn enable_vorticity(&mut self) {
+        if self.vorticity.is_some() {
+            return;
+        }
+
+        let vorticity = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            self.capacity as u64,
+        )
+        .unwrap();
+        self.vorticity = Some(vorticity);
+    }
+
+    /// Allocate the moments buffer, if not already present
+    pub fn enable_moments(&mut self) {
+        if self.moments.is_some() {
+            return;
+        }
+
+        let moments = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            2,
+        )
+        .unwrap();
+        self.moments = Some(moments);
+    }
+
+    /// Allocate the bounds buffer, if not already present
+    pub fn enable_bounds(&mut self) {
+        if self.bounds.is_some() {
+            return;
+        }
+
+        let bounds = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            2,
+        )
+        .unwrap();
+        self.bounds = Some(bounds);
+    }
+
+    /// Allocate the density_sum buffer, if not already present
+    ///
+    /// It holds no sums until [`Particles::clear_density_sum`] runs.
+    pub fn enable_density_sum(&mut self) {
+        if self.density_sum.is_some() {
+            return;
+        }
+
+        let density_sum = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::TRANSFER_SRC
+                    | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            self.capacity as u64,
+        )
+        .unwrap();
+        self.density_sum = Some(density_sum);
+    }
+
+    /// Allocate the isolated_count buffer, if not already present
+    pub fn enable_isolated_count(&mut self) {
+        if self.isolated_count.is_some() {
+            return;
+        }
+
+        let isolated_count = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            1,
+        )
+        .unwrap();
+        self.isolated_count = Some(isolated_count);
+    }
+
+    /// Allocate the max_speed buffer, if not already present
+    pub fn enable_max_speed(&mut self) {
+        if self.max_speed.is_some() {
+            return;
+        }
+
+        let max_speed = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            1,
+        )
+        .unwrap();
+        self.max_speed = Some(max_speed);
+    }
+
+    /// Allocate the field_grid buffer with exactly `cell_count` cells
+    pub fn enable_field_grid(&mut self, cell_count: u32) {
+        if self
+            .field_grid
+            .as_ref()
+            .is_some_and(|field_grid| field_grid.len() == cell_count as u64)
+        {
+            return;
+        }
+
+        let field_grid = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            cell_count.max(1) as u64,
+        )
+        .unwrap();
+        self.field_grid = Some(field_grid);
+        // A cached descriptor set may still bind the previous grid
+        self.descriptor_sets.clear();
+    }
+
+    /// Upload the samples of `sdf`, reallocating the buffer if their count changed
+    pub fn set_sdf(&mut self, sdf: &Sdf, task_executor: &impl GpuTaskExecutor) {
+        let len = sdf.values().len() as u64;
+        if self.sdf.as_ref().is_none_or(|buffer| buffer.len() != len) {
+            let buffer = Buffer::new_slice(
+                self.memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
+                    ..Default::default()
+                },
+                self.allocation_create_info.clone(),
+                len,
+            )
+            .unwrap();
+            self.sdf = Some(buffer);
+            // A cached descriptor set may still bind the previous field
+            self.descriptor_sets.clear();
+        }
+
+        let bits = sdf.values().iter().map(|d| d.to_bits()).collect::<Vec<_>>();
+        let stage_buffer = self.stage_u32(&bits);
+        let regions = vec![BufferCopy {
+            size: len,
+            ..Default::default()
+        }];
+        let mut copy_task =
+            BufferCopyTask::new(stage_buffer, self.sdf().clone().reinterpret(), regions);
+        task_executor.execute(&mut copy_task);
+    }
+
+    /// Allocate the neighbor list buffers, if not already present
+    pub fn enable_contacts(&mut self) {
+        if self.contacts.is_some() {
+            return;
+        }
+
+        let contacts = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            self.capacity as u64 * CONTACTS_PER_PARTICLE as u64,
+        )
+        .unwrap();
+        let contact_counts = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::TRANSFER_SRC
+                    | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            self.capacity as u64,
+        )
+        .unwrap();
+        let neighbor_overflow = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER
+                    | BufferUsage::TRANSFER_SRC
+                    | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            1,
+        )
+        .unwrap();
+        self.contacts = Some(contacts);
+        self.contact_counts = Some(contact_counts);
+        self.neighbor_overflow = Some(neighbor_overflow);
+        self.contacts_stale = true;
+    }
+
+    /// Allocate the buffers for reusing neighbor lists across frames, if not already present
+    ///
+    /// Marks `contacts` stale, so the next search records the positions it was built from.
+    pub fn enable_neighbor_reuse(&mut self) {
+        if self.search_position.is_some() {
+            return;
+        }
+
+        let search_position = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            self.capacity as u64,
+        )
+        .unwrap();
+        let mean_displacement = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            1,
+        )
+        .unwrap();
+        self.search_position = Some(search_position);
+        self.mean_displacement = Some(mean_displacement);
+        self.contacts_stale = true;
+    }
+
+    /// Allocate the buffers for skipping the radix sort across frames, if not already present
+    pub fn enable_adaptive_sort(&mut self) {
+        if self.sort_position.is_some() {
+            return;
+        }
+
+        let sort_position = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            self.capacity as u64,
+        )
+        .unwrap();
+        let max_displacement = Buffer::new_slice(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            self.allocation_create_info.clone(),
+            1,
+        )
+        .unwrap();
+        self.sort_position = Some(sort_position);
+        self.max_displacement = Some(max_displacement);
+    }
+
+    /// Enable the mass buffer and allocate the scratch buffers of the duplicate merge, if
+    /// not already present
+    pub fn enable_merge(&mut self, task_executor: &dyn GpuTaskExecutor) {
+        self.enable_masses(task_executor);
+        if self.merge_state.is_some() {
+            return;
+        }
+
+        let storage = |usage| BufferCreateInfo {
+            usage: BufferUsage::STORAGE_BUFFER | usage,
+            ..Default::default()
+        };
+        let max_count = self.capacity as u64;
+        let allocator = &self.memory_allocator;
+        let allocation_create_info = &self.allocation_create_info;
+        self.merge_state = Some(
+            Buffer::new_slice(
+                allocator.clone(),
+                storage(BufferUsage::empty()),
+                allocation_create_info.clone(),
+                3 * max_count,
+            )
+            .unwrap(),
+        );
+        self.merge_keep = Some(
+            Buffer::new_slice(
+                allocator.clone(),
+                storage(BufferUsage::empty()),
+                allocation_create_info.clone(),
+                max_count,
+            )
+            .unwrap(),
+        );
+        self.merge_survivors = Some(
+            Buffer::new_slice(
+                allocator.clone(),
+                storage(BufferUsage::empty()),
+                allocation_create_info.clone(),
+                max_count,
+            )
+            .unwrap(),
+        );
+        self.merge_count = Some(
+            Buffer::new_slice(
+                allocator.clone(),
+                storage(BufferUsage::TRANSFER_SRC),
+                allocation_create_info.clone(),
+                1,
+            )
+            .unwrap(),
+        );
+    }
+
+    /// Total size in bytes of all currently allocated particle buffers
+    pub fn memory_usage_bytes(&self) -> u64 {
+        [
+            self.position.size(),
+            self.velocity.size(),
+            self.hash.size(),
+            self.index.size(),
+            self.hash_temp.size(),
+            self.index_temp.size(),
+            self.histograms.size(),
+            self.prefix_sums.size(),
+            self.density.size(),
+            self.predicted_position.size(),
+            self.pinned.size(),
+            self.gradient_correction.size(),
+            self.mass.size(),
+            self.rest_density.size(),
+            self.boundary.size(),
+        ]
+        .into_iter()
+        .chain(self.predicted_velocity.as_ref().map(|b| b.size()))
+        .chain(self.smoothed_velocity.as_ref().map(|b| b.size()))
+        .chain(self.normal.as_ref().map(|b| b.size()))
+        .chain(self.vorticity.as_ref().map(|b| b.size()))
+        .chain(self.field_grid.as_ref().map(|b| b.size()))
+        .chain(self.sdf.as_ref().map(|b| b.size()))
+        .chain(self.moments.as_ref().map(|b| b.size()))
+        .chain(self.bounds.as_ref().map(|b| b.size()))
+        .chain(self.density_sum.as_ref().map(|b| b.size()))
+        .chain(self.isolated_count.as_ref().map(|b| b.size()))
+        .chain(self.max_speed.as_ref().map(|b| b.size()))
+        .chain(self.previous_position.as_ref().map(|b| b.size()))
+        .chain(self.contacts.as_ref().map(|b| b.size()))
+        .chain(self.contact_counts.as_ref().map(|b| b.size()))
+        .chain(self.neighbor_overflow.as_ref().map(|b| b.size()))
+        .chain(self.search_position.as_ref().map(|b| b.size()))
+        .chain(self.mean_displacement.as_ref().map(|b| b.size()))
+        .chain(self.sort_position.as_ref().map(|b| b.size()))
+        .chain(self.max_displacement.as_ref().map(|b| b.size()))
+        .chain(self.merge_state.as_ref().map(|b| b.size()))
+        .chain(self.merge_keep.as_ref().map(|b| b.size()))
+        .chain(self.merge_survivors.as_ref().map(|b| b.size()))
+        .chain(self.merge_count.as_ref().map(|b| b.size()))
+        .chain(self.free_list.as_ref().map(|b| b.size()))
+        .sum()
+    }
+
+    pub fn position(&self) -> &Subbuffer<[ParticlePosition]> {
+        &self.position
+    }
+
+    pub fn velocity(&self) -> &Subbuffer<[ParticleVelocity]> {
+        &self.velocity
+    }
+
+    pub fn hash(&self) -> &Subbuffer<[u32]> {
+        &self.hash
+    }
+
+    pub fn index(&self) -> &Subbuffer<[u32]> {
+        &self.index
+    }
+
+    /// Particle index at each position of the Morton-sorted order
+    ///
+    /// Particle attributes are never reordered: after a sort, `sorted_indices()[k]` is the
+    /// index into `position`, `velocity` and the other per-particle buffers of the k-th
+    /// particle along the Morton curve. Morton hashing resets it to the identity, so it
+    /// only reflects the spatial order after the sort of the current step.
+    pub fn sorted_indices(&self) -> &Subbuffer<[u32]> {
+        &self.index
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Most particles the buffers can hold
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Allocator the particle buffers were created from
+    pub fn memory_allocator(&self) -> &Arc<StandardMemoryAllocator> {
+        &self.memory_allocator
+    }
+
+    pub fn histograms(&self) -> &Subbuffer<[u32]> {
+        &self.histograms
+    }
+
+    pub fn hash_temp(&self) -> &Subbuffer<[u32]> {
+        &self.hash_temp
+    }
+
+    pub fn index_temp(&self) -> &Subbuffer<[u32]> {
+        &self.index_temp
+    }
+
+    pub fn prefix_sums(&self) -> &Subbuffer<[u32]> {
+        &self.prefix_sums
+    }
+
+    // SPH related buffer accessors
+    pub fn density(&self) -> &Subbuffer<[f32]> {
+        &self.density
+    }
+
+    // 新增: predicted_position访问器
+    pub fn predicted_position(&self) -> &Subbuffer<[ParticlePosition]> {
+        &self.predicted_position
+    }
+
+    pub fn pinned(&self) -> &Subbuffer<[u32]> {
+        &self.pinned
+    }
+
+    /// Only meaningful once [`Particles::enable_gradient_correction`] has been called
+    pub fn gradient_correction(&self) -> &Subbuffer<[[[f32; 4]; 3]]> {
+        &self.gradient_correction
+    }
+
+    /// Only meaningful once [`Particles::enable_masses`] has been called
+    pub fn mass(&self) -> &Subbuffer<[f32]> {
+        &self.mass
+    }
+
+    /// Only meaningful once [`Particles::enable_phases`] has been called
+    /// Positions of the fixed boundary shell, or a single-element placeholder without one
+    pub fn boundary(&self) -> &Subbuffer<[ParticlePosition]> {
+        &self.boundary
+    }
+
+    pub fn rest_density(&self) -> &Subbuffer<[f32]> {
+        &self.rest_density
+    }
+
+    /// Panics if [`Particles::enable_predicted_velocity`] has not been called
+    pub fn predicted_velocity(&self) -> &Subbuffer<[ParticleVelocity]> {
+        self.predicted_velocity
+            .as_ref()
+            .expect("predicted_velocity buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_previous_position`] has not been called
+    pub fn previous_position(&self) -> &Subbuffer<[ParticlePosition]> {
+        self.previous_position
+            .as_ref()
+            .expect("previous_position buffer is not enabled")
+    }
+
+    /// Number of particles queued by [`Particles::remove`]
+    pub fn pending_removals(&self) -> u32 {
+        self.pending_removals.len() as u32
+    }
+
+    /// Panics if [`Particles::remove`] has not been called
+    pub fn free_list(&self) -> &Subbuffer<[u32]> {
+        self.free_list
+            .as_ref()
+            .expect("free_list buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_merge`] has not been called
+    pub fn merge_state(&self) -> &Subbuffer<[[f32; 4]]> {
+        self.merge_state
+            .as_ref()
+            .expect("merge_state buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_merge`] has not been called
+    pub fn merge_keep(&self) -> &Subbuffer<[u32]> {
+        self.merge_keep
+            .as_ref()
+            .expect("merge_keep buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_merge`] has not been called
+    pub fn merge_survivors(&self) -> &Subbuffer<[u32]> {
+        self.merge_survivors
+            .as_ref()
+            .expect("merge_survivors buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_merge`] has not been called
+    pub fn merge_count(&self) -> &Subbuffer<[u32]> {
+        self.merge_count
+            .as_ref()
+            .expect("merge_count buffer is not enabled")
+    }
+
+    /// Whether `previous_position` misses particles spawned since the last reset
+    pub fn previous_position_stale(&self) -> bool {
+        self.previous_position_stale
+    }
+
+    /// Panics if [`Particles::enable_smoothed_velocity`] has not been called
+    pub fn smoothed_velocity(&self) -> &Subbuffer<[ParticleVelocity]> {
+        self.smoothed_velocity
+            .as_ref()
+            .expect("smoothed_velocity buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_normal`] has not been called
+    pub fn normal(&self) -> &Subbuffer<[[f32; 4]]> {
+        self.normal.as_ref().expect("normal buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_vorticity`] has not been called
+    pub fn vorticity(&self) -> &Subbuffer<[[f32; 4]]> {
+        self.vorticity
+            .as_ref()
+            .expect("vorticity buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_bounds`] has not been called
+    pub fn bounds(&self) -> &Subbuffer<[[f32; 4]]> {
+        self.bounds.as_ref().expect("bounds buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_moments`] has not been called
+    pub fn moments(&self) -> &Subbuffer<[[f32; 4]]> {
+        self.moments
+            .as_ref()
+            .expect("moments buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_density_sum`] has not been called
+    pub fn density_sum(&self) -> &Subbuffer<[f32]> {
+        self.density_sum
+            .as_ref()
+            .expect("density_sum buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_isolated_count`] has not been called
+    pub fn isolated_count(&self) -> &Subbuffer<[u32]> {
+        self.isolated_count
+            .as_ref()
+            .expect("isolated_count buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_max_speed`] has not been called
+    pub fn max_speed(&self) -> &Subbuffer<[f32]> {
+        self.max_speed
+            .as_ref()
+            .expect("max_speed buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_field_grid`] has not been called
+    pub fn field_grid(&self) -> &Subbuffer<[f32]> {
+        self.field_grid
+            .as_ref()
+            .expect("field_grid buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::set_sdf`] has not been called
+    pub fn sdf(&self) -> &Subbuffer<[f32]> {
+        self.sdf.as_ref().expect("sdf buffer is not set")
+    }
+
+    /// Panics if [`Particles::enable_contacts`] has not been called
+    pub fn contacts(&self) -> &Subbuffer<[u32]> {
+        self.contacts
+            .as_ref()
+            .expect("contacts buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_contacts`] has not been called
+    pub fn contact_counts(&self) -> &Subbuffer<[u32]> {
+        self.contact_counts
+            .as_ref()
+            .expect("contact_counts buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_contacts`] has not been called
+    pub fn neighbor_overflow(&self) -> &Subbuffer<[u32]> {
+        self.neighbor_overflow
+            .as_ref()
+            .expect("neighbor_overflow buffer is not enabled")
+    }
+
+    /// Whether `contacts` may reference particles from before the last spawn or despawn
+    pub fn contacts_stale(&self) -> bool {
+        self.contacts_stale
+    }
+
+    /// Panics if [`Particles::enable_neighbor_reuse`] has not been called
+    pub fn search_position(&self) -> &Subbuffer<[ParticlePosition]> {
+        self.search_position
+            .as_ref()
+            .expect("search_position buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_neighbor_reuse`] has not been called
+    pub fn mean_displacement(&self) -> &Subbuffer<[f32]> {
+        self.mean_displacement
+            .as_ref()
+            .expect("mean_displacement buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_adaptive_sort`] has not been called
+    pub fn sort_position(&self) -> &Subbuffer<[ParticlePosition]> {
+        self.sort_position
+            .as_ref()
+            .expect("sort_position buffer is not enabled")
+    }
+
+    /// Panics if [`Particles::enable_adaptive_sort`] has not been called
+    pub fn max_displacement(&self) -> &Subbuffer<[f32]> {
+        self.max_displacement
+            .as_ref()
+            .expect("max_displacement buffer is not enabled")
+    }
+
+    /// Record that `contacts` was rebuilt for the current particle set
+    pub fn mark_contacts_valid(&mut self) {
+        self.contacts_stale = false;
+    }
+
+    pub fn descriptor_sets(&mut self) -> &mut HashMap<TaskId, Arc<DescriptorSet>> {
+        &mut self.descriptor_sets
+    }
+
+    /// Drop the cached descriptor sets if they were built for a different number of work groups
+    ///
+    /// Spawning or despawning across a work group boundary changes the dispatch size, so any
+    /// binding that depends on the particle count has to be rebuilt.
+    pub fn retain_descriptor_sets_for(&mut self, work_groups: u32) {
+        if self.descriptor_sets_work_groups != work_groups {
+            self.descriptor_sets.clear();
+            self.descriptor_sets_work_groups = work_groups;
+        }
+    }
+
+    /// Swap main hash buffer and temporary hash buffer
+    pub fn swap_hash_buffers(&mut self) {
+        std::mem::swap(&mut self.hash, &mut self.hash_temp);
+    }
+
+    /// Swap main index buffer and temporary index buffer
+    pub fn swap_index_buffers(&mut self) {
+        std::mem::swap(&mut self.index, &mut self.index_temp);
+    }
+
+    /// Write particles at the cursor, wrapping around to overwrite the oldest once the buffers
+    /// are full
+    ///
+    /// Of a batch longer than the capacity only its last `capacity` particles are kept, the
+    /// ones that would survive wrapping around.
+    pub fn add_particles(
+        &mut self,
+        particles_init_data: &[ParticleInitData],
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &dyn GpuTaskExecutor,
+    ) {
+        let excess = particles_init_data
+            .len()
+            .saturating_sub(self.capacity as usize);
+        let particles_init_data = &particles_init_data[excess..];
+        let regions =
+            if (self.cursor + particles_init_data.len() as u32) <= self.position.len() as u32 {
+                vec![BufferCopy {
+                    src_offset: 0,
+                    dst_offset: self.cursor as u64,
+                    size: particles_init_data.len() as u64,
+                    ..Default::default()
+                }]
+            } else {
+                let head_size = self.position.len() as u32 - self.cursor;
+                let tail_size = particles_init_data.len() as u32 - head_size;
+                vec![
+                    BufferCopy {
+                        src_offset: 0,
+                        dst_offset: self.cursor as u64,
+                        size: head_size as u64,
+                        ..Default::default()
+                    },
+                    BufferCopy {
+                        src_offset: head_size as u64,
+                        dst_offset: 0,
+                        size: tail_size as u64,
+                        ..Default::default()
+                    },
+                ]
+            };
+        self.replace_particles_from_init_data(
+            particles_init_data,
+            &regions,
+            memory_allocator,
+            task_executor,
+        );
+        self.count = (self.count + particles_init_data.len() as u32).min(self.capacity);
+        self.cursor = (self.cursor + particles_init_data.len() as u32) % self.capacity;
+        self.contacts_stale = true;
+        self.previous_position_stale = self.previous_position.is_some();
+    }
+
+    /// Adopt the first `count` particles as the live set after the merge pass compacted them
+    pub fn finish_merge(&mut self, count: u32) {
+        if count == self.count {
+            return;
+        }
+        self.count = count;
+        self.cursor = count;
+        self.contacts_stale = true;
+        self.previous_position_stale = self.previous_position.is_some();
+    }
+
+    /// Adopt the first `count` particles as the live set after the removal pass compacted
+    /// them, and clear the free list
+    pub fn finish_removal(&mut self, count: u32) {
+        self.pending_removals.clear();
+        self.finish_merge(count);
+    }
+
+    /// Despawn every particle at or beyond `count`
+    pub fn truncate(&mut self, count: u32) {
+        if count < self.count {
+            self.count = count;
+            self.cursor = self.cursor.min(count);
+            self.contacts_stale = true;
+            self.previous_position_stale = self.previous_position.is_some();
+        }
+    }
+
+    /// Copy the live particle positions back to the host
+    pub fn download_positions(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Vec<Vec3> {
+        self.download(&self.position, memory_allocator, task_executor)
+            .iter()
+            .map(|p| Vec3::from_slice(&p.position[..3]))
+            .collect()
+    }
+
+    /// Copy the live particle velocities back to the host
+    pub fn download_velocities(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Vec<Vec3> {
+        self.download(&self.velocity, memory_allocator, task_executor)
+            .iter()
+            .map(|v| Vec3::from_slice(&v.velocity[..3]))
+            .collect()
+    }
+
+    /// Copy the live particle densities back to the host
+    pub fn download_densities(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Vec<f32> {
+        self.download(&self.density, memory_allocator, task_executor)
+    }
+
+    /// Indices of the live particles held in place by [`Particles::set_pinned`]
+    pub fn download_pinned_indices(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Vec<u32> {
+        self.download(&self.pinned, memory_allocator, task_executor)
+            .iter()
+            .enumerate()
+            .filter(|(_, &pinned)| pinned != 0)
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Copy the live part of [`Particles::sorted_indices`] back to the host
+    pub fn snapshot_sorted_indices(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Vec<u32> {
+        self.download(&self.index, memory_allocator, task_executor)
+    }
+
+    /// Copy the live smoothed velocities back to the host, if the pass is enabled
+    pub fn download_smoothed_velocities(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<Vec<Vec3>> {
+        self.smoothed_velocity.as_ref().map(|smoothed_velocity| {
+            self.download(smoothed_velocity, memory_allocator, task_executor)
+                .iter()
+                .map(|v| Vec3::from_slice(&v.velocity[..3]))
+                .collect()
+        })
+    }
+
+    /// Copy the live surface normals back to the host, if the pass is enabled
+    pub fn download_normals(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<Vec<Vec3>> {
+        self.normal.as_ref().map(|normal| {
+            self.download(normal, memory_allocator, task_executor)
+                .iter()
+                .map(|n| Vec3::from_slice(&n[..3]))
+                .collect()
+        })
+    }
+
+    /// Copy every cell of the field grid back to the host, if it is enabled
+    pub fn download_field_grid(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<Vec<f32>> {
+        self.field_grid.as_ref().map(|field_grid| {
+            self.download_len(
+                field_grid,
+                field_grid.len(),
+                memory_allocator,
+                task_executor,
+            )
+        })
+    }
+
+    /// Copy the mass moment sums back to the host, if they are enabled
+    pub fn download_moments(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<[Vec4; 2]> {
+        self.moments.as_ref().map(|moments| {
+            let moments = self.download_len(moments, 2, memory_allocator, task_executor);
+            [Vec4::from_array(moments[0]), Vec4::from_array(moments[1])]
+        })
+    }
+
+    /// Copy the last bounds reduction back to the host, if it is enabled
+    pub fn download_bounds(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<Aabb> {
+        self.bounds.as_ref().map(|bounds| {
+            let bounds = self.download_len(bounds, 2, memory_allocator, task_executor);
+            Aabb::new(
+                Vec4::from_array(bounds[0]).truncate(),
+                Vec4::from_array(bounds[1]).truncate(),
+            )
+        })
+    }
+
+    /// Copy the live density sums back to the host, if they are enabled
+    pub fn download_density_sums(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<Vec<f32>> {
+        self.density_sum
+            .as_ref()
+            .map(|density_sum| self.download(density_sum, memory_allocator, task_executor))
+    }
+
+    /// Copy the last isolated particle count back to the host, if it is enabled
+    pub fn download_isolated_count(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<u32> {
+        self.isolated_count.as_ref().map(|isolated_count| {
+            self.download_len(isolated_count, 1, memory_allocator, task_executor)[0]
+        })
+    }
+
+    /// Copy the last maximum speed reduction back to the host, if it is enabled
+    pub fn download_max_speed(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<f32> {
+        self.max_speed
+            .as_ref()
+            .map(|max_speed| self.download_len(max_speed, 1, memory_allocator, task_executor)[0])
+    }
+
+    /// Copy the last mean displacement reduction back to the host, if neighbor reuse is enabled
+    pub fn download_mean_displacement(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<f32> {
+        self.mean_displacement.as_ref().map(|mean_displacement| {
+            self.download_len(mean_displacement, 1, memory_allocator, task_executor)[0]
+        })
+    }
+
+    /// Copy the last max displacement reduction back to the host, if adaptive sorting is enabled
+    pub fn download_max_displacement(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<f32> {
+        self.max_displacement.as_ref().map(|max_displacement| {
+            self.download_len(max_displacement, 1, memory_allocator, task_executor)[0]
+        })
+    }
+
+    /// Copy the live particle masses back to the host, if masses are enabled
+    pub fn download_masses(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<Vec<f32>> {
+        self.masses_enabled()
+            .then(|| self.download(&self.mass, memory_allocator, task_executor))
+    }
+
+    /// Copy the live particle rest densities back to the host, if phases are enabled
+    pub fn download_rest_densities(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<Vec<f32>> {
+        self.phases_enabled()
+            .then(|| self.download(&self.rest_density, memory_allocator, task_executor))
+    }
+
+    /// Copy the number of particles the last merge pass kept back to the host
+    pub fn download_merge_count(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<u32> {
+        self.merge_count.as_ref().map(|merge_count| {
+            self.download_len(merge_count, 1, memory_allocator, task_executor)[0]
+        })
+    }
+
+    /// Copy the neighbor lists of the live particles back to the host, if they are enabled
+    ///
+    /// Each particle has `CONTACTS_PER_PARTICLE` entries, of which the first
+    /// `contact_counts` are valid.
+    pub fn download_contacts(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<Vec<u32>> {
+        self.contacts.as_ref().map(|contacts| {
+            let len = self.count as u64 * CONTACTS_PER_PARTICLE as u64;
+            self.download_len(contacts, len, memory_allocator, task_executor)
+        })
+    }
+
+    /// Copy the live neighbor counts back to the host, if neighbor lists are enabled
+    pub fn download_contact_counts(
+        &self,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Option<Vec<u32>> {
+        self.contact_counts
+            .as_ref()
+            .map(|counts| self.download(counts, memory_allocator, task_executor))
+    }
+
+    /// Neighbor count statistics of the live particles from the last neighbor search, if
+    /// neighbor lists are enabled
+    pub fn neighbor_stats(&self, task_executor: &impl GpuTaskExecutor) -> Option<NeighborStats> {
+        self.download_contact_counts(&self.memory_allocator, task_executor)
+            .map(|counts| NeighborStats::from_counts(&counts))
+    }
+
+    /// Number of particles whose last neighbor search found more neighbors than fit in their
+    /// list, if neighbor lists are enabled
+    ///
+    /// Lists are cut at `max_contacts`, so a nonzero count means dense regions are missing
+    /// neighbors.
+    pub fn neighbor_overflow_count(&self, task_executor: &impl GpuTaskExecutor) -> Option<u32> {
+        self.neighbor_overflow.as_ref().map(|neighbor_overflow| {
+            self.download_len(neighbor_overflow, 1, &self.memory_allocator, task_executor)[0]
+        })
+    }
+
+    /// Copy the state a step evolves into host-readable buffers, e.g. to re-run from here
+    ///
+    /// Covers position, velocity, predicted position, density and pinned flags of the live
+    /// particles, plus the live count. Masses, rest densities and the boundary position
+    /// history are covered while enabled, since merging and removal move them too.
+    pub fn snapshot(&self, task_executor: &impl GpuTaskExecutor) -> ParticleSnapshot {
+        let len = self.count as u64;
+        let memory_allocator = &self.memory_allocator;
+        let readback_scalars = |enabled: bool, buffer: &Subbuffer<[f32]>| {
+            enabled.then(|| self.readback(buffer, len, memory_allocator, task_executor))
+        };
+        ParticleSnapshot {
+            count: self.count,
+            cursor: self.cursor,
+            position: self.readback(&self.position, len, memory_allocator, task_executor),
+            velocity: self.readback(&self.velocity, len, memory_allocator, task_executor),
+            predicted_position: self.readback(
+                &self.predicted_position,
+                len,
+                memory_allocator,
+                task_executor,
+            ),
+            density: self.readback(&self.density, len, memory_allocator, task_executor),
+            pinned: self.readback(&self.pinned, len, memory_allocator, task_executor),
+            mass: readback_scalars(self.masses_enabled(), &self.mass),
+            rest_density: readback_scalars(self.phases_enabled(), &self.rest_density),
+            previous_position: self
+                .previous_position
+                .as_ref()
+                .filter(|_| !self.previous_position_stale)
+                .map(|previous_position| {
+                    self.readback(previous_position, len, memory_allocator, task_executor)
+                }),
+        }
+    }
+
+    /// Upload a [`Particles::snapshot`] back, restoring its live count
+    ///
+    /// Masses and rest densities the snapshot was taken without go back to the scalars.
+    /// Neighbor lists are marked stale as after spawning, and so is the boundary history
+    /// unless the snapshot holds it. Panics if the snapshot holds more particles than fit.
+    pub fn restore(&mut self, snapshot: &ParticleSnapshot, task_executor: &impl GpuTaskExecutor) {
+        assert!(
+            snapshot.count <= self.capacity,
+            "snapshot of {} particles exceeds the capacity of {}",
+            snapshot.count,
+            self.capacity
+        );
+        let count = snapshot.count as u64;
+        Self::copy_prefix(&snapshot.position, &self.position, count, task_executor);
+        Self::copy_prefix(
+            &snapshot.predicted_position,
+            &self.predicted_position,
+            count,
+            task_executor,
+        );
+        Self::copy_prefix(&snapshot.velocity, &self.velocity, count, task_executor);
+        Self::copy_prefix(&snapshot.density, &self.density, count, task_executor);
+        Self::copy_prefix(&snapshot.pinned, &self.pinned, count, task_executor);
+
+        match &snapshot.mass {
+            Some(mass) => {
+                self.enable_masses(task_executor);
+                Self::copy_prefix(mass, &self.mass, count, task_executor);
+            }
+            None if self.masses_enabled() => {
+                self.mass = self.new_placeholder_buffer();
+                self.descriptor_sets.clear();
+            }
+            None => {}
+        }
+        match &snapshot.rest_density {
+            Some(rest_density) => {
+                self.enable_phases(task_executor);
+                Self::copy_prefix(rest_density, &self.rest_density, count, task_executor);
+            }
+            None if self.phases_enabled() => {
+                self.rest_density = self.new_placeholder_buffer();
+                self.descriptor_sets.clear();
+            }
+            None => {}
+        }
+
+        self.count = snapshot.count;
+        self.cursor = snapshot.cursor;
+        // Removals queued since the snapshot name indices of the discarded particle set
+        self.pending_removals.clear();
+        self.contacts_stale = true;
+        self.previous_position_stale = self.previous_position.is_some();
+        if let (Some(src), Some(dst)) = (&snapshot.previous_position, &self.previous_position) {
+            Self::copy_prefix(src, dst, count, task_executor);
+            self.previous_position_stale = false;
+        }
+    }
+
+    /// Copy the first `len` elements of `src` over those of `dst`
+    fn copy_prefix<T: BufferContents + Copy>(
+        src: &Subbuffer<[T]>,
+        dst: &Subbuffer<[T]>,
+        len: u64,
+        task_executor: &impl GpuTaskExecutor,
+    ) {
+        if len == 0 {
+            return;
+        }
+        task_executor.execute(&mut BufferCopyTask::new(
+            src.clone(),
+            dst.clone(),
+            vec![BufferCopy {
+                size: len,
+                ..Default::default()
+            }],
+        ));
+    }
+
+    fn download<T: BufferContents + Copy>(
+        &self,
+        buffer: &Subbuffer<[T]>,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Vec<T> {
+        self.download_len(buffer, self.count as u64, memory_allocator, task_executor)
+    }
+
+    /// Copy the first `len` elements of `buffer` back to the host
+    fn download_len<T: BufferContents + Copy>(
+        &self,
+        buffer: &Subbuffer<[T]>,
+        len: u64,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Vec<T> {
+        if len == 0 {
+            return Vec::new();
+        }
+
+        let readback = self.readback(buffer, len, memory_allocator, task_executor);
+        let contents = readback.read().unwrap();
+        contents.to_vec()
+    }
+
+    /// Copy the first `len` elements of `buffer` into a new host-readable buffer, which can
+    /// also be copied back from
+    fn readback<T: BufferContents + Copy>(
+        &self,
+        buffer: &Subbuffer<[T]>,
+        len: u64,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> Subbuffer<[T]> {
+        // Buffers cannot be empty, so an empty copy still gets one element
+        let readback = Buffer::new_slice::<T>(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC | BufferUsage::TRANSFER_DST,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            len.max(1),
+        )
+        .unwrap();
+
+        if len > 0 {
+            let regions = [BufferCopy {
+                src_offset: 0,
+                dst_offset: 0,
+                size: len,
+                ..Default::default()
+            }];
+            let mut copy_task =
+                BufferCopyTask::new(buffer.clone(), readback.clone(), regions.to_vec());
+            task_executor.execute(&mut copy_task);
+        }
+        readback
+    }
+
+    /// Zero `contact_counts` so stale neighbor lists read as empty
+    pub fn clear_contact_counts(&mut self, task_executor: &impl GpuTaskExecutor) {
+        let mut fill_task = BufferFillTask::new(self.contact_counts().clone(), 0);
+        task_executor.execute(&mut fill_task);
+        self.contacts_stale = false;
+    }
+
+    /// Zero the neighbor overflow count, which each search only adds to
+    pub fn clear_neighbor_overflow_count(&mut self, task_executor: &impl GpuTaskExecutor) {
+        let mut fill_task = BufferFillTask::new(self.neighbor_overflow().clone(), 0);
+        task_executor.execute(&mut fill_task);
+    }
+
+    /// Zero `density_sum` to start a new time average
+    pub fn clear_density_sum(&mut self, task_executor: &impl GpuTaskExecutor) {
+        // All-zero bits are 0.0 as f32 too
+        let mut fill_task = BufferFillTask::new(self.density_sum().clone().reinterpret(), 0);
+        task_executor.execute(&mut fill_task);
+    }
+
+    /// Hold the particles at `indices` in place
+    ///
+    /// Pinned particles keep their position and zero velocity during steps but still
+    /// count as neighbors, e.g. a reservoir wall of fluid particles.
+    pub fn set_pinned(&mut self, indices: &[u32], task_executor: &impl GpuTaskExecutor) {
+        if indices.is_empty() {
+            return;
+        }
+
+        let stage_buffer = self.stage_u32(&[1]);
+        let regions = indices
+            .iter()
+            .map(|&index| BufferCopy {
+                src_offset: 0,
+                dst_offset: index as u64,
+                size: 1,
+                ..Default::default()
+            })
+            .collect();
+        let mut copy_task = BufferCopyTask::new(stage_buffer, self.pinned.clone(), regions);
+        task_executor.execute(&mut copy_task);
+    }
+
+    /// Give the particles at `indices` their own fluid phase, with `mass` and `rest_density`
+    /// relative to the scalars in `SphParams`, e.g. 0.9 of both for oil on water
+    ///
+    /// Enables the phase buffers first. Merging and removal carry phases along; a merged
+    /// particle gets the mass-weighted mean rest density of the ones it absorbed.
+    pub fn set_phase(
+        &mut self,
+        indices: &[u32],
+        mass: f32,
+        rest_density: f32,
+        task_executor: &impl GpuTaskExecutor,
+    ) {
+        if indices.is_empty() {
+            return;
+        }
+        self.enable_phases(task_executor);
+
+        let regions = indices
+            .iter()
+            .map(|&index| BufferCopy {
+                src_offset: 0,
+                dst_offset: index as u64,
+                size: 1,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        for (value, buffer) in [(mass, &self.mass), (rest_density, &self.rest_density)] {
+            let stage_buffer = self.stage_u32(&[value.to_bits()]);
+            let mut copy_task =
+                BufferCopyTask::new(stage_buffer, buffer.clone().reinterpret(), regions.clone());
+            task_executor.execute(&mut copy_task);
+        }
+    }
+
+    /// Queue the particles at `indices` for removal
+    ///
+    /// The indices go to a GPU free list, and the next removal pass compacts the survivors
+    /// to the front of the buffers in their original order, so `count` and every index
+    /// stay unchanged until then. Out-of-range and repeated indices are ignored.
+    pub fn remove(&mut self, indices: &[u32], task_executor: &impl GpuTaskExecutor) {
+        let count = self.count;
+        self.pending_removals
+            .extend(indices.iter().copied().filter(|&i| i < count));
+        self.pending_removals.sort_unstable();
+        self.pending_removals.dedup();
+        if self.pending_removals.is_empty() {
+            return;
+        }
+        self.enable_merge(task_executor);
+
+        let len = self.pending_removals.len() as u64;
+        if self.free_list.as_ref().is_none_or(|b| b.len() < len) {
+            self.free_list = Some(
+                Buffer::new_slice(
+                    self.memory_allocator.clone(),
+                    BufferCreateInfo {
+                        usage: BufferUsage::STORAGE_BUFFER | BufferUsage::TRANSFER_DST,
+                        ..Default::default()
+                    },
+                    self.allocation_create_info.clone(),
+                    len.next_power_of_two(),
+                )
+                .unwrap(),
+            );
+            // Cached descriptor sets still point at the old free list
+            self.descriptor_sets.clear();
+        }
+
+        let stage_buffer = self.stage_u32(&self.pending_removals);
+        let regions = vec![BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size: len,
+            ..Default::default()
+        }];
+        let mut copy_task = BufferCopyTask::new(stage_buffer, self.free_list().clone(), regions);
+        task_executor.execute(&mut copy_task);
+    }
+
+    /// Move the particles at `indices` to `positions`, e.g. to script pinned boundary particles
+    pub fn set_positions(
+        &mut self,
+        indices: &[u32],
+        positions: &[Vec3],
+        task_executor: &impl GpuTaskExecutor,
+    ) {
+        assert_eq!(indices.len(), positions.len());
+        if indices.is_empty() {
+            return;
+        }
+
+        let stage_buffer = Buffer::from_iter(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            positions.iter().map(|p| ParticlePosition {
+                position: p.extend(0.0).to_array(),
+            }),
+        )
+        .unwrap();
+        let regions = indices
+            .iter()
+            .enumerate()
+            .map(|(i, &index)| BufferCopy {
+                src_offset: i as u64,
+                dst_offset: index as u64,
+                size: 1,
+                ..Default::default()
+            })
+            .collect();
+        let mut copy_task = BufferCopyTask::new(stage_buffer, self.position.clone(), regions);
+        task_executor.execute(&mut copy_task);
+    }
+
+    /// Overwrite the densities of the first `densities.len()` particles, e.g. from a checkpoint
+    pub fn set_densities(&mut self, densities: &[f32], task_executor: &impl GpuTaskExecutor) {
+        if densities.is_empty() {
+            return;
+        }
+
+        let bits = densities.iter().map(|d| d.to_bits()).collect::<Vec<_>>();
+        let stage_buffer = self.stage_u32(&bits);
+        let regions = vec![BufferCopy {
+            size: densities.len() as u64,
+            ..Default::default()
+        }];
+        let mut copy_task =
+            BufferCopyTask::new(stage_buffer, self.density.clone().reinterpret(), regions);
+        task_executor.execute(&mut copy_task);
+    }
+
+    fn stage_u32(&self, values: &[u32]) -> Subbuffer<[u32]> {
+        Buffer::from_iter(
+            self.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            values.iter().copied(),
+        )
+        .unwrap()
+    }
+
+    pub fn replace_particles_from_init_data(
+        &mut self,
+        particles_init_data: &[ParticleInitData],
+        regions: &[BufferCopy],
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        task_executor: &dyn GpuTaskExecutor,
+    ) {
+        if particles_init_data.iter().any(|p| p.rest_density.is_some()) {
+            self.enable_phases(task_executor);
+        } else if particles_init_data.iter().any(|p| p.mass.is_some()) {
+            self.enable_masses(task_executor);
+        }
+
+        let positions = particles_init_data
+            .iter()
+            .map(|p| ParticlePosition {
+                position: p.position.extend(0.0).to_array(),
+            })
+            .collect::<Vec<_>>();
+        let velocities = particles_init_data
+            .iter()
+            .map(|p| ParticleVelocity {
+                velocity: p.velocity.extend(0.0).to_array(),
+            })
+            .collect::<Vec<_>>();
+
+        let stage_position_buffer = Buffer::from_iter(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            positions.iter().cloned(),
+        )
+        .unwrap();
+        let stage_velocity_buffer = Buffer::from_iter(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            velocities.iter().cloned(),
+        )
+        .unwrap();
+
+        let mut stage_task = ParticleStageTask::new(
+            stage_position_buffer,
+            stage_velocity_buffer,
+            self.position.clone(),
             self.velocity.clone(),
             regions.to_vec(),
         );
         task_executor.execute(&mut stage_task);
+
+        let stage_pinned_buffer = self.stage_u32(&vec![0; particles_init_data.len()]);
+        let mut pinned_task =
+            BufferCopyTask::new(stage_pinned_buffer, self.pinned.clone(), regions.to_vec());
+        task_executor.execute(&mut pinned_task);
+
+        // Unset masses and rest densities fall back to the scalar
+        let masses = particles_init_data.iter().map(|p| p.mass);
+        let rest_densities = particles_init_data.iter().map(|p| p.rest_density);
+        for (enabled, buffer, values) in [
+            (
+                self.masses_enabled(),
+                &self.mass,
+                masses.collect::<Vec<_>>(),
+            ),
+            (
+                self.phases_enabled(),
+                &self.rest_density,
+                rest_densities.collect(),
+            ),
+        ] {
+            if !enabled {
+                continue;
+            }
+            let bits = values
+                .iter()
+                .map(|value| value.unwrap_or(1.0).to_bits())
+                .collect::<Vec<_>>();
+            let mut scalar_task = BufferCopyTask::new(
+                self.stage_u32(&bits),
+                buffer.clone().reinterpret(),
+                regions.to_vec(),
+            );
+            task_executor.execute(&mut scalar_task);
+        }
     }
 
     pub fn replace_particles_from_particles(
@@ -370,6 +2213,8 @@ impl Particles {
     ) {
         self.count = src.count;
         self.cursor = src.cursor;
+        self.contacts_stale = true;
+        self.previous_position_stale = self.previous_position.is_some();
 
         if src.count() == 0 {
             return; // No particles to swap
@@ -389,6 +2234,36 @@ impl Particles {
             regions.to_vec(),
         );
         task_executor.execute(&mut swap_task);
+
+        let mut density_task =
+            BufferCopyTask::new(src.density.clone(), self.density.clone(), regions.to_vec());
+        task_executor.execute(&mut density_task);
+
+        let mut pinned_task =
+            BufferCopyTask::new(src.pinned.clone(), self.pinned.clone(), regions.to_vec());
+        task_executor.execute(&mut pinned_task);
+
+        if src.masses_enabled() {
+            self.enable_masses(task_executor);
+        }
+        if src.phases_enabled() {
+            self.enable_phases(task_executor);
+        }
+        for (src_enabled, src_buffer, buffer) in [
+            (src.masses_enabled(), &src.mass, &self.mass),
+            (src.phases_enabled(), &src.rest_density, &self.rest_density),
+        ] {
+            if src_enabled {
+                let mut scalar_task =
+                    BufferCopyTask::new(src_buffer.clone(), buffer.clone(), regions.to_vec());
+                task_executor.execute(&mut scalar_task);
+            } else if buffer.len() > 1 {
+                // Back to the scalar, as in `src`
+                let mut fill_task =
+                    BufferFillTask::new(buffer.clone().reinterpret(), 1.0f32.to_bits());
+                task_executor.execute(&mut fill_task);
+            }
+        }
     }
 
     // 新增: 将position复制到predicted_position
@@ -404,13 +2279,99 @@ impl Particles {
             ..Default::default()
         }];
 
-        let mut copy_task = PositionCopyTask::new(
+        let mut copy_task = BufferCopyTask::new(
             self.position.clone(),
             self.predicted_position.clone(),
             regions.to_vec(),
         );
         task_executor.execute(&mut copy_task);
     }
+
+    /// Start the position history over from the current positions, so no particle
+    /// appears to have moved
+    pub fn reset_previous_position(&mut self, task_executor: &impl GpuTaskExecutor) {
+        self.previous_position_stale = false;
+        if self.count == 0 {
+            return;
+        }
+
+        let regions = [BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size: self.count() as u64,
+            ..Default::default()
+        }];
+
+        let mut copy_task = BufferCopyTask::new(
+            self.position.clone(),
+            self.previous_position().clone(),
+            regions.to_vec(),
+        );
+        task_executor.execute(&mut copy_task);
+    }
+
+    /// Record the current positions as the ones `contacts` was built from
+    pub fn snapshot_search_position(&mut self, task_executor: &impl GpuTaskExecutor) {
+        if self.count == 0 {
+            return;
+        }
+
+        let regions = [BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size: self.count() as u64,
+            ..Default::default()
+        }];
+
+        let mut copy_task = BufferCopyTask::new(
+            self.position.clone(),
+            self.search_position().clone(),
+            regions.to_vec(),
+        );
+        task_executor.execute(&mut copy_task);
+    }
+
+    /// Record the current positions as the ones the radix sort ordered
+    pub fn snapshot_sort_position(&mut self, task_executor: &impl GpuTaskExecutor) {
+        if self.count == 0 {
+            return;
+        }
+
+        let regions = [BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size: self.count() as u64,
+            ..Default::default()
+        }];
+
+        let mut copy_task = BufferCopyTask::new(
+            self.position.clone(),
+            self.sort_position().clone(),
+            regions.to_vec(),
+        );
+        task_executor.execute(&mut copy_task);
+    }
+
+    /// Replace velocity with the viscosity pass result in predicted_velocity
+    pub fn copy_predicted_velocity_to_velocity(&mut self, task_executor: &impl GpuTaskExecutor) {
+        if self.count == 0 {
+            return;
+        }
+
+        let regions = [BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size: self.count() as u64,
+            ..Default::default()
+        }];
+
+        let mut copy_task = BufferCopyTask::new(
+            self.predicted_velocity().clone(),
+            self.velocity.clone(),
+            regions.to_vec(),
+        );
+        task_executor.execute(&mut copy_task);
+    }
 }
 
 pub(super) struct ParticleStageTask {
@@ -467,24 +2428,52 @@ impl GpuTask for ParticleStageTask {
     }
 }
 
-// 新增: PositionCopyTask，用于在GPU上复制位置数据
-pub(super) struct PositionCopyTask {
-    src: Subbuffer<[ParticlePosition]>,
-    dst: Subbuffer<[ParticlePosition]>,
+/// Fills a `u32` particle buffer with a constant value on the GPU
+pub(super) struct BufferFillTask {
+    dst: Subbuffer<[u32]>,
+    value: u32,
+}
+
+impl BufferFillTask {
+    pub fn new(dst: Subbuffer<[u32]>, value: u32) -> Self {
+        Self { dst, value }
+    }
+}
+
+impl GpuTask for BufferFillTask {
+    fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        builder.fill_buffer(self.dst.clone(), self.value).unwrap();
+    }
+
+    fn submit(
+        &mut self,
+        command_buffer: Arc<PrimaryAutoCommandBuffer>,
+        queue: &Arc<Queue>,
+        device: &Arc<Device>,
+    ) {
+        let future = sync::now(device.clone())
+            .then_execute(queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap();
+        future.wait(None).unwrap();
+    }
+}
+
+/// Copies regions between two particle buffers of the same element type on the GPU
+pub(super) struct BufferCopyTask<T: BufferContents + Copy> {
+    src: Subbuffer<[T]>,
+    dst: Subbuffer<[T]>,
     regions: Vec<BufferCopy>,
 }
 
-impl PositionCopyTask {
-    pub fn new(
-        src: Subbuffer<[ParticlePosition]>,
-        dst: Subbuffer<[ParticlePosition]>,
-        regions: Vec<BufferCopy>,
-    ) -> Self {
+impl<T: BufferContents + Copy> BufferCopyTask<T> {
+    pub fn new(src: Subbuffer<[T]>, dst: Subbuffer<[T]>, regions: Vec<BufferCopy>) -> Self {
         Self { src, dst, regions }
     }
 }
 
-impl GpuTask for PositionCopyTask {
+impl<T: BufferContents + Copy> GpuTask for BufferCopyTask<T> {
     fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
         let mut copy_info = CopyBufferInfoTyped::buffers(self.src.clone(), self.dst.clone());
         copy_info.regions = self.regions.clone().into();
@@ -505,3 +2494,237 @@ impl GpuTask for PositionCopyTask {
         future.wait(None).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::VulkanoHeadlessBackend;
+
+    #[test]
+    fn test_block_and_grid_positions() {
+        let origin = Vec3::new(1.0, -1.0, 0.5);
+        let block = ParticleInitData::block(UVec3::splat(2), 0.1, origin);
+        let expected = [
+            [0.0, 0.0, 0.0],
+            [0.1, 0.0, 0.0],
+            [0.0, 0.1, 0.0],
+            [0.1, 0.1, 0.0],
+            [0.0, 0.0, 0.1],
+            [0.1, 0.0, 0.1],
+            [0.0, 0.1, 0.1],
+            [0.1, 0.1, 0.1],
+        ]
+        .map(|offset| origin + Vec3::from_array(offset));
+        assert_eq!(block.len(), 8);
+        for (particle, expected) in block.iter().zip(expected) {
+            assert!(particle.position.abs_diff_eq(expected, 1e-6));
+            assert_eq!(particle.velocity, Vec3::ZERO);
+        }
+
+        // A full cube is the block of its side; otherwise the last layer is partial
+        let grid = ParticleInitData::grid(8, 0.1, origin);
+        assert!(grid
+            .iter()
+            .zip(&block)
+            .all(|(a, b)| a.position == b.position));
+        let grid = ParticleInitData::grid(10, 0.1, Vec3::ZERO);
+        assert_eq!(grid.len(), 10);
+        assert_eq!(grid[9].position, Vec3::new(0.0, 0.0, 0.1));
+        assert!(ParticleInitData::grid(0, 0.1, Vec3::ZERO).is_empty());
+    }
+
+    #[test]
+    fn test_neighbor_stats_from_counts() {
+        let stats = NeighborStats::from_counts(&[0, 3, 5, 0]);
+        assert_eq!(
+            stats,
+            NeighborStats {
+                min: 0,
+                max: 5,
+                mean: 2.0,
+                particles_with_neighbors: 2,
+            }
+        );
+        assert_eq!(NeighborStats::from_counts(&[]), NeighborStats::default());
+    }
+
+    #[test]
+    fn test_init_data_is_staged_with_its_velocity() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        let particle = ParticleInitData {
+            position: Vec3::new(0.0, 1.0, 0.0),
+            velocity: Vec3::new(0.5, 0.0, -0.5),
+            ..Default::default()
+        };
+        particles.add_particles(&[particle], backend.memory_allocator(), &backend);
+
+        let velocities = particles.download_velocities(backend.memory_allocator(), &backend);
+        assert_eq!(velocities, vec![particle.velocity]);
+    }
+
+    #[test]
+    fn test_init_data_masses_and_rest_densities_are_staged() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &ParticleInitData::grid(2, 0.1, Vec3::ZERO),
+            backend.memory_allocator(),
+            &backend,
+        );
+        assert!(particles
+            .download_masses(backend.memory_allocator(), &backend)
+            .is_none());
+
+        // A batch with its own phase enables the buffers, earlier particles keep the scalars
+        let oil = ParticleInitData {
+            mass: Some(0.9),
+            rest_density: Some(0.8),
+            ..Default::default()
+        };
+        let heavy = ParticleInitData {
+            mass: Some(2.0),
+            ..Default::default()
+        };
+        particles.add_particles(&[oil, heavy], backend.memory_allocator(), &backend);
+
+        let masses = particles.download_masses(backend.memory_allocator(), &backend);
+        let rest_densities =
+            particles.download_rest_densities(backend.memory_allocator(), &backend);
+        assert_eq!(masses, Some(vec![1.0, 1.0, 0.9, 2.0]));
+        assert_eq!(rest_densities, Some(vec![1.0, 1.0, 0.8, 1.0]));
+    }
+
+    #[test]
+    fn test_consecutive_batches_are_appended() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        let batch = |y: f32, len: usize| {
+            (0..len)
+                .map(|i| ParticleInitData {
+                    position: Vec3::new(i as f32, y, 0.0),
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>()
+        };
+        let (first, second) = (batch(1.0, 3), batch(2.0, 5));
+        particles.add_particles(&first, backend.memory_allocator(), &backend);
+        particles.add_particles(&second, backend.memory_allocator(), &backend);
+
+        assert_eq!(particles.count(), 8);
+        let positions = particles.download_positions(backend.memory_allocator(), &backend);
+        let expected = first
+            .iter()
+            .chain(&second)
+            .map(|p| p.position)
+            .collect::<Vec<_>>();
+        assert_eq!(positions, expected);
+    }
+
+    #[test]
+    fn test_with_capacity_sizes_buffers() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::with_capacity(backend.memory_allocator(), 1000);
+        particles.enable_predicted_velocity();
+        particles.enable_contacts();
+        particles.enable_merge(&backend);
+        assert_eq!(particles.capacity(), 1000);
+
+        for len in [
+            particles.position.len(),
+            particles.velocity.len(),
+            particles.hash.len(),
+            particles.index.len(),
+            particles.hash_temp.len(),
+            particles.index_temp.len(),
+            particles.density.len(),
+            particles.predicted_position.len(),
+            particles.pinned.len(),
+            particles.predicted_velocity().len(),
+            particles.contact_counts().len(),
+            particles.mass().len(),
+        ] {
+            assert_eq!(len, 1000);
+        }
+        assert_eq!(
+            particles.contacts().len(),
+            1000 * CONTACTS_PER_PARTICLE as u64
+        );
+
+        // Radix sort scratch follows its work groups rather than the capacity alone
+        assert_eq!(particles.histograms.len(), 1024);
+        assert_eq!(particles.prefix_sums.len(), 1024);
+    }
+
+    #[test]
+    fn test_optional_buffers_are_allocated_lazily() {
+        let backend = VulkanoHeadlessBackend::new();
+
+        let minimal = Particles::new(backend.memory_allocator());
+        let mut full = Particles::new(backend.memory_allocator());
+        full.enable_predicted_velocity();
+
+        assert!(minimal.predicted_velocity.is_none());
+        assert!(full.predicted_velocity.is_some());
+        assert_eq!(
+            full.memory_usage_bytes() - minimal.memory_usage_bytes(),
+            PARTICLE_MAX_COUNT as u64 * std::mem::size_of::<ParticleVelocity>() as u64
+        );
+
+        // Enabling twice must not allocate again
+        let usage = full.memory_usage_bytes();
+        full.enable_predicted_velocity();
+        assert_eq!(full.memory_usage_bytes(), usage);
+    }
+
+    #[test]
+    fn test_truncate_marks_previous_positions_stale() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::with_capacity(backend.memory_allocator(), 16);
+        particles.add_particles(
+            &ParticleInitData::grid(8, 0.1, Vec3::ZERO),
+            backend.memory_allocator(),
+            &backend,
+        );
+        particles.enable_previous_position();
+        particles.reset_previous_position(&backend);
+        assert!(!particles.previous_position_stale());
+
+        particles.truncate(4);
+        assert_eq!(particles.count(), 4);
+        assert!(particles.previous_position_stale());
+    }
+
+    #[test]
+    fn test_batch_longer_than_capacity_keeps_its_last_particles() {
+        let backend = VulkanoHeadlessBackend::new();
+        let batch = (0..6)
+            .map(|i| ParticleInitData {
+                position: Vec3::new(i as f32, 0.0, 0.0),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let positions = |particles: &Particles| {
+            particles.download_positions(backend.memory_allocator(), &backend)
+        };
+
+        let mut particles = Particles::with_capacity(backend.memory_allocator(), 4);
+        particles.add_particles(&batch, backend.memory_allocator(), &backend);
+        assert_eq!(particles.count(), 4);
+        assert_eq!(
+            positions(&particles),
+            batch[2..].iter().map(|p| p.position).collect::<Vec<_>>()
+        );
+
+        // Starting mid-buffer the kept particles wrap around to the front
+        let mut particles = Particles::with_capacity(backend.memory_allocator(), 4);
+        particles.add_particles(&batch[..1], backend.memory_allocator(), &backend);
+        particles.add_particles(&batch, backend.memory_allocator(), &backend);
+        assert_eq!(particles.count(), 4);
+        assert_eq!(
+            positions(&particles),
+            [5, 2, 3, 4].map(|i| batch[i].position).to_vec()
+        );
+    }
+}
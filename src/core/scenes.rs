@@ -0,0 +1,55 @@
+use glam::Vec3;
+
+use super::{geometry::Aabb, particle::ParticleInitData};
+
+/// Particles at rest on a regular lattice over `aabb`, keeping only points where `keep` holds
+///
+/// The lattice starts at `aabb.min()` and steps by `spacing` along each axis without passing
+/// `aabb.max()`, so any shape with an inside test (letters, logos, SDF interiors) can be filled.
+pub fn fill_predicate(
+    aabb: Aabb,
+    spacing: f32,
+    keep: impl Fn(Vec3) -> bool,
+) -> Vec<ParticleInitData> {
+    let extent = aabb.max() - aabb.min();
+    let steps = (extent / spacing).floor().as_uvec3() + 1;
+
+    (0..steps.z)
+        .flat_map(|z| (0..steps.y).flat_map(move |y| (0..steps.x).map(move |x| (x, y, z))))
+        .map(|(x, y, z)| aabb.min() + Vec3::new(x as f32, y as f32, z as f32) * spacing)
+        .filter(|&position| keep(position))
+        .map(|position| ParticleInitData {
+            position,
+            velocity: Vec3::ZERO,
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_sphere_keeps_only_interior_points() {
+        let (center, radius) = (Vec3::new(0.2, 0.5, -0.1), 0.3);
+        let aabb = Aabb::new(center - radius, center + radius);
+        let spacing = 0.05;
+
+        let particles = fill_predicate(aabb, spacing, |p| p.distance(center) <= radius);
+
+        assert!(!particles.is_empty());
+        for particle in &particles {
+            assert!(
+                particle.position.distance(center) <= radius,
+                "{:?}",
+                particle.position
+            );
+        }
+
+        // Roughly the sphere's volume worth of lattice cells
+        let expected = 4.0 / 3.0 * std::f32::consts::PI * radius.powi(3) / spacing.powi(3);
+        let ratio = particles.len() as f32 / expected;
+        assert!((0.8..1.2).contains(&ratio), "{}", ratio);
+    }
+}
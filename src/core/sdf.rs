@@ -0,0 +1,129 @@
+use std::{io, path::Path};
+
+use glam::{UVec3, Vec3};
+use serde::{Deserialize, Serialize};
+
+use super::{geometry::Aabb, mesh::TriangleMesh};
+
+/// Signed distance field of a static obstacle, sampled on a grid over an [`Aabb`]
+///
+/// Negative inside the obstacle. Samples sit on the grid points, the first and last of each
+/// axis on the faces of the box, and are read back with trilinear interpolation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sdf {
+    resolution: UVec3,
+    aabb: Aabb,
+    /// x-major samples, `resolution.x * resolution.y * resolution.z` of them
+    values: Vec<f32>,
+}
+
+impl Sdf {
+    /// Bake `distance` at every grid point; each axis gets at least 2 samples
+    pub fn from_fn(resolution: UVec3, aabb: Aabb, distance: impl Fn(Vec3) -> f32) -> Self {
+        let resolution = resolution.max(UVec3::splat(2));
+        let cell_size = (aabb.max() - aabb.min()) / (resolution - 1).as_vec3();
+        let mut values = Vec::with_capacity((resolution.x * resolution.y * resolution.z) as usize);
+        for z in 0..resolution.z {
+            for y in 0..resolution.y {
+                for x in 0..resolution.x {
+                    let point = aabb.min() + UVec3::new(x, y, z).as_vec3() * cell_size;
+                    values.push(distance(point));
+                }
+            }
+        }
+        Self {
+            resolution,
+            aabb,
+            values,
+        }
+    }
+
+    /// Solid sphere at `center`
+    pub fn sphere(resolution: UVec3, aabb: Aabb, center: Vec3, radius: f32) -> Self {
+        Self::from_fn(resolution, aabb, |p| p.distance(center) - radius)
+    }
+
+    /// Solid axis-aligned box `obstacle`
+    pub fn cuboid(resolution: UVec3, aabb: Aabb, obstacle: Aabb) -> Self {
+        let center = (obstacle.min() + obstacle.max()) * 0.5;
+        let half_extent = (obstacle.max() - obstacle.min()) * 0.5;
+        Self::from_fn(resolution, aabb, |p| {
+            let q = (p - center).abs() - half_extent;
+            q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+        })
+    }
+
+    /// Bake the closed triangle mesh in an OBJ file, over its bounds grown by `padding`
+    ///
+    /// Every grid point is checked against every triangle, so keep meshes and resolutions small.
+    pub fn from_obj(path: impl AsRef<Path>, resolution: UVec3, padding: f32) -> io::Result<Self> {
+        let mesh = TriangleMesh::load_obj(path)?;
+        let bounds = mesh.bounds();
+        let aabb = Aabb::new(
+            bounds.min() - Vec3::splat(padding),
+            bounds.max() + Vec3::splat(padding),
+        );
+        Ok(Self::from_fn(resolution, aabb, |p| mesh.signed_distance(p)))
+    }
+
+    pub fn resolution(&self) -> UVec3 {
+        self.resolution
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        self.aabb
+    }
+
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cuboid_is_negative_inside_and_exact_on_grid_points() {
+        let aabb = Aabb::new(Vec3::splat(-1.0), Vec3::splat(1.0));
+        let obstacle = Aabb::new(Vec3::splat(-0.5), Vec3::splat(0.5));
+        let sdf = Sdf::cuboid(UVec3::splat(5), aabb, obstacle);
+
+        let value_at = |x: usize, y: usize, z: usize| sdf.values()[x + 5 * (y + 5 * z)];
+        // Grid points are 0.5 apart: the center, a face, and a corner of the domain
+        assert_eq!(value_at(2, 2, 2), -0.5);
+        assert_eq!(value_at(3, 2, 2), 0.0);
+        assert!((value_at(0, 0, 0) - 0.75f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_unit_cube_obj_is_negative_inside_and_positive_outside() {
+        let path = std::env::temp_dir().join(format!("aqua_gpu_cube_{}.obj", std::process::id()));
+        std::fs::write(
+            &path,
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\nv 1 0 1\nv 1 1 1\nv 0 1 1\n\
+             f 1 4 3 2\nf 5 6 7 8\nf 1 2 6 5\nf 4 8 7 3\nf 1 5 8 4\nf 2 3 7 6\n",
+        )
+        .unwrap();
+        let sdf = Sdf::from_obj(&path, UVec3::splat(9), 0.5);
+        std::fs::remove_file(&path).unwrap();
+        let sdf = sdf.unwrap();
+
+        // Padded to [-0.5, 1.5] with grid points 0.25 apart
+        assert_eq!(sdf.aabb(), Aabb::new(Vec3::splat(-0.5), Vec3::splat(1.5)));
+        let value_at = |x: usize, y: usize, z: usize| sdf.values()[x + 9 * (y + 9 * z)];
+        assert!(
+            (value_at(4, 4, 4) + 0.5).abs() < 1e-6,
+            "{}",
+            value_at(4, 4, 4)
+        );
+        assert!(
+            (value_at(0, 4, 4) - 0.5).abs() < 1e-6,
+            "{}",
+            value_at(0, 4, 4)
+        );
+        assert!((value_at(0, 0, 0) - 0.75f32.sqrt()).abs() < 1e-6);
+        assert!(value_at(3, 5, 4) < 0.0);
+        assert!(value_at(8, 5, 4) > 0.0);
+    }
+}
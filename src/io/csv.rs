@@ -0,0 +1,92 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{core::Particles, utils::GpuTaskExecutor};
+
+const CSV_HEADER: &str = "index,x,y,z,vx,vy,vz,density,contact_count";
+
+/// Write the state of every live particle as one CSV row, e.g. to find where densities blow up
+///
+/// Rows cover the live `count` particles only, not the spare capacity of the buffers. The
+/// `contact_count` column is empty while neighbor lists are disabled.
+pub fn dump_csv(
+    path: impl AsRef<Path>,
+    particles: &Particles,
+    task_executor: &impl GpuTaskExecutor,
+) -> io::Result<()> {
+    let memory_allocator = particles.memory_allocator();
+    let positions = particles.download_positions(memory_allocator, task_executor);
+    let velocities = particles.download_velocities(memory_allocator, task_executor);
+    let densities = particles.download_densities(memory_allocator, task_executor);
+    let contact_counts = particles.download_contact_counts(memory_allocator, task_executor);
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "{}", CSV_HEADER)?;
+    for (i, ((position, velocity), density)) in positions
+        .iter()
+        .zip(&velocities)
+        .zip(&densities)
+        .enumerate()
+    {
+        write!(
+            writer,
+            "{},{},{},{},{},{},{},{},",
+            i, position.x, position.y, position.z, velocity.x, velocity.y, velocity.z, density
+        )?;
+        if let Some(contact_counts) = &contact_counts {
+            write!(writer, "{}", contact_counts[i])?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use glam::Vec3;
+
+    use super::*;
+    use crate::{core::ParticleInitData, utils::VulkanoHeadlessBackend};
+
+    #[test]
+    fn test_csv_dump_has_a_row_per_live_particle() {
+        let backend = VulkanoHeadlessBackend::new();
+        let particles_init_data = (0..5)
+            .map(|i| ParticleInitData {
+                position: Vec3::new(i as f32 * 0.1, 0.5, -0.25),
+                velocity: Vec3::new(0.0, -1.0, i as f32),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particles_init_data, backend.memory_allocator(), &backend);
+        // The despawned tail stays in the buffers but must not be dumped
+        particles.truncate(3);
+
+        let path = std::env::temp_dir().join(format!("aqua_gpu_dump_{}.csv", std::process::id()));
+        dump_csv(&path, &particles, &backend).unwrap();
+        let csv = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(CSV_HEADER));
+        let rows = lines
+            .map(|line| line.split(',').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), particles.count() as usize);
+        for (row, init) in rows.iter().zip(&particles_init_data) {
+            assert_eq!(row.len(), CSV_HEADER.split(',').count());
+            let values = row[1..7]
+                .iter()
+                .map(|v| v.parse::<f32>().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(Vec3::from_slice(&values[..3]), init.position);
+            assert_eq!(Vec3::from_slice(&values[3..]), init.velocity);
+        }
+    }
+}
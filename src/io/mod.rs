@@ -0,0 +1,7 @@
+mod csv;
+mod ply;
+mod sequence;
+
+pub use csv::dump_csv;
+pub use ply::{export_ply, export_ply_with_options, PlyFormat, PlyOptions};
+pub use sequence::{export_sequence, sequence_frame_path, SequenceWriter};
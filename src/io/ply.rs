@@ -0,0 +1,182 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use glam::Vec3;
+
+use crate::{core::Particles, utils::GpuTaskExecutor};
+
+/// Encoding of the vertex data after the PLY header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlyFormat {
+    /// One line of text per vertex, readable but several times larger
+    #[default]
+    Ascii,
+    BinaryLittleEndian,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PlyOptions {
+    pub format: PlyFormat,
+    /// Add a `speed` property holding each particle's velocity magnitude
+    pub velocity_magnitude: bool,
+}
+
+/// Write the particle positions to an ASCII PLY point cloud, e.g. to open in MeshLab or Blender
+pub fn export_ply(
+    path: impl AsRef<Path>,
+    particles: &Particles,
+    task_executor: &impl GpuTaskExecutor,
+) -> io::Result<()> {
+    export_ply_with_options(path, particles, task_executor, PlyOptions::default())
+}
+
+/// [`export_ply`] with a choice of encoding and per-particle properties
+pub fn export_ply_with_options(
+    path: impl AsRef<Path>,
+    particles: &Particles,
+    task_executor: &impl GpuTaskExecutor,
+    options: PlyOptions,
+) -> io::Result<()> {
+    let memory_allocator = particles.memory_allocator();
+    let positions = particles.download_positions(memory_allocator, task_executor);
+    let speeds = options.velocity_magnitude.then(|| {
+        particles
+            .download_velocities(memory_allocator, task_executor)
+            .iter()
+            .map(|velocity| velocity.length())
+            .collect::<Vec<_>>()
+    });
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_ply(&mut writer, &positions, speeds.as_deref(), options.format)?;
+    writer.flush()
+}
+
+fn write_ply(
+    writer: &mut impl Write,
+    positions: &[Vec3],
+    speeds: Option<&[f32]>,
+    format: PlyFormat,
+) -> io::Result<()> {
+    let format_name = match format {
+        PlyFormat::Ascii => "ascii",
+        PlyFormat::BinaryLittleEndian => "binary_little_endian",
+    };
+    writeln!(writer, "ply")?;
+    writeln!(writer, "format {} 1.0", format_name)?;
+    writeln!(writer, "element vertex {}", positions.len())?;
+    for axis in ["x", "y", "z"] {
+        writeln!(writer, "property float {}", axis)?;
+    }
+    if speeds.is_some() {
+        writeln!(writer, "property float speed")?;
+    }
+    writeln!(writer, "end_header")?;
+
+    for (i, position) in positions.iter().enumerate() {
+        let speed = speeds.map(|speeds| speeds[i]);
+        match format {
+            PlyFormat::Ascii => {
+                write!(writer, "{} {} {}", position.x, position.y, position.z)?;
+                if let Some(speed) = speed {
+                    write!(writer, " {}", speed)?;
+                }
+                writeln!(writer)?;
+            }
+            PlyFormat::BinaryLittleEndian => {
+                for value in position.to_array().into_iter().chain(speed) {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{core::ParticleInitData, utils::VulkanoHeadlessBackend};
+
+    /// Header lines and the vertex rows of a PLY file written by [`write_ply`]
+    fn parse_ply(bytes: &[u8]) -> (Vec<String>, Vec<Vec<f32>>) {
+        let header_end = bytes
+            .windows(b"end_header\n".len())
+            .position(|window| window == b"end_header\n")
+            .unwrap()
+            + b"end_header\n".len();
+        let header = String::from_utf8(bytes[..header_end].to_vec())
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        let property_count = header
+            .iter()
+            .filter(|line| line.starts_with("property"))
+            .count();
+        let body = &bytes[header_end..];
+
+        let rows = if header[1] == "format ascii 1.0" {
+            String::from_utf8(body.to_vec())
+                .unwrap()
+                .lines()
+                .map(|line| line.split(' ').map(|v| v.parse().unwrap()).collect())
+                .collect()
+        } else {
+            body.chunks(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect::<Vec<_>>()
+                .chunks(property_count)
+                .map(<[f32]>::to_vec)
+                .collect()
+        };
+        (header, rows)
+    }
+
+    #[test]
+    fn test_exported_ply_round_trips_positions() {
+        let backend = VulkanoHeadlessBackend::new();
+        let particles_init_data = [
+            (Vec3::new(0.0, 0.0, 0.0), Vec3::ZERO),
+            (Vec3::new(1.5, -2.25, 0.125), Vec3::new(3.0, 4.0, 0.0)),
+            (Vec3::new(-0.5, 0.75, 1.0), Vec3::new(0.0, -2.0, 0.0)),
+        ]
+        .map(|(position, velocity)| ParticleInitData {
+            position,
+            velocity,
+            ..Default::default()
+        });
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particles_init_data, backend.memory_allocator(), &backend);
+
+        let path = std::env::temp_dir().join(format!("aqua_gpu_export_{}.ply", std::process::id()));
+        for options in [
+            PlyOptions::default(),
+            PlyOptions {
+                format: PlyFormat::BinaryLittleEndian,
+                velocity_magnitude: true,
+            },
+        ] {
+            export_ply_with_options(&path, &particles, &backend, options).unwrap();
+            let (header, rows) = parse_ply(&fs::read(&path).unwrap());
+
+            assert_eq!(header[0], "ply");
+            assert!(header.contains(&"element vertex 3".to_string()));
+            assert_eq!(rows.len(), 3);
+            for (row, init) in rows.iter().zip(&particles_init_data) {
+                assert_eq!(Vec3::from_slice(&row[..3]), init.position);
+                if options.velocity_magnitude {
+                    assert_eq!(row[3], init.velocity.length());
+                } else {
+                    assert_eq!(row.len(), 3);
+                }
+            }
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{core::Particles, utils::GpuTaskExecutor};
+
+use super::ply::{export_ply_with_options, PlyFormat, PlyOptions};
+
+/// Path of `frame` in a sequence under `dir`, numbered like Houdini's `$F4`
+pub fn sequence_frame_path(dir: impl AsRef<Path>, frame: u32) -> PathBuf {
+    dir.as_ref().join(format!("particles.{:04}.ply", frame))
+}
+
+/// Write `frame` of a particle cache under `dir` as a numbered binary PLY
+///
+/// Houdini and Blender load the numbered files as one animated point cloud.
+pub fn export_sequence(
+    dir: impl AsRef<Path>,
+    frame: u32,
+    particles: &Particles,
+    task_executor: &impl GpuTaskExecutor,
+) -> io::Result<PathBuf> {
+    let path = sequence_frame_path(dir, frame);
+    export_ply_with_options(&path, particles, task_executor, SequenceWriter::OPTIONS)?;
+    Ok(path)
+}
+
+/// Numbered PLY files of consecutive frames, a replayable cache of a simulation run
+pub struct SequenceWriter {
+    dir: PathBuf,
+    next_frame: u32,
+}
+
+impl SequenceWriter {
+    /// Velocity magnitudes are kept so the cache can be shaded like the viewer
+    const OPTIONS: PlyOptions = PlyOptions {
+        format: PlyFormat::BinaryLittleEndian,
+        velocity_magnitude: true,
+    };
+
+    /// Write frames to `dir`, creating it if needed, starting at frame 1
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        Self::starting_at(dir, 1)
+    }
+
+    /// Write frames to `dir` starting at `first_frame`, e.g. to continue a cache
+    pub fn starting_at(dir: impl AsRef<Path>, first_frame: u32) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            next_frame: first_frame,
+        })
+    }
+
+    /// Write the particles as the next frame and return the file's path
+    pub fn write_frame(
+        &mut self,
+        particles: &Particles,
+        task_executor: &impl GpuTaskExecutor,
+    ) -> io::Result<PathBuf> {
+        let path = export_sequence(&self.dir, self.next_frame, particles, task_executor)?;
+        self.next_frame += 1;
+        Ok(path)
+    }
+
+    /// Number the next written frame gets
+    pub fn next_frame(&self) -> u32 {
+        self.next_frame
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::*;
+    use crate::{core::ParticleInitData, utils::VulkanoHeadlessBackend};
+
+    fn vertex_count(path: &Path) -> usize {
+        let bytes = fs::read(path).unwrap();
+        let header = String::from_utf8_lossy(&bytes);
+        header
+            .lines()
+            .find_map(|line| line.strip_prefix("element vertex "))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sequence_writes_numbered_frames() {
+        let backend = VulkanoHeadlessBackend::new();
+        let dir = std::env::temp_dir().join(format!("aqua_gpu_sequence_{}", std::process::id()));
+        let particle = |x: f32| ParticleInitData {
+            position: Vec3::new(x, 0.0, 0.0),
+            velocity: Vec3::ZERO,
+            ..Default::default()
+        };
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &[particle(0.0), particle(0.1)],
+            backend.memory_allocator(),
+            &backend,
+        );
+
+        let mut writer = SequenceWriter::new(&dir).unwrap();
+        let first = writer.write_frame(&particles, &backend).unwrap();
+        particles.add_particles(&[particle(0.2)], backend.memory_allocator(), &backend);
+        let second = writer.write_frame(&particles, &backend).unwrap();
+
+        assert_eq!(first, dir.join("particles.0001.ply"));
+        assert_eq!(second, sequence_frame_path(&dir, 2));
+        assert_eq!(writer.next_frame(), 3);
+        assert_eq!(vertex_count(&first), 2);
+        assert_eq!(vertex_count(&second), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+//! GPU fluid simulation on Vulkan
+//!
+//! [`SimulationSystem`] steps a [`Particles`] set on the device of a [`VulkanoBackend`], so
+//! the solver can share a renderer's device and buffers. [`HeadlessSimulation`] owns a
+//! [`VulkanoHeadlessBackend`] instead and advances with [`HeadlessSimulation::step`], for
+//! offline runs without a window, and [`run_headless`] wraps it for a fixed number of steps.
+//! [`export_ply`] writes particle positions as a point cloud for other tools. [`App`] is the
+//! interactive viewer of the `aqua_gpu` binary, whose render settings such as the
+//! [`RenderMode`] and [`ColorMode`] are set on it before the event loop starts.
+
+mod application;
+mod core;
+mod io;
+mod shaders;
+mod systems;
+mod utils;
+
+pub use application::{App, BenchmarkApp};
+pub use core::{
+    Aabb, EmissionShape, Emitter, ForceField, NeighborStats, ParticleDensity, ParticleInitData,
+    ParticlePosition, ParticleSnapshot, ParticleVelocity, Particles, Sdf,
+};
+pub use io::{
+    dump_csv, export_ply, export_ply_with_options, export_sequence, sequence_frame_path, PlyFormat,
+    PlyOptions, SequenceWriter,
+};
+pub use systems::{
+    run_headless, ColorMode, Colormap, ContactResetStrategy, DensityAlphaRange, DensityKernel,
+    FieldGrid, Follow, GridField, HeadlessSimulation, Msaa, PbdSolveOrder, PipelineStages,
+    RenderMode, SimulationConfig, SimulationConfigBuilder, SimulationSnapshot,
+    SimulationStepTiming, SimulationSystem, SortPositionMode, SphParams, ViscosityMode,
+};
+pub use utils::{GpuTask, GpuTaskExecutor, TaskBatch, VulkanoBackend, VulkanoHeadlessBackend};
@@ -1,15 +1,22 @@
-mod application;
-mod core;
-mod shaders;
-mod systems;
-mod utils;
+use std::{env, error::Error};
 
-use std::error::Error;
-
-use application::App;
+use aqua_gpu::{App, BenchmarkApp};
 use winit::event_loop::EventLoop;
 
+/// Frames simulated by `--headless` unless `AQUA_HEADLESS_FRAMES` says otherwise
+const DEFAULT_HEADLESS_FRAMES: u64 = 1000;
+
 fn main() -> Result<(), impl Error> {
+    // `--headless` or `AQUA_HEADLESS` runs the physics without a window, for profiling
+    if env::args().any(|arg| arg == "--headless") || env::var_os("AQUA_HEADLESS").is_some() {
+        let frames = env::var("AQUA_HEADLESS_FRAMES")
+            .ok()
+            .and_then(|frames| frames.parse().ok())
+            .unwrap_or(DEFAULT_HEADLESS_FRAMES);
+        BenchmarkApp::new().run(frames);
+        return Ok(());
+    }
+
     let event_loop = EventLoop::new().unwrap();
     let mut app = App::new(&event_loop);
 
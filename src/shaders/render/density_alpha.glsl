@@ -0,0 +1,8 @@
+// Alpha growing with density over [min_density, max_density], same as DensityAlphaRange; an
+// empty range disables the mapping and leaves particles opaque
+float density_alpha(float density, float min_density, float max_density)
+{
+    float range = max_density - min_density;
+    if (range <= 0.0) return 1.0;
+    return clamp((density - min_density) / range, 0.0, 1.0);
+}
@@ -0,0 +1,225 @@
+pub mod depth_vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        include: ["src/shaders/render"],
+        src: r"
+            #version 450
+
+            layout(location = 0) in vec4 position;
+
+            layout(location = 0) out vec3 v_view_center;
+
+            layout(set = 0, binding = 0) uniform Data {
+                mat4 view;
+                mat4 proj;
+                float viewport_height;
+                float density_alpha_min;
+                float density_alpha_max;
+                float point_size_min;
+                float point_size_max;
+                float particle_radius;
+                uint colormap;
+                float color_max_speed;
+                vec4 flat_color;
+            } uniforms;
+
+            #include <point_size.glsl>
+
+            void main() {
+                vec4 view_position = uniforms.view * vec4(position.xyz, 1.0);
+                gl_Position = uniforms.proj * view_position;
+                v_view_center = view_position.xyz;
+                gl_PointSize = sphere_point_size(
+                    -view_position.z,
+                    uniforms.particle_radius,
+                    uniforms.proj[1][1],
+                    uniforms.viewport_height,
+                    uniforms.point_size_min,
+                    uniforms.point_size_max);
+            }
+        ",
+    }
+}
+
+pub mod depth_fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        src: r"
+            #version 450
+
+            layout(location = 0) in vec3 v_view_center;
+
+            // Linear distance in front of the camera, 0 where no particle was drawn
+            layout(location = 0) out float f_depth;
+
+            layout(set = 0, binding = 0) uniform Data {
+                mat4 view;
+                mat4 proj;
+                float viewport_height;
+                float density_alpha_min;
+                float density_alpha_max;
+                float point_size_min;
+                float point_size_max;
+                float particle_radius;
+                uint colormap;
+                float color_max_speed;
+                vec4 flat_color;
+            } uniforms;
+
+            void main() {
+                vec2 coord = gl_PointCoord * 2.0 - 1.0;
+                float r_sq = dot(coord, coord);
+                if (r_sq > 1.0) discard;
+                vec3 normal = vec3(coord.x, -coord.y, sqrt(1.0 - r_sq));
+
+                vec3 surface = v_view_center + normal * uniforms.particle_radius;
+                vec4 clip_position = uniforms.proj * vec4(surface, 1.0);
+                gl_FragDepth = clip_position.z / clip_position.w;
+                f_depth = -surface.z;
+            }
+        ",
+    }
+}
+
+pub mod smooth_cs {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        src: r"
+            #version 450
+
+            layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+            layout(set = 0, binding = 0, r32f) uniform readonly image2D src_depth;
+            layout(set = 0, binding = 1, r32f) uniform writeonly image2D dst_depth;
+
+            layout(push_constant) uniform SmoothingConstants {
+                int filter_radius;
+                float spatial_sigma;
+                float depth_falloff;
+            } constants;
+
+            // Bilateral filter: neighbors are weighted by screen distance and by how close
+            // their depth is, so small steps between adjacent particles blur out while the
+            // silhouette against the background and against distant fluid stays sharp
+            void main() {
+                ivec2 size = imageSize(src_depth);
+                ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+                if (pixel.x >= size.x || pixel.y >= size.y) return;
+
+                float depth = imageLoad(src_depth, pixel).r;
+                if (depth <= 0.0) {
+                    imageStore(dst_depth, pixel, vec4(0.0));
+                    return;
+                }
+
+                float sum = 0.0;
+                float weight_sum = 0.0;
+                for (int y = -constants.filter_radius; y <= constants.filter_radius; y++) {
+                    for (int x = -constants.filter_radius; x <= constants.filter_radius; x++) {
+                        ivec2 neighbor = clamp(pixel + ivec2(x, y), ivec2(0), size - 1);
+                        float neighbor_depth = imageLoad(src_depth, neighbor).r;
+                        if (neighbor_depth <= 0.0) continue;
+
+                        float spatial = float(x * x + y * y)
+                            / (2.0 * constants.spatial_sigma * constants.spatial_sigma);
+                        float range = (neighbor_depth - depth) / constants.depth_falloff;
+                        float weight = exp(-spatial - range * range);
+                        sum += neighbor_depth * weight;
+                        weight_sum += weight;
+                    }
+                }
+                imageStore(dst_depth, pixel, vec4(sum / weight_sum));
+            }
+        ",
+    }
+}
+
+pub mod shade_vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        src: r"
+            #version 450
+
+            // One triangle covering the screen
+            void main() {
+                vec2 corner = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+                gl_Position = vec4(corner * 2.0 - 1.0, 0.0, 1.0);
+            }
+        ",
+    }
+}
+
+pub mod shade_fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        src: r"
+            #version 450
+
+            layout(location = 0) out vec4 f_color;
+
+            layout(set = 0, binding = 0) uniform Data {
+                mat4 view;
+                mat4 proj;
+                float viewport_height;
+                float density_alpha_min;
+                float density_alpha_max;
+                float point_size_min;
+                float point_size_max;
+                float particle_radius;
+                uint colormap;
+                float color_max_speed;
+                vec4 flat_color;
+            } uniforms;
+
+            layout(set = 0, binding = 2) uniform sampler2D surface_depth;
+
+            // View-space position of the smoothed surface at `pixel`, z is 0 off the fluid
+            vec3 view_position(ivec2 pixel) {
+                ivec2 size = textureSize(surface_depth, 0);
+                float depth = texelFetch(surface_depth, clamp(pixel, ivec2(0), size - 1), 0).r;
+                if (depth <= 0.0) return vec3(0.0);
+                vec2 ndc = (vec2(pixel) + 0.5) / vec2(size) * 2.0 - 1.0;
+                return vec3(
+                    ndc.x * depth / uniforms.proj[0][0],
+                    ndc.y * depth / uniforms.proj[1][1],
+                    -depth
+                );
+            }
+
+            // Of the forward and backward differences, the shorter one, which does not
+            // reach across a silhouette
+            vec3 derivative(vec3 center, ivec2 pixel, ivec2 step) {
+                vec3 forward = view_position(pixel + step);
+                vec3 backward = view_position(pixel - step);
+                vec3 to_forward = forward.z == 0.0 ? vec3(1e6) : forward - center;
+                vec3 from_backward = backward.z == 0.0 ? vec3(1e6) : center - backward;
+                return abs(to_forward.z) < abs(from_backward.z) ? to_forward : from_backward;
+            }
+
+            void main() {
+                ivec2 pixel = ivec2(gl_FragCoord.xy);
+                vec3 position = view_position(pixel);
+                if (position.z == 0.0) discard;
+
+                vec3 normal = normalize(cross(
+                    derivative(position, pixel, ivec2(1, 0)),
+                    derivative(position, pixel, ivec2(0, 1))
+                ));
+                if (normal.z < 0.0) normal = -normal;
+
+                vec3 to_eye = normalize(-position);
+                vec3 light = normalize(vec3(0.3, 0.6, 0.7));
+                float diffuse = max(dot(normal, light), 0.0);
+                float specular = pow(max(dot(normal, normalize(light + to_eye)), 0.0), 64.0);
+                float fresnel = 0.02 + 0.98 * pow(1.0 - max(dot(normal, to_eye), 0.0), 5.0);
+
+                vec3 water = vec3(0.1, 0.4, 0.8) * (0.3 + 0.7 * diffuse);
+                vec3 color = mix(water, vec3(0.9, 0.95, 1.0), fresnel) + specular;
+                f_color = vec4(color, 1.0);
+
+                vec4 clip_position = uniforms.proj * vec4(position, 1.0);
+                gl_FragDepth = clip_position.z / clip_position.w;
+            }
+        ",
+    }
+}
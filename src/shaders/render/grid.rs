@@ -0,0 +1,43 @@
+pub mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        src: r"
+            #version 450
+
+            layout(location = 0) in vec3 position;
+
+            layout(set = 0, binding = 0) uniform Data {
+                mat4 view;
+                mat4 proj;
+                float viewport_height;
+                float density_alpha_min;
+                float density_alpha_max;
+                float point_size_min;
+                float point_size_max;
+                float particle_radius;
+                uint colormap;
+                float color_max_speed;
+                vec4 flat_color;
+            } uniforms;
+
+            void main() {
+                gl_Position = uniforms.proj * uniforms.view * vec4(position, 1.0);
+            }
+        ",
+    }
+}
+
+pub mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        src: r"
+            #version 450
+
+            layout(location = 0) out vec4 f_color;
+
+            void main() {
+                f_color = vec4(0.4, 0.8, 0.4, 1.0);
+            }
+        ",
+    }
+}
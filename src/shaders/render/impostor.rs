@@ -0,0 +1,106 @@
+pub mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        include: ["src/shaders/render"],
+        src: r"
+            #version 450
+
+            layout(location = 0) in vec4 position;
+            layout(location = 1) in vec4 velocity;
+            layout(location = 2) in float density;
+
+            layout(location = 0) out float v_speed;
+            layout(location = 1) out float v_alpha;
+            layout(location = 2) out vec3 v_view_center;
+
+            layout(set = 0, binding = 0) uniform Data {
+                mat4 view;
+                mat4 proj;
+                float viewport_height;
+                float density_alpha_min;
+                float density_alpha_max;
+                float point_size_min;
+                float point_size_max;
+                float particle_radius;
+                uint colormap;
+                float color_max_speed;
+                vec4 flat_color;
+            } uniforms;
+
+            #include <density_alpha.glsl>
+            #include <point_size.glsl>
+
+            void main() {
+                vec4 view_position = uniforms.view * vec4(position.xyz, 1.0);
+                gl_Position = uniforms.proj * view_position;
+                v_speed = length(velocity);
+                v_alpha =
+                    density_alpha(density, uniforms.density_alpha_min, uniforms.density_alpha_max);
+                v_view_center = view_position.xyz;
+
+                gl_PointSize = sphere_point_size(
+                    -view_position.z,
+                    uniforms.particle_radius,
+                    uniforms.proj[1][1],
+                    uniforms.viewport_height,
+                    uniforms.point_size_min,
+                    uniforms.point_size_max);
+            }
+        ",
+    }
+}
+
+pub mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        include: ["src/shaders/render"],
+        src: r"
+            #version 450
+
+            layout(location = 0) in float v_speed;
+            layout(location = 1) in float v_alpha;
+            layout(location = 2) in vec3 v_view_center;
+
+            layout(location = 0) out vec4 f_color;
+
+            layout(set = 0, binding = 0) uniform Data {
+                mat4 view;
+                mat4 proj;
+                float viewport_height;
+                float density_alpha_min;
+                float density_alpha_max;
+                float point_size_min;
+                float point_size_max;
+                float particle_radius;
+                uint colormap;
+                float color_max_speed;
+                vec4 flat_color;
+            } uniforms;
+
+            layout(set = 0, binding = 1) uniform sampler2D sprite_texture;
+
+            #include <speed_color.glsl>
+
+            void main() {
+                // Reconstruct the sphere normal from the sprite coordinate
+                vec2 coord = gl_PointCoord * 2.0 - 1.0;
+                float r_sq = dot(coord, coord);
+                if (r_sq > 1.0) discard;
+                vec3 normal = vec3(coord.x, -coord.y, sqrt(1.0 - r_sq));
+
+                // Depth of the sphere surface rather than the flat sprite, so overlapping
+                // particles intersect like spheres
+                vec4 clip_position =
+                    uniforms.proj * vec4(v_view_center + normal * uniforms.particle_radius, 1.0);
+                gl_FragDepth = clip_position.z / clip_position.w;
+
+                vec3 color = speed_color(
+                    v_speed, uniforms.colormap, uniforms.color_max_speed, uniforms.flat_color.rgb);
+
+                float diffuse = max(dot(normal, normalize(vec3(0.3, 0.6, 0.7))), 0.0);
+                f_color = vec4(color * (0.3 + 0.7 * diffuse), v_alpha)
+                    * texture(sprite_texture, gl_PointCoord);
+            }
+        ",
+    }
+}
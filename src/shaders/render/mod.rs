@@ -1 +1,5 @@
+pub(crate) mod fluid_surface;
+pub(crate) mod grid;
+pub(crate) mod impostor;
+pub(crate) mod sphere;
 pub(crate) mod unlit;
@@ -0,0 +1,9 @@
+// On-screen diameter in pixels of a sphere of the given radius at view depth, kept within
+// [min_size, max_size] so near particles do not fill the screen and far ones do not vanish.
+// proj_y is proj[1][1] of the perspective projection.
+float sphere_point_size(
+    float depth, float radius, float proj_y, float viewport_height, float min_size, float max_size)
+{
+    float size = 2.0 * radius * proj_y * 0.5 * viewport_height / max(depth, 1e-4);
+    return clamp(size, min_size, max_size);
+}
@@ -0,0 +1,24 @@
+// Speed through the colormap selected by ColorMode, same as Colormap::sample; colormap index 0
+// is the flat color
+vec3 speed_color(float speed, uint colormap, float max_speed, vec3 flat_color)
+{
+    if (colormap == 0u) return flat_color;
+    float t = clamp(speed / max_speed, 0.0, 1.0);
+    if (colormap == 1u)
+    {
+        // Polynomial fit of matplotlib's viridis
+        vec3 c0 = vec3(0.27772733, 0.0054073445, 0.3340998);
+        vec3 c1 = vec3(0.10509304, 1.4046135, 1.3845902);
+        vec3 c2 = vec3(-0.33086183, 0.21484756, 0.09509516);
+        vec3 c3 = vec3(-4.6342306, -5.799101, -19.332441);
+        vec3 c4 = vec3(6.22827, 14.179933, 56.69055);
+        vec3 c5 = vec3(4.776385, -13.745145, -65.35303);
+        vec3 c6 = vec3(-5.435456, 4.6458526, 26.312435);
+        return c0 + t * (c1 + t * (c2 + t * (c3 + t * (c4 + t * (c5 + t * c6)))));
+    }
+    if (colormap == 2u)
+    {
+        return clamp(1.5 - abs(4.0 * t - vec3(3.0, 2.0, 1.0)), 0.0, 1.0);
+    }
+    return vec3(t);
+}
@@ -0,0 +1,85 @@
+pub mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        include: ["src/shaders/render"],
+        src: r"
+            #version 450
+
+            // Unit sphere mesh, per vertex
+            layout(location = 0) in vec3 offset;
+            // Particle data, per instance
+            layout(location = 1) in vec4 position;
+            layout(location = 2) in vec4 velocity;
+            layout(location = 3) in float density;
+
+            layout(location = 0) out float v_speed;
+            layout(location = 1) out vec3 v_normal;
+            layout(location = 2) out float v_alpha;
+
+            layout(set = 0, binding = 0) uniform Data {
+                mat4 view;
+                mat4 proj;
+                float viewport_height;
+                float density_alpha_min;
+                float density_alpha_max;
+                float point_size_min;
+                float point_size_max;
+                float particle_radius;
+                uint colormap;
+                float color_max_speed;
+                vec4 flat_color;
+            } uniforms;
+
+            #include <density_alpha.glsl>
+
+            void main() {
+                vec3 world_position = position.xyz + offset * uniforms.particle_radius;
+                gl_Position = uniforms.proj * uniforms.view * vec4(world_position, 1.0);
+                v_speed = length(velocity);
+                v_alpha =
+                    density_alpha(density, uniforms.density_alpha_min, uniforms.density_alpha_max);
+                v_normal = mat3(uniforms.view) * offset;
+            }
+        ",
+    }
+}
+
+pub mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        include: ["src/shaders/render"],
+        src: r"
+            #version 450
+
+            layout(location = 0) in float v_speed;
+            layout(location = 1) in vec3 v_normal;
+            layout(location = 2) in float v_alpha;
+
+            layout(location = 0) out vec4 f_color;
+
+            layout(set = 0, binding = 0) uniform Data {
+                mat4 view;
+                mat4 proj;
+                float viewport_height;
+                float density_alpha_min;
+                float density_alpha_max;
+                float point_size_min;
+                float point_size_max;
+                float particle_radius;
+                uint colormap;
+                float color_max_speed;
+                vec4 flat_color;
+            } uniforms;
+
+            #include <speed_color.glsl>
+
+            void main() {
+                vec3 color = speed_color(
+                    v_speed, uniforms.colormap, uniforms.color_max_speed, uniforms.flat_color.rgb);
+
+                float diffuse = max(dot(normalize(v_normal), normalize(vec3(0.3, 0.6, 0.7))), 0.0);
+                f_color = vec4(color * (0.3 + 0.7 * diffuse), v_alpha);
+            }
+        ",
+    }
+}
@@ -1,23 +1,48 @@
 pub mod vs {
     vulkano_shaders::shader! {
         ty: "vertex",
+        include: ["src/shaders/render"],
         src: r"
             #version 450
 
             layout(location = 0) in vec4 position;
             layout(location = 1) in vec4 velocity;
+            layout(location = 2) in float density;
 
             layout(location = 0) out float v_speed;
+            layout(location = 1) out float v_alpha;
 
             layout(set = 0, binding = 0) uniform Data {
                 mat4 view;
                 mat4 proj;
+                float viewport_height;
+                float density_alpha_min;
+                float density_alpha_max;
+                float point_size_min;
+                float point_size_max;
+                float particle_radius;
+                uint colormap;
+                float color_max_speed;
+                vec4 flat_color;
             } uniforms;
 
+            #include <density_alpha.glsl>
+            #include <point_size.glsl>
+
             void main() {
-                gl_Position = uniforms.proj * uniforms.view * vec4(position.xyz, 1.0);
+                vec4 view_position = uniforms.view * vec4(position.xyz, 1.0);
+                gl_Position = uniforms.proj * view_position;
                 v_speed = length(velocity);
-                gl_PointSize = 2.0;
+                v_alpha =
+                    density_alpha(density, uniforms.density_alpha_min, uniforms.density_alpha_max);
+                // Points are as wide as an impostor of particle_radius at this depth
+                gl_PointSize = sphere_point_size(
+                    -view_position.z,
+                    uniforms.particle_radius,
+                    uniforms.proj[1][1],
+                    uniforms.viewport_height,
+                    uniforms.point_size_min,
+                    uniforms.point_size_max);
             }
         ",
     }
@@ -26,19 +51,37 @@ pub mod vs {
 pub mod fs {
     vulkano_shaders::shader! {
         ty: "fragment",
+        include: ["src/shaders/render"],
         src: r"
             #version 450
 
             layout(location = 0) in float v_speed;
+            layout(location = 1) in float v_alpha;
 
             layout(location = 0) out vec4 f_color;
 
-            void main() {
-                float max_speed = 3.0;
-                float t = clamp(v_speed / max_speed, 0.0, 1.0);
+            layout(set = 0, binding = 0) uniform Data {
+                mat4 view;
+                mat4 proj;
+                float viewport_height;
+                float density_alpha_min;
+                float density_alpha_max;
+                float point_size_min;
+                float point_size_max;
+                float particle_radius;
+                uint colormap;
+                float color_max_speed;
+                vec4 flat_color;
+            } uniforms;
+
+            layout(set = 0, binding = 1) uniform sampler2D sprite_texture;
 
-                vec3 color = mix(vec3(0.0, 1.0, 1.0), vec3(1.0, 1.0, 0.0), t);
-                f_color = vec4(color, 1.0);
+            #include <speed_color.glsl>
+
+            void main() {
+                vec3 color = speed_color(
+                    v_speed, uniforms.colormap, uniforms.color_max_speed, uniforms.flat_color.rgb);
+                f_color = vec4(color, v_alpha) * texture(sprite_texture, gl_PointCoord);
             }
         ",
     }
@@ -0,0 +1,9 @@
+// Gradient of the spiky kernel with support h, where factor is the spiky_grad_kernel_factor
+// the host computes for h. It points along r_vec, the offset between the two particles, and
+// vanishes outside the support and for coincident particles.
+vec3 spiky_gradient(vec3 r_vec, float r, float h, float factor)
+{
+    if (r >= h || r == 0.0) return vec3(0.0);
+    float diff = h - r;
+    return factor * diff * diff * (r_vec / r);
+}
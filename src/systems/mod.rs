@@ -1,5 +1,10 @@
 mod render;
 mod simulation;
 
-pub(crate) use render::RenderSystem;
-pub(crate) use simulation::{SimulationConfig, SimulationSystem};
+pub use render::{ColorMode, Colormap, DensityAlphaRange, Follow, Msaa, RenderMode};
+pub(crate) use render::{RenderSystem, SpriteTexture};
+pub use simulation::{
+    run_headless, ContactResetStrategy, DensityKernel, FieldGrid, GridField, HeadlessSimulation,
+    PbdSolveOrder, PipelineStages, SimulationConfig, SimulationConfigBuilder, SimulationSnapshot,
+    SimulationStepTiming, SimulationSystem, SortPositionMode, SphParams, ViscosityMode,
+};
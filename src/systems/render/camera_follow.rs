@@ -0,0 +1,116 @@
+use glam::Vec3;
+
+/// What the camera tracks between frames
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Follow {
+    /// The camera stays where it was placed
+    #[default]
+    Fixed,
+    /// The camera translates with the mass-weighted mean particle position
+    CenterOfMass,
+}
+
+/// Exponentially smoothed follow target
+///
+/// Each update moves the target the fraction `1 - exp(-dt / damping)` of the way to the
+/// goal, which is frame-rate independent and, being first order, can never overshoot.
+#[derive(Clone, Copy, Debug)]
+pub struct CameraFollow {
+    /// Time constant in seconds; zero snaps to the goal every frame
+    damping: f32,
+    target: Option<Vec3>,
+}
+
+impl Default for CameraFollow {
+    fn default() -> Self {
+        Self::new(0.5)
+    }
+}
+
+impl CameraFollow {
+    pub fn new(damping: f32) -> Self {
+        Self {
+            damping: damping.max(0.0),
+            target: None,
+        }
+    }
+
+    pub fn set_damping(&mut self, damping: f32) {
+        self.damping = damping.max(0.0);
+    }
+
+    /// Forget the smoothed target, so the next update starts at its goal
+    pub fn reset(&mut self) {
+        self.target = None;
+    }
+
+    /// Advance the smoothed target toward `goal` over `dt` seconds and return how far it moved
+    pub fn update(&mut self, goal: Vec3, dt: f32) -> Vec3 {
+        let Some(target) = self.target else {
+            self.target = Some(goal);
+            return Vec3::ZERO;
+        };
+
+        let blend = if self.damping > 0.0 {
+            1.0 - (-dt.max(0.0) / self.damping).exp()
+        } else {
+            1.0
+        };
+        let new_target = target.lerp(goal, blend);
+        self.target = Some(new_target);
+        new_target - target
+    }
+
+    #[allow(unused)]
+    pub fn target(&self) -> Option<Vec3> {
+        self.target
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_follow_target_converges_without_overshoot() {
+        let dt = 1.0 / 60.0;
+        let mut follow = CameraFollow::new(0.25);
+        follow.update(Vec3::ZERO, dt);
+
+        // A point moving along +X, then stopping
+        let velocity = Vec3::new(2.0, 0.0, 0.0);
+        let mut goal = Vec3::ZERO;
+        let mut previous_gap = f32::MAX;
+        for frame in 0..600 {
+            if frame < 120 {
+                goal += velocity * dt;
+            }
+            let moved = follow.update(goal, dt);
+            let target = follow.target().unwrap();
+
+            // Never moves backwards or past the goal
+            assert!(moved.x >= 0.0, "frame {} moved {:?}", frame, moved);
+            assert!(
+                target.x <= goal.x + 1e-5,
+                "frame {} {:?} {:?}",
+                frame,
+                target,
+                goal
+            );
+            assert!(target.y == 0.0 && target.z == 0.0);
+
+            // Once the goal stops the gap only shrinks
+            let gap = goal.x - target.x;
+            if frame >= 120 {
+                assert!(gap <= previous_gap, "frame {} gap {}", frame, gap);
+            }
+            previous_gap = gap;
+        }
+        assert!(previous_gap < 1e-4, "{}", previous_gap);
+
+        // Without damping the target snaps to the goal
+        follow.set_damping(0.0);
+        follow.update(Vec3::ONE, dt);
+        assert_eq!(follow.target(), Some(Vec3::ONE));
+    }
+}
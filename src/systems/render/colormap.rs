@@ -0,0 +1,110 @@
+use glam::Vec3;
+
+/// Color of every particle in [`ColorMode::Flat`], and of speeds without a range to map onto
+pub const FLAT_COLOR: Vec3 = Vec3::new(0.2, 0.5, 1.0);
+
+/// Gradient that particle speeds are mapped through in [`ColorMode::Velocity`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Colormap {
+    /// Perceptually uniform dark purple to yellow
+    #[default]
+    Viridis,
+    /// Blue through cyan, yellow and red
+    Jet,
+    /// Black to white
+    Grayscale,
+}
+
+impl Colormap {
+    /// Color at `t` in [0, 1], same as `speed_color` in the particle fragment shaders
+    pub fn sample(self, t: f32) -> Vec3 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Colormap::Viridis => {
+                // Polynomial fit of matplotlib's viridis
+                const COEFFICIENTS: [[f32; 3]; 7] = [
+                    [0.27772733, 0.0054073445, 0.3340998],
+                    [0.10509304, 1.4046135, 1.3845902],
+                    [-0.33086183, 0.21484756, 0.09509516],
+                    [-4.6342306, -5.799101, -19.332441],
+                    [6.22827, 14.179933, 56.69055],
+                    [4.776385, -13.745145, -65.35303],
+                    [-5.435456, 4.6458526, 26.312435],
+                ];
+                COEFFICIENTS
+                    .iter()
+                    .rev()
+                    .fold(Vec3::ZERO, |color, &c| color * t + Vec3::from_array(c))
+            }
+            Colormap::Jet => (Vec3::splat(1.5)
+                - (Vec3::splat(4.0 * t) - Vec3::new(3.0, 2.0, 1.0)).abs())
+            .clamp(Vec3::ZERO, Vec3::ONE),
+            Colormap::Grayscale => Vec3::splat(t),
+        }
+    }
+
+    /// Index the fragment shaders switch on, 0 being the flat color
+    fn shader_index(self) -> u32 {
+        match self {
+            Colormap::Viridis => 1,
+            Colormap::Jet => 2,
+            Colormap::Grayscale => 3,
+        }
+    }
+}
+
+/// How the particle fragment shaders pick a color
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorMode {
+    /// One color for every particle
+    Flat,
+    /// Speed mapped through the colormap, saturating at `max_speed`
+    Velocity { max_speed: f32 },
+}
+
+impl Default for ColorMode {
+    fn default() -> Self {
+        ColorMode::Velocity { max_speed: 3.0 }
+    }
+}
+
+impl ColorMode {
+    /// Uniform colormap index and max speed; a speed range that is not positive has nothing
+    /// to map onto and falls back to the flat color
+    pub fn uniform_values(self, colormap: Colormap) -> (u32, f32) {
+        match self {
+            ColorMode::Velocity { max_speed } if max_speed > 0.0 => {
+                (colormap.shader_index(), max_speed)
+            }
+            _ => (0, 0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colormap_endpoints() {
+        let viridis_low = Colormap::Viridis.sample(0.0);
+        let viridis_high = Colormap::Viridis.sample(1.0);
+        assert!(viridis_low.abs_diff_eq(Vec3::new(0.267, 0.005, 0.329), 0.02));
+        assert!(viridis_high.abs_diff_eq(Vec3::new(0.993, 0.906, 0.144), 0.02));
+
+        assert_eq!(Colormap::Jet.sample(0.0), Vec3::new(0.0, 0.0, 0.5));
+        assert_eq!(Colormap::Jet.sample(0.5), Vec3::new(0.5, 1.0, 0.5));
+        assert_eq!(Colormap::Jet.sample(1.0), Vec3::new(0.5, 0.0, 0.0));
+
+        assert_eq!(Colormap::Grayscale.sample(-1.0), Vec3::ZERO);
+        assert_eq!(Colormap::Grayscale.sample(2.0), Vec3::ONE);
+    }
+
+    #[test]
+    fn test_zero_max_speed_falls_back_to_flat() {
+        let velocity = |max_speed| ColorMode::Velocity { max_speed };
+        assert_eq!(velocity(0.0).uniform_values(Colormap::Jet), (0, 0.0));
+        assert_eq!(ColorMode::Flat.uniform_values(Colormap::Jet), (0, 0.0));
+        assert_eq!(velocity(4.0).uniform_values(Colormap::Jet), (2, 4.0));
+    }
+}
@@ -0,0 +1,16 @@
+/// Density interval mapped to fragment alpha for a volumetric look
+///
+/// Particles at or below `min` are fully transparent and particles at or above `max`
+/// fully opaque, so dense fluid reads as solid and spray fades out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DensityAlphaRange {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl DensityAlphaRange {
+    /// Uniform bounds for an optional range; an empty range disables the mapping in the shaders
+    pub fn uniform_bounds(range: Option<Self>) -> (f32, f32) {
+        range.map_or((0.0, 0.0), |range| (range.min, range.max))
+    }
+}
@@ -0,0 +1,633 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{BufferContents, Subbuffer},
+    command_buffer::{
+        AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, RenderPassBeginInfo, SubpassBeginInfo,
+        SubpassContents,
+    },
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, DeviceOwned},
+    format::Format,
+    image::{
+        sampler::{Sampler, SamplerCreateInfo},
+        view::ImageView,
+        Image, ImageCreateInfo, ImageType, ImageUsage,
+    },
+    memory::allocator::{AllocationCreateInfo, StandardMemoryAllocator},
+    pipeline::{
+        compute::ComputePipelineCreateInfo,
+        graphics::{
+            input_assembly::PrimitiveTopology,
+            vertex_input::{Vertex, VertexDefinition, VertexInputState},
+            viewport::Viewport,
+        },
+        layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, GraphicsPipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass},
+};
+
+use crate::{core::ParticlePosition, shaders};
+
+use super::render_context::create_graphics_pipeline;
+
+/// Binding of the smoothed depth in the surface shading fragment shader
+pub(crate) const SURFACE_DEPTH_BINDING: u32 = 2;
+
+/// Bilateral filter passes over the particle depth
+const SMOOTHING_ITERATIONS: usize = 2;
+
+/// `local_size_x` and `local_size_y` of the smoothing shader
+const WORK_GROUP_SIZE: u32 = 8;
+
+/// Linear depth written by the depth pass and filtered by the smoothing pass
+const SURFACE_DEPTH_FORMAT: Format = Format::R32_SFLOAT;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub(crate) struct SmoothingConstants {
+    filter_radius: i32,
+    spatial_sigma: f32,
+    depth_falloff: f32,
+}
+
+impl SmoothingConstants {
+    /// Filter for particles of `particle_radius`; depth steps well beyond one particle
+    /// diameter are kept as silhouettes
+    pub fn new(particle_radius: f32) -> Self {
+        Self {
+            filter_radius: 6,
+            spatial_sigma: 3.0,
+            depth_falloff: 2.0 * particle_radius.max(1e-4),
+        }
+    }
+}
+
+/// Deferred screen-space fluid surface
+///
+/// Particles are first drawn as sphere impostors into a linear depth image, which a
+/// bilateral filter then smooths into one continuous surface. A full-screen pass in the
+/// main render pass reconstructs normals from the smoothed depth and shades water,
+/// writing the surface depth so the grid overlay still intersects it.
+pub(crate) struct FluidSurface {
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
+    depth_render_pass: Arc<RenderPass>,
+    depth_framebuffer: Arc<Framebuffer>,
+    depth_pipeline: Arc<GraphicsPipeline>,
+    smooth_pipeline: Arc<ComputePipeline>,
+    /// Ping-pong depth images; the depth pass writes the first
+    depth_images: [Arc<ImageView>; 2],
+    /// Reads image `i` and writes the other for each index `i`
+    smooth_descriptor_sets: [Arc<DescriptorSet>; 2],
+    shade_pipeline: Arc<GraphicsPipeline>,
+    sampler: Arc<Sampler>,
+}
+
+impl FluidSurface {
+    /// Surface shaded into the first subpass of `render_pass` over `viewport`
+    pub fn new(
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        render_pass: &Arc<RenderPass>,
+        viewport: &Viewport,
+    ) -> Self {
+        let device = memory_allocator.device();
+        let depth_render_pass = vulkano::single_pass_renderpass!(
+            device.clone(),
+            attachments: {
+                surface_depth: {
+                    format: SURFACE_DEPTH_FORMAT,
+                    samples: 1,
+                    load_op: Clear,
+                    store_op: Store,
+                },
+                depth_stencil: {
+                    format: Format::D16_UNORM,
+                    samples: 1,
+                    load_op: Clear,
+                    store_op: DontCare,
+                },
+            },
+            pass: {
+                color: [surface_depth],
+                depth_stencil: {depth_stencil},
+            },
+        )
+        .unwrap();
+
+        let smooth_shader = shaders::render::fluid_surface::smooth_cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap();
+        let stage = PipelineShaderStageCreateInfo::new(smooth_shader);
+        let layout = PipelineLayout::new(
+            device.clone(),
+            PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                .into_pipeline_layout_create_info(device.clone())
+                .unwrap(),
+        )
+        .unwrap();
+        let smooth_pipeline = ComputePipeline::new(
+            device.clone(),
+            None,
+            ComputePipelineCreateInfo::stage_layout(stage, layout),
+        )
+        .unwrap();
+
+        let (depth_framebuffer, depth_images, smooth_descriptor_sets) = size_dependent_setup(
+            memory_allocator,
+            descriptor_set_allocator,
+            &depth_render_pass,
+            &smooth_pipeline,
+            viewport,
+        );
+
+        Self {
+            memory_allocator: memory_allocator.clone(),
+            descriptor_set_allocator: descriptor_set_allocator.clone(),
+            depth_pipeline: depth_pipeline(device, &depth_render_pass, viewport),
+            depth_render_pass,
+            depth_framebuffer,
+            smooth_pipeline,
+            depth_images,
+            smooth_descriptor_sets,
+            shade_pipeline: shade_pipeline(device, render_pass, viewport),
+            sampler: Sampler::new(device.clone(), SamplerCreateInfo::default()).unwrap(),
+        }
+    }
+
+    /// Rebuild the images and pipelines for a new swapchain
+    pub fn resize(&mut self, render_pass: &Arc<RenderPass>, viewport: &Viewport) {
+        let device = self.memory_allocator.device().clone();
+        (
+            self.depth_framebuffer,
+            self.depth_images,
+            self.smooth_descriptor_sets,
+        ) = size_dependent_setup(
+            &self.memory_allocator,
+            &self.descriptor_set_allocator,
+            &self.depth_render_pass,
+            &self.smooth_pipeline,
+            viewport,
+        );
+        self.depth_pipeline = depth_pipeline(&device, &self.depth_render_pass, viewport);
+        self.shade_pipeline = shade_pipeline(&device, render_pass, viewport);
+    }
+
+    /// Takes the render uniforms at binding 0
+    pub fn depth_pipeline(&self) -> &Arc<GraphicsPipeline> {
+        &self.depth_pipeline
+    }
+
+    /// Takes the render uniforms at binding 0 and [`Self::depth_descriptor_write`]
+    pub fn shade_pipeline(&self) -> &Arc<GraphicsPipeline> {
+        &self.shade_pipeline
+    }
+
+    /// Linear depth as drawn by the depth pass, before smoothing overwrites it
+    #[allow(unused)]
+    pub fn rendered_depth(&self) -> &Arc<Image> {
+        self.depth_images[0].image()
+    }
+
+    /// Linear depth once every smoothing pass ran
+    #[allow(unused)]
+    pub fn smoothed_depth(&self) -> &Arc<Image> {
+        self.depth_images[SMOOTHING_ITERATIONS % 2].image()
+    }
+
+    /// The smoothed depth, sampled by the shading pass
+    pub fn depth_descriptor_write(&self) -> WriteDescriptorSet {
+        WriteDescriptorSet::image_view_sampler(
+            SURFACE_DEPTH_BINDING,
+            self.depth_images[SMOOTHING_ITERATIONS % 2].clone(),
+            self.sampler.clone(),
+        )
+    }
+
+    /// Draw the first `count` particles as spheres into the linear depth image
+    pub fn record_depth(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        descriptor_set: &Arc<DescriptorSet>,
+        position: &Subbuffer<[ParticlePosition]>,
+        count: u32,
+    ) {
+        builder
+            .begin_render_pass(
+                RenderPassBeginInfo {
+                    clear_values: vec![Some([0.0; 4].into()), Some(1.0f32.into())],
+                    ..RenderPassBeginInfo::framebuffer(self.depth_framebuffer.clone())
+                },
+                SubpassBeginInfo {
+                    contents: SubpassContents::Inline,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        builder
+            .bind_pipeline_graphics(self.depth_pipeline.clone())
+            .unwrap();
+        builder
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.depth_pipeline.layout().clone(),
+                0,
+                descriptor_set.clone(),
+            )
+            .unwrap();
+        builder.bind_vertex_buffers(0, position.clone()).unwrap();
+        unsafe { builder.draw(count, 1, 0, 0) }.unwrap();
+        builder.end_render_pass(Default::default()).unwrap();
+    }
+
+    /// Bilateral filter the depth image, recorded after [`Self::record_depth`]
+    pub fn record_smoothing(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        constants: SmoothingConstants,
+    ) {
+        let [width, height, _] = self.depth_images[0].image().extent();
+        builder
+            .bind_pipeline_compute(self.smooth_pipeline.clone())
+            .unwrap();
+        builder
+            .push_constants(self.smooth_pipeline.layout().clone(), 0, constants)
+            .unwrap();
+        for iteration in 0..SMOOTHING_ITERATIONS {
+            builder
+                .bind_descriptor_sets(
+                    PipelineBindPoint::Compute,
+                    self.smooth_pipeline.layout().clone(),
+                    0,
+                    self.smooth_descriptor_sets[iteration % 2].clone(),
+                )
+                .unwrap();
+            unsafe {
+                builder
+                    .dispatch([
+                        width.div_ceil(WORK_GROUP_SIZE),
+                        height.div_ceil(WORK_GROUP_SIZE),
+                        1,
+                    ])
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Shade the smoothed surface, recorded inside the main render pass
+    pub fn record_shade(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        descriptor_set: &Arc<DescriptorSet>,
+    ) {
+        builder
+            .bind_pipeline_graphics(self.shade_pipeline.clone())
+            .unwrap();
+        builder
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.shade_pipeline.layout().clone(),
+                0,
+                descriptor_set.clone(),
+            )
+            .unwrap();
+        unsafe { builder.draw(3, 1, 0, 0) }.unwrap();
+    }
+}
+
+/// Depth framebuffer, ping-pong images and smoothing descriptor sets sized to `viewport`
+fn size_dependent_setup(
+    memory_allocator: &Arc<StandardMemoryAllocator>,
+    descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+    depth_render_pass: &Arc<RenderPass>,
+    smooth_pipeline: &Arc<ComputePipeline>,
+    viewport: &Viewport,
+) -> (
+    Arc<Framebuffer>,
+    [Arc<ImageView>; 2],
+    [Arc<DescriptorSet>; 2],
+) {
+    let extent = [
+        (viewport.extent[0] as u32).max(1),
+        (viewport.extent[1] as u32).max(1),
+        1,
+    ];
+    let image = |format: Format, usage: ImageUsage| {
+        ImageView::new_default(
+            Image::new(
+                memory_allocator.clone(),
+                ImageCreateInfo {
+                    image_type: ImageType::Dim2d,
+                    format,
+                    extent,
+                    usage,
+                    ..Default::default()
+                },
+                AllocationCreateInfo::default(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    let surface_depth_usage = ImageUsage::COLOR_ATTACHMENT
+        | ImageUsage::STORAGE
+        | ImageUsage::SAMPLED
+        | ImageUsage::TRANSFER_SRC;
+    let depth_images = [
+        image(SURFACE_DEPTH_FORMAT, surface_depth_usage),
+        image(SURFACE_DEPTH_FORMAT, surface_depth_usage),
+    ];
+
+    let depth_framebuffer = Framebuffer::new(
+        depth_render_pass.clone(),
+        FramebufferCreateInfo {
+            attachments: vec![
+                depth_images[0].clone(),
+                image(
+                    Format::D16_UNORM,
+                    ImageUsage::DEPTH_STENCIL_ATTACHMENT | ImageUsage::TRANSIENT_ATTACHMENT,
+                ),
+            ],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let layout = &smooth_pipeline.layout().set_layouts()[0];
+    let smooth_descriptor_set = |src: usize| {
+        DescriptorSet::new(
+            descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(0, depth_images[src].clone()),
+                WriteDescriptorSet::image_view(1, depth_images[1 - src].clone()),
+            ],
+            [],
+        )
+        .unwrap()
+    };
+    let smooth_descriptor_sets = [smooth_descriptor_set(0), smooth_descriptor_set(1)];
+
+    (depth_framebuffer, depth_images, smooth_descriptor_sets)
+}
+
+/// Sphere impostors writing linear depth, reading only particle positions
+fn depth_pipeline(
+    device: &Arc<Device>,
+    depth_render_pass: &Arc<RenderPass>,
+    viewport: &Viewport,
+) -> Arc<GraphicsPipeline> {
+    let vertex_shader = shaders::render::fluid_surface::depth_vs::load(device.clone())
+        .unwrap()
+        .entry_point("main")
+        .unwrap();
+    let fragment_shader = shaders::render::fluid_surface::depth_fs::load(device.clone())
+        .unwrap()
+        .entry_point("main")
+        .unwrap();
+    let vertex_input_state = [ParticlePosition::per_vertex()]
+        .definition(&vertex_shader)
+        .unwrap();
+
+    create_graphics_pipeline(
+        device,
+        depth_render_pass,
+        viewport,
+        (vertex_shader, fragment_shader),
+        vertex_input_state,
+        PrimitiveTopology::PointList,
+        None,
+    )
+}
+
+/// Full-screen triangle shading the smoothed depth
+fn shade_pipeline(
+    device: &Arc<Device>,
+    render_pass: &Arc<RenderPass>,
+    viewport: &Viewport,
+) -> Arc<GraphicsPipeline> {
+    let vertex_shader = shaders::render::fluid_surface::shade_vs::load(device.clone())
+        .unwrap()
+        .entry_point("main")
+        .unwrap();
+    let fragment_shader = shaders::render::fluid_surface::shade_fs::load(device.clone())
+        .unwrap()
+        .entry_point("main")
+        .unwrap();
+
+    create_graphics_pipeline(
+        device,
+        render_pass,
+        viewport,
+        (vertex_shader, fragment_shader),
+        VertexInputState::new(),
+        PrimitiveTopology::TriangleList,
+        None,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+    use crate::{
+        systems::render::{
+            colormap::{ColorMode, Colormap, FLAT_COLOR},
+            render_context::get_render_pass,
+        },
+        utils::{GpuTask, GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::{Mat4, Vec3};
+    use vulkano::{
+        buffer::{Buffer, BufferCreateInfo, BufferUsage},
+        command_buffer::CopyImageToBufferInfo,
+        device::Queue,
+        image::SampleCount,
+        memory::allocator::MemoryTypeFilter,
+        sync::{self, GpuFuture},
+    };
+
+    const SIZE: u32 = 64;
+
+    /// Runs the depth and smoothing passes, copying the depth out before and after smoothing
+    struct SmoothingRun<'a> {
+        fluid_surface: &'a FluidSurface,
+        descriptor_set: Arc<DescriptorSet>,
+        position: Subbuffer<[ParticlePosition]>,
+        smoothing: SmoothingConstants,
+        rendered: Subbuffer<[f32]>,
+        smoothed: Subbuffer<[f32]>,
+    }
+
+    impl GpuTask for SmoothingRun<'_> {
+        fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+            self.fluid_surface.record_depth(
+                builder,
+                &self.descriptor_set,
+                &self.position,
+                self.position.len() as u32,
+            );
+            builder
+                .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+                    self.fluid_surface.rendered_depth().clone(),
+                    self.rendered.clone(),
+                ))
+                .unwrap();
+            self.fluid_surface.record_smoothing(builder, self.smoothing);
+            builder
+                .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+                    self.fluid_surface.smoothed_depth().clone(),
+                    self.smoothed.clone(),
+                ))
+                .unwrap();
+        }
+
+        fn submit(
+            &mut self,
+            command_buffer: Arc<PrimaryAutoCommandBuffer>,
+            queue: &Arc<Queue>,
+            device: &Arc<Device>,
+        ) {
+            sync::now(device.clone())
+                .then_execute(queue.clone(), command_buffer)
+                .unwrap()
+                .then_signal_fence_and_flush()
+                .unwrap()
+                .wait(None)
+                .unwrap();
+        }
+    }
+
+    /// Largest depth step between horizontally or vertically adjacent surface pixels
+    fn largest_step(depth: &[f32]) -> f32 {
+        let at = |x: u32, y: u32| depth[(y * SIZE + x) as usize];
+        let mut largest = 0.0f32;
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                for (nx, ny) in [(x + 1, y), (x, y + 1)] {
+                    if nx < SIZE && ny < SIZE && at(x, y) > 0.0 && at(nx, ny) > 0.0 {
+                        largest = largest.max((at(x, y) - at(nx, ny)).abs());
+                    }
+                }
+            }
+        }
+        largest
+    }
+
+    #[test]
+    fn test_smoothing_reduces_depth_steps_between_particles() {
+        let backend = VulkanoHeadlessBackend::new();
+        let memory_allocator = backend.memory_allocator();
+        let render_pass = get_render_pass(
+            backend.device(),
+            Format::R8G8B8A8_UNORM,
+            SampleCount::Sample1,
+        );
+        let viewport = Viewport {
+            offset: [0.0, 0.0],
+            extent: [SIZE as f32; 2],
+            depth_range: 0.0..=1.0,
+        };
+        let fluid_surface = FluidSurface::new(
+            memory_allocator,
+            backend.descriptor_set_allocator(),
+            &render_pass,
+            &viewport,
+        );
+
+        let particle_radius = 0.25;
+        let (colormap, color_max_speed) = ColorMode::default().uniform_values(Colormap::default());
+        let uniform_buffer = Buffer::from_data(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::UNIFORM_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            shaders::render::unlit::vs::Data {
+                view: Mat4::look_at_rh(Vec3::Z, Vec3::ZERO, Vec3::Y).to_cols_array_2d(),
+                proj: Mat4::perspective_rh(FRAC_PI_2, 1.0, 0.1, 10.0).to_cols_array_2d(),
+                viewport_height: SIZE as f32,
+                density_alpha_min: 0.0,
+                density_alpha_max: 0.0,
+                point_size_min: 1.0,
+                point_size_max: 64.0,
+                particle_radius,
+                colormap,
+                color_max_speed,
+                flat_color: FLAT_COLOR.extend(1.0).to_array(),
+            },
+        )
+        .unwrap();
+        let descriptor_set = DescriptorSet::new(
+            backend.descriptor_set_allocator().clone(),
+            fluid_surface.depth_pipeline().layout().set_layouts()[0].clone(),
+            [WriteDescriptorSet::buffer(0, uniform_buffer)],
+            [],
+        )
+        .unwrap();
+
+        let host_buffer_info = |usage: BufferUsage| BufferCreateInfo {
+            usage,
+            ..Default::default()
+        };
+        let host_visible = || AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+            ..Default::default()
+        };
+        let readback = || {
+            Buffer::new_slice(
+                memory_allocator.clone(),
+                host_buffer_info(BufferUsage::TRANSFER_DST),
+                host_visible(),
+                (SIZE * SIZE) as u64,
+            )
+            .unwrap()
+        };
+        // Two overlapping particles, the rim of the front one stepping onto the back one
+        let position = Buffer::from_iter(
+            memory_allocator.clone(),
+            host_buffer_info(BufferUsage::VERTEX_BUFFER),
+            host_visible(),
+            [Vec3::new(-0.15, 0.0, 0.0), Vec3::new(0.2, 0.0, -0.6)].map(ParticlePosition::new),
+        )
+        .unwrap();
+        let mut run = SmoothingRun {
+            fluid_surface: &fluid_surface,
+            descriptor_set,
+            position,
+            smoothing: SmoothingConstants::new(particle_radius),
+            rendered: readback(),
+            smoothed: readback(),
+        };
+        backend.execute(&mut run);
+
+        let rendered = run.rendered.read().unwrap();
+        let smoothed = run.smoothed.read().unwrap();
+        let covered = |depth: &[f32]| depth.iter().filter(|&&d| d > 0.0).count();
+        assert!(covered(&rendered) > 0, "no particle was drawn");
+        assert_eq!(covered(&rendered), covered(&smoothed));
+
+        let rendered_step = largest_step(&rendered);
+        let smoothed_step = largest_step(&smoothed);
+        assert!(rendered_step > 0.2, "no depth step: {}", rendered_step);
+        assert!(
+            smoothed_step < 0.7 * rendered_step,
+            "{} vs {}",
+            smoothed_step,
+            rendered_step
+        );
+    }
+}
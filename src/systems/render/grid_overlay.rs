@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use glam::{IVec3, Vec3};
+use vulkano::{
+    buffer::BufferContents,
+    device::Device,
+    pipeline::graphics::{input_assembly::PrimitiveTopology, vertex_input::Vertex},
+    shader::EntryPoint,
+};
+
+use crate::shaders;
+
+/// Vertex of the occupied-cell wireframe drawn by the grid overlay
+#[repr(C)]
+#[derive(Copy, Clone, Debug, BufferContents, Vertex)]
+pub(crate) struct GridLineVertex {
+    #[format(R32G32B32_SFLOAT)]
+    pub position: [f32; 3],
+}
+
+/// Corner index pairs of the 12 edges of a unit box
+const BOX_EDGES: [(usize, usize); 12] = [
+    (0, 1),
+    (1, 3),
+    (3, 2),
+    (2, 0),
+    (4, 5),
+    (5, 7),
+    (7, 6),
+    (6, 4),
+    (0, 4),
+    (1, 5),
+    (2, 6),
+    (3, 7),
+];
+
+pub(crate) const GRID_TOPOLOGY: PrimitiveTopology = PrimitiveTopology::LineList;
+
+pub(crate) fn grid_shaders(device: &Arc<Device>) -> (EntryPoint, EntryPoint) {
+    (
+        shaders::render::grid::vs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap(),
+        shaders::render::grid::fs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap(),
+    )
+}
+
+/// Unique grid cells containing at least one particle, sorted
+///
+/// Cells follow morton_hash.comp: `floor((position - grid_origin) / grid_size)`.
+pub(crate) fn occupied_cells(positions: &[Vec3], grid_size: f32, grid_origin: Vec3) -> Vec<IVec3> {
+    let mut cells = positions
+        .iter()
+        .map(|&p| ((p - grid_origin) / grid_size).floor().as_ivec3())
+        .collect::<Vec<_>>();
+    cells.sort_unstable_by_key(|c| (c.z, c.y, c.x));
+    cells.dedup();
+    cells
+}
+
+/// Line list with one wireframe box (24 vertices) per cell
+pub(crate) fn cell_box_lines(
+    cells: &[IVec3],
+    grid_size: f32,
+    grid_origin: Vec3,
+) -> Vec<GridLineVertex> {
+    cells
+        .iter()
+        .flat_map(|cell| {
+            let min = grid_origin + cell.as_vec3() * grid_size;
+            let corners: [Vec3; 8] = std::array::from_fn(|i| {
+                min + Vec3::new(
+                    (i & 1) as f32 * grid_size,
+                    (i >> 1 & 1) as f32 * grid_size,
+                    (i >> 2 & 1) as f32 * grid_size,
+                )
+            });
+            BOX_EDGES.into_iter().flat_map(move |(a, b)| {
+                [corners[a], corners[b]].map(|p| GridLineVertex {
+                    position: p.to_array(),
+                })
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cell_box_geometry_matches_occupied_cells() {
+        let grid_size = 0.1;
+        let positions = [
+            Vec3::new(0.01, 0.01, 0.01),
+            Vec3::new(0.02, 0.05, 0.09), // Same cell as the first particle
+            Vec3::new(0.15, 0.01, 0.01),
+            Vec3::new(-0.05, 0.01, 0.01),
+            Vec3::new(0.35, 0.25, -0.15),
+        ];
+
+        let cells = occupied_cells(&positions, grid_size, Vec3::ZERO);
+        assert_eq!(cells.len(), 4);
+        assert!(cells.contains(&IVec3::new(-1, 0, 0)));
+
+        let lines = cell_box_lines(&cells, grid_size, Vec3::ZERO);
+        assert_eq!(lines.len(), cells.len() * BOX_EDGES.len() * 2);
+
+        // Every vertex lies on a cell corner
+        for vertex in &lines {
+            let p = Vec3::from_array(vertex.position) / grid_size;
+            assert!((p - p.round()).abs().max_element() < 1e-4);
+        }
+    }
+}
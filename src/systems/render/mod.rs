@@ -1,6 +1,21 @@
+mod camera_follow;
+mod colormap;
+mod density_alpha;
+mod fluid_surface;
+mod grid_overlay;
+mod msaa;
+mod point_size;
 mod render_context;
+mod render_mode;
 mod render_system;
 mod render_task;
+mod sprite_texture;
 
+pub use camera_follow::Follow;
+pub use colormap::{ColorMode, Colormap};
+pub use density_alpha::DensityAlphaRange;
+pub use msaa::Msaa;
 pub(crate) use render_context::RenderContext;
+pub use render_mode::RenderMode;
 pub(crate) use render_system::RenderSystem;
+pub(crate) use sprite_texture::SpriteTexture;
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use vulkano::{device::Device, image::SampleCount};
+
+/// Samples per pixel of the particle render pass, resolved into the swapchain image
+///
+/// Smooths the edges of point sprites, impostors and sphere meshes at the cost of
+/// multisampled color and depth attachments.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Msaa {
+    #[default]
+    Off,
+    X2,
+    X4,
+    X8,
+}
+
+impl Msaa {
+    pub fn sample_count(self) -> SampleCount {
+        match self {
+            Msaa::Off => SampleCount::Sample1,
+            Msaa::X2 => SampleCount::Sample2,
+            Msaa::X4 => SampleCount::Sample4,
+            Msaa::X8 => SampleCount::Sample8,
+        }
+    }
+
+    /// This setting, lowered until `device` supports it for both color and depth attachments
+    pub fn supported_by(self, device: &Arc<Device>) -> Self {
+        let properties = device.physical_device().properties();
+        let sample_counts =
+            properties.framebuffer_color_sample_counts & properties.framebuffer_depth_sample_counts;
+        [Msaa::X8, Msaa::X4, Msaa::X2]
+            .into_iter()
+            .filter(|msaa| msaa.sample_count() as u32 <= self.sample_count() as u32)
+            .find(|msaa| sample_counts.contains_enum(msaa.sample_count()))
+            .unwrap_or(Msaa::Off)
+    }
+}
@@ -0,0 +1,39 @@
+/// On-screen size band in pixels that point sprites are clamped to after distance scaling
+///
+/// Keeps near particles from filling the screen and far ones from vanishing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PointSizeRange {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Default for PointSizeRange {
+    /// The point size range every Vulkan implementation supports
+    fn default() -> Self {
+        Self {
+            min: 1.0,
+            max: 64.0,
+        }
+    }
+}
+
+impl PointSizeRange {
+    /// Bounds given in either order are sorted
+    pub fn new(min: f32, max: f32) -> Self {
+        Self {
+            min: min.min(max),
+            max: max.max(min),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_point_size_range_sorts_bounds() {
+        let range = PointSizeRange::new(24.0, 2.0);
+        assert_eq!((range.min, range.max), (2.0, 24.0));
+    }
+}
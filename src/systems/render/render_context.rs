@@ -1,19 +1,24 @@
 use std::sync::Arc;
 
 use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
     command_buffer::{CommandBufferExecFuture, PrimaryAutoCommandBuffer},
+    descriptor_set::allocator::StandardDescriptorSetAllocator,
     device::{Device, DeviceOwned, Queue},
+    format::ClearValue,
     format::Format,
-    image::{view::ImageView, Image, ImageCreateInfo, ImageType, ImageUsage},
-    memory::allocator::{AllocationCreateInfo, StandardMemoryAllocator},
+    image::{view::ImageView, Image, ImageCreateInfo, ImageType, ImageUsage, SampleCount},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
     pipeline::{
         graphics::{
-            color_blend::{ColorBlendAttachmentState, ColorBlendState},
-            depth_stencil::{DepthState, DepthStencilState},
+            color_blend::{
+                AttachmentBlend, BlendFactor, BlendOp, ColorBlendAttachmentState, ColorBlendState,
+            },
+            depth_stencil::{CompareOp, DepthState, DepthStencilState},
             input_assembly::{InputAssemblyState, PrimitiveTopology},
             multisample::MultisampleState,
             rasterization::{PolygonMode, RasterizationState},
-            vertex_input::{Vertex, VertexDefinition},
+            vertex_input::{Vertex, VertexDefinition, VertexInputState},
             viewport::{Viewport, ViewportState},
             GraphicsPipelineCreateInfo,
         },
@@ -31,24 +36,44 @@ use vulkano::{
 use winit::{event_loop::ActiveEventLoop, window::Window};
 
 use crate::{
-    core::{ParticlePosition, ParticleVelocity},
-    shaders,
+    core::{ParticleDensity, ParticlePosition, ParticleVelocity},
     utils::VulkanoBackend,
 };
 
+use super::{
+    fluid_surface::FluidSurface,
+    grid_overlay::{grid_shaders, GridLineVertex, GRID_TOPOLOGY},
+    msaa::Msaa,
+    render_mode::{sphere_mesh, RenderMode, SphereVertex},
+};
+
 pub(crate) struct RenderContext {
     window: Arc<Window>,
     swapchain: Arc<Swapchain>,
     render_pass: Arc<RenderPass>,
     framebuffers: Vec<Arc<Framebuffer>>,
     pipeline: Arc<GraphicsPipeline>,
+    grid_pipeline: Arc<GraphicsPipeline>,
+    render_mode: RenderMode,
+    /// Whether particles are alpha blended additively by density
+    density_alpha: bool,
+    /// Sample count the render pass is built with, already lowered to what the device supports
+    msaa: Msaa,
+    sphere_mesh: Subbuffer<[SphereVertex]>,
+    /// Screen-space surface drawn instead of the particles while surface rendering is on
+    fluid_surface: Option<FluidSurface>,
     viewport: Viewport,
     recreate_swapchain: bool,
     pub previous_frame_end: Option<Box<dyn GpuFuture>>,
 }
 
 impl RenderContext {
-    pub fn new(event_loop: &ActiveEventLoop, vulkano_backend: &VulkanoBackend) -> Self {
+    pub fn new(
+        event_loop: &ActiveEventLoop,
+        vulkano_backend: &VulkanoBackend,
+        render_mode: RenderMode,
+        msaa: Msaa,
+    ) -> Self {
         let window = Arc::new(
             event_loop
                 .create_window(Window::default_attributes())
@@ -61,20 +86,34 @@ impl RenderContext {
             extent: window.inner_size().into(),
             ..Default::default()
         };
-        let render_pass = get_render_pass(vulkano_backend.device(), swapchain.image_format());
+        let msaa = msaa.supported_by(vulkano_backend.device());
+        let render_pass = get_render_pass(
+            vulkano_backend.device(),
+            swapchain.image_format(),
+            msaa.sample_count(),
+        );
         let pipeline = get_render_pipeline(
             vulkano_backend.device(),
             &render_pass,
             &viewport,
-            shaders::render::unlit::vs::load(vulkano_backend.device().clone())
-                .unwrap()
-                .entry_point("main")
-                .unwrap(),
-            shaders::render::unlit::fs::load(vulkano_backend.device().clone())
-                .unwrap()
-                .entry_point("main")
-                .unwrap(),
+            render_mode,
+            false,
         );
+        let grid_pipeline = get_grid_pipeline(vulkano_backend.device(), &render_pass, &viewport);
+        let sphere_mesh = Buffer::from_iter(
+            vulkano_backend.memory_allocator().clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::VERTEX_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            sphere_mesh(1),
+        )
+        .unwrap();
         let framebuffers =
             window_size_dependent_setup(&images, &render_pass, vulkano_backend.memory_allocator());
 
@@ -87,6 +126,12 @@ impl RenderContext {
             render_pass,
             framebuffers,
             pipeline,
+            grid_pipeline,
+            render_mode,
+            density_alpha: false,
+            msaa,
+            sphere_mesh,
+            fluid_surface: None,
             viewport,
             recreate_swapchain,
             previous_frame_end,
@@ -109,6 +154,89 @@ impl RenderContext {
         &self.pipeline
     }
 
+    pub fn grid_pipeline(&self) -> &Arc<GraphicsPipeline> {
+        &self.grid_pipeline
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+
+    pub fn sphere_mesh(&self) -> &Subbuffer<[SphereVertex]> {
+        &self.sphere_mesh
+    }
+
+    pub fn fluid_surface(&self) -> Option<&FluidSurface> {
+        self.fluid_surface.as_ref()
+    }
+
+    /// Switch screen-space surface rendering, building its passes the first time it is enabled
+    pub fn set_surface_rendering(
+        &mut self,
+        surface_rendering: bool,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+    ) {
+        if surface_rendering == self.fluid_surface.is_some() {
+            return;
+        }
+        self.fluid_surface = surface_rendering.then(|| {
+            FluidSurface::new(
+                memory_allocator,
+                descriptor_set_allocator,
+                &self.render_pass,
+                &self.viewport,
+            )
+        });
+    }
+
+    /// Switch the render mode, rebuilding the graphics pipeline if it changed
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        if self.render_mode == render_mode {
+            return;
+        }
+        self.render_mode = render_mode;
+        self.pipeline = get_render_pipeline(
+            self.swapchain.device(),
+            &self.render_pass,
+            &self.viewport,
+            self.render_mode,
+            self.density_alpha,
+        );
+    }
+
+    /// Switch density-based additive blending, rebuilding the graphics pipeline if it changed
+    pub fn set_density_alpha(&mut self, density_alpha: bool) {
+        if self.density_alpha == density_alpha {
+            return;
+        }
+        self.density_alpha = density_alpha;
+        self.pipeline = get_render_pipeline(
+            self.swapchain.device(),
+            &self.render_pass,
+            &self.viewport,
+            self.render_mode,
+            self.density_alpha,
+        );
+    }
+
+    /// Switch multisampling; the render pass and everything built on it is rebuilt with the swapchain
+    pub fn set_msaa(&mut self, msaa: Msaa) {
+        let msaa = msaa.supported_by(self.swapchain.device());
+        if self.msaa == msaa {
+            return;
+        }
+        self.msaa = msaa;
+        self.recreate_swapchain = true;
+    }
+
+    /// One clear value per attachment; the resolve target is fully overwritten and not cleared
+    pub fn clear_values(&self, clear_color: [f32; 4]) -> Vec<Option<ClearValue>> {
+        let mut clear_values = vec![Some(clear_color.into()), Some(1.0f32.into())];
+        clear_values.resize(self.render_pass.attachments().len(), None);
+        clear_values
+    }
+
     pub fn request_recreate_swapchain(&mut self) {
         self.recreate_swapchain = true;
     }
@@ -137,21 +265,25 @@ impl RenderContext {
 
             self.swapchain = new_swapchain;
 
+            self.render_pass = get_render_pass(
+                self.swapchain.device(),
+                self.swapchain.image_format(),
+                self.msaa.sample_count(),
+            );
             self.framebuffers =
                 window_size_dependent_setup(&new_images, &self.render_pass, memory_allocator);
             self.pipeline = get_render_pipeline(
                 self.swapchain.device(),
                 &self.render_pass,
                 &self.viewport,
-                shaders::render::unlit::vs::load(self.swapchain.device().clone())
-                    .unwrap()
-                    .entry_point("main")
-                    .unwrap(),
-                shaders::render::unlit::fs::load(self.swapchain.device().clone())
-                    .unwrap()
-                    .entry_point("main")
-                    .unwrap(),
+                self.render_mode,
+                self.density_alpha,
             );
+            self.grid_pipeline =
+                get_grid_pipeline(self.swapchain.device(), &self.render_pass, &self.viewport);
+            if let Some(fluid_surface) = &mut self.fluid_surface {
+                fluid_surface.resize(&self.render_pass, &self.viewport);
+            }
             self.recreate_swapchain = false;
         }
     }
@@ -192,7 +324,47 @@ impl RenderContext {
     }
 }
 
-fn get_render_pass(device: &Arc<Device>, format: Format) -> Arc<RenderPass> {
+/// Color and depth attachments with `samples` samples
+///
+/// When multisampled, a third attachment takes the resolved color, so attachments 0 and 1
+/// are always color and depth.
+pub(super) fn get_render_pass(
+    device: &Arc<Device>,
+    format: Format,
+    samples: SampleCount,
+) -> Arc<RenderPass> {
+    if samples != SampleCount::Sample1 {
+        return vulkano::single_pass_renderpass!(
+            device.clone(),
+            attachments: {
+                color: {
+                    format: format,
+                    samples: samples as u32,
+                    load_op: Clear,
+                    store_op: DontCare,
+                },
+                depth_stencil: {
+                    format: Format::D16_UNORM,
+                    samples: samples as u32,
+                    load_op: Clear,
+                    store_op: DontCare,
+                },
+                resolve: {
+                    format: format,
+                    samples: 1,
+                    load_op: DontCare,
+                    store_op: Store,
+                },
+            },
+            pass: {
+                color: [color],
+                color_resolve: [resolve],
+                depth_stencil: {depth_stencil},
+            },
+        )
+        .unwrap();
+    }
+
     vulkano::single_pass_renderpass!(
         device.clone(),
         attachments: {
@@ -221,16 +393,79 @@ fn get_render_pipeline(
     device: &Arc<Device>,
     render_pass: &Arc<RenderPass>,
     viewport: &Viewport,
-    vertex_shader: EntryPoint,
-    fragment_shader: EntryPoint,
+    render_mode: RenderMode,
+    density_alpha: bool,
 ) -> Arc<GraphicsPipeline> {
-    let vertex_input_state = [
-        ParticlePosition::per_vertex(),
-        ParticleVelocity::per_vertex(),
-    ]
-    .definition(&vertex_shader)
-    .unwrap();
+    let (vertex_shader, fragment_shader) = render_mode.shaders(device);
+    let vertex_input_state = if render_mode.is_instanced() {
+        [
+            SphereVertex::per_vertex(),
+            ParticlePosition::per_instance(),
+            ParticleVelocity::per_instance(),
+            ParticleDensity::per_instance(),
+        ]
+        .definition(&vertex_shader)
+        .unwrap()
+    } else {
+        [
+            ParticlePosition::per_vertex(),
+            ParticleVelocity::per_vertex(),
+            ParticleDensity::per_vertex(),
+        ]
+        .definition(&vertex_shader)
+        .unwrap()
+    };
+
+    create_graphics_pipeline(
+        device,
+        render_pass,
+        viewport,
+        (vertex_shader, fragment_shader),
+        vertex_input_state,
+        render_mode.topology(),
+        density_alpha.then_some(AttachmentBlend {
+            // Alpha-weighted additive blending for a volumetric look
+            src_color_blend_factor: BlendFactor::SrcAlpha,
+            dst_color_blend_factor: BlendFactor::One,
+            color_blend_op: BlendOp::Add,
+            src_alpha_blend_factor: BlendFactor::One,
+            dst_alpha_blend_factor: BlendFactor::One,
+            alpha_blend_op: BlendOp::Add,
+        }),
+    )
+}
+
+/// Pipeline drawing the occupied grid cells as a wireframe
+fn get_grid_pipeline(
+    device: &Arc<Device>,
+    render_pass: &Arc<RenderPass>,
+    viewport: &Viewport,
+) -> Arc<GraphicsPipeline> {
+    let (vertex_shader, fragment_shader) = grid_shaders(device);
+    let vertex_input_state = [GridLineVertex::per_vertex()]
+        .definition(&vertex_shader)
+        .unwrap();
+
+    create_graphics_pipeline(
+        device,
+        render_pass,
+        viewport,
+        (vertex_shader, fragment_shader),
+        vertex_input_state,
+        GRID_TOPOLOGY,
+        None,
+    )
+}
 
+pub(super) fn create_graphics_pipeline(
+    device: &Arc<Device>,
+    render_pass: &Arc<RenderPass>,
+    viewport: &Viewport,
+    (vertex_shader, fragment_shader): (EntryPoint, EntryPoint),
+    vertex_input_state: VertexInputState,
+    topology: PrimitiveTopology,
+    blend: Option<AttachmentBlend>,
+) -> Arc<GraphicsPipeline> {
     let stages = [
         PipelineShaderStageCreateInfo::new(vertex_shader.clone()),
         PipelineShaderStageCreateInfo::new(fragment_shader.clone()),
@@ -245,6 +480,15 @@ fn get_render_pipeline(
     .unwrap();
 
     let subpass = Subpass::from(render_pass.clone(), 0).unwrap();
+    // Blended particles are depth tested but must not occlude each other
+    let depth = if blend.is_some() {
+        DepthState {
+            write_enable: false,
+            compare_op: CompareOp::Less,
+        }
+    } else {
+        DepthState::simple()
+    };
 
     GraphicsPipeline::new(
         device.clone(),
@@ -253,7 +497,7 @@ fn get_render_pipeline(
             stages: stages.into_iter().collect(),
             vertex_input_state: Some(vertex_input_state),
             input_assembly_state: Some(InputAssemblyState {
-                topology: PrimitiveTopology::PointList,
+                topology,
                 ..Default::default()
             }),
             viewport_state: Some(ViewportState {
@@ -264,13 +508,19 @@ fn get_render_pipeline(
                 polygon_mode: PolygonMode::Fill,
                 ..Default::default()
             }),
-            multisample_state: Some(MultisampleState::default()),
+            multisample_state: Some(MultisampleState {
+                rasterization_samples: subpass.num_samples().unwrap_or(SampleCount::Sample1),
+                ..Default::default()
+            }),
             color_blend_state: Some(ColorBlendState::with_attachment_states(
                 subpass.num_color_attachments(),
-                ColorBlendAttachmentState::default(),
+                ColorBlendAttachmentState {
+                    blend,
+                    ..Default::default()
+                },
             )),
             depth_stencil_state: Some(DepthStencilState {
-                depth: Some(DepthState::simple()),
+                depth: Some(depth),
                 ..Default::default()
             }),
             subpass: Some(subpass.into()),
@@ -321,31 +571,44 @@ pub fn window_size_dependent_setup(
     render_pass: &Arc<RenderPass>,
     memory_allocator: &Arc<StandardMemoryAllocator>,
 ) -> Vec<Arc<Framebuffer>> {
-    let depth_buffer = ImageView::new_default(
-        Image::new(
-            memory_allocator.clone(),
-            ImageCreateInfo {
-                image_type: ImageType::Dim2d,
-                format: Format::D16_UNORM,
-                extent: images[0].extent(),
-                usage: ImageUsage::DEPTH_STENCIL_ATTACHMENT | ImageUsage::TRANSIENT_ATTACHMENT,
-                ..Default::default()
-            },
-            AllocationCreateInfo::default(),
+    let samples = render_pass.attachments()[0].samples;
+    let transient_attachment = |format: Format, usage: ImageUsage| {
+        ImageView::new_default(
+            Image::new(
+                memory_allocator.clone(),
+                ImageCreateInfo {
+                    image_type: ImageType::Dim2d,
+                    format,
+                    extent: images[0].extent(),
+                    usage: usage | ImageUsage::TRANSIENT_ATTACHMENT,
+                    samples,
+                    ..Default::default()
+                },
+                AllocationCreateInfo::default(),
+            )
+            .unwrap(),
         )
-        .unwrap(),
-    )
-    .unwrap();
+        .unwrap()
+    };
+    let depth_buffer =
+        transient_attachment(Format::D16_UNORM, ImageUsage::DEPTH_STENCIL_ATTACHMENT);
+    // Rendered at the sample count and resolved into the swapchain image
+    let multisampled_color = (samples != SampleCount::Sample1)
+        .then(|| transient_attachment(images[0].format(), ImageUsage::COLOR_ATTACHMENT));
 
     images
         .iter()
         .map(|image| {
             let view = ImageView::new_default(image.clone()).unwrap();
+            let attachments = match &multisampled_color {
+                Some(color) => vec![color.clone(), depth_buffer.clone(), view],
+                None => vec![view, depth_buffer.clone()],
+            };
 
             Framebuffer::new(
                 render_pass.clone(),
                 FramebufferCreateInfo {
-                    attachments: vec![view, depth_buffer.clone()],
+                    attachments,
                     ..Default::default()
                 },
             )
@@ -353,3 +616,574 @@ pub fn window_size_dependent_setup(
         })
         .collect::<Vec<_>>()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+    use crate::{
+        systems::render::{
+            colormap::{ColorMode, Colormap, FLAT_COLOR},
+            density_alpha::DensityAlphaRange,
+            sprite_texture::{SpriteTexture, SPRITE_TEXTURE_BINDING},
+        },
+        utils::{GpuTask, GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::{Mat4, Vec3};
+    use vulkano::{
+        command_buffer::{AutoCommandBufferBuilder, CopyImageToBufferInfo, RenderPassBeginInfo},
+        descriptor_set::{layout::DescriptorType, DescriptorSet, WriteDescriptorSet},
+        pipeline::{
+            graphics::{input_assembly::PrimitiveTopology, vertex_input::VertexInputRate},
+            Pipeline, PipelineBindPoint,
+        },
+    };
+
+    #[test]
+    fn test_render_pipeline_matches_render_mode() {
+        let backend = VulkanoHeadlessBackend::new();
+        let device = backend.device();
+        let render_pass = get_render_pass(device, Format::B8G8R8A8_UNORM, SampleCount::Sample1);
+        let viewport = Viewport {
+            offset: [0.0, 0.0],
+            extent: [640.0, 480.0],
+            depth_range: 0.0..=1.0,
+        };
+
+        for (render_mode, topology) in [
+            (RenderMode::Points, PrimitiveTopology::PointList),
+            (RenderMode::Impostors, PrimitiveTopology::PointList),
+            (RenderMode::Spheres, PrimitiveTopology::TriangleList),
+        ] {
+            let pipeline = get_render_pipeline(device, &render_pass, &viewport, render_mode, false);
+
+            assert_eq!(pipeline.input_assembly_state().unwrap().topology, topology);
+
+            // Particle attributes advance per instance only when drawing sphere meshes
+            let bindings = &pipeline.vertex_input_state().unwrap().bindings;
+            let expected_rate = if render_mode == RenderMode::Spheres {
+                VertexInputRate::Instance { divisor: 1 }
+            } else {
+                VertexInputRate::Vertex
+            };
+            let position_binding = if render_mode.is_instanced() { 1 } else { 0 };
+            assert_eq!(bindings[&position_binding].input_rate, expected_rate);
+        }
+    }
+
+    #[test]
+    fn test_msaa_render_pass_resolves_into_single_sample_attachment() {
+        let backend = VulkanoHeadlessBackend::new();
+        let device = backend.device();
+        let viewport = Viewport {
+            offset: [0.0, 0.0],
+            extent: [640.0, 480.0],
+            depth_range: 0.0..=1.0,
+        };
+
+        // Every implementation supports 4 samples for color and depth attachments
+        let msaa = Msaa::X4.supported_by(device);
+        assert_eq!(msaa, Msaa::X4);
+        let render_pass = get_render_pass(device, Format::B8G8R8A8_UNORM, msaa.sample_count());
+
+        let attachments = render_pass.attachments();
+        assert_eq!(attachments.len(), 3);
+        assert_eq!(attachments[0].samples, SampleCount::Sample4);
+        assert_eq!(attachments[1].samples, SampleCount::Sample4);
+        assert_eq!(attachments[2].samples, SampleCount::Sample1);
+        let subpass = &render_pass.subpasses()[0];
+        assert_eq!(
+            subpass.color_resolve_attachments[0]
+                .as_ref()
+                .unwrap()
+                .attachment,
+            2
+        );
+
+        for render_mode in [
+            RenderMode::Points,
+            RenderMode::Impostors,
+            RenderMode::Spheres,
+        ] {
+            let pipeline = get_render_pipeline(device, &render_pass, &viewport, render_mode, false);
+            assert_eq!(
+                pipeline.multisample_state().unwrap().rasterization_samples,
+                SampleCount::Sample4
+            );
+        }
+        let grid_pipeline = get_grid_pipeline(device, &render_pass, &viewport);
+        assert_eq!(
+            grid_pipeline
+                .multisample_state()
+                .unwrap()
+                .rasterization_samples,
+            SampleCount::Sample4
+        );
+
+        // Without MSAA there is nothing to resolve
+        let render_pass = get_render_pass(device, Format::B8G8R8A8_UNORM, SampleCount::Sample1);
+        assert_eq!(render_pass.attachments().len(), 2);
+        assert!(render_pass.subpasses()[0]
+            .color_resolve_attachments
+            .is_empty());
+    }
+
+    #[test]
+    fn test_point_sprite_pipelines_bind_sprite_texture() {
+        let backend = VulkanoHeadlessBackend::new();
+        let device = backend.device();
+        let render_pass = get_render_pass(device, Format::B8G8R8A8_UNORM, SampleCount::Sample1);
+        let viewport = Viewport {
+            offset: [0.0, 0.0],
+            extent: [640.0, 480.0],
+            depth_range: 0.0..=1.0,
+        };
+        let sprite_texture = SpriteTexture::from_rgba8(
+            device,
+            backend.memory_allocator(),
+            &backend,
+            [2, 2],
+            &[255; 16],
+        );
+
+        for render_mode in [RenderMode::Points, RenderMode::Impostors] {
+            let pipeline = get_render_pipeline(device, &render_pass, &viewport, render_mode, false);
+            let layout = pipeline.layout().set_layouts()[0].clone();
+            assert_eq!(
+                layout.bindings()[&SPRITE_TEXTURE_BINDING].descriptor_type,
+                DescriptorType::CombinedImageSampler,
+                "{:?}",
+                render_mode
+            );
+
+            let uniform_buffer = Buffer::new_sized::<crate::shaders::render::unlit::vs::Data>(
+                backend.memory_allocator().clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::UNIFORM_BUFFER,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                        | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            DescriptorSet::new(
+                backend.descriptor_set_allocator().clone(),
+                layout,
+                [
+                    WriteDescriptorSet::buffer(0, uniform_buffer),
+                    sprite_texture.descriptor_write(),
+                ],
+                [],
+            )
+            .unwrap();
+        }
+
+        // Sphere meshes are not sprites and keep the single uniform binding
+        let pipeline =
+            get_render_pipeline(device, &render_pass, &viewport, RenderMode::Spheres, false);
+        assert!(!pipeline.layout().set_layouts()[0]
+            .bindings()
+            .contains_key(&SPRITE_TEXTURE_BINDING));
+    }
+
+    /// Side of the square offscreen image in pixels
+    const OFFSCREEN_SIZE: u32 = 64;
+
+    /// Draws particles into an offscreen image and copies the image back
+    struct OffscreenDraw {
+        framebuffer: Arc<Framebuffer>,
+        pipeline: Arc<GraphicsPipeline>,
+        descriptor_set: Arc<DescriptorSet>,
+        position: Subbuffer<[ParticlePosition]>,
+        velocity: Subbuffer<[ParticleVelocity]>,
+        density: Subbuffer<[ParticleDensity]>,
+        readback: Subbuffer<[u8]>,
+    }
+
+    impl GpuTask for OffscreenDraw {
+        fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+            builder
+                .begin_render_pass(
+                    RenderPassBeginInfo {
+                        clear_values: vec![Some([0.0; 4].into()), Some(1.0f32.into())],
+                        ..RenderPassBeginInfo::framebuffer(self.framebuffer.clone())
+                    },
+                    Default::default(),
+                )
+                .unwrap();
+            builder
+                .bind_pipeline_graphics(self.pipeline.clone())
+                .unwrap();
+            builder
+                .bind_descriptor_sets(
+                    PipelineBindPoint::Graphics,
+                    self.pipeline.layout().clone(),
+                    0,
+                    self.descriptor_set.clone(),
+                )
+                .unwrap();
+            builder
+                .bind_vertex_buffers(
+                    0,
+                    (
+                        self.position.clone(),
+                        self.velocity.clone(),
+                        self.density.clone(),
+                    ),
+                )
+                .unwrap();
+            unsafe { builder.draw(self.position.len() as u32, 1, 0, 0) }.unwrap();
+            builder.end_render_pass(Default::default()).unwrap();
+            builder
+                .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+                    self.framebuffer.attachments()[0].image().clone(),
+                    self.readback.clone(),
+                ))
+                .unwrap();
+        }
+
+        fn submit(
+            &mut self,
+            command_buffer: Arc<PrimaryAutoCommandBuffer>,
+            queue: &Arc<Queue>,
+            device: &Arc<Device>,
+        ) {
+            sync::now(device.clone())
+                .then_execute(queue.clone(), command_buffer)
+                .unwrap()
+                .then_signal_fence_and_flush()
+                .unwrap()
+                .wait(None)
+                .unwrap();
+        }
+    }
+
+    /// Uniforms of a 90° camera one unit in front of the origin, which sees [-1, 1] in the
+    /// z = 0 plane, with fixed point sizes and the default coloring
+    fn offscreen_uniforms() -> crate::shaders::render::unlit::vs::Data {
+        let (colormap, color_max_speed) = ColorMode::default().uniform_values(Colormap::default());
+        crate::shaders::render::unlit::vs::Data {
+            view: Mat4::look_at_rh(Vec3::Z, Vec3::ZERO, Vec3::Y).to_cols_array_2d(),
+            proj: Mat4::perspective_rh(FRAC_PI_2, 1.0, 0.1, 10.0).to_cols_array_2d(),
+            viewport_height: OFFSCREEN_SIZE as f32,
+            density_alpha_min: 0.0,
+            density_alpha_max: 0.0,
+            point_size_min: 1.0,
+            point_size_max: 64.0,
+            particle_radius: 0.02,
+            colormap,
+            color_max_speed,
+            flat_color: FLAT_COLOR.extend(1.0).to_array(),
+        }
+    }
+
+    /// Draw particles given as (position, velocity) at rest density and read back the RGBA8
+    /// pixels row by row
+    fn render_offscreen(
+        render_mode: RenderMode,
+        uniforms: crate::shaders::render::unlit::vs::Data,
+        particles: &[(Vec3, Vec3)],
+    ) -> Vec<[u8; 4]> {
+        let particles = particles
+            .iter()
+            .map(|&(position, velocity)| (position, velocity, 1000.0))
+            .collect::<Vec<_>>();
+        render_offscreen_with_density(render_mode, uniforms, &particles)
+    }
+
+    /// Draw particles given as (position, velocity, density) and read back the RGBA8 pixels
+    /// row by row
+    fn render_offscreen_with_density(
+        render_mode: RenderMode,
+        uniforms: crate::shaders::render::unlit::vs::Data,
+        particles: &[(Vec3, Vec3, f32)],
+    ) -> Vec<[u8; 4]> {
+        let backend = VulkanoHeadlessBackend::new();
+        let device = backend.device();
+        let memory_allocator = backend.memory_allocator();
+        let format = Format::R8G8B8A8_UNORM;
+        let render_pass = get_render_pass(device, format, SampleCount::Sample1);
+        let viewport = Viewport {
+            offset: [0.0, 0.0],
+            extent: [OFFSCREEN_SIZE as f32; 2],
+            depth_range: 0.0..=1.0,
+        };
+        let pipeline = get_render_pipeline(device, &render_pass, &viewport, render_mode, false);
+
+        let attachment = |format: Format, usage: ImageUsage| {
+            ImageView::new_default(
+                Image::new(
+                    memory_allocator.clone(),
+                    ImageCreateInfo {
+                        image_type: ImageType::Dim2d,
+                        format,
+                        extent: [OFFSCREEN_SIZE, OFFSCREEN_SIZE, 1],
+                        usage,
+                        ..Default::default()
+                    },
+                    AllocationCreateInfo::default(),
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+        let framebuffer = Framebuffer::new(
+            render_pass.clone(),
+            FramebufferCreateInfo {
+                attachments: vec![
+                    attachment(
+                        format,
+                        ImageUsage::COLOR_ATTACHMENT | ImageUsage::TRANSFER_SRC,
+                    ),
+                    attachment(Format::D16_UNORM, ImageUsage::DEPTH_STENCIL_ATTACHMENT),
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let uniform_buffer = Buffer::from_data(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::UNIFORM_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            uniforms,
+        )
+        .unwrap();
+        let sprite_texture = SpriteTexture::white(device, memory_allocator, &backend);
+        let descriptor_set = DescriptorSet::new(
+            backend.descriptor_set_allocator().clone(),
+            pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::buffer(0, uniform_buffer),
+                sprite_texture.descriptor_write(),
+            ],
+            [],
+        )
+        .unwrap();
+
+        let buffer_info = |usage: BufferUsage| BufferCreateInfo {
+            usage,
+            ..Default::default()
+        };
+        let host_visible = || AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+            ..Default::default()
+        };
+        let mut draw = OffscreenDraw {
+            framebuffer,
+            pipeline,
+            descriptor_set,
+            position: Buffer::from_iter(
+                memory_allocator.clone(),
+                buffer_info(BufferUsage::VERTEX_BUFFER),
+                host_visible(),
+                particles
+                    .iter()
+                    .map(|&(position, _, _)| ParticlePosition::new(position)),
+            )
+            .unwrap(),
+            velocity: Buffer::from_iter(
+                memory_allocator.clone(),
+                buffer_info(BufferUsage::VERTEX_BUFFER),
+                host_visible(),
+                particles
+                    .iter()
+                    .map(|&(_, velocity, _)| ParticleVelocity::new(velocity)),
+            )
+            .unwrap(),
+            density: Buffer::from_iter(
+                memory_allocator.clone(),
+                buffer_info(BufferUsage::VERTEX_BUFFER),
+                host_visible(),
+                particles
+                    .iter()
+                    .map(|&(_, _, density)| ParticleDensity { density }),
+            )
+            .unwrap(),
+            readback: Buffer::new_slice(
+                memory_allocator.clone(),
+                buffer_info(BufferUsage::TRANSFER_DST),
+                host_visible(),
+                (OFFSCREEN_SIZE * OFFSCREEN_SIZE * 4) as u64,
+            )
+            .unwrap(),
+        };
+        backend.execute(&mut draw);
+
+        let pixels = draw.readback.read().unwrap();
+        pixels
+            .chunks_exact(4)
+            .map(|pixel| pixel.try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_impostor_covers_a_disc() {
+        // A sphere of radius 0.25 spans a quarter of the offscreen image, 16 pixels
+        let uniforms = crate::shaders::render::unlit::vs::Data {
+            particle_radius: 0.25,
+            ..offscreen_uniforms()
+        };
+        let pixels = render_offscreen(RenderMode::Impostors, uniforms, &[(Vec3::ZERO, Vec3::ZERO)]);
+
+        // Covered pixels are the ones the cleared alpha of 0 was overwritten in
+        let covered = (0..OFFSCREEN_SIZE * OFFSCREEN_SIZE)
+            .filter(|&i| pixels[i as usize][3] > 0)
+            .map(|i| (i % OFFSCREEN_SIZE, i / OFFSCREEN_SIZE))
+            .collect::<Vec<_>>();
+        assert!(!covered.is_empty(), "the particle was not drawn");
+
+        let min_x = covered.iter().map(|p| p.0).min().unwrap();
+        let max_x = covered.iter().map(|p| p.0).max().unwrap();
+        let min_y = covered.iter().map(|p| p.1).min().unwrap();
+        let max_y = covered.iter().map(|p| p.1).max().unwrap();
+        let (width, height) = (max_x - min_x + 1, max_y - min_y + 1);
+        assert!((14..=18).contains(&width), "width {}", width);
+        assert!(width.abs_diff(height) <= 1, "{}x{}", width, height);
+        // A disc fills π/4 of its bounding square, a square sprite all of it
+        let fill = covered.len() as f32 / (width * height) as f32;
+        assert!((0.65..0.9).contains(&fill), "fill {}", fill);
+    }
+
+    #[test]
+    fn test_velocity_colormap_separates_slow_and_fast_particles() {
+        // A slow particle a quarter in from the left edge and a fast one a quarter from the right
+        let particles = [
+            (Vec3::new(-0.5, 0.0, 0.0), Vec3::ZERO),
+            (Vec3::new(0.5, 0.0, 0.0), Vec3::new(0.0, -8.0, 0.0)),
+        ];
+        let render = |color_mode: ColorMode| {
+            let (colormap, color_max_speed) = color_mode.uniform_values(Colormap::Viridis);
+            let uniforms = crate::shaders::render::unlit::vs::Data {
+                point_size_min: 8.0,
+                point_size_max: 8.0,
+                colormap,
+                color_max_speed,
+                ..offscreen_uniforms()
+            };
+            let pixels = render_offscreen(RenderMode::Points, uniforms, &particles);
+            let at = |x: u32| {
+                let [r, g, b, _] = pixels[(OFFSCREEN_SIZE / 2 * OFFSCREEN_SIZE + x) as usize];
+                Vec3::new(r as f32, g as f32, b as f32) / 255.0
+            };
+            (at(OFFSCREEN_SIZE / 4), at(OFFSCREEN_SIZE * 3 / 4))
+        };
+
+        let (slow, fast) = render(ColorMode::Velocity { max_speed: 8.0 });
+        assert!(
+            slow.abs_diff_eq(Colormap::Viridis.sample(0.0), 0.02),
+            "{}",
+            slow
+        );
+        assert!(
+            fast.abs_diff_eq(Colormap::Viridis.sample(1.0), 0.02),
+            "{}",
+            fast
+        );
+        assert!(slow.distance(fast) > 0.5, "{} vs {}", slow, fast);
+
+        // Without a speed range both fall back to the same flat color
+        let (slow, fast) = render(ColorMode::Velocity { max_speed: 0.0 });
+        assert!(slow.abs_diff_eq(FLAT_COLOR, 0.02), "{}", slow);
+        assert!(fast.abs_diff_eq(FLAT_COLOR, 0.02), "{}", fast);
+    }
+
+    #[test]
+    fn test_density_alpha_fades_sparse_particles() {
+        // Particles below, halfway through and above the density range, a quarter, a half and
+        // three quarters across the image
+        let particles = [
+            (Vec3::new(-0.5, 0.0, 0.0), Vec3::ZERO, 200.0),
+            (Vec3::ZERO, Vec3::ZERO, 600.0),
+            (Vec3::new(0.5, 0.0, 0.0), Vec3::ZERO, 5000.0),
+        ];
+        let render = |range: Option<DensityAlphaRange>| {
+            let (density_alpha_min, density_alpha_max) = DensityAlphaRange::uniform_bounds(range);
+            let uniforms = crate::shaders::render::unlit::vs::Data {
+                density_alpha_min,
+                density_alpha_max,
+                point_size_min: 8.0,
+                point_size_max: 8.0,
+                ..offscreen_uniforms()
+            };
+            let pixels = render_offscreen_with_density(RenderMode::Points, uniforms, &particles);
+            [1, 2, 3].map(|quarter| {
+                pixels
+                    [(OFFSCREEN_SIZE / 2 * OFFSCREEN_SIZE + OFFSCREEN_SIZE * quarter / 4) as usize]
+                    [3]
+            })
+        };
+
+        let [sparse, half, dense] = render(Some(DensityAlphaRange {
+            min: 400.0,
+            max: 800.0,
+        }));
+        assert_eq!(sparse, 0);
+        assert!(half.abs_diff(128) <= 2, "alpha {}", half);
+        assert_eq!(dense, 255);
+
+        // No range leaves every particle opaque
+        assert_eq!(render(None), [255; 3]);
+    }
+
+    #[test]
+    fn test_point_size_clamps_at_extreme_distances() {
+        // A sphere of radius 0.05 projects to 3.2 / depth pixels in the offscreen image,
+        // clamped to [4, 12]
+        let uniforms = crate::shaders::render::unlit::vs::Data {
+            point_size_min: 4.0,
+            point_size_max: 12.0,
+            particle_radius: 0.05,
+            ..offscreen_uniforms()
+        };
+        // Width in pixels of a particle drawn at `depth` in front of the camera
+        let width_at = |render_mode: RenderMode, depth: f32| {
+            let position = Vec3::new(0.0, 0.0, 1.0 - depth);
+            let pixels = render_offscreen(render_mode, uniforms, &[(position, Vec3::ZERO)]);
+            let covered_columns = (0..OFFSCREEN_SIZE * OFFSCREEN_SIZE)
+                .filter(|&i| pixels[i as usize][3] > 0)
+                .map(|i| i % OFFSCREEN_SIZE)
+                .collect::<Vec<_>>();
+            assert!(
+                !covered_columns.is_empty(),
+                "nothing drawn at depth {}",
+                depth
+            );
+            covered_columns.iter().max().unwrap() - covered_columns.iter().min().unwrap() + 1
+        };
+
+        for render_mode in [RenderMode::Points, RenderMode::Impostors] {
+            // Next to the near plane the projected 16 pixels are capped at the maximum
+            let near = width_at(render_mode, 0.2);
+            assert!(
+                near.abs_diff(12) <= 1,
+                "{:?} near width {}",
+                render_mode,
+                near
+            );
+            // Next to the far plane the projected 0.36 pixels are raised to the minimum
+            let far = width_at(render_mode, 9.0);
+            assert!(far.abs_diff(4) <= 1, "{:?} far width {}", render_mode, far);
+            // In between the distance scaling is untouched
+            let mid = width_at(render_mode, 0.5);
+            assert!(
+                (5..=7).contains(&mid),
+                "{:?} mid width {}",
+                render_mode,
+                mid
+            );
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents,
+    device::Device,
+    pipeline::graphics::{input_assembly::PrimitiveTopology, vertex_input::Vertex},
+    shader::EntryPoint,
+};
+
+use crate::shaders;
+
+/// How particles are drawn, trading visual quality for performance
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// One flat point per particle, as wide as an impostor at the same depth
+    #[default]
+    Points,
+    /// Camera-facing point sprites shaded as spheres
+    Impostors,
+    /// An instanced sphere mesh per particle
+    Spheres,
+}
+
+impl RenderMode {
+    pub fn topology(self) -> PrimitiveTopology {
+        match self {
+            RenderMode::Points | RenderMode::Impostors => PrimitiveTopology::PointList,
+            RenderMode::Spheres => PrimitiveTopology::TriangleList,
+        }
+    }
+
+    /// Whether particle attributes are read per instance rather than per vertex
+    pub fn is_instanced(self) -> bool {
+        matches!(self, RenderMode::Spheres)
+    }
+
+    pub fn shaders(self, device: &Arc<Device>) -> (EntryPoint, EntryPoint) {
+        let (vs, fs) = match self {
+            RenderMode::Points => (
+                shaders::render::unlit::vs::load(device.clone()).unwrap(),
+                shaders::render::unlit::fs::load(device.clone()).unwrap(),
+            ),
+            RenderMode::Impostors => (
+                shaders::render::impostor::vs::load(device.clone()).unwrap(),
+                shaders::render::impostor::fs::load(device.clone()).unwrap(),
+            ),
+            RenderMode::Spheres => (
+                shaders::render::sphere::vs::load(device.clone()).unwrap(),
+                shaders::render::sphere::fs::load(device.clone()).unwrap(),
+            ),
+        };
+        (
+            vs.entry_point("main").unwrap(),
+            fs.entry_point("main").unwrap(),
+        )
+    }
+}
+
+/// Vertex of the unit sphere mesh instanced in [`RenderMode::Spheres`]
+#[repr(C)]
+#[derive(Copy, Clone, Debug, BufferContents, Vertex)]
+pub(crate) struct SphereVertex {
+    #[format(R32G32B32_SFLOAT)]
+    pub offset: [f32; 3],
+}
+
+/// Build a non-indexed unit icosphere triangle list
+pub(crate) fn sphere_mesh(subdivisions: u32) -> Vec<SphereVertex> {
+    let t = (1.0 + 5.0f32.sqrt()) / 2.0;
+    let vertices = [
+        [-1.0, t, 0.0],
+        [1.0, t, 0.0],
+        [-1.0, -t, 0.0],
+        [1.0, -t, 0.0],
+        [0.0, -1.0, t],
+        [0.0, 1.0, t],
+        [0.0, -1.0, -t],
+        [0.0, 1.0, -t],
+        [t, 0.0, -1.0],
+        [t, 0.0, 1.0],
+        [-t, 0.0, -1.0],
+        [-t, 0.0, 1.0],
+    ]
+    .map(|v| glam::Vec3::from_array(v).normalize());
+    let faces: [[usize; 3]; 20] = [
+        [0, 11, 5],
+        [0, 5, 1],
+        [0, 1, 7],
+        [0, 7, 10],
+        [0, 10, 11],
+        [1, 5, 9],
+        [5, 11, 4],
+        [11, 10, 2],
+        [10, 7, 6],
+        [7, 1, 8],
+        [3, 9, 4],
+        [3, 4, 2],
+        [3, 2, 6],
+        [3, 6, 8],
+        [3, 8, 9],
+        [4, 9, 5],
+        [2, 4, 11],
+        [6, 2, 10],
+        [8, 6, 7],
+        [9, 8, 1],
+    ];
+
+    let mut triangles = faces
+        .iter()
+        .map(|f| [vertices[f[0]], vertices[f[1]], vertices[f[2]]])
+        .collect::<Vec<_>>();
+
+    for _ in 0..subdivisions {
+        triangles = triangles
+            .iter()
+            .flat_map(|&[a, b, c]| {
+                let ab = ((a + b) * 0.5).normalize();
+                let bc = ((b + c) * 0.5).normalize();
+                let ca = ((c + a) * 0.5).normalize();
+                [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+            })
+            .collect();
+    }
+
+    triangles
+        .into_iter()
+        .flatten()
+        .map(|v| SphereVertex {
+            offset: v.to_array(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sphere_mesh_is_unit_triangle_list() {
+        let mesh = sphere_mesh(1);
+        assert_eq!(mesh.len(), 20 * 4 * 3);
+        for vertex in mesh {
+            let length = glam::Vec3::from_array(vertex.offset).length();
+            assert!((length - 1.0).abs() < 1e-5);
+        }
+    }
+}
@@ -1,8 +1,10 @@
-use std::{cell::RefCell, rc::Rc, sync::Arc};
+use std::{cell::RefCell, rc::Rc, sync::Arc, time::Instant};
 
-use glam::Vec4;
+use glam::{Vec3, Vec4};
 use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage},
     descriptor_set::{layout::DescriptorSetLayout, DescriptorSet, WriteDescriptorSet},
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter},
     pipeline::Pipeline,
 };
 use winit::event_loop::ActiveEventLoop;
@@ -13,17 +15,45 @@ use crate::{
     utils::{FpsCounter, GpuTaskExecutor, VulkanoBackend},
 };
 
-use super::{render_task::RenderTask, RenderContext};
+use super::{
+    camera_follow::{CameraFollow, Follow},
+    colormap::{ColorMode, Colormap, FLAT_COLOR},
+    density_alpha::DensityAlphaRange,
+    fluid_surface::{FluidSurface, SmoothingConstants, SURFACE_DEPTH_BINDING},
+    grid_overlay::{cell_box_lines, occupied_cells},
+    msaa::Msaa,
+    point_size::PointSizeRange,
+    render_task::{GridOverlayDraw, RenderTask, SurfaceDraw},
+    sprite_texture::{SpriteTexture, SPRITE_TEXTURE_BINDING},
+    RenderContext, RenderMode,
+};
 
 pub struct RenderSystem {
     vulkano_backend: Option<Rc<VulkanoBackend>>,
     render_context: Option<Rc<RefCell<RenderContext>>>,
     clean_color: Vec4,
     fps_counter: FpsCounter,
+    render_mode: RenderMode,
+    show_grid: bool,
+    /// Draw a smoothed screen-space fluid surface instead of individual particles
+    surface_rendering: bool,
+    grid_size: f32,
+    density_alpha_range: Option<DensityAlphaRange>,
+    point_size_range: PointSizeRange,
+    /// World-space radius of the sphere drawn per particle, which also sizes point sprites
+    particle_radius: f32,
+    color_mode: ColorMode,
+    colormap: Colormap,
+    /// Multiplied into point sprite colors, plain white until one is set
+    sprite_texture: Option<SpriteTexture>,
+    msaa: Msaa,
+    follow_target: Follow,
+    camera_follow: CameraFollow,
+    last_follow_update: Option<Instant>,
 }
 
 impl RenderSystem {
-    pub fn new() -> Self {
+    pub fn new(render_mode: RenderMode) -> Self {
         let fps_counter = FpsCounter::new(16, 1.0);
         let clean_color = Vec4::new(0.1, 0.1, 0.1, 1.0);
         Self {
@@ -31,6 +61,20 @@ impl RenderSystem {
             render_context: None,
             clean_color,
             fps_counter,
+            render_mode,
+            show_grid: false,
+            surface_rendering: false,
+            grid_size: 0.1,
+            density_alpha_range: None,
+            point_size_range: PointSizeRange::default(),
+            particle_radius: 0.02,
+            color_mode: ColorMode::default(),
+            colormap: Colormap::default(),
+            sprite_texture: None,
+            msaa: Msaa::Off,
+            follow_target: Follow::Fixed,
+            camera_follow: CameraFollow::default(),
+            last_follow_update: None,
         }
     }
 
@@ -39,7 +83,134 @@ impl RenderSystem {
         self.render_context = Some(Rc::new(RefCell::new(RenderContext::new(
             event_loop,
             &vulkano_backend.clone(),
+            self.render_mode,
+            self.msaa,
         ))));
+        self.set_density_alpha_range(self.density_alpha_range);
+        self.set_surface_rendering(self.surface_rendering);
+        if self.sprite_texture.is_none() {
+            self.sprite_texture = Some(SpriteTexture::white(
+                vulkano_backend.device(),
+                vulkano_backend.memory_allocator(),
+                vulkano_backend.as_ref(),
+            ));
+        }
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+
+    /// Switch the render mode, rebuilding the pipeline if the context is already initialized
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+        if let Some(render_context) = &self.render_context {
+            render_context.borrow_mut().set_render_mode(render_mode);
+        }
+    }
+
+    /// Scale particle alpha by density over `range`, blending additively; `None` draws opaque
+    pub fn set_density_alpha_range(&mut self, range: Option<DensityAlphaRange>) {
+        self.density_alpha_range = range;
+        if let Some(render_context) = &self.render_context {
+            render_context
+                .borrow_mut()
+                .set_density_alpha(range.is_some());
+        }
+    }
+
+    /// Shade a smoothed water surface reconstructed from the particle depth instead of
+    /// drawing the particles themselves
+    pub fn set_surface_rendering(&mut self, surface_rendering: bool) {
+        self.surface_rendering = surface_rendering;
+        if let (Some(render_context), Some(vulkano_backend)) =
+            (&self.render_context, &self.vulkano_backend)
+        {
+            render_context.borrow_mut().set_surface_rendering(
+                surface_rendering,
+                vulkano_backend.memory_allocator(),
+                vulkano_backend.descriptor_set_allocator(),
+            );
+        }
+    }
+
+    /// Keep point sprites between `min` and `max` pixels after distance scaling
+    pub fn set_point_size_range(&mut self, min: f32, max: f32) {
+        self.point_size_range = PointSizeRange::new(min, max);
+    }
+
+    /// Draw particles as spheres of `particle_radius`, e.g. half the particle spacing; point
+    /// sprites are sized to cover the same sphere
+    pub fn set_particle_radius(&mut self, particle_radius: f32) {
+        self.particle_radius = particle_radius.max(0.0);
+    }
+
+    /// Color particles flatly or by speed; a `max_speed` of zero falls back to the flat color
+    pub fn set_color_mode(&mut self, color_mode: ColorMode) {
+        self.color_mode = color_mode;
+    }
+
+    /// Gradient speeds are mapped through in [`ColorMode::Velocity`]
+    pub fn set_colormap(&mut self, colormap: Colormap) {
+        self.colormap = colormap;
+    }
+
+    /// Texture sampled over point sprites and multiplied with the particle color
+    pub fn set_sprite_texture(&mut self, sprite_texture: SpriteTexture) {
+        self.sprite_texture = Some(sprite_texture);
+    }
+
+    /// Multisample the particle render, taking effect when the swapchain is next rebuilt
+    ///
+    /// Settings the device does not support fall back to the highest one it does.
+    pub fn set_msaa(&mut self, msaa: Msaa) {
+        self.msaa = msaa;
+        if let Some(render_context) = &self.render_context {
+            render_context.borrow_mut().set_msaa(msaa);
+        }
+    }
+
+    pub fn follow_target(&self) -> Follow {
+        self.follow_target
+    }
+
+    /// Choose what the camera tracks; switching restarts the smoothing at the next goal
+    pub fn set_follow_target(&mut self, follow_target: Follow) {
+        self.follow_target = follow_target;
+        self.camera_follow.reset();
+        self.last_follow_update = None;
+    }
+
+    /// Time constant in seconds of the camera follow smoothing, zero to follow rigidly
+    pub fn set_follow_damping(&mut self, damping: f32) {
+        self.camera_follow.set_damping(damping);
+    }
+
+    /// Translate `camera` with the smoothed follow target, keeping its orientation
+    ///
+    /// The camera keeps its offset from where the target was first seen, so it should be
+    /// placed relative to the fluid before following starts. Does nothing while fixed.
+    pub fn follow(&mut self, camera: &mut Camera, goal: Vec3) {
+        if self.follow_target == Follow::Fixed {
+            return;
+        }
+
+        let now = Instant::now();
+        let dt = self
+            .last_follow_update
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f32());
+        self.last_follow_update = Some(now);
+        camera.translate(self.camera_follow.update(goal, dt));
+    }
+
+    /// Toggle the wireframe overlay of grid cells occupied by particles
+    pub fn set_show_grid(&mut self, show_grid: bool) {
+        self.show_grid = show_grid;
+    }
+
+    /// Cell size of the overlay, should match `SimulationConfig::grid_size`
+    pub fn set_grid_size(&mut self, grid_size: f32) {
+        self.grid_size = grid_size;
     }
 
     pub fn request_recreate_swapchain(&mut self) {
@@ -50,8 +221,7 @@ impl RenderSystem {
     }
 
     pub fn render(&mut self, camera: &Camera, particles: &Particles) {
-        let vulkano_backend = self.vulkano_backend.as_ref().unwrap();
-        let mut render_context = self.render_context.as_mut().unwrap().borrow_mut();
+        let mut render_context = self.render_context.as_ref().unwrap().borrow_mut();
         let window = render_context.window().clone();
         render_context.cleanup_finished();
 
@@ -68,21 +238,46 @@ impl RenderSystem {
         let pipeline_layout = render_context.pipeline().layout().clone();
         let descriptor_set_layout = render_context.pipeline().layout().set_layouts()[0].clone();
 
-        let descriptor_set = create_descriptor_set(
-            vulkano_backend,
+        let descriptor_set = self.create_descriptor_set(
             camera,
             aspect_ratio,
+            window_size.height as f32,
+            self.density_alpha_range,
             &descriptor_set_layout,
+            None,
         );
 
         let binding = pipeline_layout.clone();
 
+        let grid_overlay = if self.show_grid {
+            self.create_grid_overlay(
+                camera,
+                aspect_ratio,
+                window_size.height as f32,
+                &render_context,
+                particles,
+            )
+        } else {
+            None
+        };
+
+        let surface = render_context.fluid_surface().map(|fluid_surface| {
+            self.create_surface_draw(
+                camera,
+                aspect_ratio,
+                window_size.height as f32,
+                fluid_surface,
+            )
+        });
+
         let mut render_task = RenderTask::setup(
             &mut render_context,
             self.clean_color,
             &descriptor_set,
             &binding,
             particles,
+            grid_overlay,
+            surface,
         );
 
         self.vulkano_backend
@@ -95,38 +290,147 @@ impl RenderSystem {
         window.set_title(&format!("Aqua GPU -FPS: {}", fps as u32));
     }
 
+    /// Build the occupied cell wireframe from a readback of the particle positions
+    fn create_grid_overlay(
+        &self,
+        camera: &Camera,
+        aspect_ratio: f32,
+        viewport_height: f32,
+        render_context: &RenderContext,
+        particles: &Particles,
+    ) -> Option<GridOverlayDraw> {
+        let vulkano_backend = self.vulkano_backend.as_ref().unwrap();
+        let positions = particles
+            .download_positions(vulkano_backend.memory_allocator(), vulkano_backend.as_ref());
+        let cells = occupied_cells(&positions, self.grid_size, Vec3::ZERO);
+        if cells.is_empty() {
+            return None;
+        }
+
+        let lines = Buffer::from_iter(
+            vulkano_backend.memory_allocator().clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::VERTEX_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            cell_box_lines(&cells, self.grid_size, Vec3::ZERO),
+        )
+        .unwrap();
+
+        let layout = render_context.grid_pipeline().layout().set_layouts()[0].clone();
+        let descriptor_set =
+            self.create_descriptor_set(camera, aspect_ratio, viewport_height, None, &layout, None);
+
+        Some(GridOverlayDraw {
+            descriptor_set,
+            lines,
+        })
+    }
+
+    /// Descriptor sets of the fluid surface depth and shading passes
+    fn create_surface_draw(
+        &self,
+        camera: &Camera,
+        aspect_ratio: f32,
+        viewport_height: f32,
+        fluid_surface: &FluidSurface,
+    ) -> SurfaceDraw {
+        let descriptor_set = |layout: &Arc<DescriptorSetLayout>| {
+            self.create_descriptor_set(
+                camera,
+                aspect_ratio,
+                viewport_height,
+                None,
+                layout,
+                Some(fluid_surface),
+            )
+        };
+        SurfaceDraw {
+            depth_descriptor_set: descriptor_set(
+                &fluid_surface.depth_pipeline().layout().set_layouts()[0],
+            ),
+            shade_descriptor_set: descriptor_set(
+                &fluid_surface.shade_pipeline().layout().set_layouts()[0],
+            ),
+            smoothing: SmoothingConstants::new(self.particle_radius),
+        }
+    }
+
     pub fn request_redraw(&mut self) {
         if let Some(render_context) = &self.render_context {
             let render_context = render_context.borrow();
             render_context.window().request_redraw();
         }
     }
-}
 
-fn create_descriptor_set(
-    vulkano_backend: &VulkanoBackend,
-    camera: &Camera,
-    aspect_ratio: f32,
-    layout: &Arc<DescriptorSetLayout>,
-) -> Arc<DescriptorSet> {
-    let view_matrix = camera.view_matrix();
-    let projection_matrix = camera.projection_matrix(aspect_ratio);
-
-    let uniform_data = shaders::render::unlit::vs::Data {
-        view: view_matrix.to_cols_array_2d(),
-        proj: projection_matrix.to_cols_array_2d(),
-    };
-    let uniform_buffer = vulkano_backend
-        .uniform_buffer_allocator()
-        .allocate_sized()
-        .unwrap();
-    *uniform_buffer.write().unwrap() = uniform_data;
-
-    DescriptorSet::new(
-        vulkano_backend.descriptor_set_allocator().clone(),
-        layout.clone(),
-        [WriteDescriptorSet::buffer(0, uniform_buffer)],
-        [],
-    )
-    .unwrap()
+    /// Uniforms for `layout`, plus the sprite texture or surface depth when the layout
+    /// samples one
+    fn create_descriptor_set(
+        &self,
+        camera: &Camera,
+        aspect_ratio: f32,
+        viewport_height: f32,
+        density_alpha_range: Option<DensityAlphaRange>,
+        layout: &Arc<DescriptorSetLayout>,
+        fluid_surface: Option<&FluidSurface>,
+    ) -> Arc<DescriptorSet> {
+        let vulkano_backend = self.vulkano_backend.as_ref().unwrap();
+        let view_matrix = camera.view_matrix();
+        let projection_matrix = camera.projection_matrix(aspect_ratio);
+
+        let (density_alpha_min, density_alpha_max) =
+            DensityAlphaRange::uniform_bounds(density_alpha_range);
+        let (colormap, color_max_speed) = self.color_mode.uniform_values(self.colormap);
+
+        let uniform_data = shaders::render::unlit::vs::Data {
+            view: view_matrix.to_cols_array_2d(),
+            proj: projection_matrix.to_cols_array_2d(),
+            viewport_height,
+            density_alpha_min,
+            density_alpha_max,
+            point_size_min: self.point_size_range.min,
+            point_size_max: self.point_size_range.max,
+            particle_radius: self.particle_radius,
+            colormap,
+            color_max_speed,
+            flat_color: FLAT_COLOR.extend(1.0).to_array(),
+        };
+        let uniform_buffer = vulkano_backend
+            .uniform_buffer_allocator()
+            .allocate_sized()
+            .unwrap();
+        *uniform_buffer.write().unwrap() = uniform_data;
+
+        let mut writes = vec![WriteDescriptorSet::buffer(0, uniform_buffer)];
+        // Only the point sprite shaders sample the sprite texture
+        if layout.bindings().contains_key(&SPRITE_TEXTURE_BINDING) {
+            writes.push(
+                self.sprite_texture
+                    .as_ref()
+                    .expect("sprite texture is not initialized")
+                    .descriptor_write(),
+            );
+        }
+        // Only the fluid surface shading samples the smoothed depth
+        if layout.bindings().contains_key(&SURFACE_DEPTH_BINDING) {
+            writes.push(
+                fluid_surface
+                    .expect("fluid surface is not enabled")
+                    .depth_descriptor_write(),
+            );
+        }
+
+        DescriptorSet::new(
+            vulkano_backend.descriptor_set_allocator().clone(),
+            layout.clone(),
+            writes,
+            [],
+        )
+        .unwrap()
+    }
 }
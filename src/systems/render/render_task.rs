@@ -2,18 +2,21 @@ use std::sync::Arc;
 
 use glam::Vec4;
 use vulkano::{
+    buffer::Subbuffer,
     command_buffer::{
         AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, RenderPassBeginInfo, SubpassBeginInfo,
         SubpassContents,
     },
     descriptor_set::DescriptorSet,
     device::{Device, Queue},
-    pipeline::{PipelineBindPoint, PipelineLayout},
+    pipeline::{Pipeline, PipelineBindPoint, PipelineLayout},
     swapchain::{SwapchainAcquireFuture, SwapchainPresentInfo},
     sync, Validated, VulkanError,
 };
 
-use super::RenderContext;
+use super::{
+    fluid_surface::SmoothingConstants, grid_overlay::GridLineVertex, RenderContext, RenderMode,
+};
 use crate::{core::Particles, utils::GpuTask};
 use vulkano::sync::GpuFuture;
 
@@ -24,6 +27,21 @@ pub(crate) struct RenderTask<'a> {
     descriptor_set: &'a Arc<DescriptorSet>,
     pipeline_layout: &'a Arc<PipelineLayout>,
     particles: &'a Particles,
+    grid_overlay: Option<GridOverlayDraw>,
+    surface: Option<SurfaceDraw>,
+}
+
+/// Wireframe lines drawn after the particles, with their own descriptor set
+pub(crate) struct GridOverlayDraw {
+    pub descriptor_set: Arc<DescriptorSet>,
+    pub lines: Subbuffer<[GridLineVertex]>,
+}
+
+/// Descriptor sets and filter settings of the fluid surface passes, drawn instead of particles
+pub(crate) struct SurfaceDraw {
+    pub depth_descriptor_set: Arc<DescriptorSet>,
+    pub shade_descriptor_set: Arc<DescriptorSet>,
+    pub smoothing: SmoothingConstants,
 }
 
 impl<'a> RenderTask<'a> {
@@ -33,6 +51,8 @@ impl<'a> RenderTask<'a> {
         descriptor_set: &'a Arc<DescriptorSet>,
         pipeline_layout: &'a Arc<PipelineLayout>,
         particles: &'a Particles,
+        grid_overlay: Option<GridOverlayDraw>,
+        surface: Option<SurfaceDraw>,
     ) -> Self {
         let (image_index, acquire_future) = render_context.get_acquire_next_image().unwrap();
         let acquired_frame = AcquiredFrame {
@@ -47,19 +67,77 @@ impl<'a> RenderTask<'a> {
             descriptor_set,
             pipeline_layout,
             particles,
+            grid_overlay,
+            surface,
+        }
+    }
+
+    /// Draw every particle with the render mode's pipeline
+    fn record_particles(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        builder
+            .bind_pipeline_graphics(self.render_context.pipeline().clone())
+            .unwrap();
+        builder
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.pipeline_layout.clone(),
+                0,
+                self.descriptor_set.clone(),
+            )
+            .unwrap();
+        if self.render_context.render_mode() == RenderMode::Spheres {
+            let sphere_mesh = self.render_context.sphere_mesh();
+            builder
+                .bind_vertex_buffers(
+                    0,
+                    (
+                        sphere_mesh.clone(),
+                        self.particles.position().clone(),
+                        self.particles.velocity().clone(),
+                        self.particles.density().clone(),
+                    ),
+                )
+                .unwrap();
+            unsafe { builder.draw(sphere_mesh.len() as u32, self.particles.count(), 0, 0) }
+                .unwrap();
+        } else {
+            builder
+                .bind_vertex_buffers(
+                    0,
+                    (
+                        self.particles.position().clone(),
+                        self.particles.velocity().clone(),
+                        self.particles.density().clone(),
+                    ),
+                )
+                .unwrap();
+            unsafe { builder.draw(self.particles.count(), 1, 0, 0) }.unwrap();
         }
     }
 }
 
 impl GpuTask for RenderTask<'_> {
     fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        let fluid_surface = self
+            .surface
+            .as_ref()
+            .and_then(|surface| Some((surface, self.render_context.fluid_surface()?)));
+        if let Some((surface, fluid_surface)) = fluid_surface {
+            fluid_surface.record_depth(
+                builder,
+                &surface.depth_descriptor_set,
+                self.particles.position(),
+                self.particles.count(),
+            );
+            fluid_surface.record_smoothing(builder, surface.smoothing);
+        }
+
         builder
             .begin_render_pass(
                 RenderPassBeginInfo {
-                    clear_values: vec![
-                        Some(self.clean_color.to_array().into()),
-                        Some(1.0f32.into()),
-                    ],
+                    clear_values: self
+                        .render_context
+                        .clear_values(self.clean_color.to_array()),
                     ..RenderPassBeginInfo::framebuffer(
                         self.render_context.framebuffers()
                             [self.acquired_frame.image_index as usize]
@@ -80,27 +158,29 @@ impl GpuTask for RenderTask<'_> {
                     .collect(),
             )
             .unwrap();
-        builder
-            .bind_pipeline_graphics(self.render_context.pipeline().clone())
-            .unwrap();
-        builder
-            .bind_descriptor_sets(
-                PipelineBindPoint::Graphics,
-                self.pipeline_layout.clone(),
-                0,
-                self.descriptor_set.clone(),
-            )
-            .unwrap();
-        builder
-            .bind_vertex_buffers(
-                0,
-                (
-                    self.particles.position().clone(),
-                    self.particles.velocity().clone(),
-                ),
-            )
-            .unwrap();
-        unsafe { builder.draw(self.particles.count(), 1, 0, 0) }.unwrap();
+        if let Some((surface, fluid_surface)) = fluid_surface {
+            fluid_surface.record_shade(builder, &surface.shade_descriptor_set);
+        } else {
+            self.record_particles(builder);
+        }
+        if let Some(grid_overlay) = &self.grid_overlay {
+            let grid_pipeline = self.render_context.grid_pipeline();
+            builder
+                .bind_pipeline_graphics(grid_pipeline.clone())
+                .unwrap();
+            builder
+                .bind_descriptor_sets(
+                    PipelineBindPoint::Graphics,
+                    grid_pipeline.layout().clone(),
+                    0,
+                    grid_overlay.descriptor_set.clone(),
+                )
+                .unwrap();
+            builder
+                .bind_vertex_buffers(0, grid_overlay.lines.clone())
+                .unwrap();
+            unsafe { builder.draw(grid_overlay.lines.len() as u32, 1, 0, 0) }.unwrap();
+        }
         builder.end_render_pass(Default::default()).unwrap();
     }
 
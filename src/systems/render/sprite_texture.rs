@@ -0,0 +1,136 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{AutoCommandBufferBuilder, CopyBufferToImageInfo, PrimaryAutoCommandBuffer},
+    descriptor_set::WriteDescriptorSet,
+    device::{Device, Queue},
+    format::Format,
+    image::{
+        sampler::{Sampler, SamplerCreateInfo},
+        view::ImageView,
+        Image, ImageCreateInfo, ImageType, ImageUsage,
+    },
+    memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator},
+    sync::{self, GpuFuture},
+};
+
+use crate::utils::{GpuTask, GpuTaskExecutor};
+
+/// Binding of the sprite sampler in the point sprite fragment shaders
+pub(crate) const SPRITE_TEXTURE_BINDING: u32 = 1;
+
+/// Texture sampled over each point sprite and multiplied with the particle color
+#[derive(Clone)]
+pub struct SpriteTexture {
+    view: Arc<ImageView>,
+    sampler: Arc<Sampler>,
+}
+
+impl SpriteTexture {
+    /// Wrap an existing image, sampled with linear filtering
+    pub fn new(device: &Arc<Device>, view: Arc<ImageView>) -> Self {
+        let sampler =
+            Sampler::new(device.clone(), SamplerCreateInfo::simple_repeat_linear()).unwrap();
+        Self { view, sampler }
+    }
+
+    /// Upload tightly packed RGBA8 sRGB pixels, row by row
+    pub fn from_rgba8(
+        device: &Arc<Device>,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        executor: &impl GpuTaskExecutor,
+        [width, height]: [u32; 2],
+        pixels: &[u8],
+    ) -> Self {
+        assert_eq!(
+            pixels.len(),
+            (width * height * 4) as usize,
+            "sprite pixels must be {}x{} RGBA8",
+            width,
+            height
+        );
+
+        let image = Image::new(
+            memory_allocator.clone(),
+            ImageCreateInfo {
+                image_type: ImageType::Dim2d,
+                format: Format::R8G8B8A8_SRGB,
+                extent: [width, height, 1],
+                usage: ImageUsage::TRANSFER_DST | ImageUsage::SAMPLED,
+                ..Default::default()
+            },
+            AllocationCreateInfo::default(),
+        )
+        .unwrap();
+        let staging = Buffer::from_iter(
+            memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            pixels.iter().copied(),
+        )
+        .unwrap();
+
+        executor.execute(&mut ImageUploadTask {
+            src: staging,
+            dst: image.clone(),
+        });
+
+        Self::new(device, ImageView::new_default(image).unwrap())
+    }
+
+    /// Single white texel, leaving the particle color unchanged
+    pub fn white(
+        device: &Arc<Device>,
+        memory_allocator: &Arc<StandardMemoryAllocator>,
+        executor: &impl GpuTaskExecutor,
+    ) -> Self {
+        Self::from_rgba8(device, memory_allocator, executor, [1, 1], &[255; 4])
+    }
+
+    pub fn descriptor_write(&self) -> WriteDescriptorSet {
+        WriteDescriptorSet::image_view_sampler(
+            SPRITE_TEXTURE_BINDING,
+            self.view.clone(),
+            self.sampler.clone(),
+        )
+    }
+}
+
+/// Copies staged pixels into a sampled image
+struct ImageUploadTask {
+    src: Subbuffer<[u8]>,
+    dst: Arc<Image>,
+}
+
+impl GpuTask for ImageUploadTask {
+    fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        builder
+            .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+                self.src.clone(),
+                self.dst.clone(),
+            ))
+            .unwrap();
+    }
+
+    fn submit(
+        &mut self,
+        command_buffer: Arc<PrimaryAutoCommandBuffer>,
+        queue: &Arc<Queue>,
+        device: &Arc<Device>,
+    ) {
+        let future = sync::now(device.clone())
+            .then_execute(queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap();
+        future.wait(None).unwrap();
+    }
+}
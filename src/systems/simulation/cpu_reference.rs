@@ -0,0 +1,341 @@
+use glam::{DVec3, Vec3};
+
+use crate::core::ParticleInitData;
+
+use super::simulation_config::{DensityKernel, SimulationConfig, ViscosityMode};
+
+/// Neighborhood limit shared by the density, PBD and viscosity passes
+const MAX_NEIGHBORS: usize = 64;
+
+/// Double precision CPU implementation of one simulation step
+///
+/// Mirrors `SimulationTasks::execute` pass by pass, including the stable Morton sort
+/// and the strided neighbor sampling, so small scenes can be checked against it
+/// exactly instead of only for plausibility. PBD corrections are Jacobi iterations
+/// over a snapshot of predicted positions.
+pub(crate) struct CpuStepReference {
+    positions: Vec<DVec3>,
+    velocities: Vec<DVec3>,
+    predicted_positions: Vec<DVec3>,
+    densities: Vec<f64>,
+}
+
+impl CpuStepReference {
+    pub fn new(particles_init_data: &[ParticleInitData]) -> Self {
+        let positions = particles_init_data
+            .iter()
+            .map(|p| p.position.as_dvec3())
+            .collect::<Vec<_>>();
+        let velocities = particles_init_data
+            .iter()
+            .map(|p| p.velocity.as_dvec3())
+            .collect();
+        let count = positions.len();
+
+        Self {
+            predicted_positions: positions.clone(),
+            positions,
+            velocities,
+            densities: vec![0.0; count],
+        }
+    }
+
+    pub fn positions(&self) -> &[DVec3] {
+        &self.positions
+    }
+
+    pub fn velocities(&self) -> &[DVec3] {
+        &self.velocities
+    }
+
+    pub fn densities(&self) -> &[f64] {
+        &self.densities
+    }
+
+    pub fn step(&mut self, config: &SimulationConfig, dt: f32) {
+        let dt = dt as f64;
+        let sph = &config.sph_params;
+        let h = sph.smoothing_radius as f64;
+        let mass = sph.particle_mass as f64;
+
+        // 1. Gravity
+        let gravity = config.gravity.as_dvec3();
+        for velocity in &mut self.velocities {
+            *velocity += gravity * dt;
+        }
+
+        // 2-3. Morton hash and stable sort; ties keep ascending particle index
+        let hashes = self
+            .positions
+            .iter()
+            .map(|p| morton_code(p.as_vec3(), config.grid_size))
+            .collect::<Vec<_>>();
+        let mut sorted_indices = (0..self.positions.len()).collect::<Vec<_>>();
+        sorted_indices.sort_by_key(|&i| hashes[i]);
+        let sorted_hashes = sorted_indices
+            .iter()
+            .map(|&i| hashes[i])
+            .collect::<Vec<_>>();
+
+        // 4. SPH density
+        let poly6_factor = 315.0 / (64.0 * std::f64::consts::PI * h.powi(9));
+        for (i, &sorted_hash) in sorted_hashes.iter().enumerate() {
+            let density = neighbor_candidates(&sorted_indices)
+                .map(|j| {
+                    let r_sq = self.positions[i].distance_squared(self.positions[j]);
+                    density_kernel(sph.density_kernel, r_sq, h)
+                })
+                .sum::<f64>()
+                * mass;
+            // The GPU pass folds the hash buffer into the density to keep its binding alive
+            self.densities[i] = density + sorted_hash as f64 * 1e-10;
+        }
+
+        // 5-6. PBD density constraint on predicted positions
+        self.predicted_positions.clone_from(&self.positions);
+        let grad_factor = -45.0 / (std::f64::consts::PI * h.powi(6));
+        let epsilon = sph.pbd_constraint_epsilon as f64;
+        for _ in 0..sph.pbd_iterations {
+            let snapshot = self.predicted_positions.clone();
+            for i in 0..snapshot.len() {
+                let constraint = self.densities[i] / sph.rest_density as f64 - 1.0;
+                if constraint.abs() < epsilon {
+                    continue;
+                }
+
+                let mut gradient_i = DVec3::ZERO;
+                let mut gradient_sum_sq = 0.0;
+                for j in neighbor_candidates(&sorted_indices).filter(|&j| j != i) {
+                    let grad = spiky_gradient(i, j, snapshot[i] - snapshot[j], h, grad_factor);
+                    gradient_i += grad;
+                    gradient_sum_sq += grad.length_squared();
+                }
+                gradient_sum_sq += gradient_i.length_squared();
+
+                let lambda = if gradient_sum_sq > epsilon {
+                    -constraint / (gradient_sum_sq + epsilon)
+                } else {
+                    0.0
+                };
+                let correction = (sph.pbd_relaxation_factor as f64 * lambda * gradient_i)
+                    .clamp_length_max(h * 0.1);
+                self.predicted_positions[i] += correction;
+            }
+        }
+
+        // 7. Implicit viscosity
+        if sph.viscosity_mode == ViscosityMode::Implicit {
+            let viscosity = sph.viscosity as f64;
+            let mut solved = Vec::with_capacity(self.velocities.len());
+            for i in 0..self.positions.len() {
+                let mut weighted_sum = DVec3::ZERO;
+                let mut weight_sum = 0.0;
+                for j in neighbor_candidates(&sorted_indices).filter(|&j| j != i) {
+                    if self.densities[j] <= 0.0 {
+                        continue;
+                    }
+                    let r_sq = self.positions[i].distance_squared(self.positions[j]);
+                    let w = viscosity * mass / self.densities[j]
+                        * poly6_kernel(r_sq, h * h, poly6_factor);
+                    weighted_sum += w * self.velocities[j];
+                    weight_sum += w;
+                }
+                solved.push((self.velocities[i] + weighted_sum) / (1.0 + weight_sum));
+            }
+            self.velocities = solved;
+        }
+
+        // 8. Position update with AABB reflection
+        let aabb_min = config.simulation_aabb.min().as_dvec3();
+        let aabb_max = config.simulation_aabb.max().as_dvec3();
+        for (position, velocity) in self.positions.iter_mut().zip(&mut self.velocities) {
+            *position += *velocity * dt;
+            for axis in 0..3 {
+                if position[axis] < aabb_min[axis] {
+                    position[axis] = aabb_min[axis];
+                    velocity[axis] = -velocity[axis];
+                }
+                if position[axis] > aabb_max[axis] {
+                    position[axis] = aabb_max[axis];
+                    velocity[axis] = -velocity[axis];
+                }
+            }
+        }
+    }
+}
+
+/// SPH density of every particle as spiky_sph.comp computes it for up to `MAX_NEIGHBORS`
+/// particles, `mass * Σ_j W(|x_i - x_j|, h)` over all particles including `i` itself
+///
+/// Unlike [`CpuStepReference`] it skips the Morton sort, whose order does not change the
+/// sum, and the hash the GPU pass folds in, which is negligible for small hashes.
+pub(crate) fn reference_densities(
+    positions: &[Vec3],
+    mass: f32,
+    smoothing_radius: f32,
+    kernel: DensityKernel,
+) -> Vec<f64> {
+    assert!(
+        positions.len() <= MAX_NEIGHBORS,
+        "the GPU pass samples larger sets"
+    );
+    let h = smoothing_radius as f64;
+    positions
+        .iter()
+        .map(|&position_i| {
+            positions
+                .iter()
+                .map(|&position_j| {
+                    let r_sq = position_i
+                        .as_dvec3()
+                        .distance_squared(position_j.as_dvec3());
+                    density_kernel(kernel, r_sq, h)
+                })
+                .sum::<f64>()
+                * mass as f64
+        })
+        .collect()
+}
+
+/// Same candidate set as the GPU passes: every particle for small counts,
+/// otherwise `MAX_NEIGHBORS` strided samples of the sorted order
+fn neighbor_candidates(sorted_indices: &[usize]) -> impl Iterator<Item = usize> + '_ {
+    let count = sorted_indices.len();
+    let (search_count, step) = if count <= MAX_NEIGHBORS {
+        (count, 1)
+    } else {
+        (MAX_NEIGHBORS, (count / MAX_NEIGHBORS).max(1))
+    };
+    (0..search_count).map(move |s| sorted_indices[(s * step) % count])
+}
+
+/// `density_kernel` of spiky_sph.comp, with the factors of [`DensityKernel::factor`]
+fn density_kernel(kernel: DensityKernel, r_sq: f64, h: f64) -> f64 {
+    match kernel {
+        DensityKernel::Poly6 => poly6_kernel(
+            r_sq,
+            h * h,
+            315.0 / (64.0 * std::f64::consts::PI * h.powi(9)),
+        ),
+        DensityKernel::Spiky => spiky_kernel(r_sq, h, 15.0 / (std::f64::consts::PI * h.powi(6))),
+    }
+}
+
+fn poly6_kernel(r_sq: f64, h_sq: f64, factor: f64) -> f64 {
+    if r_sq >= h_sq {
+        return 0.0;
+    }
+    let diff = h_sq - r_sq;
+    factor * diff * diff * diff
+}
+
+fn spiky_kernel(r_sq: f64, h: f64, factor: f64) -> f64 {
+    if r_sq >= h * h {
+        return 0.0;
+    }
+    let diff = h - r_sq.sqrt();
+    factor * diff * diff * diff
+}
+
+fn spiky_gradient(i: usize, j: usize, mut r_vec: DVec3, h: f64, factor: f64) -> DVec3 {
+    let mut r = r_vec.length();
+    if r == 0.0 {
+        r = h * 1e-3;
+        r_vec = DVec3::new(if i < j { r } else { -r }, 0.0, 0.0);
+    }
+    if r >= h {
+        return DVec3::ZERO;
+    }
+    let diff = h - r;
+    factor * diff * diff * (r_vec / r)
+}
+
+/// Morton code computed in single precision, matching morton_hash.comp bit for bit
+fn morton_code(position: Vec3, grid_size: f32) -> u32 {
+    fn expand_bits(v: u32) -> u32 {
+        let v = v.wrapping_mul(0x00010001) & 0xFF0000FF;
+        let v = v.wrapping_mul(0x00000101) & 0x0F00F00F;
+        let v = v.wrapping_mul(0x00000011) & 0xC30C30C3;
+        v.wrapping_mul(0x00000005) & 0x49249249
+    }
+
+    let grid_pos = (position / grid_size).floor().as_ivec3().as_uvec3();
+    expand_bits(grid_pos.x) | (expand_bits(grid_pos.y) << 1) | (expand_bits(grid_pos.z) << 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::Particles, systems::simulation::simulation_tasks::SimulationTasks,
+        utils::VulkanoHeadlessBackend,
+    };
+    use glam::{UVec3, Vec4};
+
+    #[test]
+    fn test_gpu_step_matches_cpu_reference() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig::default();
+        let dt = 1.0 / 60.0;
+
+        // Small dam break: a 5x5x4 block resting near the floor corner
+        let particle_data = ParticleInitData::block(UVec3::new(5, 4, 5), 0.05, Vec3::splat(-1.9));
+
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+        let mut tasks = SimulationTasks::new(backend.device());
+        let mut reference = CpuStepReference::new(&particle_data);
+
+        for _ in 0..10 {
+            tasks.set_constants_from_config(&config, particles.count(), dt);
+            tasks.update_descriptor_sets(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &config,
+            );
+            tasks.execute(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &backend,
+                &config,
+            );
+            reference.step(&config, dt);
+        }
+
+        let positions = particles.position().read().unwrap();
+        let velocities = particles.velocity().read().unwrap();
+        for i in 0..particle_data.len() {
+            let gpu_position = Vec4::from_array(positions[i].position).truncate();
+            let gpu_velocity = Vec4::from_array(velocities[i].velocity).truncate();
+            let position_error = gpu_position.as_dvec3().distance(reference.positions()[i]);
+            let velocity_error = gpu_velocity.as_dvec3().distance(reference.velocities()[i]);
+
+            assert!(
+                position_error < 1e-4,
+                "particle {} position: gpu {:?} cpu {:?}",
+                i,
+                gpu_position,
+                reference.positions()[i]
+            );
+            assert!(
+                velocity_error < 1e-3,
+                "particle {} velocity: gpu {:?} cpu {:?}",
+                i,
+                gpu_velocity,
+                reference.velocities()[i]
+            );
+        }
+
+        let densities = particles.density().read().unwrap();
+        for (i, &density) in densities[..particle_data.len()].iter().enumerate() {
+            let expected = reference.densities()[i];
+            assert!(
+                (density as f64 - expected).abs() <= expected * 1e-3,
+                "particle {} density: gpu {} cpu {}",
+                i,
+                density,
+                expected
+            );
+        }
+    }
+}
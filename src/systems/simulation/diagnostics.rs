@@ -0,0 +1,172 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use glam::Vec3;
+
+use crate::core::NeighborStats;
+
+use super::{simulation_config::SimulationConfig, simulation_tasks::SimulationStepTiming};
+
+const COLUMNS: [&str; 14] = [
+    "frame",
+    "particle_count",
+    "mean_density_error",
+    "max_density_error",
+    "kinetic_energy",
+    "mean_neighbors",
+    "max_neighbors",
+    "gravity_ms",
+    "morton_hash_ms",
+    "radix_sort_ms",
+    "sph_density_ms",
+    "pbd_constraint_ms",
+    "position_update_ms",
+    "total_ms",
+];
+
+/// Per-frame statistics written as one CSV row by [`DiagnosticsLogger`]
+#[derive(Clone, Debug)]
+pub(crate) struct FrameDiagnostics {
+    pub particle_count: u32,
+    /// Mean of `|density / rest_density - 1|`
+    pub mean_density_error: f32,
+    pub max_density_error: f32,
+    pub kinetic_energy: f32,
+    /// Neighbor list sizes, `None` when neighbor lists are disabled
+    pub mean_neighbors: Option<f32>,
+    pub max_neighbors: Option<u32>,
+    pub timing: SimulationStepTiming,
+}
+
+impl FrameDiagnostics {
+    /// Reduce host copies of the live particle buffers
+    pub fn new(
+        config: &SimulationConfig,
+        velocities: &[Vec3],
+        densities: &[f32],
+        contact_counts: Option<&[u32]>,
+        timing: SimulationStepTiming,
+    ) -> Self {
+        let count = densities.len().max(1) as f32;
+        let rest_density = config.sph_params.rest_density;
+        let density_errors = densities.iter().map(|d| (d / rest_density - 1.0).abs());
+        let neighbor_stats = contact_counts.map(NeighborStats::from_counts);
+
+        Self {
+            particle_count: densities.len() as u32,
+            mean_density_error: density_errors.clone().sum::<f32>() / count,
+            max_density_error: density_errors.fold(0.0, f32::max),
+            kinetic_energy: 0.5
+                * config.sph_params.particle_mass
+                * velocities.iter().map(|v| v.length_squared()).sum::<f32>(),
+            mean_neighbors: neighbor_stats.map(|stats| stats.mean),
+            max_neighbors: neighbor_stats.map(|stats| stats.max),
+            timing,
+        }
+    }
+}
+
+/// Appends one CSV row of [`FrameDiagnostics`] per frame, for correlating parameters with stability
+pub(crate) struct DiagnosticsLogger<W: Write> {
+    writer: W,
+    frame: u64,
+}
+
+impl DiagnosticsLogger<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> DiagnosticsLogger<W> {
+    /// Write the header row
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writeln!(writer, "{}", COLUMNS.join(","))?;
+        Ok(Self { writer, frame: 0 })
+    }
+
+    pub fn log(&mut self, diagnostics: &FrameDiagnostics) -> io::Result<()> {
+        let timing = &diagnostics.timing;
+        let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+        writeln!(
+            self.writer,
+            "{},{},{},{},{},{},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}",
+            self.frame,
+            diagnostics.particle_count,
+            diagnostics.mean_density_error,
+            diagnostics.max_density_error,
+            diagnostics.kinetic_energy,
+            diagnostics
+                .mean_neighbors
+                .map_or(String::new(), |n| n.to_string()),
+            diagnostics
+                .max_neighbors
+                .map_or(String::new(), |n| n.to_string()),
+            ms(timing.gravity_time),
+            ms(timing.morton_hash_time),
+            ms(timing.radix_sort_time),
+            ms(timing.sph_density_time),
+            ms(timing.pbd_constraint_time),
+            ms(timing.position_update_time),
+            ms(timing.total_time),
+        )?;
+        self.frame += 1;
+        self.writer.flush()
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_diagnostics_csv_has_one_row_per_frame() {
+        let config = SimulationConfig::default();
+        let rest_density = config.sph_params.rest_density;
+        let timing = SimulationStepTiming {
+            morton_hash_time: Duration::from_micros(100),
+            radix_sort_time: Duration::from_micros(400),
+            sph_density_time: Duration::from_micros(300),
+            pbd_constraint_time: Duration::from_micros(200),
+            gravity_time: Duration::from_micros(50),
+            position_update_time: Duration::from_micros(50),
+            total_time: Duration::from_micros(1100),
+        };
+
+        let mut logger = DiagnosticsLogger::new(Vec::new()).unwrap();
+        for frame in 0..3 {
+            let densities = [rest_density, rest_density * 1.5];
+            let velocities = [Vec3::X, Vec3::ZERO];
+            // Neighbor lists only on some frames; the column count must not change
+            let contact_counts = [2, 4];
+            let diagnostics = FrameDiagnostics::new(
+                &config,
+                &velocities,
+                &densities,
+                (frame % 2 == 0).then_some(&contact_counts[..]),
+                timing.clone(),
+            );
+            assert!((diagnostics.max_density_error - 0.5).abs() < 1e-6);
+            assert!((diagnostics.mean_density_error - 0.25).abs() < 1e-6);
+            logger.log(&diagnostics).unwrap();
+        }
+
+        let csv = String::from_utf8(logger.into_inner()).unwrap();
+        let rows = csv.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 1 + 3);
+        for row in &rows {
+            assert_eq!(row.split(',').count(), COLUMNS.len(), "{}", row);
+        }
+        assert!(rows[1].starts_with("0,2,"));
+        assert!(rows[3].starts_with("2,2,"));
+    }
+}
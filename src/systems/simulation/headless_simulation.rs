@@ -0,0 +1,1047 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use glam::{UVec3, Vec3, Vec4};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{ParticleInitData, Particles, CONTACTS_PER_PARTICLE},
+    utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+};
+
+use super::{
+    simulation_config::SimulationConfig,
+    simulation_tasks::{SimulationStepTiming, SimulationTasks},
+    tasks::{
+        center_of_mass, AccumulateDensityConstants, AccumulateDensityTask, FieldGridConstants,
+        FieldGridTask, GridField, IsolatedCountConstants, IsolatedCountTask, MomentsConstants,
+        MomentsTask,
+    },
+};
+
+/// Bumped whenever the checkpoint layout changes
+const CHECKPOINT_VERSION: u32 = 2;
+const CHECKPOINT_HEADER: &str = "checkpoint.json";
+const CHECKPOINT_STATE: &str = "state.bin";
+/// Position, velocity and density as seven little-endian f32
+///
+/// Density is kept so a resumed run starts from the settled field instead of zero. PBD
+/// lambdas are recomputed from it on every iteration, so they need no storage.
+const STATE_BYTES_PER_PARTICLE: usize = 7 * 4;
+/// Share of particles that must be isolated before a jump in their count is reported
+const ISOLATED_WARNING_FRACTION: f32 = 0.01;
+
+/// Config and bookkeeping stored next to the raw particle state
+#[derive(Serialize, Deserialize)]
+struct CheckpointHeader {
+    version: u32,
+    config: SimulationConfig,
+    particle_count: u32,
+    pinned: Vec<u32>,
+}
+
+/// Dense samples of a particle quantity on a uniform grid over the simulation bounds
+pub struct FieldGrid {
+    pub dims: UVec3,
+    /// `dims.x * dims.y * dims.z` values, x varying fastest
+    pub values: Vec<f32>,
+}
+
+impl FieldGrid {
+    pub fn value(&self, x: u32, y: u32, z: u32) -> f32 {
+        self.values[((z * self.dims.y + y) * self.dims.x + x) as usize]
+    }
+}
+
+/// Running per-particle density sum, see [`HeadlessSimulation::begin_density_average`]
+struct DensityAverage {
+    task: AccumulateDensityTask,
+    frames: u32,
+}
+
+/// Fixed-step simulation on a headless backend, without a window or render loop
+pub struct HeadlessSimulation {
+    backend: VulkanoHeadlessBackend,
+    particles: Particles,
+    tasks: SimulationTasks,
+    config: SimulationConfig,
+    /// Full-resolution starting point, kept so the resolution can be switched
+    initial_data: Vec<ParticleInitData>,
+    full_config: SimulationConfig,
+    downsample_fraction: f32,
+    /// Result of the previous [`Self::isolated_count`], to report spikes
+    last_isolated_count: u32,
+    density_average: Option<DensityAverage>,
+    field_grid: FieldGridTask,
+    moments: MomentsTask,
+    isolated_count: IsolatedCountTask,
+}
+
+impl HeadlessSimulation {
+    pub fn new(config: SimulationConfig, particles_init_data: &[ParticleInitData]) -> Self {
+        let backend = VulkanoHeadlessBackend::new();
+        let particles = spawn_particles(&backend, particles_init_data);
+        let tasks = SimulationTasks::new(backend.device());
+        let field_grid = FieldGridTask::new(backend.device());
+        let moments = MomentsTask::new(backend.device());
+        let isolated_count = IsolatedCountTask::new(backend.device());
+
+        Self {
+            backend,
+            particles,
+            tasks,
+            full_config: config.clone(),
+            config,
+            initial_data: particles_init_data.to_vec(),
+            downsample_fraction: 1.0,
+            last_isolated_count: 0,
+            density_average: None,
+            field_grid,
+            moments,
+            isolated_count,
+        }
+    }
+
+    /// Simulate a random `fraction` of the initial particles as a fast preview
+    ///
+    /// Particle mass is scaled by the inverse of the kept share so densities stay
+    /// comparable to the full set. The simulation restarts from the initial state, and
+    /// `with_downsample(1.0)` switches back to full resolution.
+    pub fn with_downsample(mut self, fraction: f32) -> Self {
+        let fraction = fraction.clamp(0.0, 1.0);
+        let kept = downsample_indices(self.initial_data.len(), fraction);
+        let particles_init_data = kept
+            .iter()
+            .map(|&i| self.initial_data[i])
+            .collect::<Vec<_>>();
+
+        self.config = self.full_config.clone();
+        if !kept.is_empty() {
+            self.config.sph_params.particle_mass *=
+                self.initial_data.len() as f32 / kept.len() as f32;
+        }
+        self.particles = spawn_particles(&self.backend, &particles_init_data);
+        self.downsample_fraction = fraction;
+        self.density_average = None;
+        self
+    }
+
+    pub fn downsample_fraction(&self) -> f32 {
+        self.downsample_fraction
+    }
+
+    /// Advance by `dt`, in `config.substeps` iterations
+    pub fn step(&mut self, dt: f32) {
+        self.prepare_step(dt);
+        for _ in 0..self.config.substeps.max(1) {
+            self.tasks.execute(
+                self.backend.descriptor_set_allocator(),
+                &mut self.particles,
+                &self.backend,
+                &self.config,
+            );
+        }
+        self.accumulate_density();
+    }
+
+    /// [`Self::step`] with per-pass wall-clock timing
+    pub fn step_with_timing(&mut self, dt: f32) -> SimulationStepTiming {
+        self.prepare_step(dt);
+        let mut timing = self.tasks.execute_with_timing(
+            self.backend.descriptor_set_allocator(),
+            &mut self.particles,
+            &self.backend,
+            &self.config,
+        );
+        for _ in 1..self.config.substeps.max(1) {
+            timing += self.tasks.execute_with_timing(
+                self.backend.descriptor_set_allocator(),
+                &mut self.particles,
+                &self.backend,
+                &self.config,
+            );
+        }
+        self.accumulate_density();
+        timing
+    }
+
+    fn prepare_step(&mut self, dt: f32) {
+        self.tasks.remove_particles(
+            self.backend.descriptor_set_allocator(),
+            &mut self.particles,
+            &self.backend,
+        );
+        self.tasks.merge_duplicates(
+            self.backend.descriptor_set_allocator(),
+            &mut self.particles,
+            &self.backend,
+            &self.config,
+        );
+        self.tasks.set_constants_from_config(
+            &self.config,
+            self.particles.count(),
+            self.config.substep_time_step(dt),
+        );
+        self.tasks.update_descriptor_sets(
+            self.backend.descriptor_set_allocator(),
+            &mut self.particles,
+            &self.config,
+        );
+        self.tasks.refresh_stale_contacts(
+            self.backend.descriptor_set_allocator(),
+            &mut self.particles,
+            &self.backend,
+            &self.config,
+        );
+    }
+
+    /// Add the densities of the step that just ran to a running time average
+    fn accumulate_density(&mut self) {
+        let Some(density_average) = self.density_average.as_mut() else {
+            return;
+        };
+        density_average
+            .task
+            .set_constants(AccumulateDensityConstants::new(self.particles.count()));
+        density_average
+            .task
+            .update_descriptor_set(self.backend.descriptor_set_allocator(), &mut self.particles);
+        self.backend.execute(&mut density_average.task);
+        density_average.frames += 1;
+    }
+
+    /// Average each particle's density over the steps from now on, restarting any running average
+    ///
+    /// Per-frame densities are noisy; their mean over a window shows whether the solver holds
+    /// `rest_density` on average, e.g. once a pool has settled.
+    pub fn begin_density_average(&mut self) {
+        self.particles.enable_density_sum();
+        self.particles.clear_density_sum(&self.backend);
+        self.density_average = Some(DensityAverage {
+            task: AccumulateDensityTask::new(self.backend.device()),
+            frames: 0,
+        });
+    }
+
+    /// Mean density of each particle since [`Self::begin_density_average`]
+    ///
+    /// `None` when no average is running or no step has been averaged yet.
+    pub fn time_averaged_density(&self) -> Option<Vec<f32>> {
+        let frames = self
+            .density_average
+            .as_ref()
+            .map(|density_average| density_average.frames)
+            .filter(|&frames| frames > 0)?;
+        let density_sums = self
+            .particles
+            .download_density_sums(self.backend.memory_allocator(), &self.backend)?;
+        Some(
+            density_sums
+                .into_iter()
+                .map(|sum| sum / frames as f32)
+                .collect(),
+        )
+    }
+
+    pub fn backend(&self) -> &VulkanoHeadlessBackend {
+        &self.backend
+    }
+
+    pub fn particles(&self) -> &Particles {
+        &self.particles
+    }
+
+    pub fn config(&self) -> &SimulationConfig {
+        &self.config
+    }
+
+    pub fn positions(&self) -> Vec<Vec3> {
+        self.particles
+            .download_positions(self.backend.memory_allocator(), &self.backend)
+    }
+
+    pub fn velocities(&self) -> Vec<Vec3> {
+        self.particles
+            .download_velocities(self.backend.memory_allocator(), &self.backend)
+    }
+
+    /// Splat `field` onto a `resolution`³ grid over `simulation_aabb` and read it back
+    ///
+    /// Each particle adds its value within the smoothing radius of a cell center, weighted by
+    /// a poly6-shaped falloff that is 1 at the particle. Densities are those of the last step.
+    /// The particles are sorted by their current positions to find those near each cell.
+    pub fn sample_field_to_grid(&mut self, resolution: u32, field: GridField) -> FieldGrid {
+        let dims = UVec3::splat(resolution);
+        if resolution == 0 {
+            return FieldGrid {
+                dims,
+                values: Vec::new(),
+            };
+        }
+        self.particles.enable_field_grid(dims.element_product());
+        self.tasks.sort_current_positions(
+            self.backend.descriptor_set_allocator(),
+            &mut self.particles,
+            &self.backend,
+            &self.config,
+        );
+
+        self.field_grid.set_constants(FieldGridConstants::new(
+            self.particles.count(),
+            self.config.simulation_aabb,
+            dims,
+            self.config.sph_params.smoothing_radius,
+            field,
+        ));
+        self.field_grid
+            .update_descriptor_set(self.backend.descriptor_set_allocator(), &mut self.particles);
+        self.backend.execute(&mut self.field_grid);
+
+        FieldGrid {
+            dims,
+            values: self
+                .particles
+                .download_field_grid(self.backend.memory_allocator(), &self.backend)
+                .unwrap(),
+        }
+    }
+
+    /// Neighbor lists of the last step's search as an adjacency list, for graph analysis
+    ///
+    /// Entry `i` lists the neighbors of particle `i` in ascending order, never `i` itself.
+    /// Edges are symmetric: a list cut short by `max_neighbors` or by candidate sampling
+    /// still gains every particle that listed it. Panics if neighbor lists are disabled.
+    pub fn neighbor_graph(&self) -> Vec<Vec<u32>> {
+        assert!(
+            self.config.uses_neighbor_lists(),
+            "neighbor lists are disabled"
+        );
+        let memory_allocator = self.backend.memory_allocator();
+        let counts = self
+            .particles
+            .download_contact_counts(memory_allocator, &self.backend)
+            .unwrap();
+        let contacts = self
+            .particles
+            .download_contacts(memory_allocator, &self.backend)
+            .unwrap();
+        adjacency_list(&counts, &contacts)
+    }
+
+    /// Mass-weighted mean position, or the origin when there are no particles
+    pub fn center_of_mass(&mut self) -> Vec3 {
+        let [position_moment, _] = self.reduce_moments();
+        center_of_mass(position_moment)
+    }
+
+    /// Sum of mass times velocity over all particles
+    pub fn total_momentum(&mut self) -> Vec3 {
+        let [_, momentum] = self.reduce_moments();
+        momentum.truncate()
+    }
+
+    /// Number of particles without a neighbor at the last step's neighbor search
+    ///
+    /// `None` when neighbor lists are disabled; otherwise at least one step must have run.
+    /// Isolated particles are spray or a sign of a smoothing radius too small for the
+    /// spacing, and get meaningless densities, so a count that more than doubles to over
+    /// [`ISOLATED_WARNING_FRACTION`] of the particles is reported as a warning.
+    pub fn isolated_count(&mut self) -> Option<u32> {
+        if !self.config.uses_neighbor_lists() {
+            return None;
+        }
+        self.particles.enable_isolated_count();
+
+        self.isolated_count
+            .set_constants(IsolatedCountConstants::new(self.particles.count()));
+        self.isolated_count
+            .update_descriptor_set(self.backend.descriptor_set_allocator(), &mut self.particles);
+        self.backend.execute(&mut self.isolated_count);
+
+        let isolated_count = self
+            .particles
+            .download_isolated_count(self.backend.memory_allocator(), &self.backend)
+            .unwrap();
+        let warning_count = self.particles.count() as f32 * ISOLATED_WARNING_FRACTION;
+        if isolated_count > 2 * self.last_isolated_count && isolated_count as f32 > warning_count {
+            eprintln!(
+                "Warning: {} of {} particles have no neighbors (previously {})",
+                isolated_count,
+                self.particles.count(),
+                self.last_isolated_count
+            );
+        }
+        self.last_isolated_count = isolated_count;
+        Some(isolated_count)
+    }
+
+    /// `(sum(m * x), sum(m))` and `(sum(m * v), 0)`, reduced on the GPU
+    fn reduce_moments(&mut self) -> [Vec4; 2] {
+        self.particles.enable_moments();
+
+        self.moments.set_constants(MomentsConstants::new(
+            self.particles.count(),
+            self.config.sph_params.particle_mass,
+        ));
+        self.moments
+            .update_descriptor_set(self.backend.descriptor_set_allocator(), &mut self.particles);
+        self.backend.execute(&mut self.moments);
+
+        self.particles
+            .download_moments(self.backend.memory_allocator(), &self.backend)
+            .unwrap()
+    }
+
+    /// Write the config and particle state to `dir` so a run can resume with [`Self::load_checkpoint`]
+    ///
+    /// Sort order and neighbor lists are rebuilt from positions every step, so only
+    /// positions, velocities, densities and pinned flags are stored. Densities are read by
+    /// the renderer and diagnostics before the first resumed step recomputes them.
+    pub fn save_checkpoint(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let header = CheckpointHeader {
+            version: CHECKPOINT_VERSION,
+            config: self.config.clone(),
+            particle_count: self.particles.count(),
+            pinned: self
+                .particles
+                .download_pinned_indices(self.backend.memory_allocator(), &self.backend),
+        };
+        let header = serde_json::to_string_pretty(&header).map_err(io::Error::other)?;
+        fs::write(dir.join(CHECKPOINT_HEADER), header)?;
+
+        let densities = self
+            .particles
+            .download_densities(self.backend.memory_allocator(), &self.backend);
+        let state = self
+            .positions()
+            .iter()
+            .zip(self.velocities())
+            .zip(densities)
+            .flat_map(|((position, velocity), density)| {
+                position
+                    .to_array()
+                    .into_iter()
+                    .chain(velocity.to_array())
+                    .chain([density])
+            })
+            .flat_map(f32::to_le_bytes)
+            .collect::<Vec<_>>();
+        fs::write(dir.join(CHECKPOINT_STATE), state)
+    }
+
+    /// Start a fresh simulation from a checkpoint written by [`Self::save_checkpoint`]
+    pub fn load_checkpoint(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let header: CheckpointHeader =
+            serde_json::from_str(&fs::read_to_string(dir.join(CHECKPOINT_HEADER))?)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if header.version != CHECKPOINT_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "checkpoint version {} is not supported (expected {})",
+                    header.version, CHECKPOINT_VERSION
+                ),
+            ));
+        }
+
+        let state = fs::read(dir.join(CHECKPOINT_STATE))?;
+        if state.len() != header.particle_count as usize * STATE_BYTES_PER_PARTICLE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "checkpoint state holds {} bytes, expected {} particles",
+                    state.len(),
+                    header.particle_count
+                ),
+            ));
+        }
+
+        let values = state
+            .chunks_exact(STATE_BYTES_PER_PARTICLE)
+            .map(|chunk| {
+                chunk
+                    .chunks_exact(4)
+                    .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let particles_init_data = values
+            .iter()
+            .map(|values| ParticleInitData {
+                position: Vec3::from_slice(&values[..3]),
+                velocity: Vec3::from_slice(&values[3..6]),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let densities = values.iter().map(|values| values[6]).collect::<Vec<_>>();
+
+        let mut simulation = Self::new(header.config, &particles_init_data);
+        simulation
+            .particles
+            .set_pinned(&header.pinned, &simulation.backend);
+        simulation
+            .particles
+            .set_densities(&densities, &simulation.backend);
+        Ok(simulation)
+    }
+}
+
+/// Simulate `steps` fixed steps of `config.max_time_step` from `initial` and read back the
+/// final positions, e.g. to bake a scene offline or check a config in CI without a window
+pub fn run_headless(
+    config: SimulationConfig,
+    initial: &[ParticleInitData],
+    steps: u32,
+) -> Vec<Vec3> {
+    if initial.is_empty() {
+        return Vec::new();
+    }
+    let dt = config.max_time_step;
+    let mut simulation = HeadlessSimulation::new(config, initial);
+    for _ in 0..steps {
+        simulation.step(dt);
+    }
+    simulation.positions()
+}
+
+fn spawn_particles(
+    backend: &VulkanoHeadlessBackend,
+    particles_init_data: &[ParticleInitData],
+) -> Particles {
+    let mut particles = Particles::new(backend.memory_allocator());
+    if !particles_init_data.is_empty() {
+        particles.add_particles(particles_init_data, backend.memory_allocator(), backend);
+    }
+    particles
+}
+
+/// Symmetric adjacency list from strided neighbor lists, dropping self and out-of-range entries
+fn adjacency_list(counts: &[u32], contacts: &[u32]) -> Vec<Vec<u32>> {
+    let particle_count = counts.len();
+    let mut graph = vec![Vec::new(); particle_count];
+    for (i, &count) in counts.iter().enumerate() {
+        let base = i * CONTACTS_PER_PARTICLE as usize;
+        let count = count.min(CONTACTS_PER_PARTICLE) as usize;
+        for &j in &contacts[base..base + count] {
+            if j as usize != i && (j as usize) < particle_count {
+                graph[i].push(j);
+                graph[j as usize].push(i as u32);
+            }
+        }
+    }
+    for neighbors in &mut graph {
+        neighbors.sort_unstable();
+        neighbors.dedup();
+    }
+    graph
+}
+
+/// Ascending indices of `round(count * fraction)` particles picked by a seeded partial shuffle
+fn downsample_indices(count: usize, fraction: f32) -> Vec<usize> {
+    let kept = ((count as f32 * fraction).round() as usize).min(count);
+    let mut indices = (0..count).collect::<Vec<_>>();
+    let mut state = 0x9E37_79B9u32;
+    for i in 0..kept {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let j = i + state as usize % (count - i);
+        indices.swap(i, j);
+    }
+    indices.truncate(kept);
+    indices.sort_unstable();
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{scenes, Aabb};
+
+    #[test]
+    fn test_checkpoint_resume_matches_uninterrupted_run() {
+        let dt = 1.0 / 60.0;
+        let particle_data = ParticleInitData::block(UVec3::new(10, 4, 5), 0.05, Vec3::splat(-1.0))
+            .into_iter()
+            .map(|particle| ParticleInitData {
+                velocity: Vec3::new(0.0, 0.0, 0.1),
+                ..particle
+            })
+            .collect::<Vec<_>>();
+
+        let mut uninterrupted =
+            HeadlessSimulation::new(SimulationConfig::default(), &particle_data);
+        for _ in 0..5 {
+            uninterrupted.step(dt);
+        }
+
+        let dir = std::env::temp_dir().join(format!("aqua_gpu_checkpoint_{}", std::process::id()));
+        uninterrupted.save_checkpoint(&dir).unwrap();
+        uninterrupted.step(dt);
+
+        let mut resumed = HeadlessSimulation::load_checkpoint(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(resumed.particles().count(), particle_data.len() as u32);
+        assert_eq!(
+            resumed.config().sph_params.rest_density,
+            uninterrupted.config().sph_params.rest_density
+        );
+        resumed.step(dt);
+
+        let expected = uninterrupted
+            .positions()
+            .into_iter()
+            .zip(uninterrupted.velocities());
+        let actual = resumed.positions().into_iter().zip(resumed.velocities());
+        for (i, ((expected_p, expected_v), (actual_p, actual_v))) in
+            expected.zip(actual).enumerate()
+        {
+            assert!(
+                expected_p.distance(actual_p) < 1e-6 && expected_v.distance(actual_v) < 1e-6,
+                "particle {}: uninterrupted ({:?}, {:?}) resumed ({:?}, {:?})",
+                i,
+                expected_p,
+                expected_v,
+                actual_p,
+                actual_v
+            );
+        }
+    }
+
+    #[test]
+    fn test_resumed_settled_pool_does_not_pop() {
+        let dt = 1.0 / 60.0;
+        let config = SimulationConfig::default();
+        let rest_density = config.sph_params.rest_density;
+        let particle_data =
+            ParticleInitData::block(UVec3::new(10, 4, 10), 0.05, Vec3::new(-0.25, -1.9, -0.25));
+        let mean_density_error = |simulation: &HeadlessSimulation| {
+            let densities = simulation.particles().download_densities(
+                simulation.backend().memory_allocator(),
+                simulation.backend(),
+            );
+            densities
+                .iter()
+                .map(|d| (d / rest_density - 1.0).abs())
+                .sum::<f32>()
+                / densities.len() as f32
+        };
+
+        let mut settled = HeadlessSimulation::new(config, &particle_data);
+        for _ in 0..120 {
+            settled.step(dt);
+        }
+        let settled_error = mean_density_error(&settled);
+
+        let dir = std::env::temp_dir().join(format!(
+            "aqua_gpu_checkpoint_density_{}",
+            std::process::id()
+        ));
+        settled.save_checkpoint(&dir).unwrap();
+        let mut resumed = HeadlessSimulation::load_checkpoint(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The settled field is in place before any step runs
+        let expected = settled
+            .particles()
+            .download_densities(settled.backend().memory_allocator(), settled.backend());
+        let actual = resumed
+            .particles()
+            .download_densities(resumed.backend().memory_allocator(), resumed.backend());
+        assert_eq!(actual, expected);
+
+        resumed.step(dt);
+        let resumed_error = mean_density_error(&resumed);
+        assert!(
+            (resumed_error - settled_error).abs() < 0.05,
+            "settled error {} resumed error {}",
+            settled_error,
+            resumed_error
+        );
+    }
+
+    #[test]
+    fn test_substeps_calm_a_pool_at_large_time_steps() {
+        // Far above max_time_step: each step sinks the pool into itself by g * dt^2
+        let dt = 0.1;
+        let particle_data =
+            ParticleInitData::block(UVec3::new(10, 4, 10), 0.05, Vec3::new(-0.25, -1.9, -0.25));
+        let max_speed_after_steps = |substeps| {
+            let config = SimulationConfig {
+                substeps,
+                ..SimulationConfig::default()
+            };
+            let mut simulation = HeadlessSimulation::new(config, &particle_data);
+            for _ in 0..10 {
+                simulation.step(dt);
+            }
+            simulation
+                .velocities()
+                .iter()
+                .map(|v| v.length())
+                .fold(0.0, f32::max)
+        };
+
+        let single = max_speed_after_steps(1);
+        let substepped = max_speed_after_steps(4);
+        assert!(
+            substepped < single,
+            "one step {} vs four substeps {}",
+            single,
+            substepped
+        );
+    }
+
+    #[test]
+    fn test_downsampled_preview_keeps_mean_density() {
+        let config = SimulationConfig::default();
+        // A 4x4x4 block small enough that every pass sees all particles
+        let particle_data = ParticleInitData::block(UVec3::splat(4), 0.05, Vec3::ZERO);
+        let mean_density = |simulation: &mut HeadlessSimulation| {
+            simulation.step(1.0 / 60.0);
+            let densities = simulation.particles().download_densities(
+                simulation.backend().memory_allocator(),
+                simulation.backend(),
+            );
+            densities.iter().sum::<f32>() / densities.len() as f32
+        };
+
+        let mut full = HeadlessSimulation::new(config.clone(), &particle_data);
+        let full_density = mean_density(&mut full);
+
+        let mut preview = full.with_downsample(0.5);
+        assert_eq!(preview.particles().count(), 32);
+        assert_eq!(
+            preview.config().sph_params.particle_mass,
+            config.sph_params.particle_mass * 2.0
+        );
+        let preview_density = mean_density(&mut preview);
+        assert!(
+            (preview_density / full_density - 1.0).abs() < 0.25,
+            "preview {} full {}",
+            preview_density,
+            full_density
+        );
+
+        let restored = preview.with_downsample(1.0);
+        assert_eq!(restored.particles().count(), 64);
+        assert_eq!(
+            restored.config().sph_params.particle_mass,
+            config.sph_params.particle_mass
+        );
+    }
+
+    #[test]
+    fn test_downsample_indices_are_unique_and_sized() {
+        let indices = downsample_indices(1000, 0.25);
+        assert_eq!(indices.len(), 250);
+        assert!(indices.windows(2).all(|w| w[0] < w[1]));
+        assert!(indices.iter().all(|&i| i < 1000));
+        assert!(downsample_indices(10, 0.0).is_empty());
+        assert_eq!(downsample_indices(10, 1.0), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_checkpoint_rejects_other_versions() {
+        let dir = std::env::temp_dir().join(format!(
+            "aqua_gpu_checkpoint_version_{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let header = CheckpointHeader {
+            version: CHECKPOINT_VERSION + 1,
+            config: SimulationConfig::default(),
+            particle_count: 0,
+            pinned: Vec::new(),
+        };
+        fs::write(
+            dir.join(CHECKPOINT_HEADER),
+            serde_json::to_string(&header).unwrap(),
+        )
+        .unwrap();
+
+        let error = HeadlessSimulation::load_checkpoint(&dir)
+            .err()
+            .expect("a future checkpoint version must not load");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_single_particle_splats_kernel_blob_on_its_cell() {
+        let mut config = SimulationConfig::default();
+        config.sph_params.smoothing_radius = 0.6;
+        // Bounds of [-2, 2]³ at 16 cells per axis put cell (8, 8, 8) centered on 0.125
+        let mut simulation = HeadlessSimulation::new(
+            config,
+            &[ParticleInitData {
+                position: Vec3::splat(0.125),
+                velocity: Vec3::new(2.0, 0.0, 0.0),
+                ..Default::default()
+            }],
+        );
+
+        let grid = simulation.sample_field_to_grid(16, GridField::VelocityMagnitude);
+        assert_eq!(grid.dims, UVec3::splat(16));
+        assert_eq!(grid.values.len(), 16 * 16 * 16);
+
+        let peak = grid.value(8, 8, 8);
+        assert!((peak - 2.0).abs() < 1e-5, "{}", peak);
+        let max = grid.values.iter().copied().fold(0.0, f32::max);
+        assert_eq!(max, peak);
+
+        // Falls off symmetrically one cell away and vanishes beyond the smoothing radius
+        let expected = 2.0 * (1.0 - 0.25f32.powi(2) / 0.36).powi(3);
+        for neighbor in [
+            grid.value(7, 8, 8),
+            grid.value(9, 8, 8),
+            grid.value(8, 7, 8),
+            grid.value(8, 9, 8),
+            grid.value(8, 8, 7),
+            grid.value(8, 8, 9),
+        ] {
+            assert!((neighbor - expected).abs() < 1e-4, "{}", neighbor);
+        }
+        assert_eq!(grid.value(11, 8, 8), 0.0);
+        assert_eq!(grid.value(0, 0, 0), 0.0);
+    }
+
+    #[test]
+    fn test_grid_gathers_every_particle_in_reach() {
+        let mut config = SimulationConfig::default();
+        config.sph_params.smoothing_radius = 0.4;
+        let h_sq = 0.4f32 * 0.4;
+        // A scattered cloud over most of the bounds, with distinct speeds
+        let particle_data = (0..2000)
+            .map(|i| {
+                let t = i as f32;
+                ParticleInitData {
+                    position: Vec3::new(
+                        (t * 0.618).fract() * 3.6 - 1.8,
+                        (t * 0.414).fract() * 3.6 - 1.8,
+                        (t * 0.732).fract() * 3.6 - 1.8,
+                    ),
+                    velocity: Vec3::new((i % 7) as f32 * 0.5, 0.0, 0.0),
+                    ..Default::default()
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut simulation = HeadlessSimulation::new(config, &particle_data);
+
+        let resolution = 12;
+        let grid = simulation.sample_field_to_grid(resolution, GridField::VelocityMagnitude);
+        let cell_size = 4.0 / resolution as f32;
+        for z in 0..resolution {
+            for y in 0..resolution {
+                for x in 0..resolution {
+                    let center = Vec3::splat(-2.0)
+                        + (Vec3::new(x as f32, y as f32, z as f32) + 0.5) * cell_size;
+                    let expected = particle_data
+                        .iter()
+                        .map(|particle| {
+                            let r_sq = particle.position.distance_squared(center);
+                            let falloff = (1.0 - r_sq / h_sq).max(0.0);
+                            falloff.powi(3) * particle.velocity.length()
+                        })
+                        .sum::<f32>();
+                    let value = grid.value(x, y, z);
+                    assert!(
+                        (value - expected).abs() <= 1e-4 * expected.max(1.0),
+                        "cell ({}, {}, {}): {} != {}",
+                        x,
+                        y,
+                        z,
+                        value,
+                        expected
+                    );
+                }
+            }
+        }
+
+        let empty = simulation.sample_field_to_grid(0, GridField::Density);
+        assert_eq!(empty.dims, UVec3::ZERO);
+        assert!(empty.values.is_empty());
+    }
+
+    #[test]
+    fn test_momentum_changes_only_by_gravity() {
+        let dt = 1.0 / 60.0;
+        let config = SimulationConfig::default();
+        // A 4x4x4 block small enough that every pass sees all particles, so pairwise
+        // corrections are symmetric, and far enough from the walls to stay clear of them
+        let particle_data = ParticleInitData::block(UVec3::splat(4), 0.05, Vec3::ZERO)
+            .into_iter()
+            .map(|particle| ParticleInitData {
+                velocity: Vec3::new(0.2, 0.0, -0.1),
+                ..particle
+            })
+            .collect::<Vec<_>>();
+        let total_mass = config.sph_params.particle_mass * particle_data.len() as f32;
+        let mut simulation = HeadlessSimulation::new(config.clone(), &particle_data);
+
+        let expected_center =
+            particle_data.iter().map(|p| p.position).sum::<Vec3>() / particle_data.len() as f32;
+        assert!(simulation.center_of_mass().distance(expected_center) < 1e-5);
+
+        let impulse = config.gravity * total_mass * dt;
+        let mut momentum = simulation.total_momentum();
+        assert!(momentum.distance(Vec3::new(0.2, 0.0, -0.1) * total_mass) < 1e-5);
+        for step in 0..5 {
+            simulation.step(dt);
+            let next_momentum = simulation.total_momentum();
+            let internal = next_momentum - momentum - impulse;
+            assert!(
+                internal.length() < 0.05 * impulse.length(),
+                "step {}: momentum {:?} -> {:?}, gravity impulse {:?}",
+                step,
+                momentum,
+                next_momentum,
+                impulse
+            );
+            momentum = next_momentum;
+        }
+    }
+
+    #[test]
+    fn test_run_headless_dam_break_falls() {
+        let config = SimulationConfig::default();
+        // A water column against one wall of the default bounds, free to collapse
+        let initial = scenes::fill_predicate(
+            Aabb::new(Vec3::new(-1.9, -1.9, -0.5), Vec3::new(-1.4, -0.9, 0.5)),
+            0.05,
+            |_| true,
+        );
+        let mean_height = |positions: &[Vec3]| {
+            positions.iter().map(|p| p.y).sum::<f32>() / positions.len() as f32
+        };
+
+        let positions = run_headless(config, &initial, 20);
+
+        assert_eq!(positions.len(), initial.len());
+        let initial_positions = initial.iter().map(|p| p.position).collect::<Vec<_>>();
+        assert!(
+            mean_height(&positions) < mean_height(&initial_positions),
+            "mean height {} -> {}",
+            mean_height(&initial_positions),
+            mean_height(&positions)
+        );
+    }
+
+    #[test]
+    fn test_far_away_particle_is_counted_as_isolated() {
+        let config = SimulationConfig {
+            neighbor_list_enabled: true,
+            ..SimulationConfig::default()
+        };
+        // A 4x4x4 block missing one corner, plus a stray far outside the smoothing radius,
+        // 64 in total so the neighbor search sees every particle
+        let particle_data = ParticleInitData::block(UVec3::splat(4), 0.05, Vec3::ZERO)
+            .into_iter()
+            .skip(1)
+            .chain([ParticleInitData {
+                position: Vec3::splat(1.5),
+                ..Default::default()
+            }])
+            .collect::<Vec<_>>();
+
+        let mut without_lists =
+            HeadlessSimulation::new(SimulationConfig::default(), &particle_data);
+        without_lists.step(1.0 / 60.0);
+        assert_eq!(without_lists.isolated_count(), None);
+
+        let mut simulation = HeadlessSimulation::new(config, &particle_data);
+        simulation.step(1.0 / 60.0);
+        assert_eq!(simulation.isolated_count(), Some(1));
+    }
+
+    #[test]
+    fn test_time_averaged_density_matches_mean_of_frames() {
+        let dt = 1.0 / 60.0;
+        let config = SimulationConfig::default();
+        // A 4x4x4 pool on the floor of the simulation bounds
+        let floor = config.simulation_aabb.min().y;
+        let particle_data =
+            ParticleInitData::block(UVec3::splat(4), 0.05, Vec3::new(0.0, floor + 0.01, 0.0));
+        let mut simulation = HeadlessSimulation::new(config, &particle_data);
+        assert!(simulation.time_averaged_density().is_none());
+        // Let the pool settle before averaging
+        for _ in 0..30 {
+            simulation.step(dt);
+        }
+
+        simulation.begin_density_average();
+        assert!(simulation.time_averaged_density().is_none());
+        let frames = (0..10)
+            .map(|_| {
+                simulation.step(dt);
+                simulation.particles().download_densities(
+                    simulation.backend().memory_allocator(),
+                    simulation.backend(),
+                )
+            })
+            .collect::<Vec<_>>();
+        // The densities still change from frame to frame, so the average is not just the last
+        assert_ne!(frames[0], frames[frames.len() - 1]);
+
+        let averaged = simulation.time_averaged_density().unwrap();
+        assert_eq!(averaged.len(), particle_data.len());
+        for (i, &average) in averaged.iter().enumerate() {
+            let expected = frames.iter().map(|frame| frame[i]).sum::<f32>() / frames.len() as f32;
+            assert!(
+                (average - expected).abs() <= 1e-4 * expected.abs().max(1.0),
+                "particle {}: {} != {}",
+                i,
+                average,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_neighbor_graph_matches_cpu_radius_graph() {
+        let config = SimulationConfig {
+            neighbor_list_enabled: true,
+            gravity: Vec3::ZERO,
+            ..SimulationConfig::default()
+        };
+        let h = config.sph_params.smoothing_radius;
+        // A 4x4x2 grid, few enough that the search sees every particle; face and edge
+        // neighbors are within h, corner neighbors are not
+        let particle_data = ParticleInitData::block(UVec3::new(4, 2, 4), 0.6 * h, Vec3::ZERO);
+        let positions = particle_data
+            .iter()
+            .map(|particle| particle.position)
+            .collect::<Vec<_>>();
+
+        let mut simulation = HeadlessSimulation::new(config, &particle_data);
+        simulation.step(1.0 / 60.0);
+        let graph = simulation.neighbor_graph();
+
+        assert_eq!(graph.len(), positions.len());
+        for (i, neighbors) in graph.iter().enumerate() {
+            let expected = (0..positions.len() as u32)
+                .filter(|&j| j as usize != i && positions[j as usize].distance(positions[i]) < h)
+                .collect::<Vec<_>>();
+            assert_eq!(neighbors, &expected, "particle {}", i);
+        }
+    }
+
+    #[test]
+    fn test_adjacency_list_is_symmetric_without_self_loops() {
+        let stride = CONTACTS_PER_PARTICLE as usize;
+        let mut contacts = vec![u32::MAX; 3 * stride];
+        // 0 lists itself and 1; 1 lists nothing, as if its list were cut short; 2 lists 0
+        contacts[0] = 0;
+        contacts[1] = 1;
+        contacts[2 * stride] = 0;
+        let graph = adjacency_list(&[2, 0, 1], &contacts);
+        assert_eq!(graph, vec![vec![1, 2], vec![0], vec![0]]);
+    }
+}
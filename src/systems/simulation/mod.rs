@@ -1,7 +1,17 @@
+#[cfg(test)]
+mod cpu_reference;
+mod diagnostics;
+mod headless_simulation;
 mod simulation_config;
 mod simulation_system;
 mod simulation_tasks;
 mod tasks;
 
-pub(crate) use simulation_config::SimulationConfig;
-pub(crate) use simulation_system::SimulationSystem;
+pub use headless_simulation::{run_headless, FieldGrid, HeadlessSimulation};
+pub use simulation_config::{
+    ContactResetStrategy, DensityKernel, PbdSolveOrder, PipelineStages, SimulationConfig,
+    SimulationConfigBuilder, SortPositionMode, SphParams, ViscosityMode,
+};
+pub use simulation_system::{SimulationSnapshot, SimulationSystem};
+pub use simulation_tasks::SimulationStepTiming;
+pub use tasks::GridField;
@@ -1,42 +1,137 @@
+use std::{ops::BitOr, sync::Arc};
+
 use glam::Vec3;
+use serde::{Deserialize, Serialize};
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+
+use crate::{
+    core::{Aabb, Particles, Sdf},
+    utils::GpuTaskExecutor,
+};
+
+use super::tasks::ReduceAabbTask;
+
+/// Smallest smoothing radius the SPH kernels accept
+///
+/// The poly6 normalization divides by `h^9`, which underflows f32 not far below this and
+/// turns kernel factors infinite.
+pub(crate) const MIN_SMOOTHING_RADIUS: f32 = 1e-3;
+
+/// Raise `smoothing_radius` to [`MIN_SMOOTHING_RADIUS`] so kernel factors stay finite
+pub(crate) fn clamp_smoothing_radius(smoothing_radius: f32) -> f32 {
+    // `max` also replaces NaN
+    smoothing_radius.max(MIN_SMOOTHING_RADIUS)
+}
 
-use crate::core::Aabb;
+/// Morton coordinates wrap every this many cells per axis, unless normalized to the bounds
+const MORTON_GRID_CELLS: f32 = 1024.0;
 
-#[derive(Clone, Debug)]
-pub(crate) struct SimulationConfig {
+/// `grid_size` as a fraction of `smoothing_radius` unless set explicitly
+///
+/// Around 0.5-1.0 balances neighbor search accuracy and performance.
+const DEFAULT_GRID_SIZE_RATIO: f32 = 0.75;
+
+/// Missing fields take their [`Default`] values when deserialized
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimulationConfig {
     // Basic simulation parameters
     pub simulation_aabb: Aabb,
     pub gravity: Vec3,
+    /// Coulomb friction coefficient between particles and the floor of `simulation_aabb`
+    pub floor_friction: f32,
 
     // Time step limits (for numerical stability)
     pub max_time_step: f32,
     pub min_time_step: f32,
+    /// Also limit the step to `cfl_factor * smoothing_radius / max_speed`, so no particle
+    /// crosses more than that fraction of a smoothing radius per step; off when `None`, the
+    /// default, since it reads the maximum speed back from the GPU. 0.4 is a typical value.
+    pub cfl_factor: Option<f32>,
+    /// Read the maximum speed back every this many updates, reusing the last sample in
+    /// between, since the readback waits for the GPU
+    pub cfl_sample_interval: u32,
+    /// Physics iterations per update, each advancing `1 / substeps` of the update's time
+    /// step; more trade speed for stability at large steps
+    pub substeps: u32,
+    /// Step by exactly this much, as many times as the accumulated frame time allows, so
+    /// results do not depend on the frame rate; `None` steps once per frame by the frame time
+    pub fixed_dt: Option<f32>,
 
     // Spatial partitioning parameters
     pub grid_size: f32,
+    /// Positions used by Morton hashing and neighbor search
+    pub sort_position_mode: SortPositionMode,
+    /// Keep the last sort order for up to this many frames after a sort; 0 sorts every frame
+    pub sort_interval: u32,
+    /// Sort again early once any particle moved this fraction of `grid_size` since the
+    /// last sort
+    pub sort_displacement_threshold: f32,
 
     // SPH fluid simulation parameters
     pub sph_params: SphParams,
 
     // Performance optimization parameters
-    #[allow(dead_code)]
+    /// Maximum number of neighbors stored per particle in the contacts list
     pub max_neighbors: u32,
+
+    // Neighbor list parameters
+    /// Build explicit per-particle neighbor lists (`contacts`) each step
+    pub neighbor_list_enabled: bool,
+    /// How stale neighbor lists are handled after particles are spawned or despawned
+    pub contact_reset_strategy: ContactResetStrategy,
+    /// Reuse neighbor lists for up to this many frames after a search; 0 searches every frame
+    pub neighbor_rebuild_interval: u32,
+    /// Search again early once the mean displacement since the last search exceeds this
+    /// fraction of `smoothing_radius`
+    pub neighbor_rebuild_threshold: f32,
+    /// While reusing neighbor lists or a sort order, read the displacement since the last
+    /// search or sort back only every this many frames, keeping the lists or order in
+    /// between, since the readback waits for the GPU
+    pub displacement_sample_interval: u32,
+
+    // Particle budget parameters
+    /// Merge particles closer than this into one before a step, conserving mass; off when `None`
+    pub merge_distance: Option<f32>,
+    /// Merge every this many steps, since each merge hashes, sorts and reads the surviving
+    /// count back on its own
+    pub merge_interval: u32,
+
+    // Boundary parameters
+    /// Give pinned particles the velocity of their host-driven motion, for animated walls
+    pub boundary_velocity_enabled: bool,
+    /// Static obstacle that particles are pushed out of after each position update
+    pub sdf_obstacle: Option<Sdf>,
+
+    // Debugging parameters
+    /// Stages each step runs; the rest are skipped, for isolating the source of an artifact
+    pub pipeline_stages: PipelineStages,
+
+    // Export parameters
+    /// Write the neighbor-averaged velocity to `smoothed_velocity` at the end of each step
+    pub smoothed_velocity_enabled: bool,
+    /// Write outward surface normals to `normal` at the end of each step, for lit rendering
+    pub surface_normals_enabled: bool,
 }
 
-#[derive(Clone, Debug)]
-pub(crate) struct SphParams {
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SphParams {
     /// Particle mass (kg)
     pub particle_mass: f32,
     /// Kernel smoothing radius (m)
     pub smoothing_radius: f32,
+    /// Kernel used by the density pass
+    pub density_kernel: DensityKernel,
     /// Rest density (kg/m³)
-    #[allow(dead_code)]
     pub rest_density: f32,
-    /// Viscosity coefficient
-    #[allow(dead_code)]
+    /// Viscosity coefficient; with [`ViscosityMode::Xsph`] the blend fraction in [0, 1]
     pub viscosity: f32,
+    /// How viscosity is applied to the velocity field
+    pub viscosity_mode: ViscosityMode,
+    /// Strength of the vorticity confinement force; 0 skips the pass
+    pub vorticity_epsilon: f32,
     /// Surface tension coefficient
-    #[allow(dead_code)]
     pub surface_tension: f32,
 
     // PBD specific parameters
@@ -46,6 +141,137 @@ pub(crate) struct SphParams {
     pub pbd_constraint_epsilon: f32,
     /// Relaxation factor for PBD position correction (typically between 0.1 and 1.0)
     pub pbd_relaxation_factor: f32,
+    /// Correct PBD kernel gradients with a per-particle CSPM matrix, at the cost of an extra pass
+    pub gradient_correction: bool,
+    /// Whether PBD iterations solve every particle at once or in batches of cells
+    pub pbd_solve_order: PbdSolveOrder,
+    /// Largest |λ| a single PBD constraint solve may use, unlimited when `None`
+    pub pbd_max_lambda: Option<f32>,
+    /// Largest position correction of a single PBD constraint solve, as a fraction of
+    /// `smoothing_radius`
+    pub pbd_max_displacement: f32,
+    /// Strength k of the tensile instability correction s_corr = -k (W(r) / W(Δq))^n,
+    /// which keeps particles from clumping at low density. Disabled at 0
+    pub pbd_s_corr_k: f32,
+    /// Exponent n of the tensile instability correction
+    pub pbd_s_corr_n: u32,
+    /// Reference distance Δq of the tensile instability correction, as a fraction of
+    /// `smoothing_radius`
+    pub pbd_s_corr_delta_q: f32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ViscosityMode {
+    /// No viscosity pass
+    #[default]
+    Disabled,
+    /// One Jacobi step of an implicit solve on predicted_velocity, before update_position
+    Implicit,
+    /// Blend toward the neighbor-averaged velocity by `viscosity`, over the neighbor lists
+    Xsph,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DensityKernel {
+    /// 315 / (64πh⁹) · (h² - r²)³
+    #[default]
+    Poly6,
+    /// 15 / (πh⁶) · (h - r)³, which does not flatten out for close neighbors
+    Spiky,
+}
+
+impl DensityKernel {
+    /// Normalization factor for smoothing radius `h`
+    pub fn factor(self, h: f32) -> f32 {
+        match self {
+            DensityKernel::Poly6 => 315.0 / (64.0 * std::f32::consts::PI * h.powi(9)),
+            DensityKernel::Spiky => 15.0 / (std::f32::consts::PI * h.powi(6)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PbdSolveOrder {
+    /// Every particle corrects against the densities from the start of the step
+    #[default]
+    Jacobi,
+    /// Cells of size `smoothing_radius` are split into 8 colors by coordinate parity and solved
+    /// one color after another, each re-evaluating density at the latest predicted positions
+    Colored,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SortPositionMode {
+    /// Hash and search with positions as they are, even outside `simulation_aabb`
+    #[default]
+    Free,
+    /// Clamp positions to `simulation_aabb` first, so strays land in a boundary cell
+    Clamped,
+}
+
+impl SortPositionMode {
+    /// The position hashing and neighbor search see; both shaders apply the same rule
+    pub fn apply(self, position: Vec3, aabb: Aabb) -> Vec3 {
+        match self {
+            SortPositionMode::Free => position,
+            SortPositionMode::Clamped => position.clamp(aabb.min(), aabb.max()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContactResetStrategy {
+    /// Rerun hashing, sorting and neighbor search before contacts are read again
+    #[default]
+    Rebuild,
+    /// Zero `contact_counts` so every neighbor list reads as empty until the next search
+    ZeroCounts,
+}
+
+/// Set of simulation step stages, combined with `|`
+///
+/// Skipped stages leave their buffers as the previous stage or step left them, so later
+/// stages still run, just on unprocessed input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PipelineStages(u32);
+
+impl PipelineStages {
+    /// Gravity, and boundary velocities which replace it for pinned particles
+    pub const GRAVITY: Self = Self(1 << 0);
+    /// Copying positions to `predicted_position` for the solver
+    pub const PREDICTION: Self = Self(1 << 1);
+    /// Morton hashing and the radix sort
+    pub const SORTING: Self = Self(1 << 2);
+    pub const NEIGHBOR_SEARCH: Self = Self(1 << 3);
+    /// The SPH density pass
+    pub const SPH: Self = Self(1 << 4);
+    /// Gradient correction and the PBD density constraint iterations
+    pub const PBD: Self = Self(1 << 5);
+    pub const POSITION_UPDATE: Self = Self(1 << 6);
+    pub const ALL: Self = Self((1 << 7) - 1);
+    pub const NONE: Self = Self(0);
+
+    pub fn contains(self, stages: Self) -> bool {
+        self.0 & stages.0 == stages.0
+    }
+
+    pub fn without(self, stages: Self) -> Self {
+        Self(self.0 & !stages.0)
+    }
+}
+
+impl Default for PipelineStages {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl BitOr for PipelineStages {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
 }
 
 impl Default for SimulationConfig {
@@ -55,16 +281,40 @@ impl Default for SimulationConfig {
         Self {
             simulation_aabb: Aabb::new(Vec3::new(-2.0, -2.0, -2.0), Vec3::new(2.0, 2.0, 2.0)),
             gravity: Vec3::new(0.0, -9.81, 0.0),
+            floor_friction: 0.0,
 
             // Time step limits - ensure numerical stability
             max_time_step: 1.0 / 30.0, // Maximum 33ms, prevent large time jumps
             min_time_step: 1.0 / 240.0, // Minimum 4ms, prevent too small time steps
+            cfl_factor: None,
+            cfl_sample_interval: 8,
+            substeps: 1,
+            fixed_dt: None,
 
-            // grid_size should be around 0.5-1.0 times smoothing_radius for balance between accuracy and performance
-            grid_size: sph_params.smoothing_radius * 0.75,
+            grid_size: sph_params.smoothing_radius * DEFAULT_GRID_SIZE_RATIO,
+            sort_position_mode: SortPositionMode::Free,
+            sort_interval: 0,
+            sort_displacement_threshold: 0.5,
 
             sph_params,
             max_neighbors: 32,
+
+            neighbor_list_enabled: false,
+            contact_reset_strategy: ContactResetStrategy::Rebuild,
+            neighbor_rebuild_interval: 0,
+            neighbor_rebuild_threshold: 0.1,
+            displacement_sample_interval: 4,
+
+            merge_distance: None,
+            merge_interval: 16,
+
+            boundary_velocity_enabled: false,
+            sdf_obstacle: None,
+
+            pipeline_stages: PipelineStages::ALL,
+
+            smoothed_velocity_enabled: false,
+            surface_normals_enabled: false,
         }
     }
 }
@@ -72,23 +322,39 @@ impl Default for SimulationConfig {
 impl Default for SphParams {
     fn default() -> Self {
         Self {
-            particle_mass: 0.02,    // 20g per particle - suitable for fluid simulation
+            particle_mass: 0.02, // 20g per particle - suitable for fluid simulation
             smoothing_radius: 0.15,
-            rest_density: 1000.0,   // Water density 1000 kg/m³
-            viscosity: 0.001,       // Water viscosity
+            density_kernel: DensityKernel::Poly6,
+            rest_density: 1000.0, // Water density 1000 kg/m³
+            viscosity: 0.001,     // Water viscosity
+            viscosity_mode: ViscosityMode::Disabled,
+            vorticity_epsilon: 0.0,
             surface_tension: 0.073, // Water surface tension
 
             // Performance optimized PBD parameters
             pbd_iterations: 1, // Single iteration for maximum performance
             pbd_constraint_epsilon: 1e-4, // Slightly relaxed for early exit
             pbd_relaxation_factor: 0.5, // Higher relaxation for faster convergence in single iteration
+            gradient_correction: false,
+            pbd_solve_order: PbdSolveOrder::Jacobi,
+            pbd_max_lambda: None,
+            pbd_max_displacement: 0.1,
+            pbd_s_corr_k: 0.0,
+            pbd_s_corr_n: 4,
+            pbd_s_corr_delta_q: 0.2,
         }
     }
 }
 
 impl SimulationConfig {
+    /// Whether neighbor lists are built, either on request or for a pass that reads them
+    pub fn uses_neighbor_lists(&self) -> bool {
+        self.neighbor_list_enabled
+            || self.sph_params.viscosity_mode == ViscosityMode::Xsph
+            || self.sph_params.vorticity_epsilon > 0.0
+    }
+
     /// Create high performance configuration (fewer particles, high framerate)
-    #[allow(dead_code)]
     pub fn high_performance() -> Self {
         Self {
             max_time_step: 1.0 / 60.0,  // Allow higher framerate
@@ -104,7 +370,6 @@ impl SimulationConfig {
     }
 
     /// Create high quality configuration (more particles, lower framerate)
-    #[allow(dead_code)]
     pub fn high_quality() -> Self {
         Self {
             max_time_step: 1.0 / 20.0, // Allow lower framerate but maintain stability
@@ -120,7 +385,6 @@ impl SimulationConfig {
     }
 
     /// Create large scale simulation configuration (million particle level)
-    #[allow(dead_code)]
     pub fn large_scale() -> Self {
         Self {
             simulation_aabb: Aabb::new(Vec3::new(-5.0, -5.0, -5.0), Vec3::new(5.0, 5.0, 5.0)),
@@ -136,20 +400,62 @@ impl SimulationConfig {
         }
     }
 
+    /// Builder starting from the default configuration
+    pub fn builder() -> SimulationConfigBuilder {
+        SimulationConfigBuilder::default()
+    }
+
+    /// Create fountain configuration (upward jet in a tall, narrow domain)
+    pub fn fountain_spray() -> Self {
+        Self {
+            simulation_aabb: Aabb::new(Vec3::new(-1.5, 0.0, -1.5), Vec3::new(1.5, 6.0, 1.5)),
+            max_time_step: 1.0 / 60.0, // Fast jet particles need short steps
+            min_time_step: 1.0 / 300.0,
+            sph_params: SphParams {
+                smoothing_radius: 0.1, // Thin jet needs a finer kernel
+                pbd_iterations: 3,
+                ..SphParams::default()
+            },
+            grid_size: 0.1 * 0.75,
+            max_neighbors: 64,
+            ..Self::default()
+        }
+    }
+
     /// Clamp time step within reasonable range
     pub fn clamp_time_step(&self, dt: f32) -> f32 {
         dt.clamp(self.min_time_step, self.max_time_step)
     }
 
+    /// Shorten `dt` so a particle at `max_speed` moves at most `cfl_factor` smoothing radii
+    ///
+    /// May go below `min_time_step`, which only bounds the frame time.
+    pub fn cfl_time_step(&self, dt: f32, max_speed: f32) -> f32 {
+        match self.cfl_factor {
+            Some(cfl_factor) if max_speed > 0.0 => {
+                dt.min(cfl_factor * self.sph_params.smoothing_radius / max_speed)
+            }
+            _ => dt,
+        }
+    }
+
+    /// Time step of each of the `substeps` iterations that advance an update by `dt`
+    pub fn substep_time_step(&self, dt: f32) -> f32 {
+        dt / self.substeps.max(1) as f32
+    }
+
     /// Validate configuration parameter reasonableness
-    #[allow(dead_code)]
     pub fn validate(&self) -> Result<(), String> {
         if self.grid_size <= 0.0 {
             return Err("grid_size must be greater than 0".to_string());
         }
 
-        if self.sph_params.smoothing_radius <= 0.0 {
-            return Err("smoothing_radius must be greater than 0".to_string());
+        let smoothing_radius = self.sph_params.smoothing_radius;
+        if smoothing_radius.is_nan() || smoothing_radius < MIN_SMOOTHING_RADIUS {
+            return Err(format!(
+                "smoothing_radius ({}) must be at least {} to keep kernel factors finite",
+                smoothing_radius, MIN_SMOOTHING_RADIUS
+            ));
         }
 
         if self.grid_size > self.sph_params.smoothing_radius {
@@ -159,6 +465,27 @@ impl SimulationConfig {
             ));
         }
 
+        if self.sort_displacement_threshold.is_nan() || self.sort_displacement_threshold < 0.0 {
+            return Err("sort_displacement_threshold must not be negative".to_string());
+        }
+
+        let pbd_max_displacement = self.sph_params.pbd_max_displacement;
+        if pbd_max_displacement.is_nan() || pbd_max_displacement <= 0.0 {
+            return Err("pbd_max_displacement must be greater than 0".to_string());
+        }
+
+        if self
+            .sph_params
+            .pbd_max_lambda
+            .is_some_and(|max_lambda| max_lambda.is_nan() || max_lambda <= 0.0)
+        {
+            return Err("pbd_max_lambda must be greater than 0".to_string());
+        }
+
+        if !(0.0..1.0).contains(&self.sph_params.pbd_s_corr_delta_q) {
+            return Err("pbd_s_corr_delta_q must be in [0, 1)".to_string());
+        }
+
         if self.min_time_step <= 0.0 || self.max_time_step <= 0.0 {
             return Err("Time step limits must be greater than 0".to_string());
         }
@@ -167,11 +494,122 @@ impl SimulationConfig {
             return Err("min_time_step must be less than max_time_step".to_string());
         }
 
+        if self.cfl_factor.is_some_and(|cfl_factor| cfl_factor <= 0.0) {
+            return Err("cfl_factor must be greater than 0".to_string());
+        }
+
+        if self.cfl_sample_interval == 0 {
+            return Err("cfl_sample_interval must be at least 1".to_string());
+        }
+
+        if self.displacement_sample_interval == 0 {
+            return Err("displacement_sample_interval must be at least 1".to_string());
+        }
+
+        if self.merge_interval == 0 {
+            return Err("merge_interval must be at least 1".to_string());
+        }
+
+        if self.substeps == 0 {
+            return Err("substeps must be at least 1".to_string());
+        }
+
+        if self
+            .fixed_dt
+            .is_some_and(|fixed_dt| fixed_dt.is_nan() || fixed_dt <= 0.0)
+        {
+            return Err("fixed_dt must be greater than 0".to_string());
+        }
+
         Ok(())
     }
 
+    /// Grow `simulation_aabb` to hold every particle plus `padding`, e.g. after spawning
+    /// outside the default bounds
+    ///
+    /// The particle bounds are reduced on the GPU and read back. `grid_size` grows too if the
+    /// fitted box spans more cells than Morton coordinates hold, up to `smoothing_radius`.
+    pub fn autofit_aabb(
+        &mut self,
+        particles: &mut Particles,
+        padding: f32,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        task_executor: &impl GpuTaskExecutor,
+    ) {
+        if particles.count() == 0 {
+            return;
+        }
+        particles.enable_bounds();
+
+        let mut task = ReduceAabbTask::new(particles.memory_allocator());
+        task.set_buffers(
+            descriptor_set_allocator,
+            particles.count(),
+            particles.position(),
+            particles.bounds(),
+        );
+        task_executor.execute(&mut task);
+        let bounds = particles
+            .download_bounds(particles.memory_allocator(), task_executor)
+            .unwrap();
+
+        let padding = Vec3::splat(padding.max(0.0));
+        self.simulation_aabb = Aabb::new(
+            self.simulation_aabb.min().min(bounds.min() - padding),
+            self.simulation_aabb.max().max(bounds.max() + padding),
+        );
+
+        let extent = self.simulation_aabb.max() - self.simulation_aabb.min();
+        let min_grid_size = extent.max_element() / MORTON_GRID_CELLS;
+        if self.grid_size < min_grid_size {
+            self.grid_size = min_grid_size.min(self.sph_params.smoothing_radius);
+        }
+    }
+
+    /// Pretty-printed JSON, e.g. to save a tuning preset
+    ///
+    /// Kernel factors are not part of the configuration; they are derived from the
+    /// smoothing radius whenever constants are set.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("SimulationConfig serializes to JSON")
+    }
+
+    /// Load a configuration saved with [`SimulationConfig::to_json`], if it parses and
+    /// passes [`SimulationConfig::validate`]
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let config: Self = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load a hand-edited TOML file, e.g. to tune parameters without recompiling
+    ///
+    /// Keys map to the config fields, with `[sph_params]` and `[simulation_aabb]` tables.
+    /// Missing keys take their defaults, except that a missing `grid_size` follows
+    /// `smoothing_radius` as in [`SimulationConfigBuilder::build`]. The result must pass
+    /// [`SimulationConfig::validate`].
+    #[cfg(feature = "toml")]
+    pub fn from_toml_path(path: impl AsRef<std::path::Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| Self::from_toml_str(&source))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// [`SimulationConfig::from_toml_path`] on the file contents
+    #[cfg(feature = "toml")]
+    pub fn from_toml_str(source: &str) -> Result<Self, String> {
+        let table: toml::Table = toml::from_str(source).map_err(|e| e.to_string())?;
+        let mut config: Self = toml::from_str(source).map_err(|e| e.to_string())?;
+        if !table.contains_key("grid_size") {
+            config.grid_size = config.sph_params.smoothing_radius * DEFAULT_GRID_SIZE_RATIO;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
     /// Print configuration information
-    #[allow(dead_code)]
     pub fn print_info(&self) {
         println!("=== Simulation Configuration ===");
         println!("Simulation space: {:?}", self.simulation_aabb);
@@ -195,6 +633,122 @@ impl SimulationConfig {
     }
 }
 
+/// Chainable construction of a validated [`SimulationConfig`]
+///
+/// `grid_size` follows `smoothing_radius` on [`SimulationConfigBuilder::build`] unless set
+/// with [`SimulationConfigBuilder::grid_size`]; kernel factors are always derived from the
+/// smoothing radius when constants are set.
+#[derive(Clone, Debug, Default)]
+pub struct SimulationConfigBuilder {
+    config: SimulationConfig,
+    grid_size: Option<f32>,
+}
+
+impl SimulationConfigBuilder {
+    pub fn aabb(mut self, aabb: Aabb) -> Self {
+        self.config.simulation_aabb = aabb;
+        self
+    }
+
+    pub fn gravity(mut self, gravity: Vec3) -> Self {
+        self.config.gravity = gravity;
+        self
+    }
+
+    pub fn time_step_limits(mut self, min_time_step: f32, max_time_step: f32) -> Self {
+        self.config.min_time_step = min_time_step;
+        self.config.max_time_step = max_time_step;
+        self
+    }
+
+    pub fn cfl_factor(mut self, cfl_factor: Option<f32>) -> Self {
+        self.config.cfl_factor = cfl_factor;
+        self
+    }
+
+    pub fn substeps(mut self, substeps: u32) -> Self {
+        self.config.substeps = substeps;
+        self
+    }
+
+    pub fn fixed_dt(mut self, fixed_dt: Option<f32>) -> Self {
+        self.config.fixed_dt = fixed_dt;
+        self
+    }
+
+    /// Fix `grid_size` instead of deriving it from the smoothing radius
+    pub fn grid_size(mut self, grid_size: f32) -> Self {
+        self.grid_size = Some(grid_size);
+        self
+    }
+
+    pub fn smoothing_radius(mut self, smoothing_radius: f32) -> Self {
+        self.config.sph_params.smoothing_radius = smoothing_radius;
+        self
+    }
+
+    pub fn particle_mass(mut self, particle_mass: f32) -> Self {
+        self.config.sph_params.particle_mass = particle_mass;
+        self
+    }
+
+    pub fn rest_density(mut self, rest_density: f32) -> Self {
+        self.config.sph_params.rest_density = rest_density;
+        self
+    }
+
+    pub fn density_kernel(mut self, density_kernel: DensityKernel) -> Self {
+        self.config.sph_params.density_kernel = density_kernel;
+        self
+    }
+
+    pub fn viscosity(mut self, viscosity: f32, viscosity_mode: ViscosityMode) -> Self {
+        self.config.sph_params.viscosity = viscosity;
+        self.config.sph_params.viscosity_mode = viscosity_mode;
+        self
+    }
+
+    pub fn pbd_iterations(mut self, pbd_iterations: u32) -> Self {
+        self.config.sph_params.pbd_iterations = pbd_iterations;
+        self
+    }
+
+    pub fn max_neighbors(mut self, max_neighbors: u32) -> Self {
+        self.config.max_neighbors = max_neighbors;
+        self
+    }
+
+    pub fn merge_distance(mut self, merge_distance: Option<f32>) -> Self {
+        self.config.merge_distance = merge_distance;
+        self
+    }
+
+    pub fn merge_interval(mut self, merge_interval: u32) -> Self {
+        self.config.merge_interval = merge_interval;
+        self
+    }
+
+    pub fn sdf_obstacle(mut self, sdf_obstacle: Option<Sdf>) -> Self {
+        self.config.sdf_obstacle = sdf_obstacle;
+        self
+    }
+
+    pub fn pipeline_stages(mut self, pipeline_stages: PipelineStages) -> Self {
+        self.config.pipeline_stages = pipeline_stages;
+        self
+    }
+
+    /// The configuration, if it passes [`SimulationConfig::validate`]
+    pub fn build(self) -> Result<SimulationConfig, String> {
+        let mut config = self.config;
+        config.grid_size = self
+            .grid_size
+            .unwrap_or(config.sph_params.smoothing_radius * DEFAULT_GRID_SIZE_RATIO);
+        config.validate()?;
+        Ok(config)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -216,12 +770,33 @@ mod tests {
         assert!(config.min_time_step < config.max_time_step);
     }
 
+    #[test]
+    fn test_validation_rejects_non_positive_pbd_clamps() {
+        for max_lambda in [0.0, -1.0, f32::NAN] {
+            let mut config = SimulationConfig::default();
+            config.sph_params.pbd_max_lambda = Some(max_lambda);
+            let error = config.validate().unwrap_err();
+            assert!(error.contains("pbd_max_lambda"), "{}", error);
+        }
+        for max_displacement in [0.0, -0.1, f32::NAN] {
+            let mut config = SimulationConfig::default();
+            config.sph_params.pbd_max_displacement = max_displacement;
+            let error = config.validate().unwrap_err();
+            assert!(error.contains("pbd_max_displacement"), "{}", error);
+        }
+
+        let mut config = SimulationConfig::default();
+        config.sph_params.pbd_max_lambda = Some(50.0);
+        assert!(config.validate().is_ok());
+    }
+
     #[test]
     fn test_preset_configs_validation() {
         let configs = vec![
             ("high_performance", SimulationConfig::high_performance()),
             ("high_quality", SimulationConfig::high_quality()),
             ("large_scale", SimulationConfig::large_scale()),
+            ("fountain_spray", SimulationConfig::fountain_spray()),
         ];
 
         for (name, config) in configs {
@@ -234,7 +809,7 @@ mod tests {
             // Verify grid_size to smoothing_radius relationship
             let ratio = config.grid_size / config.sph_params.smoothing_radius;
             assert!(
-                ratio >= 0.5 && ratio <= 1.0,
+                (0.5..=1.0).contains(&ratio),
                 "{} config grid size/kernel radius ratio ({:.2}) should be in [0.5, 1.0] range",
                 name,
                 ratio
@@ -270,12 +845,125 @@ mod tests {
         // This ratio should be in reasonable range to balance accuracy and performance
         let ratio = config.grid_size / config.sph_params.smoothing_radius;
         assert!(
-            ratio >= 0.6 && ratio <= 0.9,
+            (0.6..=0.9).contains(&ratio),
             "Grid size should be about 60%-90% of kernel radius, actual ratio: {:.2}",
             ratio
         );
     }
 
+    #[test]
+    fn test_builder_derives_grid_size_from_smoothing_radius() {
+        let config = SimulationConfig::builder()
+            .gravity(Vec3::ZERO)
+            .smoothing_radius(0.2)
+            .rest_density(500.0)
+            .pbd_iterations(4)
+            .aabb(Aabb::new(Vec3::ZERO, Vec3::ONE))
+            .build()
+            .unwrap();
+
+        assert_eq!(config.gravity, Vec3::ZERO);
+        assert_eq!(config.sph_params.smoothing_radius, 0.2);
+        assert_eq!(config.sph_params.rest_density, 500.0);
+        assert_eq!(config.sph_params.pbd_iterations, 4);
+        assert_eq!(config.simulation_aabb, Aabb::new(Vec3::ZERO, Vec3::ONE));
+        assert_eq!(config.grid_size, 0.2 * DEFAULT_GRID_SIZE_RATIO);
+    }
+
+    #[test]
+    fn test_builder_rejects_grid_size_above_smoothing_radius() {
+        let result = SimulationConfig::builder()
+            .smoothing_radius(0.1)
+            .grid_size(0.2)
+            .build();
+
+        let error = result.unwrap_err();
+        assert!(error.contains("grid_size"), "{}", error);
+    }
+
+    #[test]
+    fn test_substeps_split_the_time_step() {
+        let config = SimulationConfig::builder().substeps(4).build().unwrap();
+        assert_eq!(config.substep_time_step(0.1), 0.025);
+
+        let error = SimulationConfig::builder().substeps(0).build().unwrap_err();
+        assert!(error.contains("substeps"), "{}", error);
+    }
+
+    #[test]
+    fn test_json_round_trip_keeps_preset() {
+        let config = SimulationConfig::high_quality();
+        let loaded = SimulationConfig::from_json(&config.to_json()).unwrap();
+        assert_eq!(loaded, config);
+        assert!(loaded.validate().is_ok());
+
+        // Invalid presets are rejected on load
+        let invalid = SimulationConfig {
+            grid_size: 1.0,
+            ..config
+        };
+        assert!(SimulationConfig::from_json(&invalid.to_json()).is_err());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_toml_file_overrides_defaults() {
+        let path =
+            std::env::temp_dir().join(format!("aqua_gpu_config_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+gravity = [0.0, -5.0, 0.0]
+max_time_step = 0.02
+cfl_factor = 0.3
+merge_distance = 0.001
+
+[simulation_aabb]
+min = [-1.0, 0.0, -1.0]
+max = [1.0, 2.0, 1.0]
+
+[sph_params]
+smoothing_radius = 0.1
+density_kernel = "Spiky"
+viscosity_mode = "Xsph"
+pbd_iterations = 3
+"#,
+        )
+        .unwrap();
+        let config = SimulationConfig::from_toml_path(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let default = SimulationConfig::default();
+        let expected = SimulationConfig {
+            simulation_aabb: Aabb::new(Vec3::new(-1.0, 0.0, -1.0), Vec3::new(1.0, 2.0, 1.0)),
+            gravity: Vec3::new(0.0, -5.0, 0.0),
+            max_time_step: 0.02,
+            cfl_factor: Some(0.3),
+            merge_distance: Some(0.001),
+            grid_size: 0.1 * DEFAULT_GRID_SIZE_RATIO,
+            sph_params: SphParams {
+                smoothing_radius: 0.1,
+                density_kernel: DensityKernel::Spiky,
+                viscosity_mode: ViscosityMode::Xsph,
+                pbd_iterations: 3,
+                ..default.sph_params.clone()
+            },
+            ..default
+        };
+        assert_eq!(config.unwrap(), expected);
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_toml_out_of_range_value_is_reported() {
+        let error = SimulationConfig::from_toml_str("[sph_params]\npbd_s_corr_delta_q = 1.5\n")
+            .unwrap_err();
+        assert!(error.contains("pbd_s_corr_delta_q"), "{}", error);
+
+        let error = SimulationConfig::from_toml_str("max_time_step = \"fast\"\n").unwrap_err();
+        assert!(error.contains("max_time_step"), "{}", error);
+    }
+
     #[test]
     fn test_time_step_clamping() {
         let config = SimulationConfig::default();
@@ -344,4 +1032,42 @@ mod tests {
             assert!(clamped_dt <= config.max_time_step);
         }
     }
+
+    #[test]
+    fn test_autofit_aabb_contains_far_particles() {
+        use crate::{core::ParticleInitData, utils::VulkanoHeadlessBackend};
+
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        let positions = [
+            Vec3::new(5.0, 5.0, 5.0),
+            Vec3::new(5.5, 4.5, 5.25),
+            Vec3::ZERO,
+        ];
+        let particle_data = positions
+            .iter()
+            .map(|&position| ParticleInitData {
+                position,
+                velocity: Vec3::ZERO,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+        let mut config = SimulationConfig::default();
+        config.autofit_aabb(
+            &mut particles,
+            0.1,
+            backend.descriptor_set_allocator(),
+            &backend,
+        );
+
+        // The box only grows, so the default corner stays put
+        assert_eq!(config.simulation_aabb.min(), Vec3::splat(-2.0));
+        assert_eq!(config.simulation_aabb.max(), Vec3::new(5.6, 5.1, 5.35));
+        for position in positions {
+            assert!(config.simulation_aabb.contains(position), "{}", position);
+        }
+        assert!(config.validate().is_ok());
+    }
 }
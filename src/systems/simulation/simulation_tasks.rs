@@ -1,22 +1,35 @@
-use std::sync::Arc;
-#[cfg(test)]
-use std::time::{Duration, Instant};
+use std::{
+    ops::AddAssign,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use glam::Mat4;
 use vulkano::{descriptor_set::allocator::StandardDescriptorSetAllocator, device::Device};
 
-use crate::{core::Particles, utils::GpuTaskExecutor};
+use crate::{
+    core::{Particles, Sdf},
+    utils::{GpuTask, GpuTaskExecutor, GpuTimer, TaskBatch},
+};
 
 use super::{
-    simulation_config::SimulationConfig,
+    simulation_config::{ContactResetStrategy, PipelineStages, SimulationConfig, ViscosityMode},
     tasks::{
-        ApplyGravityConstants, ApplyGravityTask, MortonHashConstants, MortonHashTask,
-        PbdDensityConstraintConstants, PbdDensityConstraintTask, RadixSortSystem,
-        SpikySphConstants, SpikySphTask, UpdatePositionConstants, UpdatePositionTask,
+        AdaptiveSortSystem, ApplyGravityConstants, ApplyGravityTask, BoundaryVelocityConstants,
+        BoundaryVelocityTask, CompactTask, GradientCorrectionConstants, GradientCorrectionTask,
+        ImplicitViscosityConstants, ImplicitViscosityTask, MeanDisplacementConstants,
+        MeanDisplacementTask, MergeDuplicatesConstants, MergeDuplicatesTask, MergeGatherConstants,
+        MergeGatherTask, MortonHashConstants, MortonHashTask, NeighborSearchConstants,
+        NeighborSearchTask, PbdDensityConstraintConstants, PbdDensityConstraintTask,
+        ReduceMaxSpeedTask, RemoveParticlesConstants, RemoveParticlesTask, SdfCollisionConstants,
+        SdfCollisionTask, SmoothedVelocityConstants, SmoothedVelocityTask, SpikySphConstants,
+        SpikySphTask, SurfaceNormalConstants, SurfaceNormalTask, UpdatePositionConstants,
+        UpdatePositionTask, VorticityConfinementConstants, VorticityConfinementTask, VorticityPass,
+        XsphViscosityConstants, XsphViscosityTask,
     },
 };
 
-#[derive(Debug, Clone)]
-#[cfg(test)]
+#[derive(Debug, Clone, Default)]
 pub struct SimulationStepTiming {
     pub morton_hash_time: Duration,
     pub radix_sort_time: Duration,
@@ -27,7 +40,19 @@ pub struct SimulationStepTiming {
     pub total_time: Duration,
 }
 
-#[cfg(test)]
+impl AddAssign for SimulationStepTiming {
+    /// Sum stage times, e.g. over the substeps of one update
+    fn add_assign(&mut self, rhs: Self) {
+        self.morton_hash_time += rhs.morton_hash_time;
+        self.radix_sort_time += rhs.radix_sort_time;
+        self.sph_density_time += rhs.sph_density_time;
+        self.pbd_constraint_time += rhs.pbd_constraint_time;
+        self.gravity_time += rhs.gravity_time;
+        self.position_update_time += rhs.position_update_time;
+        self.total_time += rhs.total_time;
+    }
+}
+
 impl SimulationStepTiming {
     pub fn print_detailed(&self, particle_count: u32) {
         println!("=== 仿真步骤详细耗时 ({} 粒子) ===", particle_count);
@@ -95,13 +120,79 @@ impl SimulationStepTiming {
     }
 }
 
+/// Timestamp queries of a GPU-timed step, each written once the stages up to it finished
+///
+/// The spans between them are contiguous, so the stage times add up to the total. The
+/// neighbor search counts toward the density span and the prediction copy toward PBD.
+#[derive(Clone, Copy)]
+enum StepTimestamp {
+    Start,
+    Gravity,
+    MortonHash,
+    RadixSort,
+    SphDensity,
+    PbdConstraint,
+    PositionUpdate,
+}
+
+const STEP_TIMESTAMP_COUNT: u32 = StepTimestamp::PositionUpdate as u32 + 1;
+
+impl SimulationStepTiming {
+    /// Stage times from the device time of every [`StepTimestamp`] since the first
+    fn from_step_timestamps(elapsed: &[Duration]) -> Self {
+        let span = |stage: StepTimestamp| elapsed[stage as usize] - elapsed[stage as usize - 1];
+        Self {
+            morton_hash_time: span(StepTimestamp::MortonHash),
+            radix_sort_time: span(StepTimestamp::RadixSort),
+            sph_density_time: span(StepTimestamp::SphDensity),
+            pbd_constraint_time: span(StepTimestamp::PbdConstraint),
+            gravity_time: span(StepTimestamp::Gravity),
+            position_update_time: span(StepTimestamp::PositionUpdate),
+            total_time: elapsed[StepTimestamp::PositionUpdate as usize]
+                - elapsed[StepTimestamp::Start as usize],
+        }
+    }
+}
+
 pub(crate) struct SimulationTasks {
     pub apply_gravity: ApplyGravityTask,
     pub morton_hash: MortonHashTask,
     pub update_position: UpdatePositionTask,
     pub spiky_sph: SpikySphTask,
-    pub radix_sort: RadixSortSystem,
+    pub radix_sort: AdaptiveSortSystem,
     pub pbd_density_constraint: PbdDensityConstraintTask,
+    pub implicit_viscosity: ImplicitViscosityTask,
+    pub xsph_viscosity: XsphViscosityTask,
+    pub vorticity_confinement: VorticityConfinementTask,
+    pub neighbor_search: NeighborSearchTask,
+    pub smoothed_velocity: SmoothedVelocityTask,
+    pub gradient_correction: GradientCorrectionTask,
+    pub boundary_velocity: BoundaryVelocityTask,
+    pub mean_displacement: MeanDisplacementTask,
+    /// Created on the first CFL sample, since its scratch buffer needs an allocator
+    max_speed: Option<ReduceMaxSpeedTask>,
+    pub surface_normal: SurfaceNormalTask,
+    pub merge_duplicates: MergeDuplicatesTask,
+    pub merge_compact: CompactTask,
+    pub merge_gather: MergeGatherTask,
+    pub remove_particles: RemoveParticlesTask,
+    pub sdf_collision: SdfCollisionTask,
+    /// Obstacle field currently in the particles' sdf buffer, so it is only uploaded on change
+    uploaded_sdf: Option<Sdf>,
+    /// Pose of the obstacle for the next step
+    obstacle_transform: Mat4,
+    /// Pose of the obstacle in the last executed step, for its surface velocity
+    stepped_obstacle_transform: Option<Mat4>,
+    /// Substeps left in the update before pinned positions are recorded as history
+    substeps_until_history: u32,
+    /// Frames the current neighbor lists have been reused for
+    frames_since_neighbor_search: u32,
+    /// Steps left before duplicates are merged again
+    steps_until_merge: u32,
+    /// Timestamp queries written by `execute`, when GPU timing is enabled
+    gpu_timer: Option<GpuTimer>,
+    /// Device-side stage times of the last executed step, when GPU timing is enabled
+    last_gpu_timing: Option<SimulationStepTiming>,
 }
 
 impl SimulationTasks {
@@ -110,8 +201,22 @@ impl SimulationTasks {
         let morton_hash = MortonHashTask::new(device);
         let update_position = UpdatePositionTask::new(device);
         let spiky_sph = SpikySphTask::new(device);
-        let radix_sort = RadixSortSystem::new(device);
+        let radix_sort = AdaptiveSortSystem::new(device);
         let pbd_density_constraint = PbdDensityConstraintTask::new(device);
+        let implicit_viscosity = ImplicitViscosityTask::new(device);
+        let xsph_viscosity = XsphViscosityTask::new(device);
+        let vorticity_confinement = VorticityConfinementTask::new(device);
+        let neighbor_search = NeighborSearchTask::new(device);
+        let smoothed_velocity = SmoothedVelocityTask::new(device);
+        let gradient_correction = GradientCorrectionTask::new(device);
+        let boundary_velocity = BoundaryVelocityTask::new(device);
+        let mean_displacement = MeanDisplacementTask::new(device);
+        let surface_normal = SurfaceNormalTask::new(device);
+        let merge_duplicates = MergeDuplicatesTask::new(device);
+        let merge_compact = CompactTask::new(device);
+        let merge_gather = MergeGatherTask::new(device);
+        let remove_particles = RemoveParticlesTask::new(device);
+        let sdf_collision = SdfCollisionTask::new(device);
 
         Self {
             apply_gravity,
@@ -120,7 +225,56 @@ impl SimulationTasks {
             spiky_sph,
             radix_sort,
             pbd_density_constraint,
+            implicit_viscosity,
+            xsph_viscosity,
+            vorticity_confinement,
+            neighbor_search,
+            smoothed_velocity,
+            gradient_correction,
+            boundary_velocity,
+            mean_displacement,
+            max_speed: None,
+            surface_normal,
+            merge_duplicates,
+            merge_compact,
+            merge_gather,
+            remove_particles,
+            sdf_collision,
+            uploaded_sdf: None,
+            obstacle_transform: Mat4::IDENTITY,
+            stepped_obstacle_transform: None,
+            substeps_until_history: 0,
+            frames_since_neighbor_search: 0,
+            steps_until_merge: 0,
+            gpu_timer: None,
+            last_gpu_timing: None,
+        }
+    }
+
+    /// Write timestamps around the stages of every [`SimulationTasks::execute`]
+    ///
+    /// Returns `false`, leaving timing off, when the device's queues cannot write timestamps.
+    pub fn enable_gpu_timing(&mut self, device: &Arc<Device>) -> bool {
+        if self.gpu_timer.is_none() {
+            self.gpu_timer = GpuTimer::new(device, STEP_TIMESTAMP_COUNT);
+        }
+        self.gpu_timer.is_some()
+    }
+
+    /// Device-side stage times of the last [`SimulationTasks::execute`], if GPU timing is enabled
+    pub fn last_gpu_timing(&self) -> Option<&SimulationStepTiming> {
+        self.last_gpu_timing.as_ref()
+    }
+
+    /// Record `timestamp` into `executor`, if GPU timing is enabled
+    fn write_timestamp(&self, executor: &impl GpuTaskExecutor, timestamp: StepTimestamp) {
+        let Some(timer) = &self.gpu_timer else {
+            return;
+        };
+        if let StepTimestamp::Start = timestamp {
+            executor.execute(&mut timer.reset());
         }
+        executor.execute(&mut timer.timestamp(timestamp as u32));
     }
 
     /// Set all constants using SimulationConfig
@@ -128,6 +282,9 @@ impl SimulationTasks {
     /// * `config` - Simulation configuration parameters
     /// * `particle_count` - Number of particles
     /// * `dt` - Dynamically calculated time step (calculated from actual frame time, already limited by config.clamp_time_step())
+    ///
+    /// `dt` is the time step of one substep. Obstacle and pinned particle velocities come from
+    /// motion applied once per update, so those passes divide it by the whole update's time.
     pub fn set_constants_from_config(
         &mut self,
         config: &SimulationConfig,
@@ -138,13 +295,35 @@ impl SimulationTasks {
             ApplyGravityConstants::new(particle_count, dt, config.gravity);
         self.apply_gravity.set_constants(apply_gravity_constants);
 
-        let morton_hash_constants = MortonHashConstants::new(particle_count, config.grid_size);
+        let update_dt = dt * config.substeps.max(1) as f32;
+        self.boundary_velocity
+            .set_constants(BoundaryVelocityConstants::new(particle_count, update_dt));
+        self.substeps_until_history = config.substeps.max(1) - 1;
+
+        let morton_hash_constants =
+            MortonHashConstants::new(particle_count, config.grid_size, config.simulation_aabb)
+                .with_sort_position_mode(config.sort_position_mode)
+                .with_normalized_axes();
         self.morton_hash.set_constants(morton_hash_constants);
 
-        let update_position_constants =
-            UpdatePositionConstants::new(config.simulation_aabb, particle_count, dt);
+        let update_position_constants = UpdatePositionConstants::new(
+            config.simulation_aabb,
+            particle_count,
+            dt,
+            config.floor_friction,
+        );
         self.update_position
             .set_constants(update_position_constants);
+        if let Some(sdf) = &config.sdf_obstacle {
+            self.sdf_collision.set_constants(
+                SdfCollisionConstants::new(particle_count, sdf).with_transform(
+                    self.obstacle_transform,
+                    self.stepped_obstacle_transform
+                        .unwrap_or(self.obstacle_transform),
+                    update_dt,
+                ),
+            );
+        }
 
         // SPH density calculation constants setup (for PBD) - using parameters from configuration
         let spiky_sph_constants = SpikySphConstants::new(
@@ -152,7 +331,8 @@ impl SimulationTasks {
             config.sph_params.particle_mass,
             config.sph_params.smoothing_radius,
             config.grid_size,
-        );
+        )
+        .with_kernel(config.sph_params.density_kernel);
         self.spiky_sph.set_constants(spiky_sph_constants);
 
         // PBD密度约束常量设置
@@ -162,15 +342,83 @@ impl SimulationTasks {
             config.sph_params.smoothing_radius,
             config.sph_params.pbd_constraint_epsilon,
             config.sph_params.pbd_relaxation_factor,
+        )
+        .with_gradient_correction(config.sph_params.gradient_correction)
+        .with_solve_order(
+            config.sph_params.pbd_solve_order,
+            config.sph_params.particle_mass,
+            config.sph_params.density_kernel,
+        )
+        .with_clamps(
+            config.sph_params.pbd_max_lambda,
+            config.sph_params.pbd_max_displacement,
+        )
+        .with_tensile_correction(
+            config.sph_params.pbd_s_corr_k,
+            config.sph_params.pbd_s_corr_n,
+            config.sph_params.pbd_s_corr_delta_q,
         );
         self.pbd_density_constraint
             .set_constants(pbd_constraint_constants);
+
+        let gradient_correction_constants = GradientCorrectionConstants::new(
+            particle_count,
+            config.sph_params.particle_mass,
+            config.sph_params.smoothing_radius,
+        );
+        self.gradient_correction
+            .set_constants(gradient_correction_constants);
+
+        let implicit_viscosity_constants = ImplicitViscosityConstants::new(
+            particle_count,
+            config.sph_params.particle_mass,
+            config.sph_params.smoothing_radius,
+            config.sph_params.viscosity,
+        );
+        self.implicit_viscosity
+            .set_constants(implicit_viscosity_constants);
+        self.xsph_viscosity
+            .set_constants(XsphViscosityConstants::new(
+                particle_count,
+                config.sph_params.viscosity,
+                config.sph_params.smoothing_radius,
+            ));
+        self.vorticity_confinement
+            .set_constants(VorticityConfinementConstants::new(
+                particle_count,
+                config.sph_params.vorticity_epsilon,
+                dt,
+                config.sph_params.smoothing_radius,
+            ));
+
+        let neighbor_search_constants = NeighborSearchConstants::new(
+            particle_count,
+            config.sph_params.smoothing_radius,
+            config.max_neighbors,
+        )
+        .with_bounds(config.simulation_aabb, config.sort_position_mode);
+        self.neighbor_search
+            .set_constants(neighbor_search_constants);
+        self.mean_displacement
+            .set_constants(MeanDisplacementConstants::new(particle_count));
+
+        let smoothed_velocity_constants =
+            SmoothedVelocityConstants::new(particle_count, config.sph_params.smoothing_radius);
+        self.smoothed_velocity
+            .set_constants(smoothed_velocity_constants);
+
+        self.surface_normal
+            .set_constants(SurfaceNormalConstants::new(
+                particle_count,
+                config.sph_params.smoothing_radius,
+            ));
     }
 
     pub fn update_descriptor_sets(
         &mut self,
         descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
         particles: &mut Particles,
+        config: &SimulationConfig,
     ) {
         self.apply_gravity
             .update_descriptor_set(descriptor_set_allocator, particles);
@@ -180,8 +428,417 @@ impl SimulationTasks {
             .update_descriptor_set(descriptor_set_allocator, particles);
         self.spiky_sph
             .update_descriptor_set(descriptor_set_allocator, particles);
+        // The PBD pass binds the correction buffer, so it must be full size first
+        if config.sph_params.gradient_correction {
+            particles.enable_gradient_correction();
+            self.gradient_correction
+                .update_descriptor_set(descriptor_set_allocator, particles);
+        }
         self.pbd_density_constraint
             .update_descriptor_set(descriptor_set_allocator, particles);
+
+        // Optional buffers are only allocated for the features the config enables
+        if config.sph_params.viscosity_mode == ViscosityMode::Implicit {
+            particles.enable_predicted_velocity();
+            self.implicit_viscosity
+                .update_descriptor_set(descriptor_set_allocator, particles);
+        }
+        if config.uses_neighbor_lists() {
+            particles.enable_contacts();
+            self.neighbor_search
+                .update_descriptor_set(descriptor_set_allocator, particles);
+        }
+        if config.sph_params.viscosity_mode == ViscosityMode::Xsph {
+            particles.enable_predicted_velocity();
+            self.xsph_viscosity
+                .update_descriptor_set(descriptor_set_allocator, particles);
+        }
+        if config.sph_params.vorticity_epsilon > 0.0 {
+            particles.enable_vorticity();
+            self.vorticity_confinement
+                .update_descriptor_set(descriptor_set_allocator, particles);
+        }
+        if config.uses_neighbor_lists() && config.neighbor_rebuild_interval > 0 {
+            particles.enable_neighbor_reuse();
+            self.mean_displacement
+                .update_descriptor_set(descriptor_set_allocator, particles);
+        }
+        if config.smoothed_velocity_enabled {
+            particles.enable_smoothed_velocity();
+            self.smoothed_velocity
+                .update_descriptor_set(descriptor_set_allocator, particles);
+        }
+        if config.surface_normals_enabled {
+            particles.enable_normal();
+            self.surface_normal
+                .update_descriptor_set(descriptor_set_allocator, particles);
+        }
+        if config.boundary_velocity_enabled {
+            particles.enable_previous_position();
+            self.boundary_velocity
+                .update_descriptor_set(descriptor_set_allocator, particles);
+        }
+    }
+
+    /// Run the PBD iterations, with one dispatch per color when solving in colored batches
+    fn execute_pbd_iterations(
+        &mut self,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        let constants = *self
+            .pbd_density_constraint
+            .constants()
+            .expect("PBD constants are not set");
+        if constants.color_count() == 1 {
+            // Every Jacobi iteration runs with the same constants, so they go in one batch
+            self.pbd_density_constraint
+                .set_constants(constants.with_color(0));
+            let iterations = config.sph_params.pbd_iterations as usize;
+            executor.execute_batch(&vec![
+                &self.pbd_density_constraint as &dyn GpuTask;
+                iterations
+            ]);
+            return;
+        }
+        for _ in 0..config.sph_params.pbd_iterations {
+            for color in 0..constants.color_count() {
+                self.pbd_density_constraint
+                    .set_constants(constants.with_color(color));
+                executor.execute(&mut self.pbd_density_constraint);
+            }
+        }
+    }
+
+    /// Run the configured viscosity pass and replace velocity with its result
+    fn execute_viscosity(
+        &mut self,
+        particles: &mut Particles,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        match config.sph_params.viscosity_mode {
+            ViscosityMode::Disabled => return,
+            ViscosityMode::Implicit => executor.execute(&mut self.implicit_viscosity),
+            ViscosityMode::Xsph => executor.execute(&mut self.xsph_viscosity),
+        }
+        particles.copy_predicted_velocity_to_velocity(executor);
+    }
+
+    /// Compute the velocity curl, then add the confinement force, if confinement is enabled
+    fn execute_vorticity_confinement(
+        &mut self,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        if config.sph_params.vorticity_epsilon <= 0.0 {
+            return;
+        }
+        let constants = *self
+            .vorticity_confinement
+            .constants()
+            .expect("vorticity confinement constants are not set");
+        for pass in [VorticityPass::Curl, VorticityPass::Force] {
+            self.vorticity_confinement
+                .set_constants(constants.with_pass(pass));
+            executor.execute(&mut self.vorticity_confinement);
+        }
+    }
+
+    /// Push particles out of the obstacle, uploading its field first if it changed
+    fn execute_sdf_collision(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        particles: &mut Particles,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        let Some(sdf) = &config.sdf_obstacle else {
+            return;
+        };
+        if self.uploaded_sdf.as_ref() != Some(sdf) {
+            particles.set_sdf(sdf, executor);
+            self.uploaded_sdf = Some(sdf.clone());
+        }
+        self.sdf_collision
+            .update_descriptor_set(descriptor_set_allocator, particles);
+        executor.execute(&mut self.sdf_collision);
+        self.stepped_obstacle_transform = Some(self.obstacle_transform);
+    }
+
+    /// Move the obstacle to `transform` from the next step on, which must set constants again
+    pub fn set_obstacle_transform(&mut self, transform: Mat4) {
+        self.obstacle_transform = transform;
+    }
+
+    /// Derive pinned particle velocities from their motion, replacing gravity's contribution
+    fn execute_boundary_velocity(
+        &mut self,
+        particles: &mut Particles,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        if !config.boundary_velocity_enabled {
+            return;
+        }
+        // Without a history every particle is treated as having stood still
+        if particles.previous_position_stale() {
+            particles.reset_previous_position(executor);
+        }
+        // Substeps of one update all see the update's motion, so only the last records it
+        let record_history = self.substeps_until_history == 0;
+        self.substeps_until_history = self.substeps_until_history.saturating_sub(1);
+        let constants = *self
+            .boundary_velocity
+            .constants()
+            .expect("boundary velocity constants are not set");
+        self.boundary_velocity
+            .set_constants(constants.with_history(record_history));
+        executor.execute(&mut self.boundary_velocity);
+    }
+
+    /// Largest particle speed, reduced on the GPU and read back to the host
+    pub fn sample_max_speed(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        particles: &mut Particles,
+        executor: &impl GpuTaskExecutor,
+    ) -> f32 {
+        particles.enable_max_speed();
+        let max_speed = self
+            .max_speed
+            .get_or_insert_with(|| ReduceMaxSpeedTask::new(particles.memory_allocator()));
+        max_speed.set_buffers(
+            descriptor_set_allocator,
+            particles.count(),
+            particles.velocity(),
+            particles.max_speed(),
+        );
+        executor.execute(max_speed);
+        particles
+            .download_max_speed(particles.memory_allocator(), executor)
+            .expect("max_speed buffer is not enabled")
+    }
+
+    /// Frames the current neighbor lists have been reused for, 0 right after a search
+    #[cfg(test)]
+    pub fn frames_since_neighbor_search(&self) -> u32 {
+        self.frames_since_neighbor_search
+    }
+
+    /// Rebuild the neighbor lists unless the cached ones may be reused this frame
+    fn execute_neighbor_search(
+        &mut self,
+        particles: &mut Particles,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        if self.needs_neighbor_search(particles, executor, config) {
+            self.search_neighbors(particles, executor, config);
+        }
+    }
+
+    /// Build the neighbor lists and remember the positions they were built from
+    fn search_neighbors(
+        &mut self,
+        particles: &mut Particles,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        particles.clear_neighbor_overflow_count(executor);
+        executor.execute(&mut self.neighbor_search);
+        particles.mark_contacts_valid();
+        self.frames_since_neighbor_search = 0;
+        if config.neighbor_rebuild_interval > 0 {
+            particles.snapshot_search_position(executor);
+        }
+    }
+
+    /// Whether this frame must search for neighbors, counting it as reused otherwise
+    ///
+    /// Contacts hold particle indices, which sorting does not change, so only motion and
+    /// spawning or despawning invalidate them. Reads the mean displacement back on sampled
+    /// frames, so the passes writing positions must have run.
+    fn needs_neighbor_search(
+        &mut self,
+        particles: &Particles,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) -> bool {
+        if !config.uses_neighbor_lists() {
+            return false;
+        }
+        if particles.contacts_stale()
+            || self.frames_since_neighbor_search >= config.neighbor_rebuild_interval
+        {
+            return true;
+        }
+
+        if (self.frames_since_neighbor_search + 1)
+            .is_multiple_of(config.displacement_sample_interval)
+        {
+            executor.execute(&mut self.mean_displacement);
+            let mean_displacement = particles
+                .download_mean_displacement(particles.memory_allocator(), executor)
+                .expect("neighbor reuse is not enabled");
+            if mean_displacement
+                > config.neighbor_rebuild_threshold * config.sph_params.smoothing_radius
+            {
+                return true;
+            }
+        }
+
+        self.frames_since_neighbor_search += 1;
+        false
+    }
+
+    /// Make `contacts` safe to read after particles were spawned or despawned
+    ///
+    /// Constants must already be set for the current particle count.
+    pub fn refresh_stale_contacts(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        particles: &mut Particles,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        if !config.uses_neighbor_lists() || !particles.contacts_stale() {
+            return;
+        }
+
+        match config.contact_reset_strategy {
+            ContactResetStrategy::Rebuild => {
+                executor.execute(&mut self.morton_hash);
+                self.radix_sort
+                    .sort_untracked(particles, descriptor_set_allocator, executor);
+                self.execute_neighbor_search(particles, executor, config);
+            }
+            ContactResetStrategy::ZeroCounts => particles.clear_contact_counts(executor),
+        }
+    }
+
+    /// Merge particles closer than `config.merge_distance` into one, if it is set, every
+    /// `config.merge_interval` calls
+    ///
+    /// Hashes and sorts on its own and may shrink the particle count, so it runs before
+    /// the constants of a step are set. Merged particles carry their summed mass in
+    /// `mass`, which the density and PBD passes weigh them by.
+    pub fn merge_duplicates(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        particles: &mut Particles,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        let Some(merge_distance) = config.merge_distance else {
+            return;
+        };
+        if self.steps_until_merge > 0 {
+            self.steps_until_merge -= 1;
+            return;
+        }
+        self.steps_until_merge = config.merge_interval.max(1) - 1;
+        let particle_count = particles.count();
+        if particle_count < 2 {
+            return;
+        }
+        particles.enable_merge(executor);
+        self.sort_current_positions(descriptor_set_allocator, particles, executor, config);
+
+        self.merge_duplicates
+            .set_constants(MergeDuplicatesConstants::new(
+                particle_count,
+                merge_distance,
+            ));
+        self.merge_duplicates
+            .update_descriptor_set(descriptor_set_allocator, particles);
+        executor.execute(&mut self.merge_duplicates);
+
+        self.merge_compact.set_buffers(
+            descriptor_set_allocator,
+            particle_count,
+            particles.sorted_indices(),
+            particles.merge_keep(),
+            particles.merge_survivors(),
+            &particles.merge_count().clone().index(0),
+        );
+        executor.execute(&mut self.merge_compact);
+
+        self.merge_gather
+            .set_constants(MergeGatherConstants::new(particle_count));
+        self.merge_gather
+            .update_descriptor_set(descriptor_set_allocator, particles);
+        executor.execute(&mut self.merge_gather);
+
+        let merged_count = particles
+            .download_merge_count(particles.memory_allocator(), executor)
+            .unwrap();
+        particles.finish_merge(merged_count);
+    }
+
+    /// Hash and sort the particles' current positions, outside of a step
+    pub fn sort_current_positions(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        particles: &mut Particles,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        self.morton_hash.set_constants(
+            MortonHashConstants::new(particles.count(), config.grid_size, config.simulation_aabb)
+                .with_sort_position_mode(config.sort_position_mode)
+                .with_normalized_axes(),
+        );
+        self.morton_hash
+            .update_descriptor_set(descriptor_set_allocator, particles);
+        executor.execute(&mut self.morton_hash);
+        self.radix_sort
+            .sort_untracked(particles, descriptor_set_allocator, executor);
+    }
+
+    /// Drop the particles queued by [`Particles::remove`]
+    ///
+    /// Survivors move to the front in their original order through the merge compaction
+    /// and gather, so like the merge only positions, velocities, masses, pinned flags and
+    /// rest densities travel with them.
+    pub fn remove_particles(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        particles: &mut Particles,
+        executor: &impl GpuTaskExecutor,
+    ) {
+        let removal_count = particles.pending_removals();
+        if removal_count == 0 {
+            return;
+        }
+        let particle_count = particles.count();
+
+        self.remove_particles
+            .set_constants(RemoveParticlesConstants::new(particle_count, removal_count));
+        self.remove_particles
+            .update_descriptor_set(descriptor_set_allocator, particles);
+        executor.execute(&mut self.remove_particles);
+
+        self.merge_compact.set_buffers(
+            descriptor_set_allocator,
+            particle_count,
+            particles.sorted_indices(),
+            particles.merge_keep(),
+            particles.merge_survivors(),
+            &particles.merge_count().clone().index(0),
+        );
+        executor.execute(&mut self.merge_compact);
+
+        self.merge_gather
+            .set_constants(MergeGatherConstants::new(particle_count));
+        self.merge_gather
+            .update_descriptor_set(descriptor_set_allocator, particles);
+        executor.execute(&mut self.merge_gather);
+
+        let remaining_count = particles
+            .download_merge_count(particles.memory_allocator(), executor)
+            .unwrap();
+        particles.finish_removal(remaining_count);
     }
 
     pub fn execute(
@@ -192,39 +849,106 @@ impl SimulationTasks {
         config: &SimulationConfig,
     ) {
         // === 标准PBD流体仿真流程 ===
+        let stages = config.pipeline_stages;
+        // Deciding on a skipped sort or search reads displacements back, so both precede the
+        // batch. Nothing before the search moves particles, so deciding early changes nothing.
+        let sorting = stages.contains(PipelineStages::SORTING)
+            && self
+                .radix_sort
+                .needs_sort(descriptor_set_allocator, particles, executor, config);
+        let searching = stages.contains(PipelineStages::NEIGHBOR_SEARCH)
+            && self.needs_neighbor_search(particles, executor, config);
 
-        // 1. 应用外力（重力）- 更新粒子速度
-        executor.execute(&mut self.apply_gravity);
+        // Passes are recorded into one command buffer, so the GPU is waited on only at the
+        // end and before host readbacks
+        let batch = TaskBatch::new(executor);
+        self.write_timestamp(&batch, StepTimestamp::Start);
 
+        // 1. 应用外力（重力）- 更新粒子速度
         // 2. 基于当前位置计算Morton哈希（为空间排序做准备）
-        executor.execute(&mut self.morton_hash);
+        let gravity = stages.contains(PipelineStages::GRAVITY);
+        if gravity && sorting && self.gpu_timer.is_none() {
+            // Gravity only writes velocity and the hash only reads position, so the two may
+            // overlap
+            batch.execute_concurrent(&mut [&mut self.apply_gravity], &mut [&mut self.morton_hash]);
+            self.execute_boundary_velocity(particles, &batch, config);
+        } else {
+            // Timed steps run them back to back, so each gets its own span
+            if gravity {
+                batch.execute(&mut self.apply_gravity);
+                self.execute_boundary_velocity(particles, &batch, config);
+            }
+            self.write_timestamp(&batch, StepTimestamp::Gravity);
+            if sorting {
+                batch.execute(&mut self.morton_hash);
+            }
+        }
+        self.write_timestamp(&batch, StepTimestamp::MortonHash);
 
         // 3. 执行Radix排序，按Morton码对粒子排序（优化邻居搜索）
-        self.radix_sort
-            .sort_morton_codes(particles, descriptor_set_allocator, executor);
+        if sorting {
+            self.radix_sort
+                .sort_morton_codes(particles, descriptor_set_allocator, &batch, config);
+        }
+        self.write_timestamp(&batch, StepTimestamp::RadixSort);
+
+        // Neighbor lists for passes that read contacts, reused while motion is small
+        if searching {
+            self.search_neighbors(particles, &batch, config);
+        }
 
         // 4. 使用排序后的数据执行SPH密度计算
-        executor.execute(&mut self.spiky_sph);
+        if stages.contains(PipelineStages::SPH) {
+            batch.execute(&mut self.spiky_sph);
+        }
+        self.write_timestamp(&batch, StepTimestamp::SphDensity);
 
         // === PBD约束求解阶段 ===
         // 5. PBD迭代之前，将当前位置复制到预测位置
-        particles.copy_position_to_predicted(executor);
+        if stages.contains(PipelineStages::PREDICTION) {
+            particles.copy_position_to_predicted(&batch);
+        }
 
-        // 6. PBD密度约束求解迭代循环
-        for _ in 0..config.sph_params.pbd_iterations {
-            // 执行PBD密度约束求解，更新predicted_position
-            executor.execute(&mut self.pbd_density_constraint);
+        if stages.contains(PipelineStages::PBD) {
+            // Gradient corrections from the neighborhoods the solver starts from
+            if config.sph_params.gradient_correction {
+                batch.execute(&mut self.gradient_correction);
+            }
 
-            // 注意：在更完整的PBD实现中，这里可能需要重新计算密度
-            // 当前简化版本使用初始密度进行所有迭代
+            // 6. PBD密度约束求解迭代循环，更新predicted_position
+            // Jacobi iterations reuse the initial densities; colored batches re-evaluate them
+            self.execute_pbd_iterations(&batch, config);
         }
 
-        // 7. 更新最终位置和速度（整合预测位置的变化）
-        executor.execute(&mut self.update_position);
+        // 7. Viscosity writes predicted_velocity, which then replaces velocity
+        self.execute_viscosity(particles, &batch, config);
+        self.execute_vorticity_confinement(&batch, config);
+        self.write_timestamp(&batch, StepTimestamp::PbdConstraint);
+
+        // 8. 更新最终位置和速度（整合预测位置的变化）
+        if stages.contains(PipelineStages::POSITION_UPDATE) {
+            batch.execute(&mut self.update_position);
+            self.execute_sdf_collision(descriptor_set_allocator, particles, &batch, config);
+        }
+
+        // 9. Export-only smoothed velocity and surface normals of the final state
+        if config.smoothed_velocity_enabled {
+            batch.execute(&mut self.smoothed_velocity);
+        }
+        if config.surface_normals_enabled {
+            batch.execute(&mut self.surface_normal);
+        }
+        self.write_timestamp(&batch, StepTimestamp::PositionUpdate);
+        batch.flush();
+
+        self.last_gpu_timing = self
+            .gpu_timer
+            .as_ref()
+            .and_then(GpuTimer::read_elapsed)
+            .map(|elapsed| SimulationStepTiming::from_step_timestamps(&elapsed));
     }
 
     /// Execute with detailed timing for performance analysis
-    #[cfg(test)]
     pub fn execute_with_timing(
         &mut self,
         descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
@@ -233,41 +957,80 @@ impl SimulationTasks {
         config: &SimulationConfig,
     ) -> SimulationStepTiming {
         let total_start = Instant::now();
+        let stages = config.pipeline_stages;
+        let sorting = stages.contains(PipelineStages::SORTING)
+            && self
+                .radix_sort
+                .needs_sort(descriptor_set_allocator, particles, executor, config);
 
         // 1. 应用重力
         let gravity_start = Instant::now();
-        executor.execute(&mut self.apply_gravity);
+        if stages.contains(PipelineStages::GRAVITY) {
+            executor.execute(&mut self.apply_gravity);
+            self.execute_boundary_velocity(particles, executor, config);
+        }
         let gravity_time = gravity_start.elapsed();
 
         // 2. Morton哈希计算
         let morton_start = Instant::now();
-        executor.execute(&mut self.morton_hash);
+        if sorting {
+            executor.execute(&mut self.morton_hash);
+        }
         let morton_hash_time = morton_start.elapsed();
 
         // 3. Radix排序
         let sort_start = Instant::now();
-        self.radix_sort
-            .sort_morton_codes(particles, descriptor_set_allocator, executor);
+        if sorting {
+            self.radix_sort.sort_morton_codes(
+                particles,
+                descriptor_set_allocator,
+                executor,
+                config,
+            );
+        }
         let radix_sort_time = sort_start.elapsed();
 
+        // Neighbor lists for passes that read contacts, reused while motion is small
+        if stages.contains(PipelineStages::NEIGHBOR_SEARCH) {
+            self.execute_neighbor_search(particles, executor, config);
+        }
+
         // 4. SPH密度计算
         let sph_start = Instant::now();
-        executor.execute(&mut self.spiky_sph);
+        if stages.contains(PipelineStages::SPH) {
+            executor.execute(&mut self.spiky_sph);
+        }
         let sph_density_time = sph_start.elapsed();
 
         // === PBD约束求解阶段 ===
-        particles.copy_position_to_predicted(executor);
+        if stages.contains(PipelineStages::PREDICTION) {
+            particles.copy_position_to_predicted(executor);
+        }
 
         // 5. PBD约束求解迭代
         let pbd_loop_start = Instant::now();
-        for _ in 0..config.sph_params.pbd_iterations {
-            executor.execute(&mut self.pbd_density_constraint);
+        if stages.contains(PipelineStages::PBD) {
+            if config.sph_params.gradient_correction {
+                executor.execute(&mut self.gradient_correction);
+            }
+            self.execute_pbd_iterations(executor, config);
         }
+        self.execute_viscosity(particles, executor, config);
+        self.execute_vorticity_confinement(executor, config);
         let pbd_constraint_time = pbd_loop_start.elapsed();
 
         // 6. 位置更新
         let position_start = Instant::now();
-        executor.execute(&mut self.update_position);
+        if stages.contains(PipelineStages::POSITION_UPDATE) {
+            executor.execute(&mut self.update_position);
+            self.execute_sdf_collision(descriptor_set_allocator, particles, executor, config);
+        }
+        if config.smoothed_velocity_enabled {
+            executor.execute(&mut self.smoothed_velocity);
+        }
+        if config.surface_normals_enabled {
+            executor.execute(&mut self.surface_normal);
+        }
         let position_update_time = position_start.elapsed();
 
         let total_time = total_start.elapsed();
@@ -283,3 +1046,1234 @@ impl SimulationTasks {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{ParticleInitData, CONTACTS_PER_PARTICLE},
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::{UVec3, Vec3};
+
+    #[test]
+    fn test_despawn_leaves_no_stale_contacts() {
+        for strategy in [
+            ContactResetStrategy::Rebuild,
+            ContactResetStrategy::ZeroCounts,
+        ] {
+            let backend = VulkanoHeadlessBackend::new();
+            let config = SimulationConfig {
+                neighbor_list_enabled: true,
+                contact_reset_strategy: strategy,
+                ..SimulationConfig::default()
+            };
+
+            // A tight cluster so every particle neighbors every other one
+            let particle_data = ParticleInitData::block(UVec3::new(4, 4, 1), 0.02, Vec3::ZERO);
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+            let mut tasks = SimulationTasks::new(backend.device());
+            tasks.set_constants_from_config(&config, particles.count(), 0.001);
+            tasks.update_descriptor_sets(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &config,
+            );
+            tasks.execute(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &backend,
+                &config,
+            );
+            assert!(!particles.contacts_stale());
+
+            // Despawn half the particles; contacts now reference removed indices
+            particles.truncate(8);
+            assert!(particles.contacts_stale());
+
+            tasks.set_constants_from_config(&config, particles.count(), 0.001);
+            tasks.update_descriptor_sets(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &config,
+            );
+            tasks.refresh_stale_contacts(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &backend,
+                &config,
+            );
+            assert!(!particles.contacts_stale());
+
+            backend.execute(&mut tasks.spiky_sph);
+
+            let contacts = particles.contacts().read().unwrap();
+            let contact_counts = particles.contact_counts().read().unwrap();
+            for i in 0..particles.count() as usize {
+                let count = contact_counts[i] as usize;
+                if strategy == ContactResetStrategy::ZeroCounts {
+                    assert_eq!(count, 0);
+                }
+                let base = i * CONTACTS_PER_PARTICLE as usize;
+                for &j in &contacts[base..base + count] {
+                    assert!(
+                        j < particles.count(),
+                        "{:?}: particle {} references despawned neighbor {}",
+                        strategy,
+                        i,
+                        j
+                    );
+                }
+            }
+
+            let densities = particles.density().read().unwrap();
+            assert!(densities[..8].iter().all(|d| d.is_finite() && *d > 0.0));
+        }
+    }
+
+    #[test]
+    fn test_pinned_particle_holds_position_but_adds_density() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig::default();
+        let h = config.sph_params.smoothing_radius;
+
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &[
+                ParticleInitData {
+                    position: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(1.0, 0.0, 0.0),
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(h * 0.5, 0.0, 0.0),
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                },
+            ],
+            backend.memory_allocator(),
+            &backend,
+        );
+        particles.set_pinned(&[0], &backend);
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        tasks.set_constants_from_config(&config, particles.count(), 1.0 / 60.0);
+        tasks.update_descriptor_sets(backend.descriptor_set_allocator(), &mut particles, &config);
+        tasks.execute(
+            backend.descriptor_set_allocator(),
+            &mut particles,
+            &backend,
+            &config,
+        );
+
+        let positions = particles.download_positions(backend.memory_allocator(), &backend);
+        let velocities = particles.download_velocities(backend.memory_allocator(), &backend);
+        assert_eq!(positions[0], Vec3::ZERO);
+        assert_eq!(velocities[0], Vec3::ZERO);
+        assert_ne!(positions[1], Vec3::new(h * 0.5, 0.0, 0.0));
+
+        // A lone particle's density is its own poly6 contribution; the pinned one must add to it
+        let self_density = config.sph_params.particle_mass * 315.0
+            / (64.0 * std::f32::consts::PI * h.powi(9))
+            * h.powi(6);
+        let densities = particles.download_densities(backend.memory_allocator(), &backend);
+        assert!(
+            densities[1] > self_density * 1.1,
+            "{} <= {}",
+            densities[1],
+            self_density
+        );
+    }
+
+    #[test]
+    fn test_boundary_shell_restores_near_wall_density() {
+        use crate::core::{Aabb, BoundaryParticles};
+        use crate::systems::simulation::simulation_config::DensityKernel;
+
+        let backend = VulkanoHeadlessBackend::new();
+        let mut config = SimulationConfig {
+            gravity: Vec3::ZERO,
+            ..SimulationConfig::default()
+        };
+        config.sph_params.smoothing_radius = 0.2;
+        let (mass, h) = (
+            config.sph_params.particle_mass,
+            config.sph_params.smoothing_radius,
+        );
+        let spacing = h / 2.0;
+        let aabb = Aabb::new(Vec3::ZERO, Vec3::splat(2.0 * spacing));
+        // One spacing away from every wall
+        let fluid = ParticleInitData {
+            position: Vec3::splat(spacing),
+            velocity: Vec3::ZERO,
+            ..Default::default()
+        };
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        let mut fluid_density = |mut particles: Particles| {
+            particles.add_particles(&[fluid], backend.memory_allocator(), &backend);
+            tasks.set_constants_from_config(&config, particles.count(), 1.0 / 60.0);
+            tasks.update_descriptor_sets(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &config,
+            );
+            tasks.execute(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &backend,
+                &config,
+            );
+            // The shell stays out of the particle set
+            assert_eq!(particles.count(), 1);
+            particles.download_densities(backend.memory_allocator(), &backend)[0]
+        };
+
+        let without_boundary =
+            fluid_density(Particles::with_capacity(backend.memory_allocator(), 16));
+        assert_eq!(BoundaryParticles::new(aabb, spacing).len(), 26);
+        let with_boundary = fluid_density(Particles::with_boundary(
+            backend.memory_allocator(),
+            16,
+            aabb,
+            spacing,
+            &backend,
+        ));
+
+        // Density deep inside fluid sampled on the same lattice
+        let poly6_factor = DensityKernel::Poly6.factor(h);
+        let interior_density = (-2..=2)
+            .flat_map(|x| (-2..=2).flat_map(move |y| (-2..=2).map(move |z| (x, y, z))))
+            .map(|(x, y, z)| {
+                let r_sq = (Vec3::new(x as f32, y as f32, z as f32) * spacing).length_squared();
+                let diff = (h * h - r_sq).max(0.0);
+                poly6_factor * diff * diff * diff * mass
+            })
+            .sum::<f32>();
+
+        assert!(
+            without_boundary < 0.5 * interior_density,
+            "{} should fall well short of {}",
+            without_boundary,
+            interior_density
+        );
+        assert!(
+            (with_boundary - interior_density).abs() < 1e-3 * interior_density,
+            "{} should match {}",
+            with_boundary,
+            interior_density
+        );
+    }
+
+    #[test]
+    fn test_particles_dropped_on_sphere_sdf_stay_outside() {
+        use crate::core::{Aabb, Sdf};
+        use glam::UVec3;
+
+        let backend = VulkanoHeadlessBackend::new();
+        let radius = 0.5;
+        let config = SimulationConfig {
+            sdf_obstacle: Some(Sdf::sphere(
+                UVec3::splat(32),
+                Aabb::new(Vec3::splat(-1.0), Vec3::splat(1.0)),
+                Vec3::ZERO,
+                radius,
+            )),
+            ..SimulationConfig::default()
+        };
+
+        // A 5x5 sheet falling onto the top of the sphere
+        let particle_data =
+            ParticleInitData::block(UVec3::new(5, 1, 5), 0.15, Vec3::new(-0.3, 0.7, -0.3))
+                .into_iter()
+                .map(|particle| ParticleInitData {
+                    velocity: Vec3::new(0.0, -2.0, 0.0),
+                    ..particle
+                })
+                .collect::<Vec<_>>();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        for _ in 0..30 {
+            tasks.set_constants_from_config(&config, particles.count(), 1.0 / 60.0);
+            tasks.update_descriptor_sets(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &config,
+            );
+            tasks.execute(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &backend,
+                &config,
+            );
+        }
+
+        let positions = particles.download_positions(backend.memory_allocator(), &backend);
+        // The sheet reached the sphere instead of stopping short of it
+        assert!(positions.iter().any(|p| p.length() < radius + 0.05));
+        for p in positions {
+            assert!(p.length() > radius - 0.01, "{:?} is inside the sphere", p);
+        }
+    }
+
+    /// Sweep a box obstacle into a still 5x5x5 block over four updates of `substeps`
+    /// iterations each
+    ///
+    /// Returns the sweep speed, the x velocities of the particles that started in the box's
+    /// path, and the x velocities of all particles.
+    fn sweep_box_through_block(substeps: u32) -> (f32, Vec<f32>, Vec<f32>) {
+        use crate::core::{Aabb, Sdf};
+
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig {
+            gravity: Vec3::ZERO,
+            substeps,
+            sdf_obstacle: Some(Sdf::cuboid(
+                UVec3::splat(16),
+                Aabb::new(Vec3::splat(-0.3), Vec3::splat(0.3)),
+                Aabb::new(Vec3::splat(-0.1), Vec3::splat(0.1)),
+            )),
+            ..SimulationConfig::default()
+        };
+        let dt = 1.0 / 60.0;
+        let step_distance = 0.05;
+
+        // Just ahead of the box's +x face
+        let particle_data =
+            ParticleInitData::block(UVec3::splat(5), 0.05, Vec3::new(0.15, -0.1, -0.1));
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        for step in 1..=4 {
+            tasks.set_obstacle_transform(Mat4::from_translation(
+                Vec3::X * step_distance * step as f32,
+            ));
+            tasks.set_constants_from_config(
+                &config,
+                particles.count(),
+                config.substep_time_step(dt),
+            );
+            tasks.update_descriptor_sets(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &config,
+            );
+            for _ in 0..substeps {
+                tasks.execute(
+                    backend.descriptor_set_allocator(),
+                    &mut particles,
+                    &backend,
+                    &config,
+                );
+            }
+        }
+
+        let velocities = particles
+            .download_velocities(backend.memory_allocator(), &backend)
+            .iter()
+            .map(|v| v.x)
+            .collect::<Vec<_>>();
+        let pushed = particle_data
+            .iter()
+            .zip(&velocities)
+            .filter(|(particle, _)| {
+                particle.position.y.abs() < 0.09 && particle.position.z.abs() < 0.09
+            })
+            .map(|(_, &vx)| vx)
+            .collect();
+        (step_distance / dt, pushed, velocities)
+    }
+
+    #[test]
+    fn test_sweeping_box_pushes_fluid_along() {
+        let (sweep_speed, pushed, velocities) = sweep_box_through_block(1);
+        // The front layers are hit and take on the sweep speed
+        assert!(
+            pushed.iter().filter(|&&vx| vx > 0.9 * sweep_speed).count() >= 9,
+            "{:?}",
+            pushed
+        );
+        // Nothing is pulled backward
+        assert!(velocities.iter().all(|&vx| vx > -1e-5));
+    }
+
+    #[test]
+    fn test_substeps_push_fluid_at_the_obstacle_speed() {
+        let (sweep_speed, pushed, _) = sweep_box_through_block(4);
+        // Every substep sees the box's real speed rather than its whole step over a substep
+        assert!(
+            pushed.iter().filter(|&&vx| vx > 0.9 * sweep_speed).count() >= 9,
+            "{:?}",
+            pushed
+        );
+        assert!(
+            pushed.iter().all(|&vx| vx < 1.1 * sweep_speed),
+            "{:?}",
+            pushed
+        );
+    }
+
+    #[test]
+    fn test_heavier_phase_is_denser_and_pushed_apart() {
+        let backend = VulkanoHeadlessBackend::new();
+        // Two identical 3x3x3 blocks out of each other's reach; the second is a heavier phase
+        let particle_data = [Vec3::ZERO, Vec3::X]
+            .into_iter()
+            .flat_map(|origin| ParticleInitData::block(UVec3::splat(3), 0.05, origin))
+            .collect::<Vec<_>>();
+        let spread = |positions: &[Vec3]| {
+            let centroid = positions.iter().sum::<Vec3>() / positions.len() as f32;
+            positions.iter().map(|p| p.distance(centroid)).sum::<f32>() / positions.len() as f32
+        };
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        let mut step = |config: &SimulationConfig| {
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+            particles.set_phase(&(27..54).collect::<Vec<_>>(), 2.0, 1.0, &backend);
+            tasks.set_constants_from_config(config, particles.count(), 0.0);
+            tasks.update_descriptor_sets(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                config,
+            );
+            tasks.execute(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &backend,
+                config,
+            );
+            let densities = particles.download_densities(backend.memory_allocator(), &backend);
+            // PBD corrections land in predicted_position
+            let predicted = particles.predicted_position().read().unwrap();
+            let positions = predicted[..particle_data.len()]
+                .iter()
+                .map(|p| Vec3::from_slice(&p.position[..3]))
+                .collect::<Vec<_>>();
+            (densities, positions)
+        };
+
+        let mut config = SimulationConfig {
+            gravity: Vec3::ZERO,
+            ..SimulationConfig::default()
+        };
+        let (densities, _) = step(&config);
+        let light_density = densities[..27].iter().sum::<f32>() / 27.0;
+        let heavy_density = densities[27..].iter().sum::<f32>() / 27.0;
+        assert!(
+            (heavy_density - 2.0 * light_density).abs() < 1e-3 * light_density,
+            "{} != 2 * {}",
+            heavy_density,
+            light_density
+        );
+
+        // At the light phase's own density only the heavy phase is over-compressed
+        config.sph_params.rest_density = light_density;
+        let (_, positions) = step(&config);
+        let initial_spread = spread(
+            &particle_data[..27]
+                .iter()
+                .map(|particle| particle.position)
+                .collect::<Vec<_>>(),
+        );
+        let light_spread = spread(&positions[..27]);
+        let heavy_spread = spread(&positions[27..]);
+        assert!(
+            heavy_spread > initial_spread,
+            "{} <= {}",
+            heavy_spread,
+            initial_spread
+        );
+        assert!(
+            heavy_spread > light_spread,
+            "heavy {} <= light {}",
+            heavy_spread,
+            light_spread
+        );
+    }
+
+    #[test]
+    fn test_concurrent_groups_match_serial_execution() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig::default();
+        let particle_data = (0..300)
+            .map(|i| ParticleInitData {
+                position: Vec3::new(
+                    (i % 7) as f32 * 0.03,
+                    (i % 11) as f32 * 0.02,
+                    (i % 13) as f32 * 0.01,
+                ),
+                velocity: Vec3::new(0.1, (i % 5) as f32 * 0.2, 0.0),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        // Gravity and hashing on one queue after the other, then side by side where possible
+        let results = [false, true].map(|concurrent| {
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+            let mut tasks = SimulationTasks::new(backend.device());
+            tasks.set_constants_from_config(&config, particles.count(), 1.0 / 60.0);
+            tasks.update_descriptor_sets(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &config,
+            );
+
+            if concurrent {
+                backend.execute_concurrent(
+                    &mut [&mut tasks.apply_gravity],
+                    &mut [&mut tasks.morton_hash],
+                );
+            } else {
+                backend.execute(&mut tasks.apply_gravity);
+                backend.execute(&mut tasks.morton_hash);
+            }
+
+            let velocities = particles.download_velocities(backend.memory_allocator(), &backend);
+            let hashes = particles.hash().read().unwrap()[..particles.count() as usize].to_vec();
+            (velocities, hashes)
+        });
+
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    fn test_batched_step_matches_stage_by_stage_step() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut config = SimulationConfig::default();
+        config.sph_params.pbd_iterations = 3;
+        config.sph_params.viscosity_mode = ViscosityMode::Xsph;
+        config.sph_params.viscosity = 0.1;
+        config.smoothed_velocity_enabled = true;
+        let particle_data = ParticleInitData::block(UVec3::splat(8), 0.05, Vec3::ZERO)
+            .into_iter()
+            .enumerate()
+            .map(|(i, particle)| ParticleInitData {
+                velocity: Vec3::new(0.0, (i % 3) as f32 * 0.1, 0.0),
+                ..particle
+            })
+            .collect::<Vec<_>>();
+
+        // Every pass reads what the previous one wrote, so within one command buffer each
+        // depends on the barriers recorded between them
+        let results = [false, true].map(|batched| {
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+            let mut tasks = SimulationTasks::new(backend.device());
+            tasks.set_constants_from_config(&config, particles.count(), 1.0 / 60.0);
+            tasks.update_descriptor_sets(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &config,
+            );
+            if batched {
+                tasks.execute(
+                    backend.descriptor_set_allocator(),
+                    &mut particles,
+                    &backend,
+                    &config,
+                );
+            } else {
+                tasks.execute_with_timing(
+                    backend.descriptor_set_allocator(),
+                    &mut particles,
+                    &backend,
+                    &config,
+                );
+            }
+            let densities = particles.download_densities(backend.memory_allocator(), &backend);
+            let velocities = particles.download_velocities(backend.memory_allocator(), &backend);
+            (densities, velocities)
+        });
+
+        let (densities, velocities) = &results[1];
+        assert!(densities.iter().all(|&d| d > 0.0));
+        for (batched, staged) in densities.iter().zip(&results[0].0) {
+            assert!(
+                (batched - staged).abs() <= 1e-3 * staged,
+                "{} != {}",
+                batched,
+                staged
+            );
+        }
+        for (batched, staged) in velocities.iter().zip(&results[0].1) {
+            assert!(
+                batched.distance(*staged) < 1e-4,
+                "{} != {}",
+                batched,
+                staged
+            );
+        }
+    }
+
+    #[test]
+    fn test_fluid_next_to_moving_wall_picks_up_its_velocity() {
+        let backend = VulkanoHeadlessBackend::new();
+        let dt = 1.0 / 60.0;
+        let wall_speed = 0.5;
+
+        // A 4x4 wall of pinned particles sliding along x under a 4x4 fluid layer, coupled
+        // only through implicit viscosity
+        let particle_data = [Vec3::ZERO, Vec3::Y * 0.05]
+            .into_iter()
+            .flat_map(|origin| ParticleInitData::block(UVec3::new(4, 1, 4), 0.05, origin))
+            .collect::<Vec<_>>();
+        let wall = particle_data[..16]
+            .iter()
+            .map(|particle| particle.position)
+            .collect::<Vec<_>>();
+        let wall_indices = (0..16).collect::<Vec<u32>>();
+
+        let fluid_velocity = |boundary_velocity_enabled: bool, substeps: u32| {
+            let mut config = SimulationConfig {
+                gravity: Vec3::ZERO,
+                boundary_velocity_enabled,
+                substeps,
+                ..SimulationConfig::default()
+            };
+            config.sph_params.viscosity_mode = ViscosityMode::Implicit;
+            config.sph_params.viscosity = 100.0;
+
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+            particles.set_pinned(&wall_indices, &backend);
+
+            let mut tasks = SimulationTasks::new(backend.device());
+            for step in 1..=3 {
+                let moved_wall = wall
+                    .iter()
+                    .map(|p| *p + Vec3::X * wall_speed * dt * step as f32)
+                    .collect::<Vec<_>>();
+                particles.set_positions(&wall_indices, &moved_wall, &backend);
+
+                tasks.set_constants_from_config(
+                    &config,
+                    particles.count(),
+                    config.substep_time_step(dt),
+                );
+                tasks.update_descriptor_sets(
+                    backend.descriptor_set_allocator(),
+                    &mut particles,
+                    &config,
+                );
+                for _ in 0..substeps {
+                    tasks.execute(
+                        backend.descriptor_set_allocator(),
+                        &mut particles,
+                        &backend,
+                        &config,
+                    );
+                }
+            }
+
+            let velocities = particles.download_velocities(backend.memory_allocator(), &backend);
+            velocities[16..].iter().sum::<Vec3>() / 16.0
+        };
+
+        let dragged = fluid_velocity(true, 1);
+        assert!(
+            dragged.x > 0.1 * wall_speed && dragged.x <= wall_speed * 1.01,
+            "fluid velocity {:?}",
+            dragged
+        );
+        // Substeps all see the wall at its real speed, not sped up in the first and still after
+        let substepped = fluid_velocity(true, 4);
+        assert!(
+            substepped.x > 0.1 * wall_speed && substepped.x <= wall_speed * 1.01,
+            "fluid velocity {:?}",
+            substepped
+        );
+        // Without the pass the wall reads as standing still and the fluid stays at rest
+        let still = fluid_velocity(false, 1);
+        assert!(still.x.abs() < 1e-3, "fluid velocity {:?}", still);
+    }
+
+    #[test]
+    fn test_neighbor_search_is_skipped_while_motion_is_small() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig {
+            neighbor_list_enabled: true,
+            neighbor_rebuild_interval: 3,
+            neighbor_rebuild_threshold: 0.1,
+            displacement_sample_interval: 1,
+            ..SimulationConfig::default()
+        };
+        let h = config.sph_params.smoothing_radius;
+
+        // Pinned particles only move when placed by hand
+        let particle_data = ParticleInitData::grid(64, 0.05, Vec3::ZERO);
+        let positions = particle_data
+            .iter()
+            .map(|particle| particle.position)
+            .collect::<Vec<_>>();
+        let indices = (0..64).collect::<Vec<u32>>();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+        particles.set_pinned(&indices, &backend);
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        tasks.set_constants_from_config(&config, particles.count(), 1.0 / 60.0);
+        tasks.update_descriptor_sets(backend.descriptor_set_allocator(), &mut particles, &config);
+        let mut step = |particles: &mut Particles, offset: f32| {
+            let moved = positions
+                .iter()
+                .map(|p| *p + Vec3::X * offset)
+                .collect::<Vec<_>>();
+            particles.set_positions(&indices, &moved, &backend);
+            tasks.execute(
+                backend.descriptor_set_allocator(),
+                particles,
+                &backend,
+                &config,
+            );
+            tasks.frames_since_neighbor_search()
+        };
+
+        // The first search is forced by the fresh particle set
+        assert_eq!(step(&mut particles, 0.0), 0);
+        // Drifting well below the threshold reuses the lists
+        assert_eq!(step(&mut particles, 0.02 * h), 1);
+        assert_eq!(step(&mut particles, 0.05 * h), 2);
+        // Exceeding the threshold searches again, measured from the new positions
+        assert_eq!(step(&mut particles, 0.2 * h), 0);
+        assert_eq!(step(&mut particles, 0.25 * h), 1);
+        assert_eq!(step(&mut particles, 0.25 * h), 2);
+        assert_eq!(step(&mut particles, 0.25 * h), 3);
+        // Without motion the lists still expire after the interval
+        assert_eq!(step(&mut particles, 0.25 * h), 0);
+
+        // The last reduction measured the drift from the search at 0.2h
+        let mean_displacement = particles
+            .download_mean_displacement(backend.memory_allocator(), &backend)
+            .unwrap();
+        assert!(
+            (mean_displacement - 0.05 * h).abs() < 1e-5,
+            "{}",
+            mean_displacement
+        );
+    }
+
+    #[test]
+    fn test_displacement_is_only_read_back_on_sampled_frames() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig {
+            neighbor_list_enabled: true,
+            neighbor_rebuild_interval: 8,
+            neighbor_rebuild_threshold: 0.1,
+            displacement_sample_interval: 2,
+            ..SimulationConfig::default()
+        };
+        let h = config.sph_params.smoothing_radius;
+
+        let particle_data = ParticleInitData::grid(8, 0.05, Vec3::ZERO);
+        let positions = particle_data
+            .iter()
+            .map(|particle| particle.position)
+            .collect::<Vec<_>>();
+        let indices = (0..8).collect::<Vec<u32>>();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+        particles.set_pinned(&indices, &backend);
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        tasks.set_constants_from_config(&config, particles.count(), 1.0 / 60.0);
+        tasks.update_descriptor_sets(backend.descriptor_set_allocator(), &mut particles, &config);
+        let mut step = |particles: &mut Particles, offset: f32| {
+            let moved = positions
+                .iter()
+                .map(|p| *p + Vec3::X * offset)
+                .collect::<Vec<_>>();
+            particles.set_positions(&indices, &moved, &backend);
+            tasks.execute(
+                backend.descriptor_set_allocator(),
+                particles,
+                &backend,
+                &config,
+            );
+            tasks.frames_since_neighbor_search()
+        };
+
+        assert_eq!(step(&mut particles, 0.0), 0);
+        // A jump past the threshold goes unnoticed until the next sampled frame
+        assert_eq!(step(&mut particles, 0.5 * h), 1);
+        assert_eq!(step(&mut particles, 0.5 * h), 0);
+    }
+
+    #[test]
+    fn test_sort_is_skipped_in_a_nearly_static_scene() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig {
+            neighbor_list_enabled: true,
+            max_neighbors: 64,
+            sort_interval: 8,
+            sort_displacement_threshold: 0.5,
+            displacement_sample_interval: 1,
+            ..SimulationConfig::default()
+        };
+        let h = config.sph_params.smoothing_radius;
+
+        // Pinned particles only move when placed by hand
+        let particle_data = ParticleInitData::grid(64, 0.04, Vec3::ZERO);
+        let positions = particle_data
+            .iter()
+            .map(|particle| particle.position)
+            .collect::<Vec<_>>();
+        let indices = (0..64).collect::<Vec<u32>>();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+        particles.set_pinned(&indices, &backend);
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        tasks.set_constants_from_config(&config, particles.count(), 1.0 / 60.0);
+        tasks.update_descriptor_sets(backend.descriptor_set_allocator(), &mut particles, &config);
+
+        let steps = 20;
+        let mut sorts = 0;
+        for step in 0..steps {
+            // A slow drift, far below the threshold between forced sorts
+            let moved = positions
+                .iter()
+                .map(|p| *p + Vec3::X * 0.001 * step as f32)
+                .collect::<Vec<_>>();
+            particles.set_positions(&indices, &moved, &backend);
+            tasks.execute(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &backend,
+                &config,
+            );
+            if tasks.radix_sort.frames_since_sort() == 0 {
+                sorts += 1;
+            }
+
+            // Neighbor lists built through the kept order match a brute-force search
+            let contacts = particles.contacts().read().unwrap();
+            let contact_counts = particles.contact_counts().read().unwrap();
+            for i in 0..moved.len() {
+                let base = i * CONTACTS_PER_PARTICLE as usize;
+                let mut found = contacts[base..base + contact_counts[i] as usize].to_vec();
+                found.sort_unstable();
+                let expected = (0..moved.len() as u32)
+                    .filter(|&j| j as usize != i)
+                    .filter(|&j| moved[i].distance_squared(moved[j as usize]) < h * h)
+                    .collect::<Vec<_>>();
+                assert_eq!(found, expected, "step {} particle {}", step, i);
+            }
+        }
+
+        // The first sort, then one each time the interval runs out
+        assert_eq!(sorts, 3);
+
+        // A jump past the threshold sorts right away
+        let jumped = positions
+            .iter()
+            .map(|p| *p + Vec3::X * config.grid_size)
+            .collect::<Vec<_>>();
+        particles.set_positions(&indices, &jumped, &backend);
+        tasks.execute(
+            backend.descriptor_set_allocator(),
+            &mut particles,
+            &backend,
+            &config,
+        );
+        assert_eq!(tasks.radix_sort.frames_since_sort(), 0);
+    }
+
+    #[test]
+    fn test_gpu_stage_times_add_up_to_the_total() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig::default();
+
+        let particle_data = ParticleInitData::block(UVec3::new(32, 32, 20), 0.05, Vec3::ZERO)
+            .into_iter()
+            .take(20_000)
+            .collect::<Vec<_>>();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        if !tasks.enable_gpu_timing(backend.device()) {
+            println!("Timestamp queries are not supported, skipping");
+            return;
+        }
+        assert!(tasks.last_gpu_timing().is_none());
+        tasks.set_constants_from_config(&config, particles.count(), 1.0 / 60.0);
+        tasks.update_descriptor_sets(backend.descriptor_set_allocator(), &mut particles, &config);
+        tasks.execute(
+            backend.descriptor_set_allocator(),
+            &mut particles,
+            &backend,
+            &config,
+        );
+
+        let timing = tasks.last_gpu_timing().unwrap().clone();
+        timing.print_detailed(particles.count());
+        let stage_sum = timing.gravity_time
+            + timing.morton_hash_time
+            + timing.radix_sort_time
+            + timing.sph_density_time
+            + timing.pbd_constraint_time
+            + timing.position_update_time;
+        assert!(timing.total_time > Duration::ZERO);
+        let difference = stage_sum.abs_diff(timing.total_time);
+        assert!(
+            difference <= timing.total_time / 100 + Duration::from_micros(1),
+            "stages {:?}, total {:?}",
+            stage_sum,
+            timing.total_time
+        );
+        // The sort alone runs a dozen dispatches, so its span is never empty
+        assert!(timing.radix_sort_time > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_coincident_particles_merge_conserving_mass() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig {
+            merge_distance: Some(1e-3),
+            merge_interval: 1,
+            ..SimulationConfig::default()
+        };
+
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &[
+                ParticleInitData {
+                    position: Vec3::new(0.5, 0.0, 0.0),
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::ZERO,
+                    velocity: Vec3::new(1.0, 0.0, 0.0),
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(1e-4, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 2.0, 0.0),
+                    ..Default::default()
+                },
+            ],
+            backend.memory_allocator(),
+            &backend,
+        );
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        tasks.merge_duplicates(
+            backend.descriptor_set_allocator(),
+            &mut particles,
+            &backend,
+            &config,
+        );
+        assert_eq!(particles.count(), 2);
+
+        let positions = particles.download_positions(backend.memory_allocator(), &backend);
+        let velocities = particles.download_velocities(backend.memory_allocator(), &backend);
+        let masses = particles
+            .download_masses(backend.memory_allocator(), &backend)
+            .unwrap();
+        assert_eq!(masses.iter().sum::<f32>(), 3.0);
+
+        let merged = masses.iter().position(|&m| m == 2.0).unwrap();
+        assert!(positions[merged].distance(Vec3::new(5e-5, 0.0, 0.0)) < 1e-6);
+        assert!(velocities[merged].distance(Vec3::new(0.5, 1.0, 0.0)) < 1e-6);
+
+        // The far particle is kept as it was
+        let other = 1 - merged;
+        assert_eq!(masses[other], 1.0);
+        assert_eq!(positions[other], Vec3::new(0.5, 0.0, 0.0));
+        assert_eq!(velocities[other], Vec3::ZERO);
+
+        // Nothing is left to merge on the next pass
+        tasks.merge_duplicates(
+            backend.descriptor_set_allocator(),
+            &mut particles,
+            &backend,
+            &config,
+        );
+        assert_eq!(particles.count(), 2);
+    }
+
+    #[test]
+    fn test_merged_pair_weighs_as_much_in_neighbor_density() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig {
+            gravity: Vec3::ZERO,
+            merge_distance: Some(1e-3),
+            ..SimulationConfig::default()
+        };
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &[
+                Vec3::ZERO,
+                Vec3::new(0.05, 0.0, 0.0),
+                Vec3::new(0.0501, 0.0, 0.0),
+            ]
+            .map(|position| ParticleInitData {
+                position,
+                ..Default::default()
+            }),
+            backend.memory_allocator(),
+            &backend,
+        );
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        let mut lone_density = |particles: &mut Particles| {
+            tasks.set_constants_from_config(&config, particles.count(), 0.0);
+            tasks.update_descriptor_sets(backend.descriptor_set_allocator(), particles, &config);
+            tasks.execute(
+                backend.descriptor_set_allocator(),
+                particles,
+                &backend,
+                &config,
+            );
+            let densities = particles.download_densities(backend.memory_allocator(), &backend);
+            let positions = particles.download_positions(backend.memory_allocator(), &backend);
+            let lone = positions.iter().position(|p| p.x == 0.0).unwrap();
+            densities[lone]
+        };
+        let before = lone_density(&mut particles);
+
+        SimulationTasks::new(backend.device()).merge_duplicates(
+            backend.descriptor_set_allocator(),
+            &mut particles,
+            &backend,
+            &config,
+        );
+        assert_eq!(particles.count(), 2);
+        let after = lone_density(&mut particles);
+        assert!(
+            (after - before).abs() < 1e-2 * before,
+            "{} != {}",
+            after,
+            before
+        );
+    }
+
+    #[test]
+    fn test_merges_run_every_merge_interval_steps() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig {
+            merge_distance: Some(1e-3),
+            merge_interval: 3,
+            ..SimulationConfig::default()
+        };
+        let mut particles = Particles::new(backend.memory_allocator());
+        let mut tasks = SimulationTasks::new(backend.device());
+        let pair = [Vec3::ZERO, Vec3::new(1e-4, 0.0, 0.0)].map(|position| ParticleInitData {
+            position,
+            ..Default::default()
+        });
+
+        // The first call merges, the next two leave the new pairs alone, the fourth merges all
+        let mut counts = Vec::new();
+        for _ in 0..4 {
+            particles.add_particles(&pair, backend.memory_allocator(), &backend);
+            tasks.merge_duplicates(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &backend,
+                &config,
+            );
+            counts.push(particles.count());
+        }
+        assert_eq!(counts, vec![1, 3, 5, 1]);
+    }
+
+    #[test]
+    fn test_merge_carries_phases_with_the_merged_particle() {
+        let backend = VulkanoHeadlessBackend::new();
+        let config = SimulationConfig {
+            merge_distance: Some(1e-3),
+            ..SimulationConfig::default()
+        };
+
+        // A plain particle sorted ahead of a coincident pair of two different phases
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &[
+                ParticleInitData {
+                    position: Vec3::new(-0.5, 0.0, 0.0),
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::ZERO,
+                    mass: Some(2.0),
+                    rest_density: Some(0.5),
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(1e-4, 0.0, 0.0),
+                    rest_density: Some(0.8),
+                    ..Default::default()
+                },
+            ],
+            backend.memory_allocator(),
+            &backend,
+        );
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        tasks.merge_duplicates(
+            backend.descriptor_set_allocator(),
+            &mut particles,
+            &backend,
+            &config,
+        );
+        assert_eq!(particles.count(), 2);
+
+        let positions = particles.download_positions(backend.memory_allocator(), &backend);
+        let masses = particles
+            .download_masses(backend.memory_allocator(), &backend)
+            .unwrap();
+        let rest_densities = particles
+            .download_rest_densities(backend.memory_allocator(), &backend)
+            .unwrap();
+        let merged = masses.iter().position(|&m| m == 3.0).unwrap();
+        let plain = 1 - merged;
+        assert_eq!(positions[plain], Vec3::new(-0.5, 0.0, 0.0));
+        assert_eq!((masses[plain], rest_densities[plain]), (1.0, 1.0));
+        // Mass-weighted mean of 0.5 at twice the mass and 0.8
+        assert!((rest_densities[merged] - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_removed_particles_compact_keeping_survivor_positions() {
+        let backend = VulkanoHeadlessBackend::new();
+        let init_data = (0..100)
+            .map(|i| ParticleInitData {
+                position: Vec3::new(i as f32 * 0.01, 0.0, 0.0),
+                velocity: Vec3::ZERO,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&init_data, backend.memory_allocator(), &backend);
+
+        // Every third particle goes, queued in two batches
+        let removed = (0..100)
+            .filter(|i| i % 3 == 1)
+            .take(30)
+            .collect::<Vec<u32>>();
+        particles.remove(&removed[..10], &backend);
+        particles.remove(&removed[10..], &backend);
+        assert_eq!(particles.count(), 100);
+        assert_eq!(particles.pending_removals(), 30);
+
+        let mut tasks = SimulationTasks::new(backend.device());
+        tasks.remove_particles(backend.descriptor_set_allocator(), &mut particles, &backend);
+        assert_eq!(particles.count(), 70);
+        assert_eq!(particles.pending_removals(), 0);
+
+        let survivors = (0..100u32)
+            .filter(|i| !removed.contains(i))
+            .map(|i| init_data[i as usize].position)
+            .collect::<Vec<_>>();
+        let positions = particles.download_positions(backend.memory_allocator(), &backend);
+        assert_eq!(positions, survivors);
+    }
+
+    #[test]
+    fn test_removal_moves_phases_with_their_particles() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &ParticleInitData::grid(4, 0.1, Vec3::ZERO),
+            backend.memory_allocator(),
+            &backend,
+        );
+        particles.set_phase(&[2], 0.9, 0.8, &backend);
+        particles.set_pinned(&[3], &backend);
+
+        // Removing a particle ahead of the phased one shifts it down a slot
+        particles.remove(&[1], &backend);
+        let mut tasks = SimulationTasks::new(backend.device());
+        tasks.remove_particles(backend.descriptor_set_allocator(), &mut particles, &backend);
+        assert_eq!(particles.count(), 3);
+
+        let masses = particles.download_masses(backend.memory_allocator(), &backend);
+        let rest_densities =
+            particles.download_rest_densities(backend.memory_allocator(), &backend);
+        assert_eq!(masses, Some(vec![1.0, 0.9, 1.0]));
+        assert_eq!(rest_densities, Some(vec![1.0, 0.8, 1.0]));
+        assert_eq!(
+            particles.download_pinned_indices(backend.memory_allocator(), &backend),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn test_disabled_pbd_stage_still_computes_density() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut config = SimulationConfig {
+            gravity: Vec3::ZERO,
+            ..SimulationConfig::default()
+        };
+        // Far below the pair's density, so PBD always pushes them apart
+        config.sph_params.rest_density = 1e-3;
+        let h = config.sph_params.smoothing_radius;
+        let start = [Vec3::ZERO, Vec3::new(0.1 * h, 0.0, 0.0)];
+
+        let run = |pipeline_stages: PipelineStages| {
+            let config = SimulationConfig {
+                pipeline_stages,
+                ..config.clone()
+            };
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.add_particles(
+                &start.map(|position| ParticleInitData {
+                    position,
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                }),
+                backend.memory_allocator(),
+                &backend,
+            );
+
+            let mut tasks = SimulationTasks::new(backend.device());
+            tasks.set_constants_from_config(&config, particles.count(), 1.0 / 60.0);
+            tasks.update_descriptor_sets(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &config,
+            );
+            tasks.execute(
+                backend.descriptor_set_allocator(),
+                &mut particles,
+                &backend,
+                &config,
+            );
+
+            let densities = particles.download_densities(backend.memory_allocator(), &backend);
+            let predicted = particles.predicted_position().read().unwrap();
+            let predicted =
+                [0, 1].map(|i| glam::Vec4::from_array(predicted[i].position).truncate());
+            (densities, predicted)
+        };
+
+        let (densities, predicted) = run(PipelineStages::ALL.without(PipelineStages::PBD));
+        assert!(densities.iter().all(|&d| d > 0.0), "{:?}", densities);
+        assert_eq!(predicted, start);
+
+        // The same densities, but now the solver separates the pair
+        let (constrained_densities, constrained) = run(PipelineStages::default());
+        for (d, constrained_d) in densities.iter().zip(&constrained_densities) {
+            assert!(
+                (d - constrained_d).abs() <= d * 1e-5,
+                "{} {}",
+                d,
+                constrained_d
+            );
+        }
+        assert!(
+            constrained[0].distance(constrained[1]) > start[0].distance(start[1]) + 1e-4,
+            "{:?}",
+            constrained
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::Particles;
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Density accumulation constants
+///
+/// Adds each particle's current density to `density_sum`, for time-averaged densities.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct AccumulateDensityConstants {
+    particle_count: u32,
+}
+
+impl AccumulateDensityConstants {
+    pub fn new(particle_count: u32) -> Self {
+        Self { particle_count }
+    }
+}
+
+impl ComputeGpuTaskConstants for AccumulateDensityConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/accumulate_density.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.density().clone()),
+            WriteDescriptorSet::buffer(1, particles.density_sum().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type AccumulateDensityTask = ComputeGpuTask<AccumulateDensityConstants>;
@@ -1,61 +1,113 @@
 use std::sync::Arc;
 use vulkano::{descriptor_set::allocator::StandardDescriptorSetAllocator, device::Device};
 
-use crate::{core::Particles, utils::GpuTaskExecutor};
+use crate::{core::Particles, systems::simulation::SimulationConfig, utils::GpuTaskExecutor};
 
-use super::radix_sort_system::RadixSortSystem;
+use super::{radix_sort_system::RadixSortSystem, ReduceMaxDisplacementTask};
 
-#[allow(dead_code)]
+/// Radix sort that keeps the last order while particles have barely moved
+///
+/// The sorted indices stay a permutation of every particle while the count is unchanged,
+/// so lookups through a slightly stale order still see every candidate. Within
+/// `config.sort_interval` of the last sort, every `config.displacement_sample_interval`
+/// frames measure the largest displacement since that sort and sort again once it exceeds
+/// `config.sort_displacement_threshold` of `grid_size`.
 pub struct AdaptiveSortSystem {
     sort_system: RadixSortSystem,
-    last_sort_frame: u32,
-    sort_interval: u32,      // Frames between sorts
-    movement_threshold: f32, // Threshold for particle movement
+    max_displacement: Option<ReduceMaxDisplacementTask>,
+    /// Frames the current order has been kept for, 0 right after a sort
+    frames_since_sort: u32,
+    /// Particle count of the last tracked sort, which spawning or despawning invalidates
+    sorted_count: Option<u32>,
 }
 
 impl AdaptiveSortSystem {
-    #[allow(unused)]
     pub fn new(device: &Arc<Device>) -> Self {
         Self {
             sort_system: RadixSortSystem::new(device),
-            last_sort_frame: 0,
-            sort_interval: 4,        // Sort every 4 frames by default
-            movement_threshold: 0.1, // Sort when particles move > 10% of cell size
+            max_displacement: None,
+            frames_since_sort: 0,
+            sorted_count: None,
         }
     }
 
-    /// Conditionally sort particles based on movement and time
-    #[allow(unused)]
-    pub fn update_sort(
+    /// Whether this frame must hash and sort, counting it as skipped otherwise
+    ///
+    /// Reads the max displacement back on sampled frames while the last order is young
+    /// enough to keep, so the passes writing positions must have run.
+    pub fn needs_sort(
         &mut self,
-        particles: &mut Particles,
         descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        particles: &mut Particles,
         executor: &impl GpuTaskExecutor,
-        current_frame: u32,
-        force_sort: bool,
+        config: &SimulationConfig,
     ) -> bool {
-        let should_sort =
-            force_sort || (current_frame - self.last_sort_frame >= self.sort_interval);
-
-        if should_sort {
-            self.sort_system
-                .sort_morton_codes(particles, descriptor_set_allocator, executor);
-            self.last_sort_frame = current_frame;
-            true
-        } else {
-            false
+        if config.sort_interval == 0
+            || self.sorted_count != Some(particles.count())
+            || self.frames_since_sort >= config.sort_interval
+        {
+            return true;
+        }
+
+        if (self.frames_since_sort + 1).is_multiple_of(config.displacement_sample_interval) {
+            let max_displacement = self.max_displacement.get_or_insert_with(|| {
+                ReduceMaxDisplacementTask::new(particles.memory_allocator())
+            });
+            max_displacement.set_buffers(
+                descriptor_set_allocator,
+                particles.count(),
+                particles.position(),
+                particles.sort_position(),
+                particles.max_displacement(),
+            );
+            executor.execute(max_displacement);
+            let max_displacement = particles
+                .download_max_displacement(particles.memory_allocator(), executor)
+                .expect("adaptive sort is not enabled");
+            if max_displacement > config.sort_displacement_threshold * config.grid_size {
+                return true;
+            }
+        }
+
+        self.frames_since_sort += 1;
+        false
+    }
+
+    /// Sort the Morton codes and remember the positions they were hashed from
+    pub fn sort_morton_codes(
+        &mut self,
+        particles: &mut Particles,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        executor: &impl GpuTaskExecutor,
+        config: &SimulationConfig,
+    ) {
+        self.sort_system
+            .sort_morton_codes(particles, descriptor_set_allocator, executor);
+        self.frames_since_sort = 0;
+        if config.sort_interval > 0 {
+            particles.enable_adaptive_sort();
+            particles.snapshot_sort_position(executor);
+            self.sorted_count = Some(particles.count());
         }
     }
 
-    /// Set the interval between sorts (in frames)  
-    #[allow(unused)]
-    pub fn set_sort_interval(&mut self, interval: u32) {
-        self.sort_interval = interval;
+    /// Sort Morton codes hashed for another purpose, such as merging duplicates
+    ///
+    /// The order no longer matches the step's hashing, so the next frame sorts again.
+    pub fn sort_untracked(
+        &mut self,
+        particles: &mut Particles,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        executor: &impl GpuTaskExecutor,
+    ) {
+        self.sort_system
+            .sort_morton_codes(particles, descriptor_set_allocator, executor);
+        self.sorted_count = None;
     }
 
-    /// Get current sort interval
-    #[allow(unused)]
-    pub fn sort_interval(&self) -> u32 {
-        self.sort_interval
+    /// Frames the current order has been kept for, 0 right after a sort
+    #[cfg(test)]
+    pub fn frames_since_sort(&self) -> u32 {
+        self.frames_since_sort
     }
 }
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::{ForceField, Particles};
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Radial force field constants
+///
+/// Adds one step of a [`ForceField`]'s acceleration to every particle's velocity.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct ApplyForceFieldConstants {
+    center: [f32; 4],
+    particle_count: u32,
+    strength: f32,
+    min_distance_sq: f32,
+    dt: f32,
+}
+
+impl ApplyForceFieldConstants {
+    pub fn new(particle_count: u32, force_field: &ForceField, dt: f32) -> Self {
+        Self {
+            center: force_field.center().extend(0.0).to_array(),
+            particle_count,
+            strength: force_field.strength(),
+            min_distance_sq: force_field.min_distance() * force_field.min_distance(),
+            dt,
+        }
+    }
+}
+
+impl ComputeGpuTaskConstants for ApplyForceFieldConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/apply_force_field.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.velocity().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type ApplyForceFieldTask = ComputeGpuTask<ApplyForceFieldConstants>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{ParticleInitData, Particles},
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::Vec3;
+
+    #[test]
+    fn test_repeller_pushes_particles_radially_outward() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+
+        // Resting particles on a shell around the repeller, at two radii
+        let center = Vec3::new(0.5, 1.0, -0.5);
+        let particle_data = (0..32)
+            .map(|i| {
+                let angle = i as f32 * std::f32::consts::TAU / 16.0;
+                let radius = if i < 16 { 0.3 } else { 0.6 };
+                let direction = Vec3::new(angle.cos(), (i % 3) as f32 - 1.0, angle.sin());
+                ParticleInitData {
+                    position: center + direction.normalize() * radius,
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                }
+            })
+            .collect::<Vec<_>>();
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+        let repeller = ForceField::repeller(center, 2.0);
+        let dt = 1.0 / 60.0;
+        let mut task = ApplyForceFieldTask::new(backend.device());
+        task.set_constants(ApplyForceFieldConstants::new(
+            particles.count(),
+            &repeller,
+            dt,
+        ));
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut task);
+
+        let velocities = particles.download_velocities(backend.memory_allocator(), &backend);
+        for (particle, velocity) in particle_data.iter().zip(&velocities) {
+            let outward = (particle.position - center).normalize();
+            assert!(
+                velocity.normalize().dot(outward) > 0.9999,
+                "{:?} at {:?}",
+                velocity,
+                particle.position
+            );
+            let expected = repeller.acceleration(particle.position) * dt;
+            assert!(velocity.distance(expected) < 1e-5, "{:?}", velocity);
+        }
+        // The push fades with distance
+        assert!(velocities[0].length() > velocities[16].length());
+    }
+}
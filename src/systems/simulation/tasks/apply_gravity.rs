@@ -72,15 +72,18 @@ mod tests {
             &[
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, 0.0),
-                    velocitie: Vec3::new(1.0, 0.0, 0.0),
+                    velocity: Vec3::new(1.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 1.0, 0.0),
+                    velocity: Vec3::new(0.0, 1.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 1.0),
+                    velocity: Vec3::new(0.0, 0.0, 1.0),
+                    ..Default::default()
                 },
             ],
             backend.memory_allocator(),
@@ -95,12 +98,12 @@ mod tests {
 
         let mut task = ApplyGravityTask::new(backend.device());
         task.set_constants(constant);
-        task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
 
         backend.execute(&mut task);
 
         let result_entries = particles.velocity().read().unwrap();
-        let expected_entries = vec![
+        let expected_entries = [
             ParticleVelocity::new(Vec3::new(1.1, 0.2, 0.3)),
             ParticleVelocity::new(Vec3::new(0.1, 1.2, 0.3)),
             ParticleVelocity::new(Vec3::new(0.1, 0.2, 1.3)),
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use glam::Vec3;
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::Particles;
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// One-off velocity kick constants
+///
+/// Adds `delta_v` to every particle within `radius` of `center`, scaled linearly from
+/// full strength at the center to zero at `radius`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct ApplyImpulseConstants {
+    center: [f32; 4],
+    delta_v: [f32; 4],
+    particle_count: u32,
+    radius: f32,
+}
+
+impl ApplyImpulseConstants {
+    pub fn new(particle_count: u32, center: Vec3, radius: f32, delta_v: Vec3) -> Self {
+        Self {
+            center: center.extend(0.0).to_array(),
+            delta_v: delta_v.extend(0.0).to_array(),
+            particle_count,
+            radius: radius.max(0.0),
+        }
+    }
+}
+
+impl ComputeGpuTaskConstants for ApplyImpulseConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/apply_impulse.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.velocity().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type ApplyImpulseTask = ComputeGpuTask<ApplyImpulseConstants>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{ParticleInitData, Particles},
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+
+    #[test]
+    fn test_impulse_only_reaches_particles_within_radius() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+
+        // A row of resting particles at increasing distance from the impulse center
+        let particle_data = (0..11)
+            .map(|i| ParticleInitData {
+                position: Vec3::new(i as f32 * 0.1, 0.0, 0.0),
+                velocity: Vec3::ZERO,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+        let radius = 0.55;
+        let delta_v = Vec3::new(0.0, 2.0, 0.0);
+        let mut task = ApplyImpulseTask::new(backend.device());
+        task.set_constants(ApplyImpulseConstants::new(
+            particles.count(),
+            Vec3::ZERO,
+            radius,
+            delta_v,
+        ));
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut task);
+
+        let velocities = particles.download_velocities(backend.memory_allocator(), &backend);
+        for (particle, velocity) in particle_data.iter().zip(&velocities) {
+            let distance = particle.position.length();
+            if distance < radius {
+                let expected = delta_v * (1.0 - distance / radius);
+                assert!(
+                    velocity.distance(expected) < 1e-5,
+                    "at {}: {:?}",
+                    distance,
+                    velocity
+                );
+            } else {
+                assert_eq!(*velocity, Vec3::ZERO, "at {}", distance);
+            }
+        }
+        // The gain falls off with distance
+        assert!(velocities[..6].windows(2).all(|w| w[0].y > w[1].y));
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::Particles;
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Animated boundary velocity constants
+///
+/// Sets the velocity of each pinned particle to its displacement since the recorded history
+/// over `dt`, so neighboring fluid sees a moving wall, then records every position in
+/// `previous_position` if `record_history` is set.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct BoundaryVelocityConstants {
+    particle_count: u32,
+    dt: f32,
+    record_history: u32,
+}
+
+impl BoundaryVelocityConstants {
+    pub fn new(particle_count: u32, dt: f32) -> Self {
+        Self {
+            particle_count,
+            dt,
+            record_history: 1,
+        }
+    }
+
+    /// Keep the history for later substeps of the same update when `record_history` is unset
+    pub fn with_history(mut self, record_history: bool) -> Self {
+        self.record_history = record_history as u32;
+        self
+    }
+}
+
+impl ComputeGpuTaskConstants for BoundaryVelocityConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/boundary_velocity.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.previous_position().clone()),
+            WriteDescriptorSet::buffer(2, particles.velocity().clone()),
+            WriteDescriptorSet::buffer(3, particles.pinned().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type BoundaryVelocityTask = ComputeGpuTask<BoundaryVelocityConstants>;
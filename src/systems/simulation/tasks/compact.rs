@@ -0,0 +1,200 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{BufferContents, Subbuffer},
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, Queue},
+    pipeline::{ComputePipeline, Pipeline, PipelineBindPoint},
+    sync::{self, GpuFuture},
+};
+
+use crate::utils::GpuTask;
+
+use super::compute_task::create_compute_pipeline;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct CompactConstants {
+    element_count: u32,
+}
+
+/// Stream compaction of `u32` elements
+///
+/// Copies the `input` elements whose `flags` entry is nonzero to the front of `output`,
+/// keeping their order, and writes how many were kept to `count`. Unlike the particle
+/// passes it binds caller-provided buffers, so it is not tied to [`crate::core::Particles`].
+pub(crate) struct CompactTask {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set: Option<Arc<DescriptorSet>>,
+    constants: Option<CompactConstants>,
+}
+
+impl CompactTask {
+    pub fn new(device: &Arc<Device>) -> Self {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/compact.comp",
+            }
+        }
+        let entry_point = cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap();
+
+        Self {
+            pipeline: create_compute_pipeline(device, entry_point),
+            descriptor_set: None,
+            constants: None,
+        }
+    }
+
+    /// Compact the first `element_count` elements of `input` on the next execution
+    ///
+    /// `output` must hold at least `element_count` elements.
+    pub fn set_buffers(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        element_count: u32,
+        input: &Subbuffer<[u32]>,
+        flags: &Subbuffer<[u32]>,
+        output: &Subbuffer<[u32]>,
+        count: &Subbuffer<u32>,
+    ) {
+        let layout = &self.pipeline.layout().set_layouts()[0];
+        self.descriptor_set = Some(
+            DescriptorSet::new(
+                descriptor_set_allocator.clone(),
+                layout.clone(),
+                [
+                    WriteDescriptorSet::buffer(0, input.clone()),
+                    WriteDescriptorSet::buffer(1, flags.clone()),
+                    WriteDescriptorSet::buffer(2, output.clone()),
+                    WriteDescriptorSet::buffer(3, count.clone()),
+                ],
+                [],
+            )
+            .unwrap(),
+        );
+        self.constants = Some(CompactConstants { element_count });
+    }
+}
+
+impl GpuTask for CompactTask {
+    fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap();
+        builder
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                self.descriptor_set
+                    .as_ref()
+                    .expect("compact buffers are not set")
+                    .clone(),
+            )
+            .unwrap();
+        builder
+            .push_constants(
+                self.pipeline.layout().clone(),
+                0,
+                *self.constants.as_ref().unwrap(),
+            )
+            .unwrap();
+        // A single work group carries the running offset across blocks
+        unsafe {
+            builder.dispatch([1, 1, 1]).unwrap();
+        }
+    }
+
+    fn submit(
+        &mut self,
+        command_buffer: Arc<PrimaryAutoCommandBuffer>,
+        queue: &Arc<Queue>,
+        device: &Arc<Device>,
+    ) {
+        let future = sync::now(device.clone())
+            .then_execute(queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap();
+        future.wait(None).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{GpuTaskExecutor, VulkanoHeadlessBackend};
+    use vulkano::{
+        buffer::{Buffer, BufferCreateInfo, BufferUsage},
+        memory::allocator::{AllocationCreateInfo, MemoryTypeFilter},
+    };
+
+    #[test]
+    fn test_compact_keeps_even_indices_in_order() {
+        let backend = VulkanoHeadlessBackend::new();
+        // Several blocks with a partial last one
+        let element_count = 1000u32;
+        let storage_buffer = |values: Vec<u32>| {
+            Buffer::from_iter(
+                backend.memory_allocator().clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                        | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                    ..Default::default()
+                },
+                values,
+            )
+            .unwrap()
+        };
+
+        let input = storage_buffer((0..element_count).map(|i| i * 3 + 1).collect());
+        let flags = storage_buffer((0..element_count).map(|i| (i % 2 == 0) as u32).collect());
+        let output = storage_buffer(vec![u32::MAX; element_count as usize]);
+        let count = Buffer::from_data(
+            backend.memory_allocator().clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            0u32,
+        )
+        .unwrap();
+
+        let mut task = CompactTask::new(backend.device());
+        task.set_buffers(
+            backend.descriptor_set_allocator(),
+            element_count,
+            &input,
+            &flags,
+            &output,
+            &count,
+        );
+        backend.execute(&mut task);
+
+        let expected = (0..element_count)
+            .filter(|i| i % 2 == 0)
+            .map(|i| i * 3 + 1)
+            .collect::<Vec<_>>();
+        assert_eq!(*count.read().unwrap(), expected.len() as u32);
+        let output = output.read().unwrap();
+        assert_eq!(&output[..expected.len()], &expected[..]);
+        // Nothing is written past the kept elements
+        assert!(output[expected.len()..].iter().all(|&v| v == u32::MAX));
+    }
+}
@@ -4,7 +4,8 @@ use vulkano::{
     buffer::BufferContents,
     command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
     descriptor_set::{
-        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+        allocator::StandardDescriptorSetAllocator, layout::DescriptorType, DescriptorSet,
+        WriteDescriptorSet,
     },
     device::{Device, Queue},
     pipeline::{
@@ -21,6 +22,82 @@ use crate::{
     utils::GpuTask,
 };
 
+/// `local_size_x` of every particle compute shader
+const WORK_GROUP_SIZE: u32 = 256;
+
+/// Work groups needed to cover `particle_count` invocations, without a trailing empty group
+fn work_group_count(particle_count: u32) -> u32 {
+    particle_count.div_ceil(WORK_GROUP_SIZE)
+}
+
+/// Storage buffers bound by `layout`, summed over all of its sets
+fn storage_buffer_count(layout: &PipelineDescriptorSetLayoutCreateInfo) -> u32 {
+    layout
+        .set_layouts
+        .iter()
+        .flat_map(|set_layout| set_layout.bindings.values())
+        .filter(|binding| {
+            matches!(
+                binding.descriptor_type,
+                DescriptorType::StorageBuffer | DescriptorType::StorageBufferDynamic
+            )
+        })
+        .map(|binding| binding.descriptor_count)
+        .sum()
+}
+
+/// Fail with the binding count when `layout` exceeds the device's per-stage storage buffer limit
+///
+/// Vulkan counts the bindings of every set in a pipeline layout against this limit, so
+/// moving some to another set does not help; the shader has to bind fewer buffers.
+fn check_storage_buffer_limit(
+    layout: &PipelineDescriptorSetLayoutCreateInfo,
+    max_per_stage_descriptor_storage_buffers: u32,
+) -> Result<(), String> {
+    let count = storage_buffer_count(layout);
+    if count > max_per_stage_descriptor_storage_buffers {
+        return Err(format!(
+            "compute shader binds {} storage buffers, but the device allows {} per stage",
+            count, max_per_stage_descriptor_storage_buffers
+        ));
+    }
+    Ok(())
+}
+
+/// Compute pipeline with the descriptor set layout reflected from `entry_point`
+///
+/// Panics with the storage buffer count if the shader binds more than the device allows,
+/// rather than with Vulkan's own validation error.
+pub(super) fn create_compute_pipeline(
+    device: &Arc<Device>,
+    entry_point: EntryPoint,
+) -> Arc<ComputePipeline> {
+    let stage = PipelineShaderStageCreateInfo::new(entry_point);
+    let set_layouts = PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage]);
+    if let Err(error) = check_storage_buffer_limit(
+        &set_layouts,
+        device
+            .physical_device()
+            .properties()
+            .max_per_stage_descriptor_storage_buffers,
+    ) {
+        panic!("{}", error);
+    }
+    let layout = PipelineLayout::new(
+        device.clone(),
+        set_layouts
+            .into_pipeline_layout_create_info(device.clone())
+            .unwrap(),
+    )
+    .unwrap();
+    ComputePipeline::new(
+        device.clone(),
+        None,
+        ComputePipelineCreateInfo::stage_layout(stage, layout),
+    )
+    .unwrap()
+}
+
 pub(crate) trait ComputeGpuTaskConstants {
     fn entry_point(device: &Arc<Device>) -> EntryPoint;
     fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet>;
@@ -41,21 +118,7 @@ where
     C: BufferContents + ComputeGpuTaskConstants,
 {
     pub fn new(device: &Arc<Device>) -> Self {
-        let entry_point = C::entry_point(device);
-        let stage = PipelineShaderStageCreateInfo::new(entry_point);
-        let layout = PipelineLayout::new(
-            device.clone(),
-            PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
-                .into_pipeline_layout_create_info(device.clone())
-                .unwrap(),
-        )
-        .unwrap();
-        let pipeline = ComputePipeline::new(
-            device.clone(),
-            None,
-            ComputePipelineCreateInfo::stage_layout(stage, layout),
-        )
-        .unwrap();
+        let pipeline = create_compute_pipeline(device, C::entry_point(device));
 
         Self {
             pipeline,
@@ -68,11 +131,16 @@ where
         self.constants = Some(constants);
     }
 
+    pub fn constants(&self) -> Option<&C> {
+        self.constants.as_ref()
+    }
+
     pub fn update_descriptor_set(
         &mut self,
         descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
         particles: &mut Particles,
     ) {
+        particles.retain_descriptor_sets_for(work_group_count(particles.count()));
         if self
             .try_bind_descriptor_set_from_cache(particles.descriptor_sets())
             .is_err()
@@ -140,7 +208,11 @@ where
                 *self.constants.as_ref().unwrap(),
             )
             .unwrap();
-        let work_group_num = self.constants.as_ref().unwrap().particle_count() / 256 + 1;
+        // An empty particle set records the bindings but no dispatch
+        let work_group_num = work_group_count(self.constants.as_ref().unwrap().particle_count());
+        if work_group_num == 0 {
+            return;
+        }
         unsafe {
             builder.dispatch([work_group_num, 1, 1]).unwrap();
         }
@@ -160,3 +232,155 @@ where
         future.wait(None).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{Aabb, ParticleInitData},
+        systems::simulation::tasks::{
+            ApplyGravityConstants, ApplyGravityTask, MortonHashConstants, MortonHashTask,
+            PbdDensityConstraintConstants, UpdatePositionConstants, UpdatePositionTask,
+        },
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::{Vec3, Vec4};
+
+    #[test]
+    fn test_work_group_count_has_no_trailing_group() {
+        assert_eq!(work_group_count(0), 0);
+        assert_eq!(work_group_count(1), 1);
+        assert_eq!(work_group_count(256), 1);
+        assert_eq!(work_group_count(257), 2);
+        assert_eq!(work_group_count(512), 2);
+    }
+
+    #[test]
+    fn test_storage_buffer_limit_error_names_the_binding_count() {
+        let backend = VulkanoHeadlessBackend::new();
+        let stage = PipelineShaderStageCreateInfo::new(PbdDensityConstraintConstants::entry_point(
+            backend.device(),
+        ));
+        let layout = PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage]);
+        let count = storage_buffer_count(&layout);
+        assert!(count > 4, "{}", count);
+
+        let error = check_storage_buffer_limit(&layout, 4).unwrap_err();
+        assert!(error.contains(&count.to_string()), "{}", error);
+        assert!(check_storage_buffer_limit(&layout, count).is_ok());
+    }
+
+    #[test]
+    fn test_dispatch_at_work_group_multiples_stays_in_bounds() {
+        let backend = VulkanoHeadlessBackend::new();
+        let gravity = Vec3::new(0.0, -10.0, 0.0);
+
+        for particle_count in [256, 512] {
+            // One extra particle past the dispatched range must be left untouched
+            let mut particles = Particles::new(backend.memory_allocator());
+            let particle_data = (0..particle_count + 1)
+                .map(|i| ParticleInitData {
+                    position: Vec3::new(i as f32 * 0.01, 0.0, 0.0),
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>();
+            particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+            let mut task = ApplyGravityTask::new(backend.device());
+            task.set_constants(ApplyGravityConstants::new(particle_count, 0.1, gravity));
+            task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut task);
+
+            let velocities = particles.velocity().read().unwrap();
+            for (i, velocity) in velocities[..particle_count as usize].iter().enumerate() {
+                let velocity = Vec4::from_array(velocity.velocity).truncate();
+                assert!(
+                    velocity.distance(gravity * 0.1) < 1e-5,
+                    "{} particles: particle {} velocity {:?}",
+                    particle_count,
+                    i,
+                    velocity
+                );
+            }
+            assert_eq!(
+                Vec4::from_array(velocities[particle_count as usize].velocity),
+                Vec4::ZERO,
+                "{} particles: wrote past the end",
+                particle_count
+            );
+        }
+    }
+
+    #[test]
+    fn test_small_and_empty_counts_dispatch_the_right_groups() {
+        let backend = VulkanoHeadlessBackend::new();
+        let (dt, gravity, velocity) = (0.1, Vec3::new(0.0, -10.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let aabb = Aabb::new(Vec3::splat(-100.0), Vec3::splat(100.0));
+
+        for (particle_count, expected_groups) in [(0, 0), (1, 1), (255, 1), (256, 1), (257, 2)] {
+            assert_eq!(work_group_count(particle_count), expected_groups);
+
+            // One extra particle past the dispatched range must be left untouched
+            let mut particles = Particles::new(backend.memory_allocator());
+            let particle_data = (0..particle_count + 1)
+                .map(|i| ParticleInitData {
+                    position: Vec3::new(i as f32 * 0.01, 0.0, 0.0),
+                    velocity,
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>();
+            particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+            particles.index().write().unwrap().fill(u32::MAX);
+
+            let mut gravity_task = ApplyGravityTask::new(backend.device());
+            gravity_task.set_constants(ApplyGravityConstants::new(particle_count, dt, gravity));
+            gravity_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut gravity_task);
+
+            let mut hash_task = MortonHashTask::new(backend.device());
+            hash_task.set_constants(MortonHashConstants::new(
+                particle_count,
+                0.1,
+                Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+            ));
+            hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut hash_task);
+
+            let mut update_task = UpdatePositionTask::new(backend.device());
+            update_task.set_constants(UpdatePositionConstants::new(aabb, particle_count, dt, 0.0));
+            update_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut update_task);
+
+            let positions = particles.position().read().unwrap();
+            let velocities = particles.velocity().read().unwrap();
+            let indices = particles.index().read().unwrap();
+            for (i, data) in particle_data.iter().enumerate() {
+                let position = Vec4::from_array(positions[i].position).truncate();
+                let (expected_velocity, expected_position, expected_index) =
+                    if i < particle_count as usize {
+                        let v = velocity + gravity * dt;
+                        (v, data.position + v * dt, i as u32)
+                    } else {
+                        (velocity, data.position, u32::MAX)
+                    };
+                let particle_velocity = Vec4::from_array(velocities[i].velocity).truncate();
+                assert!(
+                    particle_velocity.distance(expected_velocity) < 1e-5,
+                    "{} particles: particle {} velocity {:?}",
+                    particle_count,
+                    i,
+                    particle_velocity
+                );
+                assert!(
+                    position.distance(expected_position) < 1e-5,
+                    "{} particles: particle {} position {:?}",
+                    particle_count,
+                    i,
+                    position
+                );
+                assert_eq!(indices[i], expected_index, "{} particles", particle_count);
+            }
+        }
+    }
+}
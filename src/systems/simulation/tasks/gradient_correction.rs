@@ -0,0 +1,152 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::{core::Particles, systems::simulation::simulation_config::clamp_smoothing_radius};
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Kernel gradient correction (CSPM) constants
+///
+/// Writes the inverse of each particle's kernel moment matrix to `gradient_correction`. The PBD
+/// pass multiplies its spiky gradients by it, which removes the bias of the gradient sum near
+/// free surfaces where the kernel support is only partly filled.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct GradientCorrectionConstants {
+    particle_count: u32,
+    particle_mass: f32,
+    smoothing_radius: f32,
+    spiky_grad_kernel_factor: f32,
+    max_neighbors: u32,
+}
+
+impl GradientCorrectionConstants {
+    pub fn new(particle_count: u32, particle_mass: f32, smoothing_radius: f32) -> Self {
+        let smoothing_radius = clamp_smoothing_radius(smoothing_radius);
+        Self {
+            particle_count,
+            particle_mass,
+            smoothing_radius,
+            // Same -45 / (π * h^6) as the PBD pass
+            spiky_grad_kernel_factor: -45.0 / (std::f32::consts::PI * smoothing_radius.powi(6)),
+            max_neighbors: 64, // Same candidate sampling as the PBD pass
+        }
+    }
+}
+
+impl ComputeGpuTaskConstants for GradientCorrectionConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/gradient_correction.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.predicted_position().clone()),
+            WriteDescriptorSet::buffer(1, particles.density().clone()),
+            WriteDescriptorSet::buffer(2, particles.index().clone()),
+            WriteDescriptorSet::buffer(3, particles.gradient_correction().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type GradientCorrectionTask = ComputeGpuTask<GradientCorrectionConstants>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{Aabb, ParticleInitData, Particles},
+        systems::simulation::tasks::{
+            MortonHashConstants, MortonHashTask, RadixSortSystem, SpikySphConstants, SpikySphTask,
+        },
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::{Mat3, Vec3, Vec4};
+
+    #[test]
+    fn test_corrected_gradient_reproduces_linear_field_at_surface() {
+        let backend = VulkanoHeadlessBackend::new();
+        let (mass, h, spacing) = (0.02, 0.15, 0.06);
+
+        // A 4x4x4 block: few enough particles that every one is a PBD candidate
+        let particle_data = ParticleInitData::grid(64, spacing, Vec3::ZERO);
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.enable_gradient_correction();
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+        particles.copy_position_to_predicted(&backend);
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            h,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut sph_task = SpikySphTask::new(backend.device());
+        sph_task.set_constants(SpikySphConstants::new(particles.count(), mass, h, h));
+        sph_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut sph_task);
+
+        let mut task = GradientCorrectionTask::new(backend.device());
+        task.set_constants(GradientCorrectionConstants::new(particles.count(), mass, h));
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut task);
+
+        // Kernel gradient estimate of f(x) = a·x at the corner particle
+        let a = Vec3::new(1.0, 2.0, 3.0);
+        let densities = particles.download_densities(backend.memory_allocator(), &backend);
+        let grad_factor = -45.0 / (std::f32::consts::PI * h.powi(6));
+        let x_i = particle_data[0].position;
+        let raw = particle_data
+            .iter()
+            .zip(&densities)
+            .skip(1)
+            .map(|(p, &density)| {
+                let r_vec = x_i - p.position;
+                let r = r_vec.length();
+                if r >= h {
+                    return Vec3::ZERO;
+                }
+                let grad = grad_factor * (h - r) * (h - r) * r_vec / r;
+                mass / density * a.dot(p.position - x_i) * grad
+            })
+            .sum::<Vec3>();
+
+        let corrections = particles.gradient_correction().read().unwrap();
+        let correction = Mat3::from_cols(
+            Vec4::from_array(corrections[0][0]).truncate(),
+            Vec4::from_array(corrections[0][1]).truncate(),
+            Vec4::from_array(corrections[0][2]).truncate(),
+        );
+        let corrected = correction * raw;
+
+        let (raw_error, corrected_error) = (raw.distance(a), corrected.distance(a));
+        assert!(
+            corrected_error < raw_error * 0.1,
+            "corrected {} vs raw {}",
+            corrected_error,
+            raw_error
+        );
+        assert!(corrected_error < a.length() * 1e-2, "{}", corrected);
+    }
+}
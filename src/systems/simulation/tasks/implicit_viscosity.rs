@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::{core::Particles, systems::simulation::simulation_config::clamp_smoothing_radius};
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Implicit viscosity solve constants
+///
+/// One Jacobi step of `(I - A) v' = v`, where `A` couples each particle to its
+/// neighbors with weight `viscosity * m / rho_j * W_poly6(r)`. The result is written
+/// to `predicted_velocity`, leaving `velocity` untouched.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct ImplicitViscosityConstants {
+    particle_count: u32,
+    mass: f32,
+    smoothing_radius_sq: f32,
+    poly6_kernel_factor: f32,
+    viscosity: f32,
+    max_neighbors: u32,
+}
+
+impl ImplicitViscosityConstants {
+    pub fn new(particle_count: u32, mass: f32, smoothing_radius: f32, viscosity: f32) -> Self {
+        let smoothing_radius = clamp_smoothing_radius(smoothing_radius);
+        let smoothing_radius_sq = smoothing_radius * smoothing_radius;
+
+        // Poly6 kernel factor: 315 / (64 * π * h^9)
+        let poly6_kernel_factor = 315.0 / (64.0 * std::f32::consts::PI * smoothing_radius.powi(9));
+
+        Self {
+            particle_count,
+            mass,
+            smoothing_radius_sq,
+            poly6_kernel_factor,
+            viscosity,
+            max_neighbors: 64, // Same neighborhood limit as the density pass
+        }
+    }
+}
+
+impl ComputeGpuTaskConstants for ImplicitViscosityConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/implicit_viscosity.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.velocity().clone()),
+            WriteDescriptorSet::buffer(2, particles.predicted_velocity().clone()),
+            WriteDescriptorSet::buffer(3, particles.density().clone()),
+            WriteDescriptorSet::buffer(4, particles.index().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type ImplicitViscosityTask = ComputeGpuTask<ImplicitViscosityConstants>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{Aabb, ParticleInitData, Particles},
+        systems::simulation::tasks::{
+            MortonHashConstants, MortonHashTask, RadixSortSystem, SpikySphConstants, SpikySphTask,
+        },
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::{Vec3, Vec4};
+
+    #[test]
+    fn test_implicit_viscosity_converges_with_viscosity() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+
+        // Two close neighbors moving in opposite directions
+        particles.add_particles(
+            &[
+                ParticleInitData {
+                    position: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(1.0, 0.0, 0.0),
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(0.05, 0.0, 0.0),
+                    velocity: Vec3::new(-1.0, 0.0, 0.0),
+                    ..Default::default()
+                },
+            ],
+            backend.memory_allocator(),
+            &backend,
+        );
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut sph_task = SpikySphTask::new(backend.device());
+        sph_task.set_constants(SpikySphConstants::new(particles.count(), 0.02, 0.2, 0.1));
+        sph_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut sph_task);
+
+        particles.enable_predicted_velocity();
+        let mut task = ImplicitViscosityTask::new(backend.device());
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+
+        let mut relative_speed = |viscosity: f32| {
+            task.set_constants(ImplicitViscosityConstants::new(
+                particles.count(),
+                0.02,
+                0.2,
+                viscosity,
+            ));
+            backend.execute(&mut task);
+
+            let predicted = particles.predicted_velocity().read().unwrap();
+            (Vec4::from_array(predicted[0].velocity) - Vec4::from_array(predicted[1].velocity))
+                .length()
+        };
+
+        let low = relative_speed(0.1);
+        let high = relative_speed(10.0);
+
+        assert!(low < 2.0, "viscosity should reduce relative speed: {}", low);
+        assert!(
+            high < low,
+            "higher viscosity should converge further: high={} low={}",
+            high,
+            low
+        );
+
+        // The explicit velocity buffer is left untouched by the pass
+        let velocities = particles.velocity().read().unwrap();
+        assert_eq!(velocities[0].velocity[0], 1.0);
+        assert_eq!(velocities[1].velocity[0], -1.0);
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::Particles;
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Isolated particle count constants
+///
+/// Counts the particles whose neighbor list is empty into `isolated_count`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct IsolatedCountConstants {
+    particle_count: u32,
+}
+
+impl IsolatedCountConstants {
+    pub fn new(particle_count: u32) -> Self {
+        Self { particle_count }
+    }
+}
+
+impl ComputeGpuTaskConstants for IsolatedCountConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/isolated_count.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.contact_counts().clone()),
+            WriteDescriptorSet::buffer(1, particles.isolated_count().clone()),
+        ]
+    }
+
+    /// A single work group reduces every particle
+    fn particle_count(&self) -> u32 {
+        1
+    }
+}
+
+pub(crate) type IsolatedCountTask = ComputeGpuTask<IsolatedCountConstants>;
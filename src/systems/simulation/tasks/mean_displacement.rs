@@ -0,0 +1,55 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::Particles;
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Mean displacement reduction constants
+///
+/// Averages the distance of each particle from its `search_position` into
+/// `mean_displacement`, to decide whether cached neighbor lists are still usable.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct MeanDisplacementConstants {
+    particle_count: u32,
+}
+
+impl MeanDisplacementConstants {
+    pub fn new(particle_count: u32) -> Self {
+        Self { particle_count }
+    }
+}
+
+impl ComputeGpuTaskConstants for MeanDisplacementConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/mean_displacement.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.search_position().clone()),
+            WriteDescriptorSet::buffer(2, particles.mean_displacement().clone()),
+        ]
+    }
+
+    /// A single work group reduces every particle
+    fn particle_count(&self) -> u32 {
+        1
+    }
+}
+
+pub(crate) type MeanDisplacementTask = ComputeGpuTask<MeanDisplacementConstants>;
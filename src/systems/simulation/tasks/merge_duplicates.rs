@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::Particles;
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Sorted slots searched before and after each particle for duplicates
+const MERGE_WINDOW: u32 = 16;
+
+/// Duplicate merge constants
+///
+/// Marks particles within `merge_distance` of an earlier one in Morton order as absorbed
+/// in `merge_keep` and writes the mass-weighted merged state of every particle, rest
+/// density included, to `merge_state`. Pinned particles are never merged. Needs sorted indices of the current
+/// positions.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct MergeDuplicatesConstants {
+    particle_count: u32,
+    merge_distance_sq: f32,
+    window: u32,
+}
+
+impl MergeDuplicatesConstants {
+    pub fn new(particle_count: u32, merge_distance: f32) -> Self {
+        Self {
+            particle_count,
+            merge_distance_sq: merge_distance * merge_distance,
+            window: MERGE_WINDOW,
+        }
+    }
+}
+
+impl ComputeGpuTaskConstants for MergeDuplicatesConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/merge_duplicates.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.velocity().clone()),
+            WriteDescriptorSet::buffer(2, particles.mass().clone()),
+            WriteDescriptorSet::buffer(3, particles.pinned().clone()),
+            WriteDescriptorSet::buffer(4, particles.sorted_indices().clone()),
+            WriteDescriptorSet::buffer(5, particles.merge_state().clone()),
+            WriteDescriptorSet::buffer(6, particles.merge_keep().clone()),
+            WriteDescriptorSet::buffer(7, particles.rest_density().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type MergeDuplicatesTask = ComputeGpuTask<MergeDuplicatesConstants>;
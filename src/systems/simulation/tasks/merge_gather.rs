@@ -0,0 +1,60 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::Particles;
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Merge gather constants
+///
+/// Writes the merged state of the compacted `merge_survivors` to the front of the
+/// position, velocity, mass, pinned and, with phases, rest density buffers. `particle_count` is the count before the
+/// merge; slots past `merge_count` are left alone.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct MergeGatherConstants {
+    particle_count: u32,
+}
+
+impl MergeGatherConstants {
+    pub fn new(particle_count: u32) -> Self {
+        Self { particle_count }
+    }
+}
+
+impl ComputeGpuTaskConstants for MergeGatherConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/merge_gather.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.merge_survivors().clone()),
+            WriteDescriptorSet::buffer(1, particles.merge_count().clone()),
+            WriteDescriptorSet::buffer(2, particles.merge_state().clone()),
+            WriteDescriptorSet::buffer(3, particles.position().clone()),
+            WriteDescriptorSet::buffer(4, particles.velocity().clone()),
+            WriteDescriptorSet::buffer(5, particles.mass().clone()),
+            WriteDescriptorSet::buffer(6, particles.pinned().clone()),
+            WriteDescriptorSet::buffer(7, particles.rest_density().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type MergeGatherTask = ComputeGpuTask<MergeGatherConstants>;
@@ -1,22 +1,58 @@
 mod compute_task;
 
+mod accumulate_density;
 mod adaptive_sort_system;
+mod apply_force_field;
 mod apply_gravity;
+mod apply_impulse;
+mod boundary_velocity;
+mod compact;
+mod field_grid;
+mod gradient_correction;
+mod implicit_viscosity;
+mod isolated_count;
+mod mean_displacement;
+mod merge_duplicates;
+mod merge_gather;
+mod moments;
 mod morton_hash;
+mod neighbor_search;
 mod prefix_sum;
 mod radix_sort;
 mod radix_sort_histogram;
 mod radix_sort_system;
+mod reduction;
+mod remove_particles;
+mod sdf_collision;
+mod smoothed_velocity;
 mod spiky_sph;
+mod surface_normal;
 mod update_position;
+mod vorticity_confinement;
+mod xsph_viscosity;
 // TODO: Add PBD constraint solver
 // mod pbd_constraint_solver;
 mod pbd_density_constraint;
 
-#[allow(unused)]
+pub(super) use accumulate_density::{AccumulateDensityConstants, AccumulateDensityTask};
 pub(super) use adaptive_sort_system::AdaptiveSortSystem;
+pub(super) use apply_force_field::{ApplyForceFieldConstants, ApplyForceFieldTask};
 pub(super) use apply_gravity::{ApplyGravityConstants, ApplyGravityTask};
+pub(super) use apply_impulse::{ApplyImpulseConstants, ApplyImpulseTask};
+pub(super) use boundary_velocity::{BoundaryVelocityConstants, BoundaryVelocityTask};
+#[allow(unused)]
+pub(super) use compact::{CompactConstants, CompactTask};
+pub use field_grid::GridField;
+pub(super) use field_grid::{FieldGridConstants, FieldGridTask};
+pub(super) use gradient_correction::{GradientCorrectionConstants, GradientCorrectionTask};
+pub(super) use implicit_viscosity::{ImplicitViscosityConstants, ImplicitViscosityTask};
+pub(super) use isolated_count::{IsolatedCountConstants, IsolatedCountTask};
+pub(super) use mean_displacement::{MeanDisplacementConstants, MeanDisplacementTask};
+pub(super) use merge_duplicates::{MergeDuplicatesConstants, MergeDuplicatesTask};
+pub(super) use merge_gather::{MergeGatherConstants, MergeGatherTask};
+pub(super) use moments::{center_of_mass, MomentsConstants, MomentsTask};
 pub(super) use morton_hash::{MortonHashConstants, MortonHashTask};
+pub(super) use neighbor_search::{NeighborSearchConstants, NeighborSearchTask};
 #[allow(unused)]
 pub(super) use prefix_sum::{PrefixSumConstants, PrefixSumTask};
 #[allow(unused)]
@@ -25,7 +61,16 @@ pub(super) use radix_sort::{RadixSortConstants, RadixSortTask};
 pub(super) use radix_sort_histogram::{RadixSortCountConstants, RadixSortCountTask};
 #[allow(unused)]
 pub(super) use radix_sort_system::RadixSortSystem;
+pub(super) use reduction::{ReduceAabbTask, ReduceMaxDisplacementTask, ReduceMaxSpeedTask};
+pub(super) use remove_particles::{RemoveParticlesConstants, RemoveParticlesTask};
+pub(super) use sdf_collision::{SdfCollisionConstants, SdfCollisionTask};
+pub(super) use smoothed_velocity::{SmoothedVelocityConstants, SmoothedVelocityTask};
 pub(super) use spiky_sph::{SpikySphConstants, SpikySphTask};
+pub(super) use surface_normal::{SurfaceNormalConstants, SurfaceNormalTask};
 pub(super) use update_position::{UpdatePositionConstants, UpdatePositionTask};
+pub(super) use vorticity_confinement::{
+    VorticityConfinementConstants, VorticityConfinementTask, VorticityPass,
+};
+pub(super) use xsph_viscosity::{XsphViscosityConstants, XsphViscosityTask};
 // pub(crate) use pbd_constraint_solver::*;
 pub(super) use pbd_density_constraint::{PbdDensityConstraintConstants, PbdDensityConstraintTask};
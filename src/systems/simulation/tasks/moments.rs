@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use glam::{Vec3, Vec4};
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::Particles;
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Mass moment reduction constants
+///
+/// Sums `m * position`, `m` and `m * velocity` over all particles into the two entries of
+/// `moments`, for center of mass and total momentum.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct MomentsConstants {
+    particle_count: u32,
+    particle_mass: f32,
+}
+
+impl MomentsConstants {
+    pub fn new(particle_count: u32, particle_mass: f32) -> Self {
+        Self {
+            particle_count,
+            particle_mass,
+        }
+    }
+}
+
+impl ComputeGpuTaskConstants for MomentsConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/moments.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.velocity().clone()),
+            WriteDescriptorSet::buffer(2, particles.moments().clone()),
+        ]
+    }
+
+    /// A single work group reduces every particle
+    fn particle_count(&self) -> u32 {
+        1
+    }
+}
+
+pub(crate) type MomentsTask = ComputeGpuTask<MomentsConstants>;
+
+/// Mass-weighted mean position from the `(sum(m * x), sum(m))` moment, or the origin
+/// without mass
+pub fn center_of_mass(position_moment: Vec4) -> Vec3 {
+    if position_moment.w > 0.0 {
+        position_moment.truncate() / position_moment.w
+    } else {
+        Vec3::ZERO
+    }
+}
@@ -4,22 +4,50 @@ use vulkano::{
     buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
 };
 
+use crate::{core::Aabb, systems::simulation::simulation_config::SortPositionMode};
+
 use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, BufferContents)]
 pub struct MortonHashConstants {
+    aabb_min: [f32; 4],
+    aabb_max: [f32; 4],
     particle_count: u32,
     grid_size: f32,
+    clamp_to_bounds: u32,
+    normalize_to_bounds: u32,
 }
 
 impl MortonHashConstants {
-    pub fn new(particle_count: u32, grid_size: f32) -> Self {
+    /// Hash `grid_size` cells counted from `aabb.min()`, so every cell coordinate is
+    /// non-negative
+    pub fn new(particle_count: u32, grid_size: f32, aabb: Aabb) -> Self {
         Self {
+            aabb_min: aabb.min().extend(0.0).to_array(),
+            aabb_max: aabb.max().extend(0.0).to_array(),
             particle_count,
             grid_size,
+            clamp_to_bounds: 0,
+            normalize_to_bounds: 0,
         }
     }
+
+    /// Apply `mode` to positions before use; must match the other sort pass
+    pub fn with_sort_position_mode(mut self, mode: SortPositionMode) -> Self {
+        self.clamp_to_bounds = (mode == SortPositionMode::Clamped) as u32;
+        self
+    }
+
+    /// Split each axis into 1024 cells over its extent of the bounds instead of using
+    /// `grid_size` cells
+    ///
+    /// A tall or flat domain then keeps full code resolution on every axis. Positions
+    /// outside the bounds share the boundary cells.
+    pub fn with_normalized_axes(mut self) -> Self {
+        self.normalize_to_bounds = 1;
+        self
+    }
 }
 
 impl ComputeGpuTaskConstants for MortonHashConstants {
@@ -64,7 +92,7 @@ mod tests {
 
     #[test]
     fn test_morton_hash() {
-        use crate::utils::VulkanoHeadlessBackend;
+        use crate::{core::Aabb, utils::VulkanoHeadlessBackend};
         use glam::Vec3;
 
         let backend = VulkanoHeadlessBackend::new();
@@ -74,40 +102,142 @@ mod tests {
             &[
                 ParticleInitData {
                     position: Vec3::new(-1.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, -1.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, -1.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
             ],
             backend.memory_allocator(),
             &backend,
         );
 
-        let constants = MortonHashConstants {
-            particle_count: particles.count(),
-            grid_size: 1.0,
-        };
+        let constants = MortonHashConstants::new(
+            particles.count(),
+            1.0,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        );
         let mut task = MortonHashTask::new(backend.device());
         task.set_constants(constants);
-        task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
 
         backend.execute(&mut task);
 
         let result_entries = particles.hash().read().unwrap();
-        let expected_entries = vec![
-            0b0100_1001_0010_0100_1001_0010_0100_1001u32,
-            0b1001_0010_0100_1001_0010_0100_1001_0010u32,
-            0b0010_0100_1001_0010_0100_1001_0010_0100u32,
-        ];
+        // Cells (1, 2, 2), (2, 1, 2) and (2, 2, 1) counted from the bounds minimum
+        let expected_entries = [0b110_001u32, 0b101_010u32, 0b011_100u32];
         assert_eq!(particles.count() as usize, expected_entries.len());
         for (r, e) in result_entries.iter().zip(expected_entries.iter()) {
             assert_eq!(r, e);
         }
     }
+
+    #[test]
+    fn test_tall_domain_resolves_every_axis_fully() {
+        use crate::{core::Aabb, utils::VulkanoHeadlessBackend};
+        use glam::Vec3;
+
+        let backend = VulkanoHeadlessBackend::new();
+        // A 1x8x1 tank standing on the origin
+        let aabb = Aabb::new(Vec3::ZERO, Vec3::new(1.0, 8.0, 1.0));
+        let extent = aabb.max() - aabb.min();
+        // One code step along each axis from the same point, scaled to that axis's extent
+        let base = Vec3::new(0.25, 2.0, 0.25);
+        let positions = [
+            aabb.min(),
+            aabb.max(),
+            base,
+            base + Vec3::X * extent.x / 1023.0,
+            base + Vec3::Y * extent.y / 1023.0,
+            base + Vec3::Z * extent.z / 1023.0,
+        ];
+
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &positions.map(|position| ParticleInitData {
+                position,
+                velocity: Vec3::ZERO,
+                ..Default::default()
+            }),
+            backend.memory_allocator(),
+            &backend,
+        );
+        let mut task = MortonHashTask::new(backend.device());
+        task.set_constants(
+            MortonHashConstants::new(particles.count(), 0.1, aabb).with_normalized_axes(),
+        );
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut task);
+
+        let hashes = particles.hash().read().unwrap();
+        // The far corner takes the top code on every axis instead of wrapping
+        assert_eq!(hashes[0], 0);
+        assert_eq!(hashes[1], 0x3FFF_FFFF);
+        // A step of 1/1023 of the extent moves exactly one cell on the tall axis as on the
+        // short ones: the codes differ only in that axis's lowest interleaved bit
+        let decode_axis = |code: u32, axis: u32| {
+            (0..10).fold(0, |value, bit| {
+                value | ((code >> (bit * 3 + axis)) & 1) << bit
+            })
+        };
+        for axis in 0..3 {
+            let here = decode_axis(hashes[2], axis);
+            let stepped = decode_axis(hashes[3 + axis as usize], axis);
+            assert_eq!(stepped, here + 1, "axis {}", axis);
+            for other in (0..3).filter(|&other| other != axis) {
+                assert_eq!(
+                    decode_axis(hashes[3 + axis as usize], other),
+                    decode_axis(hashes[2], other)
+                );
+            }
+        }
+        assert_eq!(decode_axis(hashes[2], 1), (2.0f32 / 8.0 * 1023.0) as u32);
+    }
+
+    #[test]
+    fn test_cells_are_counted_from_the_bounds_minimum() {
+        use crate::{core::Aabb, utils::VulkanoHeadlessBackend};
+        use glam::{UVec3, Vec3};
+
+        let backend = VulkanoHeadlessBackend::new();
+        let aabb = Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0));
+        let grid_size = 0.25;
+        // Mirror images across the origin, which used to wrap to distant cells
+        let positions = [Vec3::splat(-0.5), Vec3::splat(0.5)];
+
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &positions.map(|position| ParticleInitData {
+                position,
+                velocity: Vec3::ZERO,
+                ..Default::default()
+            }),
+            backend.memory_allocator(),
+            &backend,
+        );
+        let mut task = MortonHashTask::new(backend.device());
+        task.set_constants(MortonHashConstants::new(particles.count(), grid_size, aabb));
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut task);
+
+        let morton = |cell: UVec3| {
+            (0..10).fold(0u32, |code, bit| {
+                code | ((cell.x >> bit) & 1) << (bit * 3)
+                    | ((cell.y >> bit) & 1) << (bit * 3 + 1)
+                    | ((cell.z >> bit) & 1) << (bit * 3 + 2)
+            })
+        };
+        let hashes = particles.hash().read().unwrap();
+        assert_eq!(hashes[0], morton(UVec3::splat(6)));
+        assert_eq!(hashes[1], morton(UVec3::splat(10)));
+        assert!(hashes[0] < hashes[1]);
+    }
 }
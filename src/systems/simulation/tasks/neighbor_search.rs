@@ -0,0 +1,390 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::{
+    core::{Aabb, Particles, CONTACTS_PER_PARTICLE},
+    systems::simulation::simulation_config::SortPositionMode,
+};
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Neighbor list construction constants
+///
+/// Writes each particle's neighbors within the smoothing radius to `contacts`,
+/// at most `max_contacts` per particle, and the number written to `contact_counts`.
+/// Particles with more neighbors than that are added to `neighbor_overflow`, which has
+/// to be zeroed beforehand.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct NeighborSearchConstants {
+    aabb_min: [f32; 4],
+    aabb_max: [f32; 4],
+    particle_count: u32,
+    smoothing_radius_sq: f32,
+    max_neighbors: u32,
+    max_contacts: u32,
+    contact_stride: u32,
+    clamp_to_bounds: u32,
+}
+
+impl NeighborSearchConstants {
+    pub fn new(particle_count: u32, smoothing_radius: f32, max_contacts: u32) -> Self {
+        Self {
+            aabb_min: [0.0; 4],
+            aabb_max: [0.0; 4],
+            particle_count,
+            smoothing_radius_sq: smoothing_radius * smoothing_radius,
+            max_neighbors: 64, // Same candidate sampling as the density pass
+            max_contacts: max_contacts.min(CONTACTS_PER_PARTICLE),
+            contact_stride: CONTACTS_PER_PARTICLE,
+            clamp_to_bounds: 0,
+        }
+    }
+
+    /// Apply `mode` to positions before use; must match the other sort pass
+    pub fn with_bounds(mut self, aabb: Aabb, mode: SortPositionMode) -> Self {
+        self.aabb_min = aabb.min().extend(0.0).to_array();
+        self.aabb_max = aabb.max().extend(0.0).to_array();
+        self.clamp_to_bounds = (mode == SortPositionMode::Clamped) as u32;
+        self
+    }
+}
+
+impl ComputeGpuTaskConstants for NeighborSearchConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/neighbor_search.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.index().clone()),
+            WriteDescriptorSet::buffer(2, particles.contacts().clone()),
+            WriteDescriptorSet::buffer(3, particles.contact_counts().clone()),
+            WriteDescriptorSet::buffer(4, particles.neighbor_overflow().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type NeighborSearchTask = ComputeGpuTask<NeighborSearchConstants>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{ParticleInitData, Particles},
+        systems::simulation::tasks::{MortonHashConstants, MortonHashTask, RadixSortSystem},
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::{UVec3, Vec3};
+
+    #[test]
+    fn test_neighbor_search_finds_particles_within_radius() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.enable_contacts();
+
+        // Particles 0 and 1 are neighbors, particle 2 is far away
+        particles.add_particles(
+            &[
+                ParticleInitData {
+                    position: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(0.05, 0.0, 0.0),
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(1.0, 1.0, 1.0),
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                },
+            ],
+            backend.memory_allocator(),
+            &backend,
+        );
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut task = NeighborSearchTask::new(backend.device());
+        task.set_constants(NeighborSearchConstants::new(particles.count(), 0.2, 32));
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut task);
+
+        let contacts = particles.contacts().read().unwrap();
+        let contact_counts = particles.contact_counts().read().unwrap();
+        let stride = CONTACTS_PER_PARTICLE as usize;
+
+        assert_eq!(&contact_counts[..3], &[1, 1, 0]);
+        assert_eq!(contacts[0], 1);
+        assert_eq!(contacts[stride], 0);
+    }
+
+    #[test]
+    fn test_clamped_sort_position_agrees_between_hash_and_search() {
+        let backend = VulkanoHeadlessBackend::new();
+        let aabb = Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0));
+        let (grid_size, h) = (0.25, 0.1);
+
+        // Particle 0 has left the bounds; clamped, it sits 0.05 from particle 1
+        let particle_data = [Vec3::new(2.1, 0.0, 0.0), Vec3::new(1.95, 0.0, 0.0)];
+
+        for mode in [SortPositionMode::Free, SortPositionMode::Clamped] {
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.enable_contacts();
+            particles.add_particles(
+                &particle_data.map(|position| ParticleInitData {
+                    position,
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                }),
+                backend.memory_allocator(),
+                &backend,
+            );
+
+            let mut hash_task = MortonHashTask::new(backend.device());
+            hash_task.set_constants(
+                MortonHashConstants::new(particles.count(), grid_size, aabb)
+                    .with_sort_position_mode(mode),
+            );
+            hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut hash_task);
+
+            // The hashed cell is the cell of the position neighbor search measures from
+            let hashes = particles.hash().read().unwrap()[..2].to_vec();
+            for (hash, position) in hashes.iter().zip(particle_data) {
+                let cell = ((mode.apply(position, aabb) - aabb.min()) / grid_size)
+                    .floor()
+                    .as_ivec3();
+                assert_eq!(*hash, morton(cell.as_uvec3()), "{:?}", mode);
+            }
+
+            let mut sort_system = RadixSortSystem::new(backend.device());
+            sort_system.sort_morton_codes(
+                &mut particles,
+                backend.descriptor_set_allocator(),
+                &backend,
+            );
+
+            let mut task = NeighborSearchTask::new(backend.device());
+            task.set_constants(
+                NeighborSearchConstants::new(particles.count(), h, 32).with_bounds(aabb, mode),
+            );
+            task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut task);
+
+            let expected = match mode {
+                SortPositionMode::Free => [0, 0],
+                SortPositionMode::Clamped => [1, 1],
+            };
+            let contact_counts = particles.contact_counts().read().unwrap();
+            assert_eq!(&contact_counts[..2], &expected, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn test_spawning_across_work_group_boundary_rebuilds_descriptor_sets() {
+        let backend = VulkanoHeadlessBackend::new();
+        let h = 0.1;
+        let lattice = ParticleInitData::block(UVec3::splat(10), 0.06, Vec3::ZERO);
+
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.enable_contacts();
+        let mut hash_task = MortonHashTask::new(backend.device());
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        let mut task = NeighborSearchTask::new(backend.device());
+        let task_id = std::any::TypeId::of::<NeighborSearchTask>();
+
+        let mut previous_set = None;
+        // One work group, then a spawn that needs two
+        for (start, end) in [(0, 200), (200, 300)] {
+            particles.add_particles(&lattice[start..end], backend.memory_allocator(), &backend);
+
+            hash_task.set_constants(MortonHashConstants::new(
+                particles.count(),
+                h,
+                Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+            ));
+            hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut hash_task);
+            sort_system.sort_morton_codes(
+                &mut particles,
+                backend.descriptor_set_allocator(),
+                &backend,
+            );
+            task.set_constants(NeighborSearchConstants::new(particles.count(), h, 32));
+            task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut task);
+
+            let descriptor_set = particles.descriptor_sets()[&task_id].clone();
+            if let Some(previous_set) = previous_set.replace(descriptor_set.clone()) {
+                assert!(!Arc::ptr_eq(&previous_set, &descriptor_set));
+            }
+
+            // Same strided candidates as the shader
+            let count = particles.count() as usize;
+            let positions = lattice[..end]
+                .iter()
+                .map(|particle| particle.position)
+                .collect::<Vec<_>>();
+            let sorted_indices =
+                particles.snapshot_sorted_indices(backend.memory_allocator(), &backend);
+            let (search_count, step) = if count > 64 {
+                (64, count / 64)
+            } else {
+                (count, 1)
+            };
+            let contact_counts = particles.contact_counts().read().unwrap();
+            for i in 0..count {
+                let expected = (0..search_count)
+                    .map(|k| sorted_indices[(k * step) % count] as usize)
+                    .filter(|&j| j != i && positions[i].distance_squared(positions[j]) < h * h)
+                    .count()
+                    .min(32);
+                assert_eq!(contact_counts[i] as usize, expected, "particle {}", i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_neighbor_stats_on_dense_grid() {
+        let backend = VulkanoHeadlessBackend::new();
+        let (h, max_contacts) = (0.1, 16);
+        let mut particles = Particles::new(backend.memory_allocator());
+        assert_eq!(particles.neighbor_stats(&backend), None);
+
+        particles.enable_contacts();
+        // Spacing well under h gives every particle several neighbors, more than fit
+        particles.add_particles(
+            &ParticleInitData::grid(512, 0.04, Vec3::ZERO),
+            backend.memory_allocator(),
+            &backend,
+        );
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            h,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut task = NeighborSearchTask::new(backend.device());
+        task.set_constants(NeighborSearchConstants::new(
+            particles.count(),
+            h,
+            max_contacts,
+        ));
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut task);
+
+        let stats = particles.neighbor_stats(&backend).unwrap();
+        assert!(stats.mean > 0.0, "{:?}", stats);
+        assert!(stats.max <= max_contacts, "{:?}", stats);
+        assert!(stats.min as f32 <= stats.mean && stats.mean <= stats.max as f32);
+        assert!(stats.particles_with_neighbors <= particles.count());
+    }
+
+    #[test]
+    fn test_overpacked_particles_overflow_without_spilling() {
+        let backend = VulkanoHeadlessBackend::new();
+        let h = 0.1;
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.enable_contacts();
+        // 64 particles within h of each other: 63 neighbors each
+        particles.add_particles(
+            &ParticleInitData::block(glam::UVec3::splat(4), 0.01, Vec3::ZERO),
+            backend.memory_allocator(),
+            &backend,
+        );
+        let count = particles.count() as usize;
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            h,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut task = NeighborSearchTask::new(backend.device());
+        for (max_contacts, expected_overflow) in [(CONTACTS_PER_PARTICLE, 0), (32, count as u32)] {
+            particles.clear_neighbor_overflow_count(&backend);
+            task.set_constants(NeighborSearchConstants::new(
+                particles.count(),
+                h,
+                max_contacts,
+            ));
+            task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut task);
+
+            assert_eq!(
+                particles.neighbor_overflow_count(&backend),
+                Some(expected_overflow)
+            );
+
+            // A list written past its stride would land in the next particle's list
+            let contacts = particles.contacts().read().unwrap();
+            let contact_counts = particles.contact_counts().read().unwrap();
+            let stride = CONTACTS_PER_PARTICLE as usize;
+            for i in 0..count {
+                assert_eq!(contact_counts[i], max_contacts.min(63), "particle {}", i);
+                let mut list =
+                    contacts[i * stride..i * stride + contact_counts[i] as usize].to_vec();
+                assert!(list
+                    .iter()
+                    .all(|&j| (j as usize) < count && j as usize != i));
+                list.sort_unstable();
+                list.dedup();
+                assert_eq!(list.len(), contact_counts[i] as usize, "particle {}", i);
+            }
+        }
+    }
+
+    fn morton(cell: glam::UVec3) -> u32 {
+        let expand = |v: u32| {
+            let v = v.wrapping_mul(0x0001_0001) & 0xFF00_00FF;
+            let v = v.wrapping_mul(0x0000_0101) & 0x0F00_F00F;
+            let v = v.wrapping_mul(0x0000_0011) & 0xC30C_30C3;
+            v.wrapping_mul(0x0000_0005) & 0x4924_9249
+        };
+        expand(cell.x) | (expand(cell.y) << 1) | (expand(cell.z) << 2)
+    }
+}
@@ -4,7 +4,12 @@ use vulkano::{
     buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
 };
 
-use crate::core::Particles;
+use crate::{
+    core::Particles,
+    systems::simulation::simulation_config::{
+        clamp_smoothing_radius, DensityKernel, PbdSolveOrder,
+    },
+};
 
 use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
 
@@ -23,6 +28,18 @@ pub struct PbdDensityConstraintConstants {
     constraint_epsilon: f32,
     relaxation_factor: f32,
     max_neighbors: u32,
+    gradient_correction: u32,
+    color: u32,
+    color_count: u32,
+    color_cell_size: f32,
+    particle_mass: f32,
+    density_kernel_factor: f32,
+    density_kernel: u32,
+    max_lambda: f32,
+    max_displacement: f32,
+    s_corr_k: f32,
+    s_corr_n: u32,
+    s_corr_delta_q: f32,
 }
 
 impl PbdDensityConstraintConstants {
@@ -33,6 +50,7 @@ impl PbdDensityConstraintConstants {
         constraint_epsilon: f32,
         relaxation_factor: f32,
     ) -> Self {
+        let smoothing_radius = clamp_smoothing_radius(smoothing_radius);
         let smoothing_radius_sq = smoothing_radius * smoothing_radius;
 
         // Spiky kernel factor: 15 / (π * h^6)
@@ -51,8 +69,75 @@ impl PbdDensityConstraintConstants {
             constraint_epsilon,
             relaxation_factor,
             max_neighbors: 64, // 限制邻居粒子数量为64
+            gradient_correction: 0,
+            color: 0,
+            color_count: 1,
+            color_cell_size: smoothing_radius,
+            particle_mass: 0.0,
+            density_kernel_factor: 0.0,
+            density_kernel: 0,
+            max_lambda: f32::MAX,
+            max_displacement: smoothing_radius * 0.1,
+            s_corr_k: 0.0,
+            s_corr_n: 4,
+            s_corr_delta_q: smoothing_radius * 0.2,
         }
     }
+
+    /// Limit |λ| and the correction of a single solve, the latter as a fraction of the smoothing radius
+    pub fn with_clamps(mut self, max_lambda: Option<f32>, max_displacement: f32) -> Self {
+        self.max_lambda = max_lambda.unwrap_or(f32::MAX);
+        self.max_displacement = max_displacement * self.smoothing_radius;
+        self
+    }
+
+    /// Add the tensile instability correction s_corr = -k (W(r) / W(Δq))^n to each neighbor term,
+    /// with `delta_q` as a fraction of the smoothing radius. `k` of 0 disables it.
+    ///
+    /// Scaled by m / ρ_0, so it needs the particle mass from [`Self::with_solve_order`].
+    pub fn with_tensile_correction(mut self, k: f32, n: u32, delta_q: f32) -> Self {
+        self.s_corr_k = k.max(0.0);
+        self.s_corr_n = n;
+        self.s_corr_delta_q = delta_q * self.smoothing_radius;
+        self
+    }
+
+    /// Multiply neighbor gradients by the matrices from the gradient correction pass
+    pub fn with_gradient_correction(mut self, enabled: bool) -> Self {
+        self.gradient_correction = enabled as u32;
+        self
+    }
+
+    /// Solve in one batch, or in 8 cell colors that re-evaluate density with `density_kernel`
+    pub fn with_solve_order(
+        mut self,
+        solve_order: PbdSolveOrder,
+        particle_mass: f32,
+        density_kernel: DensityKernel,
+    ) -> Self {
+        self.color_count = match solve_order {
+            PbdSolveOrder::Jacobi => 1,
+            PbdSolveOrder::Colored => 8,
+        };
+        self.particle_mass = particle_mass;
+        self.density_kernel = match density_kernel {
+            DensityKernel::Poly6 => 0,
+            DensityKernel::Spiky => 1,
+        };
+        self.density_kernel_factor = density_kernel.factor(self.smoothing_radius);
+        self
+    }
+
+    /// Dispatches needed per iteration, one per color
+    pub fn color_count(&self) -> u32 {
+        self.color_count
+    }
+
+    /// Only correct particles whose cell has this color
+    pub fn with_color(mut self, color: u32) -> Self {
+        self.color = color;
+        self
+    }
 }
 
 impl ComputeGpuTaskConstants for PbdDensityConstraintConstants {
@@ -75,7 +160,11 @@ impl ComputeGpuTaskConstants for PbdDensityConstraintConstants {
             WriteDescriptorSet::buffer(1, particles.predicted_position().clone()), // 预测位置（将被修改）(binding 1)
             WriteDescriptorSet::buffer(2, particles.density().clone()), // 密度值 (binding 2)
             WriteDescriptorSet::buffer(3, particles.index().clone()),   // 排序后的索引 (binding 3)
-                                                                        // 移除 hash 缓冲区绑定以匹配着色器
+            WriteDescriptorSet::buffer(4, particles.pinned().clone()),  // Pinned flags (binding 4)
+            WriteDescriptorSet::buffer(5, particles.gradient_correction().clone()), // CSPM matrices (binding 5)
+            WriteDescriptorSet::buffer(6, particles.mass().clone()),
+            WriteDescriptorSet::buffer(7, particles.rest_density().clone()),
+            WriteDescriptorSet::buffer(8, particles.boundary().clone()),
         ]
     }
 
@@ -90,13 +179,13 @@ pub(crate) type PbdDensityConstraintTask = ComputeGpuTask<PbdDensityConstraintCo
 mod tests {
     use super::*;
     use crate::{
-        core::{ParticleInitData, Particles},
+        core::{Aabb, ParticleInitData, Particles},
         systems::simulation::tasks::{
             MortonHashConstants, MortonHashTask, RadixSortSystem, SpikySphConstants, SpikySphTask,
         },
         utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
     };
-    use glam::{Vec3, Vec4};
+    use glam::{UVec3, Vec3, Vec4};
 
     #[test]
     fn test_pbd_density_constraint() {
@@ -108,19 +197,23 @@ mod tests {
             &[
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.05, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.05, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.05, 0.05, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
             ],
             backend.memory_allocator(),
@@ -131,19 +224,19 @@ mod tests {
         particles.copy_position_to_predicted(&backend);
 
         // 执行Morton哈希计算
-        let hash_constants = MortonHashConstants::new(particles.count(), 0.1);
+        let hash_constants = MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        );
         let mut hash_task = MortonHashTask::new(backend.device());
         hash_task.set_constants(hash_constants);
-        hash_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut hash_task);
 
         // 执行排序
         let mut sort_system = RadixSortSystem::new(backend.device());
-        sort_system.sort_morton_codes(
-            &mut particles,
-            &backend.descriptor_set_allocator(),
-            &backend,
-        );
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
 
         // 执行SPH密度计算
         let sph_constants = SpikySphConstants::new(
@@ -155,7 +248,7 @@ mod tests {
 
         let mut sph_task = SpikySphTask::new(backend.device());
         sph_task.set_constants(sph_constants);
-        sph_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        sph_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut sph_task);
 
         // 执行PBD密度约束
@@ -169,7 +262,7 @@ mod tests {
 
         let mut constraint_task = PbdDensityConstraintTask::new(backend.device());
         constraint_task.set_constants(constraint_constants);
-        constraint_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        constraint_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
 
         backend.execute(&mut constraint_task);
 
@@ -208,7 +301,8 @@ mod tests {
 
             particle_data.push(ParticleInitData {
                 position: Vec3::new(x, y, z),
-                velocitie: Vec3::new(0.0, 0.0, 0.0),
+                velocity: Vec3::new(0.0, 0.0, 0.0),
+                ..Default::default()
             });
         }
 
@@ -216,24 +310,24 @@ mod tests {
         particles.copy_position_to_predicted(&backend);
 
         // 预处理：哈希和排序
-        let hash_constants = MortonHashConstants::new(particles.count(), 0.1);
+        let hash_constants = MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        );
         let mut hash_task = MortonHashTask::new(backend.device());
         hash_task.set_constants(hash_constants);
-        hash_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut hash_task);
 
         let mut sort_system = RadixSortSystem::new(backend.device());
-        sort_system.sort_morton_codes(
-            &mut particles,
-            &backend.descriptor_set_allocator(),
-            &backend,
-        );
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
 
         // SPH密度计算计时
         let sph_constants = SpikySphConstants::new(particles.count(), 0.02, 0.2, 0.1);
         let mut sph_task = SpikySphTask::new(backend.device());
         sph_task.set_constants(sph_constants);
-        sph_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        sph_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
 
         let sph_start = Instant::now();
         backend.execute(&mut sph_task);
@@ -244,7 +338,7 @@ mod tests {
             PbdDensityConstraintConstants::new(particles.count(), 1000.0, 0.2, 0.001, 0.3);
         let mut constraint_task = PbdDensityConstraintTask::new(backend.device());
         constraint_task.set_constants(constraint_constants);
-        constraint_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        constraint_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
 
         // 单次PBD执行计时
         let pbd_single_start = Instant::now();
@@ -302,4 +396,314 @@ mod tests {
         assert!(pbd_iterations_time.as_secs_f64() > pbd_single_time.as_secs_f64() * 2.0);
         // 降低倍数要求
     }
+
+    #[test]
+    fn test_pbd_separates_coincident_particles() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+
+        // Two particles at exactly the same position
+        particles.add_particles(
+            &[
+                ParticleInitData {
+                    position: Vec3::new(0.5, 0.5, 0.5),
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(0.5, 0.5, 0.5),
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                },
+            ],
+            backend.memory_allocator(),
+            &backend,
+        );
+        particles.copy_position_to_predicted(&backend);
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut sph_task = SpikySphTask::new(backend.device());
+        sph_task.set_constants(SpikySphConstants::new(particles.count(), 0.02, 0.2, 0.1));
+        sph_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut sph_task);
+
+        // Each particle's density counts the other one as a neighbor
+        {
+            let densities = particles.density().read().unwrap();
+            let self_density = 0.02 * 315.0 / (64.0 * std::f32::consts::PI * 0.2f32.powi(3));
+            for density in &densities[..2] {
+                assert!((density / self_density - 2.0).abs() < 1e-3);
+            }
+        }
+
+        // A low rest density makes the pair over-dense so the constraint pushes them apart
+        let mut constraint_task = PbdDensityConstraintTask::new(backend.device());
+        constraint_task.set_constants(PbdDensityConstraintConstants::new(
+            particles.count(),
+            1.0,
+            0.2,
+            0.001,
+            0.3,
+        ));
+        constraint_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut constraint_task);
+
+        let predicted = particles.predicted_position().read().unwrap();
+        let a = Vec4::from_array(predicted[0].position).truncate();
+        let b = Vec4::from_array(predicted[1].position).truncate();
+        let center = Vec3::new(0.5, 0.5, 0.5);
+
+        assert!(a.is_finite() && b.is_finite());
+        assert!(
+            a.distance(b) > 0.0,
+            "coincident particles were not separated"
+        );
+        assert!(
+            ((a - center) + (b - center)).length() < 1e-5,
+            "coincident particles should move symmetrically: {:?} {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn test_compressed_pair_correction_is_clamped() {
+        let backend = VulkanoHeadlessBackend::new();
+        let h = 0.2;
+        let start = [
+            Vec3::new(0.5, 0.5, 0.5),
+            Vec3::new(0.5 + 0.01 * h, 0.5, 0.5),
+        ];
+
+        let solve = |max_lambda: Option<f32>, max_displacement: f32| {
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.add_particles(
+                &start.map(|position| ParticleInitData {
+                    position,
+                    velocity: Vec3::ZERO,
+                    ..Default::default()
+                }),
+                backend.memory_allocator(),
+                &backend,
+            );
+            particles.copy_position_to_predicted(&backend);
+
+            let mut hash_task = MortonHashTask::new(backend.device());
+            hash_task.set_constants(MortonHashConstants::new(
+                particles.count(),
+                0.1,
+                Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+            ));
+            hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut hash_task);
+            let mut sort_system = RadixSortSystem::new(backend.device());
+            sort_system.sort_morton_codes(
+                &mut particles,
+                backend.descriptor_set_allocator(),
+                &backend,
+            );
+
+            let mut sph_task = SpikySphTask::new(backend.device());
+            sph_task.set_constants(SpikySphConstants::new(particles.count(), 0.02, h, 0.1));
+            sph_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut sph_task);
+
+            // A rest density far below the pair's makes the unclamped correction huge
+            let mut constraint_task = PbdDensityConstraintTask::new(backend.device());
+            constraint_task.set_constants(
+                PbdDensityConstraintConstants::new(particles.count(), 1e-3, h, 0.001, 1.0)
+                    .with_clamps(max_lambda, max_displacement),
+            );
+            constraint_task
+                .update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut constraint_task);
+
+            let predicted = particles.predicted_position().read().unwrap();
+            [0, 1].map(|i| {
+                Vec4::from_array(predicted[i].position)
+                    .truncate()
+                    .distance(start[i])
+            })
+        };
+
+        for displacement in solve(None, 0.02) {
+            assert!(
+                (displacement - 0.02 * h).abs() < 1e-5,
+                "displacement {} should be clamped to {}",
+                displacement,
+                0.02 * h
+            );
+        }
+        // A zero λ bound disables the correction altogether
+        for displacement in solve(Some(0.0), 0.02) {
+            assert_eq!(displacement, 0.0);
+        }
+    }
+
+    #[test]
+    fn test_colored_solve_reaches_lower_density_error_than_jacobi() {
+        let backend = VulkanoHeadlessBackend::new();
+        let (mass, h, iterations) = (0.02, 0.2, 10);
+        // A 4x4x4 block small enough that every particle is a candidate of every other
+        let particle_data = ParticleInitData::block(UVec3::splat(4), 0.06, Vec3::ZERO);
+        let poly6_factor = DensityKernel::Poly6.factor(h);
+        let densities = |positions: &[Vec3]| {
+            positions
+                .iter()
+                .map(|pi| {
+                    positions
+                        .iter()
+                        .map(|pj| {
+                            let diff = (h * h - pi.distance_squared(*pj)).max(0.0);
+                            poly6_factor * diff * diff * diff
+                        })
+                        .sum::<f32>()
+                        * mass
+                })
+                .collect::<Vec<_>>()
+        };
+        let initial_densities =
+            densities(&particle_data.iter().map(|p| p.position).collect::<Vec<_>>());
+        // Start 20% over-dense on average, so stale Jacobi corrections overshoot
+        let rest_density =
+            initial_densities.iter().sum::<f32>() / initial_densities.len() as f32 / 1.2;
+        let mean_density_error = |positions: &[Vec3]| {
+            densities(positions)
+                .iter()
+                .map(|density| (density / rest_density - 1.0).abs())
+                .sum::<f32>()
+                / positions.len() as f32
+        };
+
+        let solve = |solve_order: PbdSolveOrder| {
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+            particles.copy_position_to_predicted(&backend);
+
+            let mut hash_task = MortonHashTask::new(backend.device());
+            hash_task.set_constants(MortonHashConstants::new(
+                particles.count(),
+                0.1,
+                Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+            ));
+            hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut hash_task);
+            let mut sort_system = RadixSortSystem::new(backend.device());
+            sort_system.sort_morton_codes(
+                &mut particles,
+                backend.descriptor_set_allocator(),
+                &backend,
+            );
+
+            let mut sph_task = SpikySphTask::new(backend.device());
+            sph_task.set_constants(SpikySphConstants::new(particles.count(), mass, h, 0.1));
+            sph_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut sph_task);
+
+            let constants =
+                PbdDensityConstraintConstants::new(particles.count(), rest_density, h, 1e-4, 0.5)
+                    .with_solve_order(solve_order, mass, DensityKernel::Poly6);
+            let mut constraint_task = PbdDensityConstraintTask::new(backend.device());
+            for _ in 0..iterations {
+                for color in 0..constants.color_count() {
+                    constraint_task.set_constants(constants.with_color(color));
+                    constraint_task
+                        .update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+                    backend.execute(&mut constraint_task);
+                }
+            }
+
+            let predicted = particles.predicted_position().read().unwrap();
+            let positions = predicted[..particle_data.len()]
+                .iter()
+                .map(|p| Vec4::from_array(p.position).truncate())
+                .collect::<Vec<_>>();
+            mean_density_error(&positions)
+        };
+
+        let jacobi = solve(PbdSolveOrder::Jacobi);
+        let colored = solve(PbdSolveOrder::Colored);
+        assert!(
+            colored < jacobi,
+            "colored error {} should be below Jacobi error {}",
+            colored,
+            jacobi
+        );
+    }
+
+    #[test]
+    fn test_tensile_correction_keeps_sparse_cluster_apart() {
+        let backend = VulkanoHeadlessBackend::new();
+        let (mass, h) = (0.02, 0.15);
+        // A 3x3x3 cluster far below rest density, which the constraint alone pulls together
+        let particle_data = ParticleInitData::block(UVec3::splat(3), 0.03, Vec3::splat(0.5));
+
+        let min_distance_after_solve = |s_corr_k: f32| {
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+            particles.copy_position_to_predicted(&backend);
+
+            let mut hash_task = MortonHashTask::new(backend.device());
+            hash_task.set_constants(MortonHashConstants::new(
+                particles.count(),
+                0.1,
+                Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+            ));
+            hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut hash_task);
+            let mut sort_system = RadixSortSystem::new(backend.device());
+            sort_system.sort_morton_codes(
+                &mut particles,
+                backend.descriptor_set_allocator(),
+                &backend,
+            );
+
+            let mut sph_task = SpikySphTask::new(backend.device());
+            sph_task.set_constants(SpikySphConstants::new(particles.count(), mass, h, 0.1));
+            sph_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut sph_task);
+
+            let mut constraint_task = PbdDensityConstraintTask::new(backend.device());
+            constraint_task.set_constants(
+                PbdDensityConstraintConstants::new(particles.count(), 1000.0, h, 1e-4, 1.0)
+                    .with_solve_order(PbdSolveOrder::Jacobi, mass, DensityKernel::Poly6)
+                    .with_clamps(None, 1.0)
+                    .with_tensile_correction(s_corr_k, 4, 0.2),
+            );
+            constraint_task
+                .update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut constraint_task);
+
+            let predicted = particles.predicted_position().read().unwrap();
+            let positions = predicted[..particle_data.len()]
+                .iter()
+                .map(|p| Vec4::from_array(p.position).truncate())
+                .collect::<Vec<_>>();
+            positions
+                .iter()
+                .enumerate()
+                .flat_map(|(i, a)| positions[i + 1..].iter().map(move |b| a.distance(*b)))
+                .fold(f32::MAX, f32::min)
+        };
+
+        let without = min_distance_after_solve(0.0);
+        let with = min_distance_after_solve(0.1);
+        assert!(
+            with > without,
+            "min distance {} with s_corr should exceed {} without",
+            with,
+            without
+        );
+    }
 }
@@ -16,7 +16,6 @@ pub struct PrefixSumConstants {
 }
 
 impl PrefixSumConstants {
-    #[allow(unused)]
     pub fn new(num_work_groups: u32, total_bins: u32) -> Self {
         Self {
             num_work_groups,
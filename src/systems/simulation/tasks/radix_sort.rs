@@ -18,7 +18,6 @@ pub struct RadixSortConstants {
 }
 
 impl RadixSortConstants {
-    #[allow(unused)]
     pub fn new(
         num_particles: u32,
         shift_bits: u32,
@@ -61,7 +60,8 @@ impl ComputeGpuTaskConstants for RadixSortConstants {
     }
 
     fn particle_count(&self) -> u32 {
-        self.num_particles
+        // One dispatched work group per histogram, each walking its own run of blocks
+        self.num_work_groups * 256
     }
 }
 
@@ -70,7 +70,7 @@ pub(crate) type RadixSortTask = ComputeGpuTask<RadixSortConstants>;
 #[cfg(test)]
 mod tests {
     use crate::{
-        core::{ParticleInitData, Particles},
+        core::{Aabb, ParticleInitData, Particles},
         systems::simulation::tasks::{
             radix_sort::{RadixSortConstants, RadixSortTask},
             radix_sort_histogram::{RadixSortCountConstants, RadixSortCountTask},
@@ -91,23 +91,28 @@ mod tests {
             &[
                 ParticleInitData {
                     position: Vec3::new(1.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 1.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, 1.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(-1.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, -1.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
             ],
             backend.memory_allocator(),
@@ -115,14 +120,18 @@ mod tests {
         );
 
         // Step 1: Calculate Morton hash values
-        let hash_constants = MortonHashConstants::new(particles.count(), 1.0);
+        let hash_constants = MortonHashConstants::new(
+            particles.count(),
+            1.0,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        );
         let mut hash_task = MortonHashTask::new(backend.device());
         hash_task.set_constants(hash_constants);
-        hash_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut hash_task);
 
         // Step 2: Generate histograms for radix sort
-        let work_group_num = (particles.count() + 255) / 256;
+        let work_group_num = particles.count().div_ceil(256);
         let histogram_constants = RadixSortCountConstants::new(
             particles.count(),
             0, // Start with least significant 8 bits
@@ -131,7 +140,7 @@ mod tests {
         );
         let mut histogram_task = RadixSortCountTask::new(backend.device());
         histogram_task.set_constants(histogram_constants);
-        histogram_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        histogram_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut histogram_task);
 
         // Step 3: Calculate prefix sums
@@ -139,7 +148,7 @@ mod tests {
         let prefix_sum_constants = PrefixSumConstants::new(work_group_num, 256);
         let mut prefix_sum_task = PrefixSumTask::new(backend.device());
         prefix_sum_task.set_constants(prefix_sum_constants);
-        prefix_sum_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        prefix_sum_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut prefix_sum_task);
 
         // Step 4: Execute radix sort reordering
@@ -151,7 +160,7 @@ mod tests {
         );
         let mut sort_task = RadixSortTask::new(backend.device());
         sort_task.set_constants(sort_constants);
-        sort_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        sort_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut sort_task);
 
         // Verify that hashes are sorted (at least partially for the first 8 bits)
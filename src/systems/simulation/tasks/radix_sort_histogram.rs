@@ -18,7 +18,6 @@ pub struct RadixSortCountConstants {
 }
 
 impl RadixSortCountConstants {
-    #[allow(unused)]
     pub fn new(
         num_particles: u32,
         shift_bits: u32,
@@ -57,7 +56,8 @@ impl ComputeGpuTaskConstants for RadixSortCountConstants {
     }
 
     fn particle_count(&self) -> u32 {
-        self.num_particles
+        // One dispatched work group per histogram, each walking its own run of blocks
+        self.num_work_groups * 256
     }
 }
 
@@ -67,7 +67,7 @@ pub(crate) type RadixSortCountTask = ComputeGpuTask<RadixSortCountConstants>;
 mod tests {
 
     use crate::{
-        core::{ParticleInitData, Particles},
+        core::{Aabb, ParticleInitData, Particles},
         systems::simulation::tasks::{
             radix_sort_histogram::{RadixSortCountConstants, RadixSortCountTask},
             MortonHashConstants,
@@ -87,23 +87,28 @@ mod tests {
             &[
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, -1.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, -1.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(-1.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, -1.0, -1.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(-1.0, 0.0, -1.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
             ],
             backend.memory_allocator(),
@@ -111,15 +116,19 @@ mod tests {
         );
 
         // Calculate hash values
-        let hash_constants = MortonHashConstants::new(particles.count(), 1.0);
+        let hash_constants = MortonHashConstants::new(
+            particles.count(),
+            1.0,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        );
         let mut hash_task =
             crate::systems::simulation::tasks::MortonHashTask::new(backend.device());
         hash_task.set_constants(hash_constants);
-        hash_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut hash_task);
 
         // Execute radix sort histogram
-        let work_group_num = particles.count() / 256 + 1;
+        let work_group_num = particles.count().div_ceil(256);
         let constants = RadixSortCountConstants {
             num_particles: particles.count(),
             shift_bits: 0,
@@ -128,7 +137,7 @@ mod tests {
         };
         let mut task = RadixSortCountTask::new(backend.device());
         task.set_constants(constants);
-        task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut task);
 
         // Verify results
@@ -9,6 +9,22 @@ use super::{
     radix_sort_histogram::{RadixSortCountConstants, RadixSortCountTask},
 };
 
+/// Blocks of 256 elements each sort work group walks in order
+const BLOCKS_PER_WORK_GROUP: u32 = 8;
+
+/// Work group count and blocks per work group for sorting `particle_count` keys
+///
+/// Every work group owns a contiguous run of blocks and scatters it in input order,
+/// so the sort stays stable across work groups.
+fn work_group_layout(particle_count: u32) -> (u32, u32) {
+    let blocks = particle_count.div_ceil(256);
+    let blocks_per_work_group = blocks.clamp(1, BLOCKS_PER_WORK_GROUP);
+    (
+        blocks.div_ceil(blocks_per_work_group),
+        blocks_per_work_group,
+    )
+}
+
 pub struct RadixSortSystem {
     histogram_task: RadixSortCountTask,
     prefix_sum_task: PrefixSumTask,
@@ -33,17 +49,7 @@ impl RadixSortSystem {
         executor: &impl GpuTaskExecutor,
     ) {
         let particle_count = particles.count();
-        // Use single workgroup to avoid complex multi-workgroup coordination
-        let work_group_num = 1;
-        // Optimize for different data sizes
-        let blocks_per_work_group = if particle_count < 25000 {
-            // For small datasets, use more threads per element for better GPU utilization
-            particle_count.div_ceil(256)
-        } else {
-            // Each thread processes 4 elements, so we need fewer work groups
-            let elements_per_workgroup = 256 * 4; // 256 threads * 4 elements per thread
-            particle_count.div_ceil(elements_per_workgroup)
-        };
+        let (work_group_num, blocks_per_work_group) = work_group_layout(particle_count);
 
         // Execute 4 rounds of 8-bit radix sort for 32-bit Morton codes
         for pass in 0..4 {
@@ -97,7 +103,7 @@ impl RadixSortSystem {
 mod tests {
     use super::*;
     use crate::{
-        core::{ParticleInitData, Particles},
+        core::{Aabb, ParticleInitData, Particles},
         systems::simulation::tasks::{MortonHashConstants, MortonHashTask},
         utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
     };
@@ -112,23 +118,28 @@ mod tests {
             &[
                 ParticleInitData {
                     position: Vec3::new(2.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(1.0, 1.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 2.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, 2.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(-1.0, -1.0, -1.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
             ],
             backend.memory_allocator(),
@@ -136,19 +147,19 @@ mod tests {
         );
 
         // 计算莫顿哈希值
-        let hash_constants = MortonHashConstants::new(particles.count(), 1.0);
+        let hash_constants = MortonHashConstants::new(
+            particles.count(),
+            1.0,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        );
         let mut hash_task = MortonHashTask::new(backend.device());
         hash_task.set_constants(hash_constants);
-        hash_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut hash_task);
 
         // 执行完整的基数排序
         let mut sort_system = RadixSortSystem::new(backend.device());
-        sort_system.sort_morton_codes(
-            &mut particles,
-            &backend.descriptor_set_allocator(),
-            &backend,
-        );
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
 
         // 验证结果已排序
         let result_hashes = particles.hash().read().unwrap();
@@ -191,4 +202,167 @@ mod tests {
             "Not all original indices found after sorting"
         );
     }
+
+    #[test]
+    fn test_sorted_indices_are_a_permutation() {
+        let backend = VulkanoHeadlessBackend::new();
+
+        let mut particles = Particles::new(backend.memory_allocator());
+        let particle_data = (0..700)
+            .map(|i| ParticleInitData {
+                position: Vec3::new(
+                    (i * 37 % 100) as f32 * 0.01,
+                    (i * 53 % 100) as f32 * 0.01,
+                    (i * 71 % 100) as f32 * 0.01,
+                ),
+                velocity: Vec3::ZERO,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            0.05,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut sorted_indices =
+            particles.snapshot_sorted_indices(backend.memory_allocator(), &backend);
+        assert_eq!(sorted_indices.len(), particle_data.len());
+        sorted_indices.sort_unstable();
+        assert!(sorted_indices
+            .iter()
+            .enumerate()
+            .all(|(i, &index)| index == i as u32));
+    }
+
+    #[test]
+    fn test_radix_sort_is_stable_for_coincident_particles() {
+        let backend = VulkanoHeadlessBackend::new();
+
+        // Coincident particles interleaved with distinct ones, spanning several
+        // 256-element blocks so ties cross block boundaries
+        let coincident = Vec3::new(0.55, 0.55, 0.55);
+        let particle_data = (0..600)
+            .map(|i| ParticleInitData {
+                position: if i % 3 == 0 {
+                    coincident
+                } else {
+                    Vec3::new((i % 10) as f32 * 0.1, (i / 10 % 10) as f32 * 0.1, 0.0)
+                },
+                velocity: Vec3::ZERO,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let count = particles.count() as usize;
+        let hashes = particles.hash().read().unwrap();
+        let indices = particles.index().read().unwrap();
+
+        // Equal Morton codes must keep ascending original indices
+        for i in 1..count {
+            assert!(
+                (hashes[i - 1], indices[i - 1]) < (hashes[i], indices[i]),
+                "unstable order at {}: ({}, {}) then ({}, {})",
+                i,
+                hashes[i - 1],
+                indices[i - 1],
+                hashes[i],
+                indices[i]
+            );
+        }
+
+        // Every coincident particle is present, in one contiguous run
+        let run = indices[..count]
+            .iter()
+            .copied()
+            .filter(|&index| index % 3 == 0)
+            .collect::<Vec<_>>();
+        assert_eq!(run, (0..600).step_by(3).collect::<Vec<_>>());
+        let first = indices[..count].iter().position(|&i| i == 0).unwrap();
+        assert!(indices[first..first + run.len()]
+            .iter()
+            .all(|&i| i % 3 == 0));
+    }
+
+    #[test]
+    fn test_million_particle_sort_spans_work_groups() {
+        let backend = VulkanoHeadlessBackend::new();
+
+        let particle_count = 1_000_000u32;
+        let (work_group_num, _) = work_group_layout(particle_count);
+        assert!(work_group_num > 1);
+
+        // Scattered positions, so every pass moves keys between work groups
+        let particle_data = (0..particle_count)
+            .map(|i| ParticleInitData {
+                position: Vec3::new(
+                    (i.wrapping_mul(2_654_435_761) % 1000) as f32 * 0.001,
+                    (i.wrapping_mul(40_503) % 1000) as f32 * 0.001,
+                    (i.wrapping_mul(9_973) % 1000) as f32 * 0.001,
+                ),
+                velocity: Vec3::ZERO,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            0.001,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let count = particles.count() as usize;
+        let hashes = particles.hash().read().unwrap();
+        let indices = particles.index().read().unwrap();
+        for i in 1..count {
+            assert!(
+                (hashes[i - 1], indices[i - 1]) < (hashes[i], indices[i]),
+                "not sorted at {}: ({}, {}) then ({}, {})",
+                i,
+                hashes[i - 1],
+                indices[i - 1],
+                hashes[i],
+                indices[i]
+            );
+        }
+
+        let mut sorted_indices = indices[..count].to_vec();
+        sorted_indices.sort_unstable();
+        assert!(sorted_indices
+            .iter()
+            .enumerate()
+            .all(|(i, &index)| index == i as u32));
+    }
 }
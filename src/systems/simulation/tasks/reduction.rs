@@ -0,0 +1,480 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::{Buffer, BufferContents, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
+    descriptor_set::{
+        allocator::StandardDescriptorSetAllocator, DescriptorSet, WriteDescriptorSet,
+    },
+    device::{Device, DeviceOwned, Queue},
+    memory::allocator::{AllocationCreateInfo, StandardMemoryAllocator},
+    pipeline::{ComputePipeline, Pipeline, PipelineBindPoint},
+    shader::EntryPoint,
+};
+
+use crate::{
+    core::{ParticlePosition, ParticleVelocity},
+    utils::{submit_and_wait, GpuTask},
+};
+
+use super::compute_task::create_compute_pipeline;
+
+/// `local_size_x` of the reduction shaders
+const WORK_GROUP_SIZE: u32 = 256;
+
+/// Most work groups of a first pass; they stride over longer inputs, so the partial
+/// results always fit one second-pass work group
+const MAX_GROUP_COUNT: u32 = 256;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct ReductionConstants {
+    element_count: u32,
+    group_count: u32,
+    pass: u32,
+}
+
+/// Pipeline and bindings shared by the two-pass reductions
+///
+/// The first pass writes one partial result per work group to a scratch buffer, the second
+/// reduces those in a single work group. Both are recorded into the same command buffer,
+/// which places a barrier between them.
+struct TwoPassReduction {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set: Option<Arc<DescriptorSet>>,
+    constants: Option<ReductionConstants>,
+}
+
+impl TwoPassReduction {
+    fn new(device: &Arc<Device>, entry_point: EntryPoint) -> Self {
+        Self {
+            pipeline: create_compute_pipeline(device, entry_point),
+            descriptor_set: None,
+            constants: None,
+        }
+    }
+
+    /// Bind the inputs, then the scratch and result buffers, in that binding order
+    fn set_buffers(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        element_count: u32,
+        writes: impl IntoIterator<Item = WriteDescriptorSet>,
+    ) {
+        let layout = &self.pipeline.layout().set_layouts()[0];
+        self.descriptor_set = Some(
+            DescriptorSet::new(descriptor_set_allocator.clone(), layout.clone(), writes, [])
+                .unwrap(),
+        );
+        self.constants = Some(ReductionConstants {
+            element_count,
+            group_count: element_count
+                .div_ceil(WORK_GROUP_SIZE)
+                .clamp(1, MAX_GROUP_COUNT),
+            pass: 0,
+        });
+    }
+
+    fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        let constants = self.constants.expect("reduction buffers are not set");
+        builder
+            .bind_pipeline_compute(self.pipeline.clone())
+            .unwrap();
+        builder
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                self.descriptor_set.as_ref().unwrap().clone(),
+            )
+            .unwrap();
+        for (pass, group_count) in [(0, constants.group_count), (1, 1)] {
+            builder
+                .push_constants(
+                    self.pipeline.layout().clone(),
+                    0,
+                    ReductionConstants { pass, ..constants },
+                )
+                .unwrap();
+            unsafe {
+                builder.dispatch([group_count, 1, 1]).unwrap();
+            }
+        }
+    }
+}
+
+/// Scratch buffer for the partial results of a first pass
+fn partial_buffer<T: BufferContents>(
+    memory_allocator: &Arc<StandardMemoryAllocator>,
+    len: u64,
+) -> Subbuffer<[T]> {
+    Buffer::new_slice(
+        memory_allocator.clone(),
+        BufferCreateInfo {
+            usage: BufferUsage::STORAGE_BUFFER,
+            ..Default::default()
+        },
+        AllocationCreateInfo::default(),
+        len,
+    )
+    .unwrap()
+}
+
+/// Bounding box of a position buffer
+///
+/// Writes the componentwise minimum and maximum of the first `element_count` positions to
+/// the two entries of `result`. Like [`super::CompactTask`] it binds caller-provided
+/// buffers, so any position buffer can be reduced.
+pub(crate) struct ReduceAabbTask {
+    reduction: TwoPassReduction,
+    partials: Subbuffer<[[f32; 4]]>,
+}
+
+impl ReduceAabbTask {
+    pub fn new(memory_allocator: &Arc<StandardMemoryAllocator>) -> Self {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/reduce_aabb.comp",
+            }
+        }
+        let device = memory_allocator.device();
+        let entry_point = cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap();
+
+        Self {
+            reduction: TwoPassReduction::new(device, entry_point),
+            partials: partial_buffer(memory_allocator, 2 * MAX_GROUP_COUNT as u64),
+        }
+    }
+
+    /// Reduce the first `element_count` positions of `input` on the next execution
+    pub fn set_buffers(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        element_count: u32,
+        input: &Subbuffer<[ParticlePosition]>,
+        result: &Subbuffer<[[f32; 4]]>,
+    ) {
+        self.reduction.set_buffers(
+            descriptor_set_allocator,
+            element_count,
+            [
+                WriteDescriptorSet::buffer(0, input.clone()),
+                WriteDescriptorSet::buffer(1, self.partials.clone()),
+                WriteDescriptorSet::buffer(2, result.clone()),
+            ],
+        );
+    }
+}
+
+impl GpuTask for ReduceAabbTask {
+    fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        self.reduction.record(builder);
+    }
+
+    fn submit(
+        &mut self,
+        command_buffer: Arc<PrimaryAutoCommandBuffer>,
+        queue: &Arc<Queue>,
+        device: &Arc<Device>,
+    ) {
+        submit_and_wait(device, queue, command_buffer);
+    }
+}
+
+/// Largest speed in a velocity buffer
+///
+/// Writes the largest length among the first `element_count` velocities to `result[0]`, or
+/// 0 without any.
+pub(crate) struct ReduceMaxSpeedTask {
+    reduction: TwoPassReduction,
+    partials: Subbuffer<[f32]>,
+}
+
+impl ReduceMaxSpeedTask {
+    pub fn new(memory_allocator: &Arc<StandardMemoryAllocator>) -> Self {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/reduce_max_speed.comp",
+            }
+        }
+        let device = memory_allocator.device();
+        let entry_point = cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap();
+
+        Self {
+            reduction: TwoPassReduction::new(device, entry_point),
+            partials: partial_buffer(memory_allocator, MAX_GROUP_COUNT as u64),
+        }
+    }
+
+    /// Reduce the first `element_count` velocities of `input` on the next execution
+    pub fn set_buffers(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        element_count: u32,
+        input: &Subbuffer<[ParticleVelocity]>,
+        result: &Subbuffer<[f32]>,
+    ) {
+        self.reduction.set_buffers(
+            descriptor_set_allocator,
+            element_count,
+            [
+                WriteDescriptorSet::buffer(0, input.clone()),
+                WriteDescriptorSet::buffer(1, self.partials.clone()),
+                WriteDescriptorSet::buffer(2, result.clone()),
+            ],
+        );
+    }
+}
+
+impl GpuTask for ReduceMaxSpeedTask {
+    fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        self.reduction.record(builder);
+    }
+
+    fn submit(
+        &mut self,
+        command_buffer: Arc<PrimaryAutoCommandBuffer>,
+        queue: &Arc<Queue>,
+        device: &Arc<Device>,
+    ) {
+        submit_and_wait(device, queue, command_buffer);
+    }
+}
+
+/// Largest distance between two position buffers
+///
+/// Writes the largest distance between the first `element_count` entries of `positions` and
+/// `reference_positions` to `result[0]`, or 0 without any, e.g. to measure how far particles
+/// moved since a snapshot of their positions.
+pub(crate) struct ReduceMaxDisplacementTask {
+    reduction: TwoPassReduction,
+    partials: Subbuffer<[f32]>,
+}
+
+impl ReduceMaxDisplacementTask {
+    pub fn new(memory_allocator: &Arc<StandardMemoryAllocator>) -> Self {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/reduce_max_displacement.comp",
+            }
+        }
+        let device = memory_allocator.device();
+        let entry_point = cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap();
+
+        Self {
+            reduction: TwoPassReduction::new(device, entry_point),
+            partials: partial_buffer(memory_allocator, MAX_GROUP_COUNT as u64),
+        }
+    }
+
+    /// Reduce the first `element_count` displacements on the next execution
+    pub fn set_buffers(
+        &mut self,
+        descriptor_set_allocator: &Arc<StandardDescriptorSetAllocator>,
+        element_count: u32,
+        positions: &Subbuffer<[ParticlePosition]>,
+        reference_positions: &Subbuffer<[ParticlePosition]>,
+        result: &Subbuffer<[f32]>,
+    ) {
+        self.reduction.set_buffers(
+            descriptor_set_allocator,
+            element_count,
+            [
+                WriteDescriptorSet::buffer(0, positions.clone()),
+                WriteDescriptorSet::buffer(1, reference_positions.clone()),
+                WriteDescriptorSet::buffer(2, self.partials.clone()),
+                WriteDescriptorSet::buffer(3, result.clone()),
+            ],
+        );
+    }
+}
+
+impl GpuTask for ReduceMaxDisplacementTask {
+    fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        self.reduction.record(builder);
+    }
+
+    fn submit(
+        &mut self,
+        command_buffer: Arc<PrimaryAutoCommandBuffer>,
+        queue: &Arc<Queue>,
+        device: &Arc<Device>,
+    ) {
+        submit_and_wait(device, queue, command_buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{GpuTaskExecutor, VulkanoHeadlessBackend};
+    use glam::Vec3;
+    use vulkano::memory::allocator::MemoryTypeFilter;
+
+    fn host_buffer<T: BufferContents>(
+        backend: &VulkanoHeadlessBackend,
+        values: impl ExactSizeIterator<Item = T>,
+    ) -> Subbuffer<[T]> {
+        Buffer::from_iter(
+            backend.memory_allocator().clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            values,
+        )
+        .unwrap()
+    }
+
+    /// Deterministic scattered vectors, more than one first pass can cover without striding
+    fn scattered(count: u32) -> Vec<Vec3> {
+        (0..count)
+            .map(|i| {
+                let hash = |seed: u32| {
+                    let mixed = i.wrapping_mul(2_654_435_761).wrapping_add(seed) % 10_007;
+                    mixed as f32 / 10_007.0 * 20.0 - 10.0
+                };
+                Vec3::new(hash(17), hash(7_919), hash(104_729))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reduced_aabb_matches_cpu() {
+        let backend = VulkanoHeadlessBackend::new();
+        let points = scattered(100_000);
+        // A trailing point past element_count must be ignored
+        let input = host_buffer(
+            &backend,
+            points
+                .iter()
+                .copied()
+                .chain([Vec3::splat(100.0)])
+                .map(ParticlePosition::new)
+                .collect::<Vec<_>>()
+                .into_iter(),
+        );
+        let result = host_buffer(&backend, [[0.0f32; 4]; 2].into_iter());
+
+        let mut task = ReduceAabbTask::new(backend.memory_allocator());
+        task.set_buffers(
+            backend.descriptor_set_allocator(),
+            points.len() as u32,
+            &input,
+            &result,
+        );
+        backend.execute(&mut task);
+
+        let expected_min = points.iter().copied().fold(Vec3::MAX, Vec3::min);
+        let expected_max = points.iter().copied().fold(Vec3::MIN, Vec3::max);
+        let result = result.read().unwrap();
+        let min = Vec3::from_slice(&result[0][..3]);
+        let max = Vec3::from_slice(&result[1][..3]);
+        assert!(
+            min.abs_diff_eq(expected_min, 1e-6),
+            "{} vs {}",
+            min,
+            expected_min
+        );
+        assert!(
+            max.abs_diff_eq(expected_max, 1e-6),
+            "{} vs {}",
+            max,
+            expected_max
+        );
+    }
+
+    #[test]
+    fn test_reduced_max_speed_matches_cpu() {
+        let backend = VulkanoHeadlessBackend::new();
+        let velocities = scattered(100_000);
+        let input = host_buffer(
+            &backend,
+            velocities.iter().map(|&v| ParticleVelocity::new(v)),
+        );
+        let result = host_buffer(&backend, [0.0f32].into_iter());
+
+        let mut task = ReduceMaxSpeedTask::new(backend.memory_allocator());
+        for count in [0, 1, 300, velocities.len()] {
+            task.set_buffers(
+                backend.descriptor_set_allocator(),
+                count as u32,
+                &input,
+                &result,
+            );
+            backend.execute(&mut task);
+
+            let expected = velocities[..count]
+                .iter()
+                .map(|v| v.length())
+                .fold(0.0, f32::max);
+            let max_speed = result.read().unwrap()[0];
+            assert!(
+                (max_speed - expected).abs() <= 1e-5 * expected.max(1.0),
+                "{} particles: {} vs {}",
+                count,
+                max_speed,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_reduced_max_displacement_matches_cpu() {
+        let backend = VulkanoHeadlessBackend::new();
+        let positions = scattered(100_000);
+        let references = positions.iter().rev().map(|&p| p * 0.5).collect::<Vec<_>>();
+        let input = host_buffer(
+            &backend,
+            positions.iter().map(|&p| ParticlePosition::new(p)),
+        );
+        let reference = host_buffer(
+            &backend,
+            references.iter().map(|&p| ParticlePosition::new(p)),
+        );
+        let result = host_buffer(&backend, [0.0f32].into_iter());
+
+        let mut task = ReduceMaxDisplacementTask::new(backend.memory_allocator());
+        for count in [0, 1, 300, positions.len()] {
+            task.set_buffers(
+                backend.descriptor_set_allocator(),
+                count as u32,
+                &input,
+                &reference,
+                &result,
+            );
+            backend.execute(&mut task);
+
+            let expected = positions[..count]
+                .iter()
+                .zip(&references)
+                .map(|(p, r)| p.distance(*r))
+                .fold(0.0, f32::max);
+            let max_displacement = result.read().unwrap()[0];
+            assert!(
+                (max_displacement - expected).abs() <= 1e-5 * expected.max(1.0),
+                "{} particles: {} vs {}",
+                count,
+                max_displacement,
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::Particles;
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Particle removal constants
+///
+/// Flags every particle not in the free list of [`Particles::remove`] as a survivor and
+/// snapshots its state, ready for the merge compaction and gather.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct RemoveParticlesConstants {
+    particle_count: u32,
+    removal_count: u32,
+}
+
+impl RemoveParticlesConstants {
+    pub fn new(particle_count: u32, removal_count: u32) -> Self {
+        Self {
+            particle_count,
+            removal_count,
+        }
+    }
+}
+
+impl ComputeGpuTaskConstants for RemoveParticlesConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/remove_particles.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.velocity().clone()),
+            WriteDescriptorSet::buffer(2, particles.mass().clone()),
+            WriteDescriptorSet::buffer(3, particles.pinned().clone()),
+            WriteDescriptorSet::buffer(4, particles.free_list().clone()),
+            WriteDescriptorSet::buffer(5, particles.merge_state().clone()),
+            WriteDescriptorSet::buffer(6, particles.merge_keep().clone()),
+            WriteDescriptorSet::buffer(7, particles.sorted_indices().clone()),
+            WriteDescriptorSet::buffer(8, particles.rest_density().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type RemoveParticlesTask = ComputeGpuTask<RemoveParticlesConstants>;
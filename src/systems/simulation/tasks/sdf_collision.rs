@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use glam::Mat4;
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::{Particles, Sdf};
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Static obstacle collision constants
+///
+/// Projects particles that ended up inside the obstacle back onto its surface, using the
+/// field uploaded by [`Particles::set_sdf`]. `sdf` only provides the grid layout here.
+///
+/// The obstacle is rigid: transforms are reduced to a rotation and translation, which keeps
+/// both poses within the guaranteed 128 bytes of push constants.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct SdfCollisionConstants {
+    sdf_min: [f32; 4],
+    sdf_max: [f32; 4],
+    resolution: [u32; 4],
+    rotation: [f32; 4],
+    translation: [f32; 4],
+    previous_rotation: [f32; 4],
+    previous_translation: [f32; 4],
+    particle_count: u32,
+    dt: f32,
+}
+
+impl SdfCollisionConstants {
+    pub fn new(particle_count: u32, sdf: &Sdf) -> Self {
+        Self {
+            sdf_min: sdf.aabb().min().extend(0.0).to_array(),
+            sdf_max: sdf.aabb().max().extend(0.0).to_array(),
+            resolution: sdf.resolution().extend(0).to_array(),
+            rotation: [0.0, 0.0, 0.0, 1.0],
+            translation: [0.0; 4],
+            previous_rotation: [0.0, 0.0, 0.0, 1.0],
+            previous_translation: [0.0; 4],
+            particle_count,
+            dt: 0.0,
+        }
+    }
+
+    /// Place the obstacle at `transform`, moving from `previous` over `dt` seconds
+    pub fn with_transform(mut self, transform: Mat4, previous: Mat4, dt: f32) -> Self {
+        let (_, rotation, translation) = transform.to_scale_rotation_translation();
+        let (_, previous_rotation, previous_translation) = previous.to_scale_rotation_translation();
+        self.rotation = rotation.to_array();
+        self.translation = translation.extend(0.0).to_array();
+        self.previous_rotation = previous_rotation.to_array();
+        self.previous_translation = previous_translation.extend(0.0).to_array();
+        self.dt = dt;
+        self
+    }
+}
+
+impl ComputeGpuTaskConstants for SdfCollisionConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/sdf_collision.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.velocity().clone()),
+            WriteDescriptorSet::buffer(2, particles.pinned().clone()),
+            WriteDescriptorSet::buffer(3, particles.sdf().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type SdfCollisionTask = ComputeGpuTask<SdfCollisionConstants>;
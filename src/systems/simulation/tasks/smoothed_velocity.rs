@@ -0,0 +1,137 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::Particles;
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Neighbor-averaged velocity constants
+///
+/// Writes the mean velocity of each particle's neighbors to `smoothed_velocity` for
+/// visualization and export. Unlike XSPH it never feeds back into `velocity`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct SmoothedVelocityConstants {
+    particle_count: u32,
+    smoothing_radius_sq: f32,
+    max_neighbors: u32,
+}
+
+impl SmoothedVelocityConstants {
+    pub fn new(particle_count: u32, smoothing_radius: f32) -> Self {
+        Self {
+            particle_count,
+            smoothing_radius_sq: smoothing_radius * smoothing_radius,
+            max_neighbors: 64, // Same candidate sampling as the density pass
+        }
+    }
+}
+
+impl ComputeGpuTaskConstants for SmoothedVelocityConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/smoothed_velocity.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.velocity().clone()),
+            WriteDescriptorSet::buffer(2, particles.index().clone()),
+            WriteDescriptorSet::buffer(3, particles.smoothed_velocity().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type SmoothedVelocityTask = ComputeGpuTask<SmoothedVelocityConstants>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{Aabb, ParticleInitData, Particles},
+        systems::simulation::tasks::{MortonHashConstants, MortonHashTask, RadixSortSystem},
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::{Vec3, Vec4};
+
+    #[test]
+    fn test_smoothed_velocity_is_neighbor_average() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.enable_smoothed_velocity();
+
+        // Particle 0 sits between two neighbors; particle 3 is isolated
+        particles.add_particles(
+            &[
+                ParticleInitData {
+                    position: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(5.0, 0.0, 0.0),
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(0.05, 0.0, 0.0),
+                    velocity: Vec3::new(1.0, 2.0, 0.0),
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(-0.05, 0.0, 0.0),
+                    velocity: Vec3::new(3.0, 0.0, -2.0),
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(1.0, 1.0, 1.0),
+                    velocity: Vec3::new(0.0, -1.0, 0.0),
+                    ..Default::default()
+                },
+            ],
+            backend.memory_allocator(),
+            &backend,
+        );
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut task = SmoothedVelocityTask::new(backend.device());
+        task.set_constants(SmoothedVelocityConstants::new(particles.count(), 0.08));
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut task);
+
+        let smoothed = particles.smoothed_velocity().read().unwrap();
+        let smoothed_at = |i: usize| Vec4::from_array(smoothed[i].velocity).truncate();
+
+        assert!(smoothed_at(0).distance(Vec3::new(2.0, 1.0, -1.0)) < 1e-6);
+        // Particles 1 and 2 are 0.1 apart, so each only sees particle 0
+        assert!(smoothed_at(1).distance(Vec3::new(5.0, 0.0, 0.0)) < 1e-6);
+        assert!(smoothed_at(2).distance(Vec3::new(5.0, 0.0, 0.0)) < 1e-6);
+        assert!(smoothed_at(3).distance(Vec3::new(0.0, -1.0, 0.0)) < 1e-6);
+
+        // The real velocity field is untouched
+        let velocities = particles.velocity().read().unwrap();
+        assert_eq!(velocities[0].velocity, [5.0, 0.0, 0.0, 0.0]);
+    }
+}
@@ -4,7 +4,10 @@ use vulkano::{
     buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
 };
 
-use crate::core::Particles;
+use crate::{
+    core::Particles,
+    systems::simulation::simulation_config::{clamp_smoothing_radius, DensityKernel},
+};
 
 use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
 
@@ -12,6 +15,15 @@ use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
 /// Only calculates particle density, not pressure or viscosity forces
 /// Density results will be used for PBD constraint solving
 ///
+/// Each particle's density is `Σ_j m_j · W(|x_i - x_j|, h)` over its neighbor candidates,
+/// itself included, where `W` is the selected [`DensityKernel`]:
+/// - poly6: `315 / (64πh⁹) · (h² - r²)³`
+/// - spiky: `15 / (πh⁶) · (h - r)³`
+///
+/// and both kernels are zero from `r = h` on. Up to `max_neighbors` particles every particle
+/// is a candidate; larger sets sample the sorted order with a stride. Every particle of a
+/// boundary shell is a candidate too, with the scalar mass.
+///
 /// PBD fluid SPH density calculation constants
 #[repr(C)]
 #[derive(Clone, Copy, Debug, BufferContents)]
@@ -20,27 +32,39 @@ pub struct SpikySphConstants {
     mass: f32,
     smoothing_radius: f32,
     smoothing_radius_sq: f32,
-    poly6_kernel_factor: f32,
+    kernel_factor: f32,
     grid_size: f32,
     max_neighbors: u32,
+    kernel: u32,
 }
 
 impl SpikySphConstants {
+    /// `smoothing_radius` is raised to `MIN_SMOOTHING_RADIUS` if smaller
     pub fn new(particle_count: u32, mass: f32, smoothing_radius: f32, grid_size: f32) -> Self {
+        let smoothing_radius = clamp_smoothing_radius(smoothing_radius);
         let smoothing_radius_sq = smoothing_radius * smoothing_radius;
 
-        // Poly6 kernel factor: 315 / (64 * π * h^9)
-        let poly6_kernel_factor = 315.0 / (64.0 * std::f32::consts::PI * smoothing_radius.powi(9));
-
         Self {
             particle_count,
             mass,
             smoothing_radius,
             smoothing_radius_sq,
-            poly6_kernel_factor,
+            kernel_factor: 0.0,
             grid_size,
             max_neighbors: 64, // Limit to 64 neighborhood particles
+            kernel: 0,
         }
+        .with_kernel(DensityKernel::Poly6)
+    }
+
+    /// Select the density kernel and recompute its normalization factor
+    pub fn with_kernel(mut self, kernel: DensityKernel) -> Self {
+        self.kernel = match kernel {
+            DensityKernel::Poly6 => 0,
+            DensityKernel::Spiky => 1,
+        };
+        self.kernel_factor = kernel.factor(self.smoothing_radius);
+        self
     }
 }
 
@@ -64,6 +88,8 @@ impl ComputeGpuTaskConstants for SpikySphConstants {
             WriteDescriptorSet::buffer(1, particles.density().clone()),
             WriteDescriptorSet::buffer(2, particles.index().clone()), // Sorted indices
             WriteDescriptorSet::buffer(3, particles.hash().clone()),  // Morton hash values
+            WriteDescriptorSet::buffer(4, particles.mass().clone()),
+            WriteDescriptorSet::buffer(5, particles.boundary().clone()),
         ]
     }
 
@@ -78,7 +104,7 @@ pub(crate) type SpikySphTask = ComputeGpuTask<SpikySphConstants>;
 mod tests {
     use super::*;
     use crate::{
-        core::{ParticleInitData, Particles},
+        core::{Aabb, ParticleInitData, Particles},
         utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
     };
     use glam::Vec3;
@@ -97,15 +123,18 @@ mod tests {
             &[
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.1, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.1, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(0.0, 0.0, 0.0),
+                    ..Default::default()
                 },
             ],
             backend.memory_allocator(),
@@ -113,19 +142,19 @@ mod tests {
         );
 
         // First execute Morton hash calculation
-        let hash_constants = MortonHashConstants::new(particles.count(), 0.1);
+        let hash_constants = MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        );
         let mut hash_task = MortonHashTask::new(backend.device());
         hash_task.set_constants(hash_constants);
-        hash_task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
         backend.execute(&mut hash_task);
 
         // Then execute sorting
         let mut sort_system = RadixSortSystem::new(backend.device());
-        sort_system.sort_morton_codes(
-            &mut particles,
-            &backend.descriptor_set_allocator(),
-            &backend,
-        );
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
 
         // Now execute SPH density calculation
         let constants = SpikySphConstants::new(
@@ -137,7 +166,7 @@ mod tests {
 
         let mut task = SpikySphTask::new(backend.device());
         task.set_constants(constants);
-        task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
 
         backend.execute(&mut task);
 
@@ -159,4 +188,102 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_density_matches_cpu_reference() {
+        use crate::systems::simulation::{
+            cpu_reference::reference_densities,
+            tasks::{MortonHashConstants, MortonHashTask, RadixSortSystem},
+        };
+
+        let (mass, smoothing_radius, grid_size) = (0.02, 0.2, 0.1);
+        // A close cluster plus one particle beyond the smoothing radius of all others,
+        // kept in the first grid cells so the hash folded into the density stays negligible
+        let positions = [
+            Vec3::new(0.05, 0.05, 0.05),
+            Vec3::new(0.12, 0.05, 0.05),
+            Vec3::new(0.05, 0.13, 0.05),
+            Vec3::new(0.05, 0.05, 0.2),
+            Vec3::new(0.09, 0.1, 0.1),
+            Vec3::new(0.35, 0.35, 0.35),
+        ];
+
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &positions.map(|position| ParticleInitData {
+                position,
+                velocity: Vec3::ZERO,
+                ..Default::default()
+            }),
+            backend.memory_allocator(),
+            &backend,
+        );
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            grid_size,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut task = SpikySphTask::new(backend.device());
+        for kernel in [DensityKernel::Poly6, DensityKernel::Spiky] {
+            task.set_constants(
+                SpikySphConstants::new(particles.count(), mass, smoothing_radius, grid_size)
+                    .with_kernel(kernel),
+            );
+            task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut task);
+
+            let expected = reference_densities(&positions, mass, smoothing_radius, kernel);
+            let densities = particles.density().read().unwrap();
+            for (i, &expected) in expected.iter().enumerate() {
+                let density = densities[i] as f64;
+                assert!(
+                    (density - expected).abs() <= 1e-4 * expected,
+                    "{:?} particle {}: gpu {} cpu {}",
+                    kernel,
+                    i,
+                    density,
+                    expected
+                );
+            }
+
+            // The isolated particle only sees itself: m · W(0)
+            let h = smoothing_radius as f64;
+            let self_weight = match kernel {
+                DensityKernel::Poly6 => 315.0 / (64.0 * std::f64::consts::PI * h.powi(3)),
+                DensityKernel::Spiky => 15.0 / (std::f64::consts::PI * h.powi(3)),
+            };
+            let isolated = expected[positions.len() - 1];
+            assert!((isolated - mass as f64 * self_weight).abs() <= 1e-9 * isolated);
+        }
+    }
+
+    #[test]
+    fn test_tiny_smoothing_radius_is_rejected_and_clamped() {
+        use crate::systems::simulation::simulation_config::{
+            SimulationConfig, MIN_SMOOTHING_RADIUS,
+        };
+
+        let mut config = SimulationConfig::default();
+        config.sph_params.smoothing_radius = 1e-6;
+        config.grid_size = 1e-6;
+        assert!(config.validate().is_err());
+
+        for kernel in [DensityKernel::Poly6, DensityKernel::Spiky] {
+            let constants = SpikySphConstants::new(1, 0.02, 1e-6, 1e-6).with_kernel(kernel);
+            assert_eq!(constants.smoothing_radius, MIN_SMOOTHING_RADIUS);
+            assert!(
+                constants.kernel_factor.is_finite() && constants.kernel_factor > 0.0,
+                "{:?} kernel factor {}",
+                kernel,
+                constants.kernel_factor
+            );
+        }
+    }
 }
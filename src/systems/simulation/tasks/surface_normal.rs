@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::{core::Particles, systems::simulation::simulation_config::clamp_smoothing_radius};
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Surface normal constants
+///
+/// Writes the outward unit normal of each particle, the negated direction of the summed
+/// spiky kernel gradient over its neighbors, to `normal` for diffuse shading. It is only
+/// meaningful near the free surface; interior particles get short or zero normals.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct SurfaceNormalConstants {
+    particle_count: u32,
+    smoothing_radius: f32,
+    spiky_grad_kernel_factor: f32,
+    max_neighbors: u32,
+}
+
+impl SurfaceNormalConstants {
+    pub fn new(particle_count: u32, smoothing_radius: f32) -> Self {
+        let smoothing_radius = clamp_smoothing_radius(smoothing_radius);
+        Self {
+            particle_count,
+            smoothing_radius,
+            // Spiky gradient kernel factor: -45 / (π * h^6)
+            spiky_grad_kernel_factor: -45.0 / (std::f32::consts::PI * smoothing_radius.powi(6)),
+            max_neighbors: 64, // Same candidate sampling as the density pass
+        }
+    }
+}
+
+impl ComputeGpuTaskConstants for SurfaceNormalConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/surface_normal.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.position().clone()),
+            WriteDescriptorSet::buffer(1, particles.index().clone()),
+            WriteDescriptorSet::buffer(2, particles.normal().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type SurfaceNormalTask = ComputeGpuTask<SurfaceNormalConstants>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{Aabb, ParticleInitData, Particles},
+        systems::simulation::tasks::{MortonHashConstants, MortonHashTask, RadixSortSystem},
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::{Vec3, Vec4};
+
+    #[test]
+    fn test_planar_surface_normals_point_out_of_the_fluid() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.enable_normal();
+
+        // A 5x5 slab two layers deep with flat top and bottom faces
+        let spacing = 0.05;
+        let index = |x: u32, y: u32, z: u32| (y * 25 + z * 5 + x) as usize;
+        let particle_data = (0..2)
+            .flat_map(|y| {
+                (0..5)
+                    .flat_map(move |z| (0..5).map(move |x| Vec3::new(x as f32, y as f32, z as f32)))
+            })
+            .map(|cell| ParticleInitData {
+                position: cell * spacing,
+                velocity: Vec3::ZERO,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut task = SurfaceNormalTask::new(backend.device());
+        task.set_constants(SurfaceNormalConstants::new(particles.count(), 0.12));
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut task);
+
+        let normals = particles.normal().read().unwrap();
+        let normal_at = |i: usize| Vec4::from_array(normals[i]).truncate();
+
+        // The centers of both faces are symmetric in x and z, so their normals are vertical
+        let top = normal_at(index(2, 1, 2));
+        assert!(top.distance(Vec3::Y) < 1e-4, "top normal {:?}", top);
+        let bottom = normal_at(index(2, 0, 2));
+        assert!(
+            bottom.distance(-Vec3::Y) < 1e-4,
+            "bottom normal {:?}",
+            bottom
+        );
+        // An edge particle also leans out of its side
+        let edge = normal_at(index(0, 1, 2));
+        assert!(edge.x < 0.0 && edge.y > 0.0, "edge normal {:?}", edge);
+    }
+}
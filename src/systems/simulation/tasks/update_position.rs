@@ -15,10 +15,11 @@ pub struct UpdatePositionConstants {
     aabb_max: [f32; 4],
     particle_count: u32,
     dt: f32,
+    floor_friction: f32,
 }
 
 impl UpdatePositionConstants {
-    pub fn new(aabb: Aabb, particle_count: u32, dt: f32) -> Self {
+    pub fn new(aabb: Aabb, particle_count: u32, dt: f32, floor_friction: f32) -> Self {
         let aabb_min = aabb.min().extend(0.).to_array();
         let aabb_max = aabb.max().extend(0.).to_array();
         Self {
@@ -26,6 +27,7 @@ impl UpdatePositionConstants {
             aabb_max,
             particle_count,
             dt,
+            floor_friction,
         }
     }
 }
@@ -48,6 +50,7 @@ impl ComputeGpuTaskConstants for UpdatePositionConstants {
         [
             WriteDescriptorSet::buffer(0, particles.velocity().clone()),
             WriteDescriptorSet::buffer(1, particles.position().clone()),
+            WriteDescriptorSet::buffer(2, particles.pinned().clone()),
         ]
     }
 
@@ -60,7 +63,7 @@ pub(crate) type UpdatePositionTask = ComputeGpuTask<UpdatePositionConstants>;
 
 #[cfg(test)]
 mod tests {
-    use crate::core::ParticlePosition;
+    use crate::core::{Aabb, ParticlePosition};
     use crate::systems::simulation::tasks::update_position::UpdatePositionConstants;
     use crate::systems::simulation::tasks::UpdatePositionTask;
     use crate::utils::approx_eq;
@@ -79,15 +82,18 @@ mod tests {
             &[
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, 0.0),
-                    velocitie: Vec3::new(1.0, 0.0, 0.0),
+                    velocity: Vec3::new(1.0, 0.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 1.0, 0.0),
+                    velocity: Vec3::new(0.0, 1.0, 0.0),
+                    ..Default::default()
                 },
                 ParticleInitData {
                     position: Vec3::new(0.0, 0.0, 0.0),
-                    velocitie: Vec3::new(0.0, 0.0, 1.0),
+                    velocity: Vec3::new(0.0, 0.0, 1.0),
+                    ..Default::default()
                 },
             ],
             backend.memory_allocator(),
@@ -99,16 +105,17 @@ mod tests {
             aabb_max: [1., 1., 1., 0.],
             particle_count: particles.count(),
             dt: 0.1,
+            floor_friction: 0.0,
         };
 
         let mut task = UpdatePositionTask::new(backend.device());
         task.set_constants(constant);
-        task.update_descriptor_set(&backend.descriptor_set_allocator(), &mut particles);
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
 
         backend.execute(&mut task);
 
         let result_entries = particles.position().read().unwrap();
-        let expected_entries = vec![
+        let expected_entries = [
             ParticlePosition::new(Vec3::new(0.1, 0.0, 0.0)),
             ParticlePosition::new(Vec3::new(0.0, 0.1, 0.0)),
             ParticlePosition::new(Vec3::new(0.0, 0.0, 0.1)),
@@ -126,4 +133,46 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_floor_friction_slows_and_stops_sliding() {
+        let backend = VulkanoHeadlessBackend::new();
+        let aabb = Aabb::new(Vec3::splat(-1.0), Vec3::splat(1.0));
+
+        // One step that lands on the floor while sliding along +x
+        let slide = |floor_friction: f32| {
+            let mut particles = Particles::new(backend.memory_allocator());
+            particles.add_particles(
+                &[ParticleInitData {
+                    position: Vec3::new(0.0, -0.99, 0.0),
+                    velocity: Vec3::new(1.0, -0.5, 0.0),
+                    ..Default::default()
+                }],
+                backend.memory_allocator(),
+                &backend,
+            );
+            let mut task = UpdatePositionTask::new(backend.device());
+            task.set_constants(UpdatePositionConstants::new(
+                aabb,
+                particles.count(),
+                0.1,
+                floor_friction,
+            ));
+            task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+            backend.execute(&mut task);
+            particles.download_velocities(backend.memory_allocator(), &backend)[0]
+        };
+
+        let frictionless = slide(0.0);
+        let low = slide(0.5);
+        let high = slide(1.0);
+        assert!(approx_eq(frictionless.x, 1.0, 1e-5), "{}", frictionless);
+        assert!(approx_eq(low.x, 0.75, 1e-5), "{}", low);
+        assert!(approx_eq(high.x, 0.5, 1e-5), "{}", high);
+
+        // Static friction exceeds the sliding speed, so the particle sticks
+        let stuck = slide(4.0);
+        assert_eq!(stuck.x, 0.0);
+        assert_eq!(stuck.z, 0.0);
+    }
 }
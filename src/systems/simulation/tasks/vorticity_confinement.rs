@@ -0,0 +1,186 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::{
+    core::{Particles, CONTACTS_PER_PARTICLE},
+    systems::simulation::simulation_config::clamp_smoothing_radius,
+};
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// Dispatches of a vorticity confinement step, run in this order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VorticityPass {
+    /// Write the velocity curl of each particle to `vorticity`
+    Curl = 0,
+    /// Add the confinement force to `velocity`
+    Force = 1,
+}
+
+/// Vorticity confinement constants
+///
+/// Restores rotation the PBD solver damps out by pushing each particle along
+/// `epsilon * (N x curl)` for `dt`, where `N` points toward stronger vorticity. Neighbors
+/// come from `contacts`, so neighbor lists must have been built this step.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct VorticityConfinementConstants {
+    particle_count: u32,
+    epsilon: f32,
+    dt: f32,
+    smoothing_radius: f32,
+    spiky_grad_kernel_factor: f32,
+    contact_stride: u32,
+    pass: u32,
+}
+
+impl VorticityConfinementConstants {
+    pub fn new(particle_count: u32, epsilon: f32, dt: f32, smoothing_radius: f32) -> Self {
+        let smoothing_radius = clamp_smoothing_radius(smoothing_radius);
+        Self {
+            particle_count,
+            epsilon,
+            dt,
+            smoothing_radius,
+            // Spiky gradient kernel factor: -45 / (π * h^6)
+            spiky_grad_kernel_factor: -45.0 / (std::f32::consts::PI * smoothing_radius.powi(6)),
+            contact_stride: CONTACTS_PER_PARTICLE,
+            pass: VorticityPass::Curl as u32,
+        }
+    }
+
+    pub fn with_pass(mut self, pass: VorticityPass) -> Self {
+        self.pass = pass as u32;
+        self
+    }
+}
+
+impl ComputeGpuTaskConstants for VorticityConfinementConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/vorticity_confinement.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.predicted_position().clone()),
+            WriteDescriptorSet::buffer(1, particles.velocity().clone()),
+            WriteDescriptorSet::buffer(2, particles.contacts().clone()),
+            WriteDescriptorSet::buffer(3, particles.contact_counts().clone()),
+            WriteDescriptorSet::buffer(4, particles.vorticity().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type VorticityConfinementTask = ComputeGpuTask<VorticityConfinementConstants>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{Aabb, ParticleInitData, Particles},
+        systems::simulation::tasks::{
+            MortonHashConstants, MortonHashTask, NeighborSearchConstants, NeighborSearchTask,
+            RadixSortSystem,
+        },
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::Vec3;
+
+    #[test]
+    fn test_confinement_spins_up_rotating_ring() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.enable_contacts();
+        particles.enable_vorticity();
+
+        // A ring turning counterclockwise about z, about four neighbors per particle
+        let (ring_radius, angular_velocity, count) = (0.2, 2.0, 32);
+        let particle_data = (0..count)
+            .map(|i| {
+                let angle = std::f32::consts::TAU * i as f32 / count as f32;
+                let position = Vec3::new(angle.cos(), angle.sin(), 0.0) * ring_radius;
+                ParticleInitData {
+                    position,
+                    velocity: Vec3::Z.cross(position) * angular_velocity,
+                    ..Default::default()
+                }
+            })
+            .collect::<Vec<_>>();
+        particles.add_particles(&particle_data, backend.memory_allocator(), &backend);
+        particles.copy_position_to_predicted(&backend);
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let smoothing_radius = 0.1;
+        let mut search_task = NeighborSearchTask::new(backend.device());
+        search_task.set_constants(NeighborSearchConstants::new(
+            particles.count(),
+            smoothing_radius,
+            32,
+        ));
+        search_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut search_task);
+
+        let angular_momentum = |particles: &Particles| {
+            let velocities = particles.download_velocities(backend.memory_allocator(), &backend);
+            particle_data
+                .iter()
+                .zip(velocities)
+                .map(|(particle, velocity)| particle.position.cross(velocity).z)
+                .sum::<f32>()
+        };
+        let without = angular_momentum(&particles);
+
+        let constants = VorticityConfinementConstants::new(
+            particles.count(),
+            0.01,
+            1.0 / 60.0,
+            smoothing_radius,
+        );
+        let mut task = VorticityConfinementTask::new(backend.device());
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        for pass in [VorticityPass::Curl, VorticityPass::Force] {
+            task.set_constants(constants.with_pass(pass));
+            backend.execute(&mut task);
+        }
+
+        // The curl of a counterclockwise rotation points up the z axis
+        let vorticity = particles.vorticity().read().unwrap();
+        assert!((0..count).all(|i| vorticity[i][2] > 0.0));
+        drop(vorticity);
+
+        let with = angular_momentum(&particles);
+        assert!(
+            with > without,
+            "angular momentum with confinement {} without {}",
+            with,
+            without
+        );
+    }
+}
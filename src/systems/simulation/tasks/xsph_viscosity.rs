@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::BufferContents, descriptor_set::WriteDescriptorSet, device::Device, shader::EntryPoint,
+};
+
+use crate::core::{Particles, CONTACTS_PER_PARTICLE};
+
+use super::compute_task::{ComputeGpuTask, ComputeGpuTaskConstants};
+
+/// XSPH viscosity constants
+///
+/// Blends each velocity toward the kernel-weighted mean velocity of its listed neighbors,
+/// by `viscosity` clamped to [0, 1]. The result is written to `predicted_velocity`, since
+/// neighbors read `velocity` while the pass runs.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, BufferContents)]
+pub struct XsphViscosityConstants {
+    particle_count: u32,
+    viscosity: f32,
+    smoothing_radius: f32,
+    contact_stride: u32,
+}
+
+impl XsphViscosityConstants {
+    pub fn new(particle_count: u32, viscosity: f32, smoothing_radius: f32) -> Self {
+        Self {
+            particle_count,
+            viscosity: viscosity.clamp(0.0, 1.0),
+            smoothing_radius,
+            contact_stride: CONTACTS_PER_PARTICLE,
+        }
+    }
+}
+
+impl ComputeGpuTaskConstants for XsphViscosityConstants {
+    fn entry_point(device: &Arc<Device>) -> EntryPoint {
+        mod cs {
+            vulkano_shaders::shader! {
+                ty: "compute",
+                path: "src/shaders/simulation/xsph_viscosity.comp",
+            }
+        }
+        cs::load(device.clone())
+            .unwrap()
+            .entry_point("main")
+            .unwrap()
+    }
+
+    fn descriptor_writes(particles: &Particles) -> impl IntoIterator<Item = WriteDescriptorSet> {
+        [
+            WriteDescriptorSet::buffer(0, particles.predicted_position().clone()),
+            WriteDescriptorSet::buffer(1, particles.velocity().clone()),
+            WriteDescriptorSet::buffer(2, particles.contacts().clone()),
+            WriteDescriptorSet::buffer(3, particles.contact_counts().clone()),
+            WriteDescriptorSet::buffer(4, particles.predicted_velocity().clone()),
+        ]
+    }
+
+    fn particle_count(&self) -> u32 {
+        self.particle_count
+    }
+}
+
+pub(crate) type XsphViscosityTask = ComputeGpuTask<XsphViscosityConstants>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{Aabb, ParticleInitData, Particles},
+        systems::simulation::tasks::{
+            MortonHashConstants, MortonHashTask, NeighborSearchConstants, NeighborSearchTask,
+            RadixSortSystem,
+        },
+        utils::{GpuTaskExecutor, VulkanoHeadlessBackend},
+    };
+    use glam::{Vec3, Vec4};
+
+    #[test]
+    fn test_xsph_pulls_opposing_neighbors_together() {
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.enable_contacts();
+        particles.enable_predicted_velocity();
+
+        // Two close neighbors moving in opposite directions
+        particles.add_particles(
+            &[
+                ParticleInitData {
+                    position: Vec3::new(0.0, 0.0, 0.0),
+                    velocity: Vec3::new(1.0, 0.0, 0.0),
+                    ..Default::default()
+                },
+                ParticleInitData {
+                    position: Vec3::new(0.05, 0.0, 0.0),
+                    velocity: Vec3::new(-1.0, 0.0, 0.0),
+                    ..Default::default()
+                },
+            ],
+            backend.memory_allocator(),
+            &backend,
+        );
+        particles.copy_position_to_predicted(&backend);
+
+        let mut hash_task = MortonHashTask::new(backend.device());
+        hash_task.set_constants(MortonHashConstants::new(
+            particles.count(),
+            0.1,
+            Aabb::new(Vec3::splat(-2.0), Vec3::splat(2.0)),
+        ));
+        hash_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut hash_task);
+
+        let mut sort_system = RadixSortSystem::new(backend.device());
+        sort_system.sort_morton_codes(&mut particles, backend.descriptor_set_allocator(), &backend);
+
+        let mut search_task = NeighborSearchTask::new(backend.device());
+        search_task.set_constants(NeighborSearchConstants::new(particles.count(), 0.2, 32));
+        search_task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut search_task);
+
+        let mut task = XsphViscosityTask::new(backend.device());
+        task.set_constants(XsphViscosityConstants::new(particles.count(), 0.25, 0.2));
+        task.update_descriptor_set(backend.descriptor_set_allocator(), &mut particles);
+        backend.execute(&mut task);
+
+        // Each blends a quarter of the way toward the other's velocity
+        let predicted = particles.predicted_velocity().read().unwrap();
+        let velocity_at = |i: usize| Vec4::from_array(predicted[i].velocity).truncate();
+        assert!(velocity_at(0).distance(Vec3::new(0.5, 0.0, 0.0)) < 1e-6);
+        assert!(velocity_at(1).distance(Vec3::new(-0.5, 0.0, 0.0)) < 1e-6);
+
+        // The explicit velocity buffer is left untouched by the pass
+        let velocities = particles.velocity().read().unwrap();
+        assert_eq!(velocities[0].velocity[0], 1.0);
+        assert_eq!(velocities[1].velocity[0], -1.0);
+    }
+}
@@ -3,10 +3,10 @@ mod fps_counter;
 mod vulkan_context;
 
 pub(crate) use fps_counter::FpsCounter;
-pub(crate) use vulkan_context::{GpuTask, GpuTaskExecutor, VulkanoBackend};
-
-#[cfg(test)]
-pub(crate) use vulkan_context::VulkanoHeadlessBackend;
+pub(crate) use vulkan_context::{submit_and_wait, GpuTimer};
+pub use vulkan_context::{
+    GpuTask, GpuTaskExecutor, TaskBatch, VulkanoBackend, VulkanoHeadlessBackend,
+};
 
 #[cfg(test)]
 pub(crate) use approx_eq::approx_eq;
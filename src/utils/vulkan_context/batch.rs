@@ -0,0 +1,65 @@
+use std::{cell::RefCell, sync::Arc};
+
+use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
+
+use super::{GpuTask, GpuTaskExecutor};
+
+/// Executor that records every task into one command buffer until flushed
+///
+/// Lets code written against [`GpuTaskExecutor`] submit a whole sequence of passes at once
+/// instead of waiting on the GPU after each. Tasks are recorded when executed, so their
+/// constants and descriptor sets may change before the next one. Results must not be read
+/// back on the host before [`TaskBatch::flush`]; dropping the batch flushes it too.
+pub struct TaskBatch<'a, E: GpuTaskExecutor> {
+    executor: &'a E,
+    builder: RefCell<Option<AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>>>,
+}
+
+impl<'a, E: GpuTaskExecutor> TaskBatch<'a, E> {
+    pub fn new(executor: &'a E) -> Self {
+        Self {
+            executor,
+            builder: RefCell::new(None),
+        }
+    }
+
+    /// Submit everything recorded so far and wait for it
+    pub fn flush(&self) {
+        if let Some(builder) = self.builder.borrow_mut().take() {
+            self.executor.submit_batch(builder.build().unwrap());
+        }
+    }
+
+    fn record(&self, task: &dyn GpuTask) {
+        let mut builder = self.builder.borrow_mut();
+        task.record(builder.get_or_insert_with(|| self.executor.batch_builder()));
+    }
+}
+
+impl<E: GpuTaskExecutor> GpuTaskExecutor for TaskBatch<'_, E> {
+    fn execute(&self, task: &mut dyn GpuTask) {
+        self.record(task);
+    }
+
+    fn batch_builder(&self) -> AutoCommandBufferBuilder<PrimaryAutoCommandBuffer> {
+        self.executor.batch_builder()
+    }
+
+    /// Runs after the tasks recorded before it
+    fn submit_batch(&self, command_buffer: Arc<PrimaryAutoCommandBuffer>) {
+        self.flush();
+        self.executor.submit_batch(command_buffer);
+    }
+
+    fn execute_batch(&self, tasks: &[&dyn GpuTask]) {
+        for &task in tasks {
+            self.record(task);
+        }
+    }
+}
+
+impl<E: GpuTaskExecutor> Drop for TaskBatch<'_, E> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
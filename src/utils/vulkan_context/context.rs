@@ -6,13 +6,13 @@ use vulkano::{
         BufferUsage,
     },
     command_buffer::{
-        allocator::StandardCommandBufferAllocator, AutoCommandBufferBuilder, CommandBufferUsage,
-        PrimaryAutoCommandBuffer,
+        allocator::StandardCommandBufferAllocator, pool::CommandPoolResetFlags,
+        AutoCommandBufferBuilder, CommandBufferUsage, PrimaryAutoCommandBuffer,
     },
     descriptor_set::allocator::StandardDescriptorSetAllocator,
     device::{
         physical::PhysicalDeviceType, Device, DeviceCreateInfo, DeviceExtensions, DeviceFeatures,
-        Queue, QueueCreateInfo, QueueFlags,
+        Queue, QueueFlags,
     },
     instance::{Instance, InstanceCreateInfo},
     memory::allocator::{MemoryTypeFilter, StandardMemoryAllocator},
@@ -21,12 +21,14 @@ use vulkano::{
 };
 use winit::event_loop::EventLoop;
 
-use super::{traits::GpuTaskExecutor, GpuTask};
+use super::{scheduler, traits::GpuTaskExecutor, GpuTask};
 
-pub(crate) struct VulkanoBackend {
+pub struct VulkanoBackend {
     instance: Arc<Instance>,
     device: Arc<Device>,
     queue: Arc<Queue>,
+    /// Second queue from the same family, when the device exposes one
+    secondary_queue: Option<Arc<Queue>>,
     memory_allocator: Arc<StandardMemoryAllocator>,
     command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
     uniform_buffer_allocator: SubbufferAllocator,
@@ -36,7 +38,7 @@ pub(crate) struct VulkanoBackend {
 impl VulkanoBackend {
     pub fn new(event_loop: &EventLoop<()>) -> Self {
         let instance = get_vulkan_instance(event_loop);
-        let (device, queue) = get_device_and_queue(&instance, event_loop);
+        let (device, queue, secondary_queue) = get_device_and_queue(&instance, event_loop);
         let command_buffer_allocator = Arc::new(StandardCommandBufferAllocator::new(
             device.clone(),
             Default::default(),
@@ -60,6 +62,7 @@ impl VulkanoBackend {
             instance,
             device,
             queue,
+            secondary_queue,
             memory_allocator,
             command_buffer_allocator,
             uniform_buffer_allocator,
@@ -79,6 +82,9 @@ impl VulkanoBackend {
         &self.uniform_buffer_allocator
     }
 
+    /// Command buffers come from a per-thread pool inside the shared allocator, so builders can
+    /// be created from any thread without contention. A builder must finish recording on the
+    /// thread that created it.
     pub fn command_buffer_builder(&self) -> AutoCommandBufferBuilder<PrimaryAutoCommandBuffer> {
         AutoCommandBufferBuilder::primary(
             self.command_buffer_allocator.clone(),
@@ -88,6 +94,19 @@ impl VulkanoBackend {
         .unwrap()
     }
 
+    /// Reset the calling thread's command pool so its memory is reused by the next frame.
+    ///
+    /// Returns `false` when a command buffer allocated from the pool is still alive, in which
+    /// case the pool is left untouched.
+    pub fn reset_command_pools(&self) -> bool {
+        self.command_buffer_allocator
+            .try_reset_pool(
+                self.queue.queue_family_index(),
+                CommandPoolResetFlags::empty(),
+            )
+            .is_ok()
+    }
+
     pub fn descriptor_set_allocator(&self) -> &Arc<StandardDescriptorSetAllocator> {
         &self.descriptor_set_allocator
     }
@@ -104,6 +123,33 @@ impl GpuTaskExecutor for VulkanoBackend {
         let command_buffer = builder.build().unwrap();
         task.submit(command_buffer, &self.queue, &self.device);
     }
+
+    fn batch_builder(&self) -> AutoCommandBufferBuilder<PrimaryAutoCommandBuffer> {
+        self.command_buffer_builder()
+    }
+
+    fn submit_batch(&self, command_buffer: Arc<PrimaryAutoCommandBuffer>) {
+        scheduler::submit_and_wait(&self.device, &self.queue, command_buffer);
+    }
+
+    fn execute_concurrent(&self, first: &mut [&mut dyn GpuTask], second: &mut [&mut dyn GpuTask]) {
+        let Some(secondary_queue) = &self.secondary_queue else {
+            for task in first.iter_mut() {
+                self.execute(&mut **task);
+            }
+            for task in second.iter_mut() {
+                self.execute(&mut **task);
+            }
+            return;
+        };
+
+        scheduler::submit_groups(
+            &self.device,
+            (&self.queue, first),
+            (secondary_queue, second),
+            || self.command_buffer_builder(),
+        );
+    }
 }
 
 fn get_vulkan_instance(event_loop: &EventLoop<()>) -> Arc<Instance> {
@@ -123,7 +169,7 @@ fn get_vulkan_instance(event_loop: &EventLoop<()>) -> Arc<Instance> {
 fn get_device_and_queue(
     instance: &Arc<Instance>,
     event_loop: &EventLoop<()>,
-) -> (Arc<Device>, Arc<Queue>) {
+) -> (Arc<Device>, Arc<Queue>, Option<Arc<Queue>>) {
     let device_extensions = DeviceExtensions {
         khr_swapchain: true,
         ..DeviceExtensions::empty()
@@ -162,14 +208,12 @@ fn get_device_and_queue(
         physical_device.properties().device_type,
     );
 
+    let queue_create_info = scheduler::queue_create_info(&physical_device, queue_family_index);
     let (device, mut queues) = Device::new(
         physical_device,
         DeviceCreateInfo {
             enabled_extensions: device_extensions,
-            queue_create_infos: vec![QueueCreateInfo {
-                queue_family_index,
-                ..Default::default()
-            }],
+            queue_create_infos: vec![queue_create_info],
             enabled_features: DeviceFeatures {
                 shader_tessellation_and_geometry_point_size: true,
                 tessellation_shader: true,
@@ -180,6 +224,7 @@ fn get_device_and_queue(
     )
     .unwrap();
     let queue = queues.next().unwrap();
+    let secondary_queue = queues.next();
 
-    (device, queue)
+    (device, queue, secondary_queue)
 }
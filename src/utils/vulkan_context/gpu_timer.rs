@@ -0,0 +1,141 @@
+use std::{sync::Arc, time::Duration};
+
+use vulkano::{
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
+    device::{Device, Queue},
+    query::{QueryPool, QueryPoolCreateInfo, QueryResultFlags, QueryType},
+    sync::PipelineStage,
+};
+
+use super::{scheduler, GpuTask};
+
+/// Timestamp queries written between recorded tasks and read back once they ran
+///
+/// Unlike host timing around `execute`, the spans between timestamps hold only device
+/// time, so they stay meaningful when many tasks share one command buffer.
+pub(crate) struct GpuTimer {
+    query_pool: Arc<QueryPool>,
+    /// Nanoseconds per timestamp tick
+    timestamp_period: f32,
+}
+
+impl GpuTimer {
+    /// `None` when a queue of `device` cannot write timestamps
+    pub fn new(device: &Arc<Device>, query_count: u32) -> Option<Self> {
+        let physical_device = device.physical_device();
+        let queue_families = physical_device.queue_family_properties();
+        let supported = device.active_queue_family_indices().iter().all(|&index| {
+            queue_families[index as usize]
+                .timestamp_valid_bits
+                .is_some()
+        });
+        if !supported {
+            return None;
+        }
+
+        let query_pool = QueryPool::new(
+            device.clone(),
+            QueryPoolCreateInfo {
+                query_count,
+                ..QueryPoolCreateInfo::query_type(QueryType::Timestamp)
+            },
+        )
+        .unwrap();
+        Some(Self {
+            query_pool,
+            timestamp_period: physical_device.properties().timestamp_period,
+        })
+    }
+
+    /// Task that makes every query writable again, recorded before the first timestamp
+    pub fn reset(&self) -> ResetQueriesTask {
+        ResetQueriesTask {
+            query_pool: self.query_pool.clone(),
+        }
+    }
+
+    /// Task that writes `query` once every task recorded or submitted before it finished
+    pub fn timestamp(&self, query: u32) -> TimestampTask {
+        TimestampTask {
+            query_pool: self.query_pool.clone(),
+            query,
+        }
+    }
+
+    /// Device time of every query since the first, or `None` while any is unwritten
+    pub fn read_elapsed(&self) -> Option<Vec<Duration>> {
+        let mut ticks = vec![0u64; self.query_pool.query_count() as usize];
+        let available = self
+            .query_pool
+            .get_results(
+                0..self.query_pool.query_count(),
+                &mut ticks,
+                QueryResultFlags::empty(),
+            )
+            .ok()?;
+        if !available {
+            return None;
+        }
+        let start = *ticks.first()?;
+        Some(
+            ticks
+                .iter()
+                .map(|&tick| {
+                    let nanos = tick.wrapping_sub(start) as f64 * self.timestamp_period as f64;
+                    Duration::from_nanos(nanos as u64)
+                })
+                .collect(),
+        )
+    }
+}
+
+pub(crate) struct ResetQueriesTask {
+    query_pool: Arc<QueryPool>,
+}
+
+impl GpuTask for ResetQueriesTask {
+    fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        unsafe {
+            builder
+                .reset_query_pool(self.query_pool.clone(), 0..self.query_pool.query_count())
+                .unwrap();
+        }
+    }
+
+    fn submit(
+        &mut self,
+        command_buffer: Arc<PrimaryAutoCommandBuffer>,
+        queue: &Arc<Queue>,
+        device: &Arc<Device>,
+    ) {
+        scheduler::submit_and_wait(device, queue, command_buffer);
+    }
+}
+
+pub(crate) struct TimestampTask {
+    query_pool: Arc<QueryPool>,
+    query: u32,
+}
+
+impl GpuTask for TimestampTask {
+    fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>) {
+        unsafe {
+            builder
+                .write_timestamp(
+                    self.query_pool.clone(),
+                    self.query,
+                    PipelineStage::AllCommands,
+                )
+                .unwrap();
+        }
+    }
+
+    fn submit(
+        &mut self,
+        command_buffer: Arc<PrimaryAutoCommandBuffer>,
+        queue: &Arc<Queue>,
+        device: &Arc<Device>,
+    ) {
+        scheduler::submit_and_wait(device, queue, command_buffer);
+    }
+}
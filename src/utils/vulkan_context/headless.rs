@@ -6,14 +6,11 @@ use vulkano::{
         BufferUsage,
     },
     command_buffer::{
-        allocator::StandardCommandBufferAllocator, AutoCommandBufferBuilder, CommandBufferUsage,
-        PrimaryAutoCommandBuffer,
+        allocator::StandardCommandBufferAllocator, pool::CommandPoolResetFlags,
+        AutoCommandBufferBuilder, CommandBufferUsage, PrimaryAutoCommandBuffer,
     },
     descriptor_set::allocator::StandardDescriptorSetAllocator,
-    device::{
-        physical::PhysicalDeviceType, Device, DeviceCreateInfo, DeviceExtensions, Queue,
-        QueueCreateInfo,
-    },
+    device::{physical::PhysicalDeviceType, Device, DeviceCreateInfo, DeviceExtensions, Queue},
     instance::{
         debug::{
             DebugUtilsMessageSeverity, DebugUtilsMessageType, DebugUtilsMessenger,
@@ -25,24 +22,32 @@ use vulkano::{
     VulkanLibrary,
 };
 
-use super::{traits::GpuTaskExecutor, GpuTask};
+use super::{scheduler, traits::GpuTaskExecutor, GpuTask};
 
-pub(crate) struct VulkanoHeadlessBackend {
+pub struct VulkanoHeadlessBackend {
     instance: Arc<Instance>,
     _debug_messenger: Option<DebugUtilsMessenger>,
     device: Arc<Device>,
     queue: Arc<Queue>,
+    /// Second queue from the same family, when the device exposes one
+    secondary_queue: Option<Arc<Queue>>,
     memory_allocator: Arc<StandardMemoryAllocator>,
     command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
     uniform_buffer_allocator: SubbufferAllocator,
     descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
 }
 
+impl Default for VulkanoHeadlessBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VulkanoHeadlessBackend {
     pub fn new() -> Self {
         let instance = get_vulkan_instance();
         let _debug_messenger = get_debug_messenger(&instance);
-        let (device, queue) = get_device_and_queue(&instance);
+        let (device, queue, secondary_queue) = get_device_and_queue(&instance);
         let command_buffer_allocator = Arc::new(StandardCommandBufferAllocator::new(
             device.clone(),
             Default::default(),
@@ -66,6 +71,7 @@ impl VulkanoHeadlessBackend {
             _debug_messenger,
             device,
             queue,
+            secondary_queue,
             memory_allocator,
             command_buffer_allocator,
             uniform_buffer_allocator,
@@ -81,11 +87,13 @@ impl VulkanoHeadlessBackend {
         &self.device
     }
 
-    #[allow(unused)]
     pub fn uniform_buffer_allocator(&self) -> &SubbufferAllocator {
         &self.uniform_buffer_allocator
     }
 
+    /// Command buffers come from a per-thread pool inside the shared allocator, so builders can
+    /// be created from any thread without contention. A builder must finish recording on the
+    /// thread that created it.
     pub fn command_buffer_builder(&self) -> AutoCommandBufferBuilder<PrimaryAutoCommandBuffer> {
         AutoCommandBufferBuilder::primary(
             self.command_buffer_allocator.clone(),
@@ -95,6 +103,19 @@ impl VulkanoHeadlessBackend {
         .unwrap()
     }
 
+    /// Reset the calling thread's command pool so its memory is reused by the next frame.
+    ///
+    /// Returns `false` when a command buffer allocated from the pool is still alive, in which
+    /// case the pool is left untouched.
+    pub fn reset_command_pools(&self) -> bool {
+        self.command_buffer_allocator
+            .try_reset_pool(
+                self.queue.queue_family_index(),
+                CommandPoolResetFlags::empty(),
+            )
+            .is_ok()
+    }
+
     pub fn descriptor_set_allocator(&self) -> &Arc<StandardDescriptorSetAllocator> {
         &self.descriptor_set_allocator
     }
@@ -111,15 +132,48 @@ impl GpuTaskExecutor for VulkanoHeadlessBackend {
         let command_buffer = builder.build().unwrap();
         task.submit(command_buffer, &self.queue, &self.device);
     }
+
+    fn batch_builder(&self) -> AutoCommandBufferBuilder<PrimaryAutoCommandBuffer> {
+        self.command_buffer_builder()
+    }
+
+    fn submit_batch(&self, command_buffer: Arc<PrimaryAutoCommandBuffer>) {
+        scheduler::submit_and_wait(&self.device, &self.queue, command_buffer);
+    }
+
+    fn execute_concurrent(&self, first: &mut [&mut dyn GpuTask], second: &mut [&mut dyn GpuTask]) {
+        let Some(secondary_queue) = &self.secondary_queue else {
+            for task in first.iter_mut() {
+                self.execute(&mut **task);
+            }
+            for task in second.iter_mut() {
+                self.execute(&mut **task);
+            }
+            return;
+        };
+
+        scheduler::submit_groups(
+            &self.device,
+            (&self.queue, first),
+            (secondary_queue, second),
+            || self.command_buffer_builder(),
+        );
+    }
 }
 
 fn get_vulkan_instance() -> Arc<Instance> {
     let library = VulkanLibrary::new().unwrap();
+    // Validation is only available where the SDK is installed, which benchmark hosts may lack
     let extensions = InstanceExtensions {
-        ext_debug_utils: true,
+        ext_debug_utils: library.supported_extensions().ext_debug_utils,
         ..InstanceExtensions::empty()
     };
-    let layers = vec!["VK_LAYER_KHRONOS_validation".to_owned()];
+    let layers = library
+        .layer_properties()
+        .unwrap()
+        .filter(|layer| layer.name() == "VK_LAYER_KHRONOS_validation")
+        .map(|layer| layer.name().to_owned())
+        .collect();
 
     Instance::new(
         library,
@@ -184,7 +238,7 @@ fn get_debug_messenger(instance: &Arc<Instance>) -> Option<DebugUtilsMessenger>
     .ok()
 }
 
-fn get_device_and_queue(instance: &Arc<Instance>) -> (Arc<Device>, Arc<Queue>) {
+fn get_device_and_queue(instance: &Arc<Instance>) -> (Arc<Device>, Arc<Queue>, Option<Arc<Queue>>) {
     let device_extensions = DeviceExtensions {
         ..DeviceExtensions::empty()
     };
@@ -213,21 +267,20 @@ fn get_device_and_queue(instance: &Arc<Instance>) -> (Arc<Device>, Arc<Queue>) {
         physical_device.properties().device_type,
     );
 
+    let queue_create_info = scheduler::queue_create_info(&physical_device, queue_family_index);
     let (device, mut queues) = Device::new(
         physical_device,
         DeviceCreateInfo {
             enabled_extensions: device_extensions,
-            queue_create_infos: vec![QueueCreateInfo {
-                queue_family_index,
-                ..Default::default()
-            }],
+            queue_create_infos: vec![queue_create_info],
             ..Default::default()
         },
     )
     .expect("failed to create device");
     let queue = queues.next().unwrap();
+    let secondary_queue = queues.next();
 
-    (device, queue)
+    (device, queue, secondary_queue)
 }
 
 #[cfg(test)]
@@ -244,4 +297,34 @@ mod tests {
         assert!(Arc::strong_count(backend.descriptor_set_allocator()) > 0);
         assert!(Arc::strong_count(&backend.command_buffer_allocator) > 0);
     }
+
+    #[test]
+    fn test_reset_command_pools_after_many_submissions() {
+        use crate::core::{ParticleInitData, Particles};
+        use glam::Vec3;
+
+        let backend = VulkanoHeadlessBackend::new();
+        let mut particles = Particles::new(backend.memory_allocator());
+        particles.add_particles(
+            &[ParticleInitData {
+                position: Vec3::new(0.0, 1.0, 0.0),
+                velocity: Vec3::new(0.0, 0.0, 0.0),
+                ..Default::default()
+            }],
+            backend.memory_allocator(),
+            &backend,
+        );
+
+        // Every submission waits on its fence and drops its command buffer, so the pool must be
+        // recyclable after each frame instead of accumulating allocations.
+        for _ in 0..10 {
+            for _ in 0..100 {
+                particles.copy_position_to_predicted(&backend);
+            }
+            assert!(
+                backend.reset_command_pools(),
+                "command pool should be free to reset between frames"
+            );
+        }
+    }
 }
@@ -1,12 +1,15 @@
+mod batch;
 mod context;
+mod gpu_timer;
+mod scheduler;
 mod traits;
 
-#[cfg(test)]
 mod headless;
 
-pub(crate) use context::VulkanoBackend;
-pub(crate) use traits::{GpuTask, GpuTaskExecutor};
+pub use batch::TaskBatch;
+pub use context::VulkanoBackend;
+pub(crate) use gpu_timer::GpuTimer;
+pub(crate) use scheduler::submit_and_wait;
+pub use traits::{GpuTask, GpuTaskExecutor};
 
-#[allow(unused)]
-#[cfg(test)]
-pub(crate) use headless::VulkanoHeadlessBackend;
+pub use headless::VulkanoHeadlessBackend;
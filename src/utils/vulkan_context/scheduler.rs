@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use vulkano::{
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
+    device::{physical::PhysicalDevice, Device, Queue, QueueCreateInfo},
+    sync::{self, GpuFuture},
+};
+
+use super::GpuTask;
+
+/// Ask for up to two queues from `queue_family_index`, so independent task groups can be
+/// submitted side by side on devices that expose more than one.
+pub(super) fn queue_create_info(
+    physical_device: &PhysicalDevice,
+    queue_family_index: u32,
+) -> QueueCreateInfo {
+    let queue_count = physical_device.queue_family_properties()[queue_family_index as usize]
+        .queue_count
+        .min(2);
+
+    QueueCreateInfo {
+        queue_family_index,
+        queues: vec![0.5; queue_count as usize],
+        ..Default::default()
+    }
+}
+
+/// Submit `command_buffer` to `queue` and block until it has finished
+pub(crate) fn submit_and_wait(
+    device: &Arc<Device>,
+    queue: &Arc<Queue>,
+    command_buffer: Arc<PrimaryAutoCommandBuffer>,
+) {
+    sync::now(device.clone())
+        .then_execute(queue.clone(), command_buffer)
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+}
+
+/// Record each group into its own command buffer and submit them to separate queues.
+///
+/// Both submissions signal a semaphore and a single fence waits on the pair, so the groups
+/// overlap on the device while the call still returns only once both have finished. The tasks'
+/// own `submit` is bypassed, which is fine for compute passes and buffer copies that only
+/// execute and wait.
+pub(super) fn submit_groups(
+    device: &Arc<Device>,
+    first: (&Arc<Queue>, &mut [&mut dyn GpuTask]),
+    second: (&Arc<Queue>, &mut [&mut dyn GpuTask]),
+    command_buffer_builder: impl Fn() -> AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+) {
+    let submit = |queue: &Arc<Queue>, tasks: &[&mut dyn GpuTask]| {
+        let mut builder = command_buffer_builder();
+        for task in tasks {
+            task.record(&mut builder);
+        }
+        sync::now(device.clone())
+            .then_execute(queue.clone(), builder.build().unwrap())
+            .unwrap()
+            .then_signal_semaphore_and_flush()
+            .unwrap()
+    };
+
+    let first = submit(first.0, first.1);
+    let second = submit(second.0, second.1);
+    first
+        .join(second)
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+}
@@ -5,7 +5,7 @@ use vulkano::{
     device::{self, Queue},
 };
 
-pub(crate) trait GpuTask {
+pub trait GpuTask {
     fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>);
     fn submit(
         &mut self,
@@ -15,6 +15,41 @@ pub(crate) trait GpuTask {
     );
 }
 
-pub(crate) trait GpuTaskExecutor {
+pub trait GpuTaskExecutor {
     fn execute(&self, task: &mut dyn GpuTask);
+
+    /// Builder that batched tasks are recorded into
+    fn batch_builder(&self) -> AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>;
+
+    /// Submit a recorded batch and wait for it to finish
+    fn submit_batch(&self, command_buffer: Arc<PrimaryAutoCommandBuffer>);
+
+    /// Record `tasks` into one command buffer and wait once for all of them
+    ///
+    /// The builder tracks the buffers each task binds and inserts the pipeline barriers
+    /// between dependent tasks, e.g. a shader write followed by a shader read of the same
+    /// range, so no explicit barriers are recorded. The tasks' own
+    /// `submit` is bypassed, which is fine for compute passes and buffer copies that only
+    /// execute and wait. A task may appear more than once, e.g. for solver iterations.
+    fn execute_batch(&self, tasks: &[&dyn GpuTask]) {
+        if tasks.is_empty() {
+            return;
+        }
+        let mut builder = self.batch_builder();
+        for task in tasks {
+            task.record(&mut builder);
+        }
+        self.submit_batch(builder.build().unwrap());
+    }
+
+    /// Run two groups of tasks that touch disjoint buffers. Backends with a second queue
+    /// submit the groups side by side; the default runs them one after the other.
+    fn execute_concurrent(&self, first: &mut [&mut dyn GpuTask], second: &mut [&mut dyn GpuTask]) {
+        for task in first.iter_mut() {
+            self.execute(&mut **task);
+        }
+        for task in second.iter_mut() {
+            self.execute(&mut **task);
+        }
+    }
 }
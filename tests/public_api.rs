@@ -0,0 +1,38 @@
+//! Drives the solver through the library's public API only, as an embedding crate would
+
+use aqua_gpu::{HeadlessSimulation, ParticleInitData, SimulationConfig};
+use glam::{UVec3, Vec3};
+
+#[test]
+fn test_headless_steps_through_public_api() {
+    let config = SimulationConfig::default();
+    config.validate().unwrap();
+    let dt = config.max_time_step;
+    // A small block of resting particles above the floor of the default bounds
+    let initial = ParticleInitData::block(UVec3::splat(6), 0.05, Vec3::ZERO);
+    let mut simulation = HeadlessSimulation::new(config, &initial);
+
+    for _ in 0..5 {
+        simulation.step(dt);
+    }
+
+    let positions = simulation.positions();
+    assert_eq!(positions.len(), initial.len());
+    assert_eq!(simulation.particles().count() as usize, initial.len());
+
+    let aabb = simulation.config().simulation_aabb;
+    for position in &positions {
+        assert!(position.is_finite(), "non-finite position {}", position);
+        assert!(
+            position.cmpge(aabb.min()).all() && position.cmple(aabb.max()).all(),
+            "{} escaped the simulation bounds",
+            position
+        );
+    }
+
+    // Gravity pulls the block down
+    let mean_height =
+        |positions: &[Vec3]| positions.iter().map(|p| p.y).sum::<f32>() / positions.len() as f32;
+    let initial_positions = initial.iter().map(|p| p.position).collect::<Vec<_>>();
+    assert!(mean_height(&positions) < mean_height(&initial_positions));
+}